@@ -0,0 +1,835 @@
+use crate::btree::{
+    btree_header_offset, corrupt, read_page_number, BtreePage, BtreePageType, Level,
+    CELL_CONTENT_OFFSET, CELL_COUNT_OFFSET, MIN_CELL_SIZE, RIGHT_CHILD_OFFSET,
+};
+use crate::errors::SqliteResult;
+use crate::format::{put_varint, read_varint};
+use crate::storage::{Page, PageNumber, Pager};
+use bytes::Bytes;
+use std::ops::Range;
+
+/// The most siblings a balance spreads cells over, as in sqlite3
+const BALANCE_SIBLINGS: usize = 3;
+
+/// Write b-tree page `number` with `cells` packed at the end of the page, leaving no
+/// freeblocks or fragments, and keeping the database header on page 1.  Interior cells start
+/// with their left child.  In an auto-vacuum database the pointer map entries of the page's
+/// children and first overflow pages are pointed at it.
+pub(crate) fn write_btree_page<T: AsRef<[u8]>>(
+    pager: &mut Pager,
+    number: PageNumber,
+    page_type: BtreePageType,
+    cells: &[T],
+    right_child: Option<PageNumber>,
+) -> SqliteResult<()> {
+    let page_size: u32 = pager.page_size().into();
+    let mut data = vec![0u8; page_size as usize];
+    let header = btree_header_offset(number);
+    if header > 0 {
+        data[..header].copy_from_slice(&pager.get(number)?.data()[..header]);
+    }
+    data[header] = page_type as u8;
+    data[header + CELL_COUNT_OFFSET..header + CELL_COUNT_OFFSET + 2]
+        .copy_from_slice(&(cells.len() as u16).to_be_bytes());
+    if let Some(right_child) = right_child {
+        data[header + RIGHT_CHILD_OFFSET..header + RIGHT_CHILD_OFFSET + 4]
+            .copy_from_slice(&right_child.to_be_bytes());
+    }
+    let mut content = pager.usable_size();
+    for (i, cell) in cells.iter().enumerate() {
+        let cell = cell.as_ref();
+        content -= cell.len().max(MIN_CELL_SIZE);
+        data[content..content + cell.len()].copy_from_slice(cell);
+        let pointer = header + page_type.header_size() + i * 2;
+        data[pointer..pointer + 2].copy_from_slice(&(content as u16).to_be_bytes());
+    }
+    // 65536 wraps around to 0, which is how the header says it
+    data[header + CELL_CONTENT_OFFSET..header + CELL_CONTENT_OFFSET + 2]
+        .copy_from_slice(&(content as u16).to_be_bytes());
+    let data = Bytes::from(data);
+    pager.write(Page::new(number, data.clone()))?;
+    if pager.has_ptrmap() {
+        pager.put_child_ptrmaps(number, &data)?;
+    }
+    Ok(())
+}
+
+/// The space a cell takes on a page with its cell pointer
+fn cell_cost(cell: &[u8]) -> usize {
+    cell.len().max(MIN_CELL_SIZE) + 2
+}
+
+/// `body` as the cell of an interior page with left child `child`
+fn with_child(child: PageNumber, body: &[u8]) -> Bytes {
+    let mut cell = Vec::with_capacity(4 + body.len());
+    cell.extend_from_slice(&child.to_be_bytes());
+    cell.extend_from_slice(body);
+    Bytes::from(cell)
+}
+
+/// The rowid of a table leaf cell, which follows the payload size
+fn leaf_rowid(cell: &[u8]) -> SqliteResult<i64> {
+    read_varint(cell)
+        .and_then(|(_, len)| read_varint(&cell[len..]))
+        .map(|(rowid, _)| rowid as i64)
+        .ok_or_else(|| corrupt(String::from("table leaf cell ends in its rowid")))
+}
+
+fn interior_type(page_type: BtreePageType) -> BtreePageType {
+    if page_type.is_table() {
+        BtreePageType::TableInterior
+    } else {
+        BtreePageType::IndexInterior
+    }
+}
+
+/// A b-tree page being changed: the bytes of its cells in order, which on an interior page
+/// start with the left child
+#[derive(Clone, Debug)]
+struct Node {
+    number: PageNumber,
+    page_type: BtreePageType,
+    cells: Vec<Bytes>,
+    right_child: Option<PageNumber>,
+}
+
+impl Node {
+    fn new(page: &BtreePage) -> Node {
+        let cells = page
+            .cells()
+            .iter()
+            .map(|cell| page.data().slice(cell.offset..cell.offset + cell.size))
+            .collect();
+        Node {
+            number: page.number(),
+            page_type: page.page_type(),
+            cells,
+            right_child: page.header().right_child,
+        }
+    }
+
+    fn read(pager: &mut Pager, number: PageNumber) -> SqliteResult<Node> {
+        let data = pager.get(number)?.into_data();
+        Ok(Node::new(&BtreePage::decode(
+            number,
+            data,
+            &pager.payload_limits(),
+        )?))
+    }
+
+    fn fits(&self, usable_size: usize) -> bool {
+        let used: usize = self.cells.iter().map(|cell| cell_cost(cell)).sum();
+        btree_header_offset(self.number) + self.page_type.header_size() + used <= usable_size
+    }
+
+    /// The child left of cell `index`, or the right-most child after the last cell
+    fn child(&self, index: usize) -> PageNumber {
+        match self.cells.get(index) {
+            Some(cell) => read_page_number(cell, 0),
+            None => self.right_child.expect("interior pages have a right child"),
+        }
+    }
+
+    fn set_child(&mut self, index: usize, child: PageNumber) {
+        match self.cells.get_mut(index) {
+            Some(cell) => *cell = with_child(child, &cell[4..]),
+            None => self.right_child = Some(child),
+        }
+    }
+
+    fn write(&self, pager: &mut Pager) -> SqliteResult<()> {
+        write_btree_page(
+            pager,
+            self.number,
+            self.page_type,
+            &self.cells,
+            self.right_child,
+        )
+    }
+}
+
+/// Put `cell` on `page`, the end of `path`, at cell `index`, replacing the cell there if
+/// `replace` is set, then balance the tree as needed.  A replacement on an interior page
+/// keeps the left child of the cell it replaces.
+pub(crate) fn insert_cell(
+    pager: &mut Pager,
+    path: Vec<Level>,
+    page: &BtreePage,
+    index: usize,
+    replace: bool,
+    cell: Vec<u8>,
+) -> SqliteResult<()> {
+    let mut node = Node::new(page);
+    let appended = !replace && index == node.cells.len();
+    if replace {
+        node.cells[index] = if page.page_type().is_leaf() {
+            Bytes::from(cell)
+        } else {
+            with_child(node.child(index), &cell)
+        };
+    } else {
+        node.cells.insert(index, Bytes::from(cell));
+    }
+    settle(pager, path, node, appended)
+}
+
+/// Write `node`, balancing it with its siblings when it no longer fits and working up the
+/// tree for as long as that leaves a parent too full.  `appended` says the one cell the node
+/// gained went at the end of a leaf.
+fn settle(
+    pager: &mut Pager,
+    mut path: Vec<Level>,
+    mut node: Node,
+    mut appended: bool,
+) -> SqliteResult<()> {
+    let usable_size = pager.usable_size();
+    while !node.fits(usable_size) {
+        let Some(parent) = path.pop() else {
+            node = balance_deeper(pager, node)?;
+            continue;
+        };
+        let mut parent_node = Node::new(&parent.page);
+        let quick = appended
+            && node.page_type == BtreePageType::TableLeaf
+            && node.cells.len() > 1
+            && parent.index == parent_node.cells.len()
+            && parent_node.number != 1;
+        if quick {
+            balance_quick(pager, &mut parent_node, node)?;
+        } else {
+            balance_nonroot(pager, &mut parent_node, parent.index, node, path.is_empty())?;
+        }
+        node = parent_node;
+        appended = false;
+    }
+    node.write(pager)
+}
+
+/// The root is too full: move its cells down to a new page that becomes the root's only
+/// child, and balance that.  Returns the root, which the balance may have left too full.
+fn balance_deeper(pager: &mut Pager, root: Node) -> SqliteResult<Node> {
+    let child = Node {
+        number: pager.allocate()?.number(),
+        ..root
+    };
+    let mut root = Node {
+        number: root.number,
+        page_type: interior_type(child.page_type),
+        cells: Vec::new(),
+        right_child: Some(child.number),
+    };
+    balance_nonroot(pager, &mut root, 0, child, true)?;
+    Ok(root)
+}
+
+/// The common case of rows added in rowid order: the new cell at the end of the right-most
+/// leaf goes on a new leaf of its own, to the right of the full one, which is left as it was
+fn balance_quick(pager: &mut Pager, parent: &mut Node, mut leaf: Node) -> SqliteResult<()> {
+    let cell = leaf.cells.pop().expect("the leaf has the new cell");
+    let rowid = leaf_rowid(leaf.cells.last().expect("the leaf has other cells"))?;
+    let number = pager.allocate()?.number();
+    write_btree_page(pager, number, BtreePageType::TableLeaf, &[cell], None)?;
+    let mut divider = leaf.number.to_be_bytes().to_vec();
+    put_varint(&mut divider, rowid as u64);
+    parent.cells.push(Bytes::from(divider));
+    parent.right_child = Some(number);
+    Ok(())
+}
+
+/// Spread the cells of `child`, the child of `parent` left of cell `child_index`, and of up
+/// to two of its siblings over as many pages as they need, updating the dividers in
+/// `parent`, which is left for the caller to write.  When `parent` is the root and all its
+/// cells came down, and what it had fits back on it, the root takes the cells instead.
+fn balance_nonroot(
+    pager: &mut Pager,
+    parent: &mut Node,
+    child_index: usize,
+    child: Node,
+    parent_is_root: bool,
+) -> SqliteResult<()> {
+    let children = parent.cells.len() + 1;
+    let first = match child_index {
+        0 => 0,
+        i if i + 1 == children => i.saturating_sub(BALANCE_SIBLINGS - 1),
+        i => i - 1,
+    };
+    let count = (children - first).min(BALANCE_SIBLINGS);
+    let page_type = child.page_type;
+    let mut child = Some(child);
+    let mut siblings = Vec::with_capacity(count);
+    for i in first..first + count {
+        let sibling = match child.take_if(|_| i == child_index) {
+            Some(child) => child,
+            None => Node::read(pager, parent.child(i))?,
+        };
+        if sibling.page_type != page_type {
+            return Err(corrupt(format!(
+                "b-tree page {} is a {:?} page but its sibling is a {:?} page",
+                sibling.number, sibling.page_type, page_type
+            )));
+        }
+        siblings.push(sibling);
+    }
+
+    // gather the cells in order with the dividers between the siblings, which in a table
+    // leaf are copies of a rowid and drop out
+    let table_leaf = page_type == BtreePageType::TableLeaf;
+    let leaf = page_type.is_leaf();
+    let right_child = siblings.last().and_then(|sibling| sibling.right_child);
+    let mut numbers = Vec::with_capacity(count);
+    let mut cells = Vec::new();
+    for (i, sibling) in siblings.into_iter().enumerate() {
+        numbers.push(sibling.number);
+        cells.extend(sibling.cells);
+        if i + 1 < count && !table_leaf {
+            let divider = &parent.cells[first + i];
+            cells.push(match sibling.right_child {
+                Some(child) => with_child(child, &divider[4..]),
+                None => divider.slice(4..),
+            });
+        }
+    }
+    parent.cells.drain(first..first + count - 1);
+
+    let usable_size = pager.usable_size();
+    let runs = distribute(&cells, usable_size - page_type.header_size(), !table_leaf);
+    if parent_is_root && parent.cells.is_empty() && runs.len() == 1 {
+        let root = Node {
+            number: parent.number,
+            page_type,
+            cells: cells.clone(),
+            right_child,
+        };
+        if root.fits(usable_size) {
+            for number in numbers {
+                pager.free_page(number)?;
+            }
+            *parent = root;
+            return Ok(());
+        }
+    }
+
+    while numbers.len() < runs.len() {
+        numbers.push(pager.allocate()?.number());
+    }
+    for number in numbers.split_off(runs.len()) {
+        pager.free_page(number)?;
+    }
+    // like sqlite3, keep the pages in key order in the file where it can
+    numbers.sort_unstable();
+
+    let mut dividers = Vec::with_capacity(runs.len() - 1);
+    for (i, run) in runs.iter().enumerate() {
+        let mut node = Node {
+            number: numbers[i],
+            page_type,
+            cells: cells[run.clone()].to_vec(),
+            right_child: None,
+        };
+        if i + 1 == runs.len() {
+            node.right_child = right_child;
+        } else if table_leaf {
+            let rowid = leaf_rowid(node.cells.last().expect("runs are not empty"))?;
+            let mut divider = node.number.to_be_bytes().to_vec();
+            put_varint(&mut divider, rowid as u64);
+            dividers.push(Bytes::from(divider));
+        } else if leaf {
+            dividers.push(with_child(node.number, &cells[run.end]));
+        } else {
+            // the divider's left child becomes the right-most child of the page before it
+            let divider = &cells[run.end];
+            node.right_child = Some(read_page_number(divider, 0));
+            dividers.push(with_child(node.number, &divider[4..]));
+        }
+        node.write(pager)?;
+    }
+    parent.set_child(first, *numbers.last().expect("there is a page"));
+    parent.cells.splice(first..first, dividers);
+    Ok(())
+}
+
+/// Split `cells` into runs that each fit in `capacity` bytes of a page.  With `dividers` set
+/// the cell after each run but the last goes up to the parent instead of onto a page.  As in
+/// sqlite3, pages are filled left to right, then cells move right for as long as that leaves
+/// each page no fuller than the one before it, so the last page is not left nearly empty.
+fn distribute(cells: &[Bytes], capacity: usize, dividers: bool) -> Vec<Range<usize>> {
+    let costs: Vec<usize> = cells.iter().map(|cell| cell_cost(cell)).collect();
+    let used = |run: &Range<usize>| costs[run.clone()].iter().sum::<usize>();
+    let mut runs = Vec::new();
+    let mut start = 0;
+    loop {
+        let mut end = start;
+        let mut run_used = 0;
+        while end < cells.len() && (end == start || run_used + costs[end] <= capacity) {
+            run_used += costs[end];
+            end += 1;
+        }
+        runs.push(start..end);
+        if end == cells.len() {
+            break;
+        }
+        start = if dividers { end + 1 } else { end };
+        if start == cells.len() {
+            runs.push(start..start);
+            break;
+        }
+    }
+
+    for i in (1..runs.len()).rev() {
+        loop {
+            let (left, right) = (runs[i - 1].clone(), runs[i].clone());
+            if left.len() < 2 {
+                break;
+            }
+            // the last cell of the left page, or the divider after it, moves right
+            let incoming = costs[if dividers { left.end } else { left.end - 1 }];
+            let (left_used, right_used) = (used(&left), used(&right));
+            if right_used + incoming > capacity
+                || (!right.is_empty() && right_used + incoming > left_used - costs[left.end - 1])
+            {
+                break;
+            }
+            runs[i - 1].end -= 1;
+            runs[i].start -= 1;
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::{IndexCursor, TableCursor};
+    use crate::database::{PageSize, SchemaFormat};
+    use crate::format::{Collation, KeyColumn, KeyInfo, Record, SortOrder, Value};
+    use crate::storage::{memory_pager, pending_byte_page, AutoVacuum, PtrmapType};
+    use std::cmp::Ordering;
+    use std::collections::{BTreeMap, HashSet};
+
+    /// A new empty table or index
+    fn create_btree(pager: &mut Pager, table: bool) -> PageNumber {
+        let root = pager.allocate_root_page().unwrap().number();
+        let page_type = if table {
+            BtreePageType::TableLeaf
+        } else {
+            BtreePageType::IndexLeaf
+        };
+        write_btree_page::<Bytes>(pager, root, page_type, &[], None).unwrap();
+        root
+    }
+
+    /// A simple random number generator, so the tests need no seed handling
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    fn payload(rowid: i64, size: usize) -> Vec<u8> {
+        let mut payload = rowid.to_be_bytes().to_vec();
+        payload.resize(size.max(8), rowid as u8);
+        payload
+    }
+
+    fn random_size(random: &mut Random) -> usize {
+        match random.next() % 20 {
+            0 => 3000,
+            1 => 600,
+            _ => (random.next() % 60) as usize,
+        }
+    }
+
+    /// What `check_btree` found
+    #[derive(Debug, Default)]
+    struct Shape {
+        depth: usize,
+        btree_pages: usize,
+        overflow_pages: usize,
+        entries: usize,
+    }
+
+    /// Check that the tree rooted at `root` is well formed, as sqlite3's integrity check
+    /// would: every page decodes, keys are in order within and across pages, leaves are all
+    /// at the same depth, only the root can be empty, no page is used twice, and in an
+    /// auto-vacuum database the pointer map agrees
+    fn check_btree(
+        pager: &mut Pager,
+        root: PageNumber,
+        key_info: Option<&KeyInfo>,
+        seen: &mut HashSet<PageNumber>,
+    ) -> Shape {
+        let mut shape = Shape::default();
+        let mut previous: Option<Bytes> = None;
+        let mut previous_rowid = None;
+        let mut leaf_depth = None;
+        let mut stack = vec![(root, 0usize, None::<i64>, None::<i64>)];
+        let mut order = Vec::new();
+        // depth first, left to right, checking bounds from the dividers above
+        while let Some((number, depth, low, high)) = stack.pop() {
+            assert!(seen.insert(number), "page {} is used twice", number);
+            let data = pager.get(number).unwrap().into_data();
+            let page = BtreePage::decode(number, data, &pager.payload_limits()).unwrap();
+            shape.btree_pages += 1;
+            if number != root {
+                assert!(!page.cells().is_empty(), "page {} is empty", number);
+            }
+            for cell in page.cells() {
+                if let Some(rowid) = cell.rowid {
+                    assert!(low.is_none_or(|low| rowid > low));
+                    assert!(high.is_none_or(|high| rowid <= high));
+                }
+                if let Some(first) = cell.overflow {
+                    let mut next = first;
+                    let mut parent = number;
+                    let mut ptrmap_type = PtrmapType::Overflow1;
+                    while next != 0 {
+                        assert!(seen.insert(next), "page {} is used twice", next);
+                        if pager.has_ptrmap() {
+                            assert_eq!(pager.ptrmap_get(next).unwrap(), (ptrmap_type, parent));
+                        }
+                        shape.overflow_pages += 1;
+                        parent = next;
+                        ptrmap_type = PtrmapType::Overflow2;
+                        next = read_page_number(pager.get(next).unwrap().data(), 0);
+                    }
+                }
+            }
+            if page.page_type().is_leaf() {
+                assert_eq!(*leaf_depth.get_or_insert(depth), depth, "page {}", number);
+                shape.depth = depth + 1;
+            }
+            order.push(number);
+            let mut children = Vec::new();
+            let mut child_low = low;
+            for cell in page.cells() {
+                if let Some(child) = cell.left_child {
+                    children.push((child, depth + 1, child_low, cell.rowid.or(high)));
+                    child_low = cell.rowid;
+                }
+            }
+            if let Some(child) = page.header().right_child {
+                children.push((child, depth + 1, child_low, high));
+            }
+            for (child, ..) in &children {
+                if pager.has_ptrmap() {
+                    assert_eq!(
+                        pager.ptrmap_get(*child).unwrap(),
+                        (PtrmapType::Btree, number)
+                    );
+                }
+            }
+            stack.extend(children.into_iter().rev());
+        }
+
+        // every entry in order, through a cursor
+        match key_info {
+            None => {
+                let mut cursor = TableCursor::new(root);
+                let mut more = cursor.first(pager).unwrap();
+                while more {
+                    let rowid = cursor.rowid().unwrap();
+                    assert!(previous_rowid.is_none_or(|previous| previous < rowid));
+                    previous_rowid = Some(rowid);
+                    shape.entries += 1;
+                    more = cursor.next(pager).unwrap();
+                }
+            }
+            Some(key_info) => {
+                let mut cursor = IndexCursor::new(root, key_info.clone());
+                let mut more = cursor.first(pager).unwrap();
+                while more {
+                    let entry = cursor.payload(pager).unwrap().unwrap();
+                    if let Some(previous) = previous {
+                        let key: Vec<Value> =
+                            Record::decode(entry.clone()).unwrap().values().collect();
+                        let previous = Record::decode(previous).unwrap();
+                        assert_eq!(key_info.compare(&previous, &key), Ordering::Less);
+                    }
+                    previous = Some(entry);
+                    shape.entries += 1;
+                    more = cursor.next(pager).unwrap();
+                }
+            }
+        }
+        shape
+    }
+
+    /// Check every page of the database is in a tree, on the freelist, a pointer map page or
+    /// the never used page at the pending byte
+    fn check_pages(pager: &mut Pager, seen: &HashSet<PageNumber>) {
+        let stats = pager.freelist_stats().unwrap();
+        let ptrmap_pages = if pager.has_ptrmap() {
+            (2..=pager.page_count())
+                .filter(|number| pager.ptrmap().is_map_page(*number))
+                .count() as u32
+        } else {
+            0
+        };
+        let pending_byte_pages =
+            (pending_byte_page(pager.page_size().into()) <= pager.page_count()) as u32;
+        assert_eq!(
+            seen.len() as u32
+                + stats.trunk_pages
+                + stats.leaf_pages
+                + ptrmap_pages
+                + pending_byte_pages,
+            pager.page_count()
+        );
+    }
+
+    #[test]
+    fn distributes_evenly() {
+        let cells: Vec<Bytes> = (0..10).map(|_| Bytes::from(vec![0u8; 98])).collect();
+        // greedy filling would leave 4, 4 and 2
+        assert_eq!(distribute(&cells, 400, false), vec![0..4, 4..7, 7..10]);
+        assert_eq!(distribute(&cells, 400, true), vec![0..3, 4..7, 8..10]);
+        assert_eq!(distribute(&cells[..9], 400, true), vec![0..4, 5..9]);
+        assert_eq!(distribute(&cells[..4], 400, false), vec![0..4]);
+        // a divider that would be last moves down to the page after it
+        assert_eq!(distribute(&cells[..5], 400, true), vec![0..2, 3..5]);
+    }
+
+    #[test]
+    fn table_inserts() {
+        let mut pager = memory_pager(PageSize::Size512, AutoVacuum::None);
+        let root = create_btree(&mut pager, true);
+        let mut cursor = TableCursor::new(root);
+        let mut random = Random(0x2545f4914f6cdd1d);
+        let mut rows = BTreeMap::new();
+        for _ in 0..5000 {
+            let rowid = (random.next() % 20000) as i64 - 10000;
+            let payload = payload(rowid, random_size(&mut random));
+            cursor.insert(&mut pager, rowid, &payload).unwrap();
+            rows.insert(rowid, payload);
+        }
+        pager.commit().unwrap();
+        assert_eq!(pager.header().size_in_pages(), pager.page_count());
+
+        let mut seen = HashSet::new();
+        let shape = check_btree(&mut pager, root, None, &mut seen);
+        check_btree(&mut pager, 1, None, &mut seen);
+        check_pages(&mut pager, &seen);
+        assert_eq!(shape.entries, rows.len());
+        assert!(shape.depth >= 3);
+        // replaced rows gave back their overflow pages
+        assert!(pager.freelist_stats().unwrap().leaf_pages > 0);
+
+        let mut more = cursor.first(&mut pager).unwrap();
+        for (rowid, payload) in &rows {
+            assert!(more);
+            assert_eq!(cursor.rowid(), Some(*rowid));
+            assert_eq!(
+                cursor.payload(&mut pager).unwrap().unwrap(),
+                payload.as_slice()
+            );
+            more = cursor.next(&mut pager).unwrap();
+        }
+        assert!(!more);
+    }
+
+    #[test]
+    fn appends_fill_pages() {
+        let mut pager = memory_pager(PageSize::Size1024, AutoVacuum::None);
+        let root = create_btree(&mut pager, true);
+        let mut cursor = TableCursor::new(root);
+        for rowid in 1..=10_000 {
+            cursor.insert(&mut pager, rowid, &[7u8; 40]).unwrap();
+        }
+        let mut seen = HashSet::new();
+        let shape = check_btree(&mut pager, root, None, &mut seen);
+        assert_eq!(shape.entries, 10_000);
+        // each leaf but the last is full: 43-byte cells, 22 to a 1024-byte page
+        let leaves = 10_000usize.div_ceil(22);
+        assert!(shape.btree_pages < leaves + leaves / 50 + 3, "{:?}", shape);
+    }
+
+    #[test]
+    fn schema_table_on_page_one() {
+        let mut pager = memory_pager(PageSize::Size512, AutoVacuum::None);
+        let mut cursor = TableCursor::new(1);
+        for rowid in (1..=300).rev() {
+            cursor
+                .insert(&mut pager, rowid, &payload(rowid, 50))
+                .unwrap();
+        }
+        pager.commit().unwrap();
+        let mut seen = HashSet::new();
+        let shape = check_btree(&mut pager, 1, None, &mut seen);
+        check_pages(&mut pager, &seen);
+        assert_eq!(shape.entries, 300);
+        assert!(shape.depth >= 2);
+        assert_eq!(
+            pager.header().page_size(),
+            PageSize::try_from(512u32).unwrap()
+        );
+    }
+
+    fn key_info() -> KeyInfo {
+        KeyInfo::new(
+            vec![KeyColumn {
+                collation: Collation::NoCase,
+                order: SortOrder::Desc,
+            }],
+            SchemaFormat::V4,
+        )
+    }
+
+    fn index_key(random: &mut Random, rowid: i64) -> Record {
+        let size = random_size(random) + 1;
+        let text: Vec<u8> = (0..size)
+            .map(|i| b"aBcD"[(random.next() as usize + i) % 4])
+            .collect();
+        Record::encode(
+            &[Value::Text(Bytes::from(text)), Value::Integer(rowid)],
+            SchemaFormat::V4,
+        )
+    }
+
+    #[test]
+    fn index_inserts() {
+        for auto_vacuum in [AutoVacuum::None, AutoVacuum::Full] {
+            let mut pager = memory_pager(PageSize::Size512, auto_vacuum);
+            let root = create_btree(&mut pager, false);
+            let key_info = key_info();
+            let mut cursor = IndexCursor::new(root, key_info.clone());
+            let mut random = Random(88172645463325252);
+            let mut keys = Vec::new();
+            for rowid in 0..3000 {
+                let key = index_key(&mut random, rowid);
+                cursor.insert(&mut pager, &key).unwrap();
+                keys.push(key);
+            }
+            // the same keys again replace the entries, interior ones included
+            for key in keys.iter().step_by(3) {
+                cursor.insert(&mut pager, key).unwrap();
+            }
+            pager.commit().unwrap();
+
+            let mut seen = HashSet::new();
+            let shape = check_btree(&mut pager, root, Some(&key_info), &mut seen);
+            check_btree(&mut pager, 1, None, &mut seen);
+            check_pages(&mut pager, &seen);
+            assert_eq!(shape.entries, 3000);
+            assert!(shape.depth >= 3);
+            assert!(shape.overflow_pages > 0);
+
+            keys.sort_by(|a, b| {
+                let b: Vec<Value> = b.values().collect();
+                key_info.compare(a, &b)
+            });
+            let mut more = cursor.first(&mut pager).unwrap();
+            for key in &keys {
+                assert!(more);
+                assert_eq!(cursor.record(&mut pager).unwrap().as_ref(), Some(key));
+                more = cursor.next(&mut pager).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn inserts_roll_back() {
+        let mut pager = memory_pager(PageSize::Size512, AutoVacuum::None);
+        let root = create_btree(&mut pager, true);
+        let mut cursor = TableCursor::new(root);
+        for rowid in 0..500 {
+            cursor
+                .insert(&mut pager, rowid, &payload(rowid, 30))
+                .unwrap();
+        }
+        pager.commit().unwrap();
+        let page_count = pager.page_count();
+        for rowid in 500..2000 {
+            cursor
+                .insert(&mut pager, rowid, &payload(rowid, 30))
+                .unwrap();
+        }
+        pager.rollback().unwrap();
+        assert_eq!(pager.page_count(), page_count);
+        let mut seen = HashSet::new();
+        assert_eq!(check_btree(&mut pager, root, None, &mut seen).entries, 500);
+    }
+
+    #[test]
+    fn corrupt_sibling_err() {
+        let mut pager = memory_pager(PageSize::Size512, AutoVacuum::None);
+        let root = create_btree(&mut pager, true);
+        let mut cursor = TableCursor::new(root);
+        for rowid in 0..200 {
+            cursor
+                .insert(&mut pager, rowid, &payload(rowid, 30))
+                .unwrap();
+        }
+        // make the first leaf an index page, which the next split next to it finds
+        let data = pager.get(root).unwrap().into_data();
+        let page = BtreePage::decode(root, data, &pager.payload_limits()).unwrap();
+        let leaf = page.cells()[0].left_child.unwrap();
+        let mut data = pager.get(leaf).unwrap().data().to_vec();
+        data[0] = BtreePageType::IndexLeaf as u8;
+        pager.write(Page::new(leaf, Bytes::from(data))).unwrap();
+        let result = (0..100).try_for_each(|i| cursor.insert(&mut pager, i, &[0u8; 200]));
+        assert!(matches!(result, Err(crate::SqliteError::Corrupt { .. })));
+    }
+
+    /// Insert `count` rows with random rowids into a table and as many random entries into an
+    /// index, committing along the way, then check both trees and every page of the database
+    fn random_inserts(seed: u64, page_size: PageSize, count: usize) {
+        let mut pager = memory_pager(page_size, AutoVacuum::None);
+        let table = create_btree(&mut pager, true);
+        let index = create_btree(&mut pager, false);
+        let key_info = key_info();
+        let mut rows = TableCursor::new(table);
+        let mut keys = IndexCursor::new(index, key_info.clone());
+        let mut random = Random(seed);
+        let mut rowids = HashSet::new();
+        for i in 0..count {
+            // some rowids come up twice and replace their row
+            let rowid = (random.next() % (count as u64 * 4)) as i64;
+            rowids.insert(rowid);
+            let size = if random.next() % 100 == 0 { 5000 } else { 30 };
+            rows.insert(&mut pager, rowid, &payload(rowid, size))
+                .unwrap();
+            let key = index_key(&mut random, i as i64);
+            keys.insert(&mut pager, &key).unwrap();
+            if i % (count / 10).max(1) == 0 {
+                pager.commit().unwrap();
+            }
+        }
+        pager.commit().unwrap();
+
+        let mut seen = HashSet::new();
+        let shape = check_btree(&mut pager, table, None, &mut seen);
+        assert_eq!(shape.entries, rowids.len());
+        let shape = check_btree(&mut pager, index, Some(&key_info), &mut seen);
+        assert_eq!(shape.entries, count);
+        check_btree(&mut pager, 1, None, &mut seen);
+        check_pages(&mut pager, &seen);
+        assert_eq!(pager.header().size_in_pages(), pager.page_count());
+    }
+
+    #[test]
+    fn random_inserts_512() {
+        random_inserts(0x9e3779b97f4a7c15, PageSize::Size512, 50_000);
+    }
+
+    #[test]
+    fn random_inserts_1024() {
+        random_inserts(0x2545f4914f6cdd1d, PageSize::Size1024, 50_000);
+    }
+
+    #[test]
+    fn random_inserts_4096() {
+        random_inserts(88172645463325252, PageSize::Size4096, 50_000);
+    }
+
+    /// Millions of keys take a few minutes.  Run with `cargo test --release -- --ignored`.
+    #[test]
+    #[ignore]
+    fn millions_of_random_keys() {
+        random_inserts(0x853c49e6748fea9b, PageSize::Size4096, 4_000_000);
+    }
+}
@@ -0,0 +1,688 @@
+use crate::btree::{insert_cell, BtreePage, BtreePageType, Cell};
+use crate::errors::{ExtendedResultCode, SqliteResult};
+use crate::format::{put_varint, Record};
+use crate::storage::{PageNumber, Pager};
+use crate::SqliteError;
+use bytes::Bytes;
+use std::cmp::Ordering;
+
+/// The deepest a b-tree goes, as in sqlite3.  A deeper one, or one whose pages loop, is
+/// corrupt.
+pub(crate) const MAX_BTREE_DEPTH: usize = 20;
+
+pub(crate) fn corrupt(message: String) -> SqliteError {
+    SqliteError::Corrupt {
+        code: ExtendedResultCode::Corrupt,
+        message,
+    }
+}
+
+/// A page on the path from the root to a cursor's row, with the cell the cursor is at on a
+/// leaf, or the child it went down to on an interior page, the right-most child coming after
+/// the last cell
+#[derive(Clone, Debug)]
+pub(crate) struct Level {
+    pub(crate) page: BtreePage,
+    pub(crate) index: usize,
+}
+
+impl Level {
+    pub(crate) fn child(&self) -> PageNumber {
+        match self.page.cells().get(self.index) {
+            Some(cell) => cell.left_child.expect("interior cells have a left child"),
+            None => self
+                .page
+                .header()
+                .right_child
+                .expect("interior pages have a right child"),
+        }
+    }
+
+    pub(crate) fn cell(&self) -> &Cell {
+        &self.page.cells()[self.index]
+    }
+}
+
+/// Page `number` of the b-tree rooted at page `root`, `depth` levels below the root, which
+/// must be a table page or an index page as `table` says
+pub(crate) fn tree_page(
+    pager: &mut Pager,
+    root: PageNumber,
+    depth: usize,
+    number: PageNumber,
+    table: bool,
+) -> SqliteResult<BtreePage> {
+    let kind = if table { "table" } else { "index" };
+    if depth >= MAX_BTREE_DEPTH {
+        return Err(corrupt(format!(
+            "{} b-tree rooted at page {} is more than {} levels deep",
+            kind, root, MAX_BTREE_DEPTH
+        )));
+    }
+    let data = pager.get(number)?.into_data();
+    let page = BtreePage::decode(number, data, &pager.payload_limits())?;
+    if page.page_type().is_table() != table {
+        return Err(corrupt(format!(
+            "page {} of the {} b-tree rooted at page {} is not a {} page",
+            number, kind, root, kind
+        )));
+    }
+    Ok(page)
+}
+
+/// The path from the root of a b-tree down to the cell a cursor is on, and the stepping
+/// along it that table and index cursors share.
+///
+/// In a table only the cells of leaves are rows, so the path always ends on a leaf.  In an
+/// index the cells of interior pages are entries too, and the path can end on one.
+#[derive(Debug)]
+pub(crate) struct BtreePath {
+    root: PageNumber,
+    table: bool,
+    pub(crate) levels: Vec<Level>,
+    /// The pager's change count when the path was read
+    change_count: u64,
+    /// Where the cursor is relative to its place after finding it again: `Greater` on the
+    /// row or entry after it, or `Less` past the end when nothing comes after it
+    moved: Ordering,
+}
+
+impl BtreePath {
+    pub(crate) fn new(root: PageNumber, table: bool) -> BtreePath {
+        BtreePath {
+            root,
+            table,
+            levels: Vec::new(),
+            change_count: 0,
+            moved: Ordering::Equal,
+        }
+    }
+
+    pub(crate) fn root(&self) -> PageNumber {
+        self.root
+    }
+
+    /// Whether the path leads to a cell
+    pub(crate) fn is_valid(&self) -> bool {
+        !self.levels.is_empty()
+    }
+
+    /// The page and cell the path ends on
+    pub(crate) fn last(&self) -> Option<&Level> {
+        self.levels.last()
+    }
+
+    /// Whether the cursor is on its own row or entry rather than one it moved to when it
+    /// found its place again
+    pub(crate) fn in_place(&self) -> bool {
+        self.moved == Ordering::Equal
+    }
+
+    /// Start again from the root, on no cell, with the tree as it is now
+    pub(crate) fn reset(&mut self, pager: &Pager) {
+        self.levels.clear();
+        self.change_count = pager.change_count();
+        self.moved = Ordering::Equal;
+    }
+
+    pub(crate) fn invalidate_on_err<T>(&mut self, result: SqliteResult<T>) -> SqliteResult<T> {
+        if result.is_err() {
+            self.levels.clear();
+        }
+        result
+    }
+
+    /// Whether the tree may have changed since the path was read
+    pub(crate) fn is_stale(&self, pager: &Pager) -> bool {
+        pager.change_count() != self.change_count
+    }
+
+    /// Take where the cursor was relative to its place, before seeking back to it
+    pub(crate) fn take_moved(&mut self) -> Ordering {
+        std::mem::replace(&mut self.moved, Ordering::Equal)
+    }
+
+    /// Record how the seek back compared with the cursor's old place.  A cursor that had
+    /// already moved on stays ahead of the place it was at.
+    pub(crate) fn set_found(&mut self, moved: Ordering, found: Ordering) {
+        self.moved = if found == Ordering::Equal {
+            moved
+        } else {
+            found
+        };
+    }
+
+    /// Take the interior pages of the path, leaving it on no cell
+    pub(crate) fn take_levels(&mut self) -> Vec<Level> {
+        std::mem::take(&mut self.levels)
+    }
+
+    /// Page `number` of the tree, one level below the end of the path
+    pub(crate) fn page(&self, pager: &mut Pager, number: PageNumber) -> SqliteResult<BtreePage> {
+        tree_page(pager, self.root, self.levels.len(), number, self.table)
+    }
+
+    /// Add an interior page to the path, going down to its child left of cell `index`, and
+    /// return the child
+    pub(crate) fn push_interior(&mut self, page: BtreePage, index: usize) -> PageNumber {
+        let level = Level { page, index };
+        let child = level.child();
+        self.levels.push(level);
+        child
+    }
+
+    /// Add the leaf page at the end of the path, on cell `index`.  Only the root of an empty
+    /// tree is a leaf without cells.
+    pub(crate) fn push_leaf(&mut self, page: BtreePage, index: usize) -> SqliteResult<bool> {
+        let cells = page.cells().len();
+        if cells == 0 {
+            if self.levels.is_empty() {
+                return Ok(false);
+            }
+            return Err(corrupt(format!(
+                "{} b-tree leaf page {} has no cells",
+                if self.table { "table" } else { "index" },
+                page.number()
+            )));
+        }
+        self.levels.push(Level {
+            page,
+            index: index.min(cells - 1),
+        });
+        Ok(true)
+    }
+
+    /// Move to the first cell of the tree, returning false if it is empty
+    pub(crate) fn first(&mut self, pager: &mut Pager) -> SqliteResult<bool> {
+        self.reset(pager);
+        let result = self.descend(pager, self.root, false);
+        self.invalidate_on_err(result)
+    }
+
+    /// Move to the last cell of the tree, returning false if it is empty
+    pub(crate) fn last_cell(&mut self, pager: &mut Pager) -> SqliteResult<bool> {
+        self.reset(pager);
+        let result = self.descend(pager, self.root, true);
+        self.invalidate_on_err(result)
+    }
+
+    /// Move to the next cell, or to the one the cursor found its place on
+    pub(crate) fn next(&mut self, pager: &mut Pager) -> SqliteResult<bool> {
+        match self.take_moved() {
+            Ordering::Greater => return Ok(true),
+            Ordering::Less => return Ok(false),
+            Ordering::Equal if self.levels.is_empty() => return Ok(false),
+            Ordering::Equal => {}
+        }
+        let result = self.step_next(pager);
+        self.invalidate_on_err(result)
+    }
+
+    /// Move to the previous cell, or to the last one if the cursor found its place past
+    /// the end
+    pub(crate) fn previous(&mut self, pager: &mut Pager) -> SqliteResult<bool> {
+        match self.take_moved() {
+            Ordering::Less => return self.last_cell(pager),
+            _ if self.levels.is_empty() => return Ok(false),
+            _ => {}
+        }
+        let result = self.step_previous(pager);
+        self.invalidate_on_err(result)
+    }
+
+    /// Go down from page `number` to the first cell under it, or the last
+    pub(crate) fn descend(
+        &mut self,
+        pager: &mut Pager,
+        number: PageNumber,
+        last: bool,
+    ) -> SqliteResult<bool> {
+        let mut number = number;
+        loop {
+            let page = self.page(pager, number)?;
+            let index = if last { page.cells().len() } else { 0 };
+            if page.page_type().is_leaf() {
+                return self.push_leaf(page, index);
+            }
+            number = self.push_interior(page, index);
+        }
+    }
+
+    pub(crate) fn step_next(&mut self, pager: &mut Pager) -> SqliteResult<bool> {
+        let level = self.levels.last_mut().expect("the path ends on a cell");
+        if !level.page.page_type().is_leaf() {
+            // after an interior entry come the entries of the subtree to its right
+            level.index += 1;
+            let child = level.child();
+            return self.descend(pager, child, false);
+        }
+        if level.index + 1 < level.page.cells().len() {
+            level.index += 1;
+            return Ok(true);
+        }
+        // after the last cell of a subtree comes the interior entry it is left of in an
+        // index, or the first row of the subtree to the right of that in a table
+        self.levels.pop();
+        while let Some(level) = self.levels.last_mut() {
+            if level.index < level.page.cells().len() {
+                if !self.table {
+                    return Ok(true);
+                }
+                level.index += 1;
+                let child = level.child();
+                return self.descend(pager, child, false);
+            }
+            self.levels.pop();
+        }
+        Ok(false)
+    }
+
+    pub(crate) fn step_previous(&mut self, pager: &mut Pager) -> SqliteResult<bool> {
+        let level = self.levels.last_mut().expect("the path ends on a cell");
+        if !level.page.page_type().is_leaf() {
+            // before an interior entry come the entries of the subtree to its left
+            let child = level.child();
+            return self.descend(pager, child, true);
+        }
+        if level.index > 0 {
+            level.index -= 1;
+            return Ok(true);
+        }
+        // before the first cell of a subtree comes the interior entry it is right of in an
+        // index, or the last row of the subtree to the left of that in a table
+        self.levels.pop();
+        while let Some(level) = self.levels.last_mut() {
+            if level.index > 0 {
+                level.index -= 1;
+                if !self.table {
+                    return Ok(true);
+                }
+                let child = level.child();
+                return self.descend(pager, child, true);
+            }
+            self.levels.pop();
+        }
+        Ok(false)
+    }
+}
+
+fn rowid(cell: &Cell) -> i64 {
+    cell.rowid.expect("table cells have a rowid")
+}
+
+/// A cursor over the rows of a table b-tree in rowid order.
+///
+/// The cursor does not hold on to the pager, so the tree can change while it is open, and
+/// every call that reads the tree takes the pager.  When the pager's change count says the
+/// pages the cursor read may be stale, the cursor finds its place again by seeking to the
+/// rowid it was on.  If that row was deleted the cursor ends up on the row after it, which
+/// the next call to `next` returns.
+#[derive(Debug)]
+pub struct TableCursor {
+    path: BtreePath,
+}
+
+impl TableCursor {
+    /// A cursor over the table b-tree rooted at page `root`, not yet on any row
+    pub fn new(root: PageNumber) -> TableCursor {
+        TableCursor {
+            path: BtreePath::new(root, true),
+        }
+    }
+
+    pub fn root(&self) -> PageNumber {
+        self.path.root()
+    }
+
+    /// Whether the cursor is on a row
+    pub fn is_valid(&self) -> bool {
+        self.path.is_valid()
+    }
+
+    /// The rowid of the row the cursor is on.  This does not look at the tree again, so after
+    /// a change it can be the rowid of a row that has since been deleted.
+    pub fn rowid(&self) -> Option<i64> {
+        self.path.last().map(|leaf| rowid(leaf.cell()))
+    }
+
+    /// Move to the first row, returning false if the table is empty
+    pub fn first(&mut self, pager: &mut Pager) -> SqliteResult<bool> {
+        self.path.first(pager)
+    }
+
+    /// Move to the last row, returning false if the table is empty
+    pub fn last(&mut self, pager: &mut Pager) -> SqliteResult<bool> {
+        self.path.last_cell(pager)
+    }
+
+    /// Move to the row with `rowid`, returning whether there is one.  When there is not, the
+    /// cursor is left where `seek_at_or_after` leaves it.
+    pub fn seek(&mut self, pager: &mut Pager, rowid: i64) -> SqliteResult<bool> {
+        Ok(self.seek_at_or_after(pager, rowid)? && self.rowid() == Some(rowid))
+    }
+
+    /// Move to the row with the smallest rowid at or after `rowid`, returning false if there
+    /// is none
+    pub fn seek_at_or_after(&mut self, pager: &mut Pager, rowid: i64) -> SqliteResult<bool> {
+        self.path.reset(pager);
+        let result = self.seek_leaf(pager, rowid);
+        self.path.invalidate_on_err(result)
+    }
+
+    /// Move to the next row, returning false when there are no more
+    pub fn next(&mut self, pager: &mut Pager) -> SqliteResult<bool> {
+        self.restore(pager)?;
+        self.path.next(pager)
+    }
+
+    /// Move to the previous row, returning false when there are no more
+    pub fn previous(&mut self, pager: &mut Pager) -> SqliteResult<bool> {
+        self.restore(pager)?;
+        self.path.previous(pager)
+    }
+
+    /// The payload of the row the cursor is on, or `None` if it is on no row or its row
+    /// has been deleted
+    pub fn payload(&mut self, pager: &mut Pager) -> SqliteResult<Option<Bytes>> {
+        self.restore(pager)?;
+        match self.path.last() {
+            Some(leaf) if self.path.in_place() => {
+                pager.read_payload(&leaf.page, leaf.cell()).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The record of the row the cursor is on, or `None` if it is on no row or its row has
+    /// been deleted
+    pub fn record(&mut self, pager: &mut Pager) -> SqliteResult<Option<Record>> {
+        self.payload(pager)?.map(Record::decode).transpose()
+    }
+
+    /// Add a row with `payload`, replacing the row with `rowid` if there is one, and
+    /// balancing the tree as pages fill.  The cursor is left on no row.
+    pub fn insert(&mut self, pager: &mut Pager, rowid: i64, payload: &[u8]) -> SqliteResult<()> {
+        self.path.reset(pager);
+        let result = self.insert_row(pager, rowid, payload);
+        self.path.invalidate_on_err(result)
+    }
+
+    /// Seek back to the rowid the cursor was on if the tree may have changed since it read
+    /// its pages
+    fn restore(&mut self, pager: &mut Pager) -> SqliteResult<()> {
+        let Some(rowid) = self.rowid().filter(|_| self.path.is_stale(pager)) else {
+            return Ok(());
+        };
+        let moved = self.path.take_moved();
+        let found = if !self.seek_at_or_after(pager, rowid)? {
+            Ordering::Less
+        } else if self.rowid() == Some(rowid) {
+            Ordering::Equal
+        } else {
+            Ordering::Greater
+        };
+        self.path.set_found(moved, found);
+        Ok(())
+    }
+
+    /// Go down to the leaf where a row with rowid `target` is or would go, returning it
+    /// with the index of the first cell at or after `target`, with the interior pages on the
+    /// way down on the path
+    fn find_leaf(&mut self, pager: &mut Pager, target: i64) -> SqliteResult<(BtreePage, usize)> {
+        let mut number = self.path.root();
+        loop {
+            let page = self.path.page(pager, number)?;
+            // interior cells hold the largest rowid of the subtree to their left
+            let index = page.cells().partition_point(|cell| rowid(cell) < target);
+            if page.page_type().is_leaf() {
+                return Ok((page, index));
+            }
+            number = self.path.push_interior(page, index);
+        }
+    }
+
+    fn seek_leaf(&mut self, pager: &mut Pager, target: i64) -> SqliteResult<bool> {
+        let (page, index) = self.find_leaf(pager, target)?;
+        let past_end = index == page.cells().len();
+        if !self.path.push_leaf(page, index)? {
+            return Ok(false);
+        }
+        if past_end {
+            self.path.step_next(pager)
+        } else {
+            Ok(true)
+        }
+    }
+
+    fn insert_row(&mut self, pager: &mut Pager, target: i64, payload: &[u8]) -> SqliteResult<()> {
+        let (page, index) = self.find_leaf(pager, target)?;
+        let replace = page
+            .cells()
+            .get(index)
+            .is_some_and(|cell| rowid(cell) == target);
+        if replace {
+            pager.free_overflow(&page, &page.cells()[index])?;
+        }
+        let mut cell = Vec::new();
+        put_varint(&mut cell, payload.len() as u64);
+        put_varint(&mut cell, target as u64);
+        pager.write_payload(BtreePageType::TableLeaf, payload, &mut cell)?;
+        let path = self.path.take_levels();
+        insert_cell(pager, path, &page, index, replace, cell)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::BtreePageType;
+    use crate::database::{PageSize, SchemaFormat};
+    use crate::format::Value;
+    use crate::storage::{memory_pager, AutoVacuum, BtreeBuilder, BtreeEntry, Page};
+
+    /// The values of row `rowid`: every fifth row has a blob that spills to overflow pages
+    fn row(rowid: i64) -> Vec<Value> {
+        let size = if rowid % 5 == 0 { 3000 } else { 20 };
+        vec![
+            Value::Integer(rowid),
+            Value::Blob(Bytes::from(vec![rowid as u8; size])),
+        ]
+    }
+
+    /// Build the table rooted at page `root` in place with the rows of `rowids`
+    fn build(pager: &mut Pager, root: PageNumber, rowids: impl IntoIterator<Item = i64>) {
+        let mut builder = BtreeBuilder::new(root, true);
+        for rowid in rowids {
+            let payload = Record::encode(&row(rowid), SchemaFormat::V4).into_bytes();
+            let entry = BtreeEntry {
+                rowid: Some(rowid),
+                payload: payload.to_vec(),
+            };
+            builder.add(pager, entry).unwrap();
+        }
+        builder.finish(pager).unwrap();
+    }
+
+    /// A table of the even rowids from 0 to 3998
+    fn table(pager: &mut Pager) -> PageNumber {
+        let root = pager.allocate_root_page().unwrap().number();
+        build(pager, root, (0..2000).map(|i| i * 2));
+        root
+    }
+
+    fn values(cursor: &mut TableCursor, pager: &mut Pager) -> Option<Vec<Value>> {
+        let record = cursor.record(pager).unwrap()?;
+        Some(record.values().collect())
+    }
+
+    #[test]
+    fn empty_table() {
+        let mut pager = memory_pager(PageSize::Size512, AutoVacuum::None);
+        let root = pager.allocate_root_page().unwrap().number();
+        build(&mut pager, root, []);
+        let mut cursor = TableCursor::new(root);
+        assert!(!cursor.first(&mut pager).unwrap());
+        assert!(!cursor.last(&mut pager).unwrap());
+        assert!(!cursor.seek_at_or_after(&mut pager, 0).unwrap());
+        assert!(!cursor.is_valid());
+        assert!(!cursor.next(&mut pager).unwrap());
+        assert!(!cursor.previous(&mut pager).unwrap());
+        assert_eq!(cursor.rowid(), None);
+        assert_eq!(cursor.record(&mut pager).unwrap(), None);
+    }
+
+    #[test]
+    fn iterates_both_ways() {
+        let mut pager = memory_pager(PageSize::Size512, AutoVacuum::None);
+        let root = table(&mut pager);
+        let mut cursor = TableCursor::new(root);
+
+        let mut rowids = Vec::new();
+        let mut more = cursor.first(&mut pager).unwrap();
+        assert!(cursor.path.levels.len() >= 3);
+        while more {
+            let rowid = cursor.rowid().unwrap();
+            assert_eq!(values(&mut cursor, &mut pager), Some(row(rowid)));
+            rowids.push(rowid);
+            more = cursor.next(&mut pager).unwrap();
+        }
+        assert_eq!(rowids, (0..2000).map(|i| i * 2).collect::<Vec<_>>());
+        assert!(!cursor.is_valid());
+        assert!(!cursor.next(&mut pager).unwrap());
+
+        rowids.clear();
+        let mut more = cursor.last(&mut pager).unwrap();
+        while more {
+            rowids.push(cursor.rowid().unwrap());
+            more = cursor.previous(&mut pager).unwrap();
+        }
+        assert_eq!(rowids, (0..2000).rev().map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn seeks() {
+        let mut pager = memory_pager(PageSize::Size512, AutoVacuum::None);
+        let root = table(&mut pager);
+        let mut cursor = TableCursor::new(root);
+
+        assert!(cursor.seek(&mut pager, 1500).unwrap());
+        assert_eq!(values(&mut cursor, &mut pager), Some(row(1500)));
+        assert!(!cursor.seek(&mut pager, 1501).unwrap());
+        assert_eq!(cursor.rowid(), Some(1502));
+        assert!(cursor.seek_at_or_after(&mut pager, -5).unwrap());
+        assert_eq!(cursor.rowid(), Some(0));
+        assert!(!cursor.seek_at_or_after(&mut pager, 3999).unwrap());
+        assert!(!cursor.is_valid());
+
+        // every rowid, including the ones past the end of a leaf, and the steps either side
+        for target in 1..3997 {
+            assert!(cursor.seek_at_or_after(&mut pager, target).unwrap());
+            let at = (target + 1) / 2 * 2;
+            assert_eq!(cursor.rowid(), Some(at));
+            assert!(cursor.previous(&mut pager).unwrap());
+            assert_eq!(cursor.rowid(), Some(at - 2));
+            assert!(cursor.next(&mut pager).unwrap());
+            assert!(cursor.next(&mut pager).unwrap());
+            assert_eq!(cursor.rowid(), Some(at + 2));
+        }
+    }
+
+    #[test]
+    fn finds_its_place_after_changes() {
+        let mut pager = memory_pager(PageSize::Size512, AutoVacuum::None);
+        let root = table(&mut pager);
+        let mut cursor = TableCursor::new(root);
+        assert!(cursor.seek(&mut pager, 1000).unwrap());
+
+        // rows before it deleted: the cursor keeps its row
+        build(&mut pager, root, (300..2000).map(|i| i * 2));
+        assert_eq!(values(&mut cursor, &mut pager), Some(row(1000)));
+        assert!(cursor.previous(&mut pager).unwrap());
+        assert_eq!(cursor.rowid(), Some(998));
+
+        // its row deleted: the row after it comes next, and the one before it before
+        build(
+            &mut pager,
+            root,
+            (300..2000).map(|i| i * 2).filter(|r| *r != 998),
+        );
+        assert_eq!(cursor.rowid(), Some(998));
+        assert_eq!(cursor.record(&mut pager).unwrap(), None);
+        assert!(cursor.next(&mut pager).unwrap());
+        assert_eq!(values(&mut cursor, &mut pager), Some(row(1000)));
+        build(
+            &mut pager,
+            root,
+            (300..2000).map(|i| i * 2).filter(|r| *r != 1000),
+        );
+        assert!(cursor.previous(&mut pager).unwrap());
+        assert_eq!(cursor.rowid(), Some(998));
+
+        // a row that goes away twice before the cursor moves on
+        build(
+            &mut pager,
+            root,
+            (0..2000).map(|i| i * 2).filter(|r| *r != 998),
+        );
+        assert_eq!(cursor.record(&mut pager).unwrap(), None);
+        build(
+            &mut pager,
+            root,
+            (0..2000).map(|i| i * 2).filter(|r| *r < 998 || *r > 1000),
+        );
+        assert!(cursor.next(&mut pager).unwrap());
+        assert_eq!(cursor.rowid(), Some(1002));
+
+        // the last row deleted: nothing comes next, but the new last row is before it
+        assert!(cursor.last(&mut pager).unwrap());
+        build(&mut pager, root, (0..1999).map(|i| i * 2));
+        assert!(!cursor.next(&mut pager).unwrap());
+        assert!(cursor.last(&mut pager).unwrap());
+        build(&mut pager, root, (0..1998).map(|i| i * 2));
+        assert!(cursor.previous(&mut pager).unwrap());
+        assert_eq!(cursor.rowid(), Some(3994));
+
+        // a rolled back change
+        pager.commit().unwrap();
+        build(&mut pager, root, [5]);
+        assert!(cursor.first(&mut pager).unwrap());
+        assert_eq!(cursor.rowid(), Some(5));
+        pager.rollback().unwrap();
+        assert_eq!(values(&mut cursor, &mut pager), None);
+        assert!(cursor.next(&mut pager).unwrap());
+        assert_eq!(cursor.rowid(), Some(6));
+    }
+
+    #[test]
+    fn corrupt_tree_err() {
+        let mut pager = memory_pager(PageSize::Size512, AutoVacuum::None);
+        let root = table(&mut pager);
+        let mut cursor = TableCursor::new(root);
+        let page_size: u32 = pager.page_size().into();
+
+        // an interior page that is its own right child
+        let mut data = vec![0u8; page_size as usize];
+        data[0] = BtreePageType::TableInterior as u8;
+        data[5..7].copy_from_slice(&(page_size as u16).to_be_bytes());
+        data[8..12].copy_from_slice(&root.to_be_bytes());
+        pager
+            .write(Page::new(root, Bytes::from(data.clone())))
+            .unwrap();
+        for result in [
+            cursor.first(&mut pager),
+            cursor.last(&mut pager),
+            cursor.seek(&mut pager, 10),
+        ] {
+            assert!(matches!(result, Err(SqliteError::Corrupt { .. })));
+            assert!(!cursor.is_valid());
+        }
+
+        // an index page in a table
+        data[0] = BtreePageType::IndexLeaf as u8;
+        pager.write(Page::new(root, Bytes::from(data))).unwrap();
+        assert!(matches!(
+            cursor.first(&mut pager),
+            Err(SqliteError::Corrupt { .. })
+        ));
+    }
+}
@@ -0,0 +1,473 @@
+use crate::btree::{insert_cell, BtreePage, BtreePath};
+use crate::errors::SqliteResult;
+use crate::format::{put_varint, KeyInfo, Record, Value};
+use crate::storage::{PageNumber, Pager};
+use bytes::Bytes;
+use std::cmp::Ordering;
+
+/// A cursor over the entries of an index b-tree in key order.
+///
+/// Unlike a table, an index keeps entries on its interior pages too: each interior cell
+/// sorts after everything in the subtree to its left.  Keys compare column by column as
+/// the `KeyInfo` says, and seeks can give just the first few columns of a key.
+///
+/// Like a `TableCursor`, the cursor does not hold on to the pager.  It keeps the entry it
+/// is on, and when the pager's change count says its pages may be stale it finds its place
+/// again by seeking to that entry, ending up on the entry after it if it was deleted.
+#[derive(Debug)]
+pub struct IndexCursor {
+    key_info: KeyInfo,
+    path: BtreePath,
+    /// The payload of the entry the cursor is on, read when it got there
+    entry: Option<Bytes>,
+}
+
+impl IndexCursor {
+    /// A cursor over the index b-tree rooted at page `root`, not yet on any entry
+    pub fn new(root: PageNumber, key_info: KeyInfo) -> IndexCursor {
+        IndexCursor {
+            key_info,
+            path: BtreePath::new(root, false),
+            entry: None,
+        }
+    }
+
+    pub fn root(&self) -> PageNumber {
+        self.path.root()
+    }
+
+    pub fn key_info(&self) -> &KeyInfo {
+        &self.key_info
+    }
+
+    /// Whether the cursor is on an entry
+    pub fn is_valid(&self) -> bool {
+        self.path.is_valid()
+    }
+
+    /// Move to the first entry, returning false if the index is empty
+    pub fn first(&mut self, pager: &mut Pager) -> SqliteResult<bool> {
+        let result = self.path.first(pager);
+        self.arrive(pager, result)
+    }
+
+    /// Move to the last entry, returning false if the index is empty
+    pub fn last(&mut self, pager: &mut Pager) -> SqliteResult<bool> {
+        let result = self.path.last_cell(pager);
+        self.arrive(pager, result)
+    }
+
+    /// Move to the first entry that starts with `key`, returning whether there is one.  When
+    /// there is not, the cursor is left where `seek_at_or_after` leaves it.
+    pub fn seek(&mut self, pager: &mut Pager, key: &[Value]) -> SqliteResult<bool> {
+        if !self.seek_at_or_after(pager, key)? {
+            return Ok(false);
+        }
+        let record = Record::decode(self.entry.clone().expect("the cursor is on an entry"))?;
+        Ok(self.key_info.compare(&record, key) == Ordering::Equal)
+    }
+
+    /// Move to the first entry that sorts at or after `key`, where an entry that starts with
+    /// `key` counts as at it.  Returns false if there is none.
+    pub fn seek_at_or_after(&mut self, pager: &mut Pager, key: &[Value]) -> SqliteResult<bool> {
+        self.path.reset(pager);
+        let result = self.seek_leaf(pager, key, false);
+        self.arrive(pager, result)
+    }
+
+    /// Move to the last entry that sorts at or before `key`, where an entry that starts with
+    /// `key` counts as at it.  Returns false if there is none.
+    pub fn seek_at_or_before(&mut self, pager: &mut Pager, key: &[Value]) -> SqliteResult<bool> {
+        self.path.reset(pager);
+        let result = self.seek_leaf(pager, key, true).and_then(|after| {
+            if after {
+                self.path.step_previous(pager)
+            } else {
+                self.path.last_cell(pager)
+            }
+        });
+        self.arrive(pager, result)
+    }
+
+    /// Move to the next entry, returning false when there are no more
+    pub fn next(&mut self, pager: &mut Pager) -> SqliteResult<bool> {
+        self.restore(pager)?;
+        let result = self.path.next(pager);
+        self.arrive(pager, result)
+    }
+
+    /// Move to the previous entry, returning false when there are no more
+    pub fn previous(&mut self, pager: &mut Pager) -> SqliteResult<bool> {
+        self.restore(pager)?;
+        let result = self.path.previous(pager);
+        self.arrive(pager, result)
+    }
+
+    /// The payload of the entry the cursor is on, or `None` if it is on no entry or its
+    /// entry has been deleted
+    pub fn payload(&mut self, pager: &mut Pager) -> SqliteResult<Option<Bytes>> {
+        self.restore(pager)?;
+        if !self.path.in_place() {
+            return Ok(None);
+        }
+        Ok(self.entry.clone())
+    }
+
+    /// The record of the entry the cursor is on, or `None` if it is on no entry or its entry
+    /// has been deleted
+    pub fn record(&mut self, pager: &mut Pager) -> SqliteResult<Option<Record>> {
+        self.payload(pager)?.map(Record::decode).transpose()
+    }
+
+    /// Add `entry` to the index, replacing an entry equal to it, and balancing the tree as
+    /// pages fill.  The cursor is left on no entry.
+    pub fn insert(&mut self, pager: &mut Pager, entry: &Record) -> SqliteResult<()> {
+        self.path.reset(pager);
+        self.entry = None;
+        let result = self.insert_entry(pager, entry);
+        self.path.invalidate_on_err(result)
+    }
+
+    /// Read the entry the cursor moved to, leaving the cursor on no entry if the move failed
+    fn arrive(&mut self, pager: &mut Pager, result: SqliteResult<bool>) -> SqliteResult<bool> {
+        let entry = result.and_then(|_| match self.path.last() {
+            Some(level) => pager.read_payload(&level.page, level.cell()).map(Some),
+            None => Ok(None),
+        });
+        self.entry = None;
+        self.entry = self.path.invalidate_on_err(entry)?;
+        Ok(self.entry.is_some())
+    }
+
+    /// Seek back to the entry the cursor was on if the tree may have changed since it read
+    /// its pages
+    fn restore(&mut self, pager: &mut Pager) -> SqliteResult<()> {
+        let Some(entry) = self.entry.clone().filter(|_| self.path.is_stale(pager)) else {
+            return Ok(());
+        };
+        let key: Vec<Value> = Record::decode(entry)?.values().collect();
+        let moved = self.path.take_moved();
+        let found = if !self.seek_at_or_after(pager, &key)? {
+            Ordering::Less
+        } else {
+            let record = Record::decode(self.entry.clone().expect("the cursor is on an entry"))?;
+            match self.key_info.compare(&record, &key) {
+                Ordering::Equal if record.len() == key.len() => Ordering::Equal,
+                _ => Ordering::Greater,
+            }
+        };
+        self.path.set_found(moved, found);
+        Ok(())
+    }
+
+    /// How the entry in cell `index` of `page` compares with `key`
+    fn compare_cell(
+        &self,
+        pager: &mut Pager,
+        page: &BtreePage,
+        index: usize,
+        key: &[Value],
+    ) -> SqliteResult<Ordering> {
+        let payload = pager.read_payload(page, &page.cells()[index])?;
+        Ok(self.key_info.compare(&Record::decode(payload)?, key))
+    }
+
+    /// Move to the first entry after `key`, or at or after it unless `after` is set
+    fn seek_leaf(&mut self, pager: &mut Pager, key: &[Value], after: bool) -> SqliteResult<bool> {
+        let mut number = self.path.root();
+        loop {
+            let page = self.path.page(pager, number)?;
+            // binary search for the first cell that does not come before the key
+            let (mut low, mut high) = (0, page.cells().len());
+            while low < high {
+                let middle = (low + high) / 2;
+                match self.compare_cell(pager, &page, middle, key)? {
+                    Ordering::Less => low = middle + 1,
+                    Ordering::Equal if after => low = middle + 1,
+                    _ => high = middle,
+                }
+            }
+            if page.page_type().is_leaf() {
+                let past_end = low == page.cells().len();
+                if !self.path.push_leaf(page, low)? {
+                    return Ok(false);
+                }
+                return if past_end {
+                    self.path.step_next(pager)
+                } else {
+                    Ok(true)
+                };
+            }
+            // the entry is in the subtree left of that cell, or failing that is the cell
+            number = self.path.push_interior(page, low);
+        }
+    }
+
+    fn insert_entry(&mut self, pager: &mut Pager, entry: &Record) -> SqliteResult<()> {
+        let key: Vec<Value> = entry.values().collect();
+        let mut number = self.path.root();
+        loop {
+            let page = self.path.page(pager, number)?;
+            let (mut low, mut high) = (0, page.cells().len());
+            let mut replace = false;
+            while low < high {
+                let middle = (low + high) / 2;
+                match self.compare_cell(pager, &page, middle, &key)? {
+                    Ordering::Less => low = middle + 1,
+                    Ordering::Greater => high = middle,
+                    Ordering::Equal => {
+                        (low, replace) = (middle, true);
+                        break;
+                    }
+                }
+            }
+            // an equal entry is replaced where it is, on an interior page or a leaf
+            if replace || page.page_type().is_leaf() {
+                if replace {
+                    pager.free_overflow(&page, &page.cells()[low])?;
+                }
+                let payload = entry.as_bytes();
+                let mut cell = Vec::new();
+                put_varint(&mut cell, payload.len() as u64);
+                pager.write_payload(page.page_type(), payload, &mut cell)?;
+                let path = self.path.take_levels();
+                return insert_cell(pager, path, &page, low, replace, cell);
+            }
+            number = self.path.push_interior(page, low);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::TableCursor;
+    use crate::database::{PageSize, SchemaFormat};
+    use crate::format::{Collation, KeyColumn, SortOrder};
+    use crate::storage::{memory_pager, AutoVacuum, BtreeBuilder, BtreeEntry};
+    use crate::SqliteError;
+
+    /// An index on `(name COLLATE NOCASE, score DESC)`
+    fn key_info() -> KeyInfo {
+        KeyInfo::new(
+            vec![
+                KeyColumn {
+                    collation: Collation::NoCase,
+                    order: SortOrder::Asc,
+                },
+                KeyColumn {
+                    collation: Collation::Binary,
+                    order: SortOrder::Desc,
+                },
+            ],
+            SchemaFormat::V4,
+        )
+    }
+
+    fn text(text: &str) -> Value {
+        Value::Text(Bytes::copy_from_slice(text.as_bytes()))
+    }
+
+    /// The key of row `rowid`, with names that differ only in case, a long name now and
+    /// then, and scores of every type
+    fn key(rowid: i64) -> Vec<Value> {
+        let names = ["apple", "Banana", "APPLE", "cherry", "banana"];
+        let mut name = format!("{}{}", names[rowid as usize % 5], rowid % 13);
+        if rowid % 40 == 0 {
+            name.push_str(&"x".repeat(300));
+        }
+        let score = match rowid % 6 {
+            0 => Value::Null,
+            1 => Value::Float(rowid as f64 / 4.0),
+            2 => text("high"),
+            3 => Value::Blob(Bytes::from_static(b"\x01")),
+            _ => Value::Integer(rowid % 9),
+        };
+        vec![text(&name), score, Value::Integer(rowid)]
+    }
+
+    fn encode(key: &[Value]) -> Record {
+        Record::encode(key, SchemaFormat::V4)
+    }
+
+    /// Build the index rooted at page `root` in place with the entries of `rowids`,
+    /// returning the keys in index order
+    fn build(
+        pager: &mut Pager,
+        root: PageNumber,
+        rowids: impl IntoIterator<Item = i64>,
+    ) -> Vec<Vec<Value>> {
+        let key_info = key_info();
+        let mut keys: Vec<Vec<Value>> = rowids.into_iter().map(key).collect();
+        keys.sort_by(|a, b| key_info.compare(&encode(a), b));
+        let mut builder = BtreeBuilder::new(root, false);
+        for key in &keys {
+            let entry = BtreeEntry {
+                rowid: None,
+                payload: encode(key).into_bytes().to_vec(),
+            };
+            builder.add(pager, entry).unwrap();
+        }
+        builder.finish(pager).unwrap();
+        keys
+    }
+
+    fn index(pager: &mut Pager) -> (PageNumber, Vec<Vec<Value>>) {
+        let root = pager.allocate_root_page().unwrap().number();
+        let keys = build(pager, root, 0..1500);
+        (root, keys)
+    }
+
+    fn current(cursor: &mut IndexCursor, pager: &mut Pager) -> Option<Vec<Value>> {
+        let record = cursor.record(pager).unwrap()?;
+        Some(record.values().collect())
+    }
+
+    #[test]
+    fn empty_index() {
+        let mut pager = memory_pager(PageSize::Size512, AutoVacuum::None);
+        let root = pager.allocate_root_page().unwrap().number();
+        build(&mut pager, root, []);
+        let mut cursor = IndexCursor::new(root, key_info());
+        assert!(!cursor.first(&mut pager).unwrap());
+        assert!(!cursor.last(&mut pager).unwrap());
+        assert!(!cursor.seek_at_or_after(&mut pager, &[]).unwrap());
+        assert!(!cursor.seek_at_or_before(&mut pager, &[]).unwrap());
+        assert!(!cursor.next(&mut pager).unwrap());
+        assert!(!cursor.previous(&mut pager).unwrap());
+        assert_eq!(cursor.record(&mut pager).unwrap(), None);
+    }
+
+    #[test]
+    fn iterates_in_key_order() {
+        let mut pager = memory_pager(PageSize::Size512, AutoVacuum::None);
+        let (root, keys) = index(&mut pager);
+        let mut cursor = IndexCursor::new(root, key_info());
+
+        let mut seen = Vec::new();
+        let mut more = cursor.first(&mut pager).unwrap();
+        assert!(cursor.path.levels.len() >= 3);
+        while more {
+            seen.push(current(&mut cursor, &mut pager).unwrap());
+            more = cursor.next(&mut pager).unwrap();
+        }
+        assert_eq!(seen, keys);
+        assert!(!cursor.next(&mut pager).unwrap());
+
+        seen.clear();
+        let mut more = cursor.last(&mut pager).unwrap();
+        while more {
+            seen.push(current(&mut cursor, &mut pager).unwrap());
+            more = cursor.previous(&mut pager).unwrap();
+        }
+        seen.reverse();
+        assert_eq!(seen, keys);
+    }
+
+    #[test]
+    fn seeks_with_partial_keys() {
+        let mut pager = memory_pager(PageSize::Size512, AutoVacuum::None);
+        let (root, keys) = index(&mut pager);
+        let key_info = key_info();
+        let mut cursor = IndexCursor::new(root, key_info.clone());
+
+        let mut probes: Vec<Vec<Value>> = vec![
+            vec![],
+            vec![Value::Null],
+            vec![text("")],
+            vec![text("BANANA7")],
+            vec![text("banana7"), Value::Integer(100)],
+            vec![text("cherry")],
+            vec![text("zzz")],
+            vec![Value::Blob(Bytes::new())],
+        ];
+        for key in keys.iter().step_by(7) {
+            probes.push(key[..1].to_vec());
+            probes.push(key[..2].to_vec());
+            probes.push(key.clone());
+        }
+        for probe in &probes {
+            let at = keys
+                .iter()
+                .position(|key| key_info.compare(&encode(key), probe) != Ordering::Less);
+            let found = cursor.seek_at_or_after(&mut pager, probe).unwrap();
+            assert_eq!(found, at.is_some(), "{:?}", probe);
+            if let Some(at) = at {
+                assert_eq!(current(&mut cursor, &mut pager).unwrap(), keys[at]);
+            }
+
+            let matches =
+                at.is_some_and(|at| key_info.compare(&encode(&keys[at]), probe) == Ordering::Equal);
+            assert_eq!(cursor.seek(&mut pager, probe).unwrap(), matches);
+
+            let before = keys
+                .iter()
+                .rposition(|key| key_info.compare(&encode(key), probe) != Ordering::Greater);
+            let found = cursor.seek_at_or_before(&mut pager, probe).unwrap();
+            assert_eq!(found, before.is_some(), "{:?}", probe);
+            if let Some(before) = before {
+                assert_eq!(current(&mut cursor, &mut pager).unwrap(), keys[before]);
+                if before + 1 < keys.len() {
+                    assert!(cursor.next(&mut pager).unwrap());
+                    assert_eq!(current(&mut cursor, &mut pager).unwrap(), keys[before + 1]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn finds_its_place_after_changes() {
+        let mut pager = memory_pager(PageSize::Size512, AutoVacuum::None);
+        let (root, keys) = index(&mut pager);
+        let mut cursor = IndexCursor::new(root, key_info());
+        assert!(cursor.seek(&mut pager, &keys[700]).unwrap());
+        let rowid = |key: &Vec<Value>| match key[2] {
+            Value::Integer(rowid) => rowid,
+            _ => unreachable!(),
+        };
+
+        // its entry deleted: the one after it comes next
+        let gone = rowid(&keys[700]);
+        build(&mut pager, root, (0..1500).filter(|r| *r != gone));
+        assert_eq!(cursor.record(&mut pager).unwrap(), None);
+        assert!(cursor.next(&mut pager).unwrap());
+        assert_eq!(current(&mut cursor, &mut pager).unwrap(), keys[701]);
+
+        // entries before it deleted: it keeps its entry
+        build(
+            &mut pager,
+            root,
+            (0..1500).filter(|r| r % 2 == 1 || *r == rowid(&keys[701])),
+        );
+        assert_eq!(current(&mut cursor, &mut pager).unwrap(), keys[701]);
+
+        // unchanged entries in a rebuilt tree, and past the end when the last entry goes
+        build(&mut pager, root, 0..1500);
+        assert!(cursor.last(&mut pager).unwrap());
+        build(&mut pager, root, 0..1500);
+        assert!(cursor.previous(&mut pager).unwrap());
+        assert_eq!(current(&mut cursor, &mut pager).unwrap(), keys[1498]);
+        assert!(cursor.last(&mut pager).unwrap());
+        let last = rowid(&keys[1499]);
+        build(&mut pager, root, (0..1500).filter(|r| *r != last));
+        assert!(!cursor.next(&mut pager).unwrap());
+    }
+
+    #[test]
+    fn wrong_tree_err() {
+        let mut pager = memory_pager(PageSize::Size512, AutoVacuum::None);
+        let (root, _) = index(&mut pager);
+        // the schema table is a table b-tree
+        let mut cursor = IndexCursor::new(1, key_info());
+        assert!(matches!(
+            cursor.first(&mut pager),
+            Err(SqliteError::Corrupt { .. })
+        ));
+        assert!(!cursor.is_valid());
+        let mut cursor = TableCursor::new(root);
+        assert!(matches!(
+            cursor.first(&mut pager),
+            Err(SqliteError::Corrupt { .. })
+        ));
+    }
+}
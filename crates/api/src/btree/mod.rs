@@ -0,0 +1,11 @@
+mod balance;
+mod cursor;
+mod index_cursor;
+mod overflow;
+mod page;
+
+pub(crate) use self::balance::*;
+pub use self::cursor::*;
+pub use self::index_cursor::*;
+pub use self::overflow::*;
+pub use self::page::*;
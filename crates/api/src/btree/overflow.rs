@@ -0,0 +1,380 @@
+use crate::btree::{corrupt, read_page_number, BtreePage, BtreePageType, Cell};
+use crate::database::SqliteHeader;
+use crate::errors::SqliteResult;
+use crate::storage::{Page, PageNumber, Pager, PtrmapType};
+use bytes::{Bytes, BytesMut};
+
+/// How much of a payload a cell keeps on its page, from the payload fractions in the database
+/// header and the usable page size.  Payloads up to the max-local size stay on the page whole.
+/// Larger ones keep between the min-local and max-local sizes, chosen so the part that
+/// spills fills its last overflow page as much as it can, and spill the rest to a chain of
+/// overflow pages.  Table leaf cells may fill nearly the whole page; index cells are limited
+/// by the max embedded fraction so that at least four fit on a page.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PayloadLimits {
+    usable_size: usize,
+    max_local: usize,
+    min_local: usize,
+    max_leaf: usize,
+    min_leaf: usize,
+}
+
+impl PayloadLimits {
+    /// The limits for pages of `usable_size` bytes with the given fractions, each out of 255
+    pub fn new(
+        usable_size: usize,
+        max_embedded_payload_fraction: u8,
+        min_embedded_payload_fraction: u8,
+        leaf_payload_fraction: u8,
+    ) -> PayloadLimits {
+        let fraction = |fraction: u8| {
+            (usable_size.saturating_sub(12) * fraction as usize / 255).saturating_sub(23)
+        };
+        PayloadLimits {
+            usable_size,
+            max_local: fraction(max_embedded_payload_fraction),
+            min_local: fraction(min_embedded_payload_fraction),
+            max_leaf: usable_size.saturating_sub(35),
+            min_leaf: fraction(leaf_payload_fraction),
+        }
+    }
+
+    pub fn usable_size(&self) -> usize {
+        self.usable_size
+    }
+
+    /// The largest payload a cell of `page_type` keeps whole on the page
+    pub fn max_local(&self, page_type: BtreePageType) -> usize {
+        if page_type == BtreePageType::TableLeaf {
+            self.max_leaf
+        } else {
+            self.max_local
+        }
+    }
+
+    /// The least a cell of `page_type` keeps on the page of a payload that spills
+    pub fn min_local(&self, page_type: BtreePageType) -> usize {
+        if page_type == BtreePageType::TableLeaf {
+            self.min_leaf
+        } else {
+            self.min_local
+        }
+    }
+
+    /// How many bytes of a payload of `payload_size` bytes a cell of `page_type` keeps on the
+    /// page.  Table interior cells have no payload.
+    pub fn local_size(&self, page_type: BtreePageType, payload_size: u64) -> usize {
+        let max_local = self.max_local(page_type);
+        if payload_size <= max_local as u64 {
+            return payload_size as usize;
+        }
+        let min_local = self.min_local(page_type);
+        let overflow_size = self.overflow_page_capacity().max(1) as u64;
+        let surplus = min_local + ((payload_size - min_local as u64) % overflow_size) as usize;
+        if surplus <= max_local {
+            surplus
+        } else {
+            min_local
+        }
+    }
+
+    /// The payload bytes an overflow page holds after the page number of the next one
+    pub fn overflow_page_capacity(&self) -> usize {
+        self.usable_size.saturating_sub(4)
+    }
+}
+
+impl From<&SqliteHeader> for PayloadLimits {
+    fn from(header: &SqliteHeader) -> Self {
+        let page_size: u32 = header.page_size().into();
+        PayloadLimits::new(
+            page_size as usize - header.page_reserved_space() as usize,
+            header.max_embedded_payload_fraction(),
+            header.min_embedded_payload_fraction(),
+            header.leaf_payload_fraction(),
+        )
+    }
+}
+
+impl Pager {
+    /// The payload limits of this database's pages
+    pub fn payload_limits(&self) -> PayloadLimits {
+        PayloadLimits::from(self.header())
+    }
+
+    /// The whole payload of a cell on `page`, following its overflow chain.  A payload that
+    /// does not spill shares the page's memory.
+    pub fn read_payload(&mut self, page: &BtreePage, cell: &Cell) -> SqliteResult<Bytes> {
+        let local = page.local_payload(cell);
+        let Some(first) = cell.overflow else {
+            return Ok(local);
+        };
+        let capacity = self.payload_limits().overflow_page_capacity();
+        let payload_size = cell.payload_size as usize;
+        let spilled = payload_size - local.len();
+        if spilled.div_ceil(capacity) > self.page_count() as usize {
+            return Err(corrupt(format!(
+                "a payload of {} bytes is larger than the database",
+                payload_size
+            )));
+        }
+        let mut payload = BytesMut::with_capacity(payload_size);
+        payload.extend_from_slice(&local);
+        let mut next = first;
+        while payload.len() < payload_size {
+            let data = self.overflow_page(next, payload.len(), payload_size)?;
+            let len = (payload_size - payload.len()).min(capacity);
+            payload.extend_from_slice(&data[4..4 + len]);
+            next = read_page_number(&data, 0);
+        }
+        Ok(payload.freeze())
+    }
+
+    /// Append the part of `payload` a new cell of `page_type` keeps on the page to `cell`,
+    /// followed by the first page of a new overflow chain holding the rest if it spills
+    pub fn write_payload(
+        &mut self,
+        page_type: BtreePageType,
+        payload: &[u8],
+        cell: &mut Vec<u8>,
+    ) -> SqliteResult<()> {
+        let limits = self.payload_limits();
+        let local = limits.local_size(page_type, payload.len() as u64);
+        cell.extend_from_slice(&payload[..local]);
+        if local == payload.len() {
+            return Ok(());
+        }
+        let page_size: u32 = self.page_size().into();
+        let mut number = self.allocate()?.number();
+        cell.extend_from_slice(&number.to_be_bytes());
+        let mut rest = &payload[local..];
+        while !rest.is_empty() {
+            let len = rest.len().min(limits.overflow_page_capacity());
+            let next = if len < rest.len() {
+                self.allocate()?.number()
+            } else {
+                0
+            };
+            let mut data = vec![0u8; page_size as usize];
+            data[..4].copy_from_slice(&next.to_be_bytes());
+            data[4..4 + len].copy_from_slice(&rest[..len]);
+            self.write(Page::new(number, Bytes::from(data)))?;
+            if next != 0 && self.has_ptrmap() {
+                self.ptrmap_put(next, PtrmapType::Overflow2, number)?;
+            }
+            rest = &rest[len..];
+            number = next;
+        }
+        Ok(())
+    }
+
+    /// Free the overflow chain of a cell on `page` that is being removed
+    pub fn free_overflow(&mut self, page: &BtreePage, cell: &Cell) -> SqliteResult<()> {
+        let Some(first) = cell.overflow else {
+            return Ok(());
+        };
+        let capacity = self.payload_limits().overflow_page_capacity();
+        let payload_size = cell.payload_size as usize;
+        let mut stored = page.local_payload(cell).len();
+        let mut next = first;
+        while stored < payload_size {
+            let data = self.overflow_page(next, stored, payload_size)?;
+            self.free_page(next)?;
+            stored += (payload_size - stored).min(capacity);
+            next = read_page_number(&data, 0);
+        }
+        Ok(())
+    }
+
+    /// Overflow page `number` of a chain that has given `stored` of `payload_size` bytes
+    fn overflow_page(
+        &mut self,
+        number: PageNumber,
+        stored: usize,
+        payload_size: usize,
+    ) -> SqliteResult<Bytes> {
+        if number < 2 || number > self.page_count() {
+            return Err(corrupt(format!(
+                "overflow chain goes to page {} after {} of {} bytes",
+                number, stored, payload_size
+            )));
+        }
+        Ok(self.get(number)?.into_data())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ExtendedResultCode;
+    use crate::format::put_varint;
+    use crate::storage::{memory_pager, AutoVacuum, DEFAULT_PAGE_SIZE};
+    use crate::SqliteError;
+
+    /// Write a table leaf holding one cell with `payload` to a new page and decode it
+    fn leaf_with_payload(pager: &mut Pager, payload: &[u8]) -> BtreePage {
+        let mut cell = BytesMut::new();
+        put_varint(&mut cell, payload.len() as u64);
+        put_varint(&mut cell, 1);
+        let mut body = cell.to_vec();
+        pager
+            .write_payload(BtreePageType::TableLeaf, payload, &mut body)
+            .unwrap();
+        // like sqlite, pad cells to the smallest size the freeblock list can take back
+        body.resize(body.len().max(4), 0);
+
+        let number = pager.allocate().unwrap().number();
+        let page_size: u32 = pager.page_size().into();
+        let content = page_size as usize - body.len();
+        let mut data = BytesMut::zeroed(page_size as usize);
+        data[0] = BtreePageType::TableLeaf as u8;
+        data[3..5].copy_from_slice(&1u16.to_be_bytes());
+        data[5..7].copy_from_slice(&(content as u16).to_be_bytes());
+        data[8..10].copy_from_slice(&(content as u16).to_be_bytes());
+        data[content..].copy_from_slice(&body);
+        let data = data.freeze();
+        pager.write(Page::new(number, data.clone())).unwrap();
+        BtreePage::decode(number, data, &pager.payload_limits()).unwrap()
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn free_pages(pager: &mut Pager) -> u32 {
+        let stats = pager.freelist_stats().unwrap();
+        stats.trunk_pages + stats.leaf_pages
+    }
+
+    #[test]
+    fn thresholds() {
+        for (usable_size, max_local, min_local, max_leaf) in [
+            (512, 102, 39, 477),
+            (4096, 1002, 489, 4061),
+            (65536, 16422, 8199, 65501),
+        ] {
+            let limits = PayloadLimits::new(usable_size, 64, 32, 32);
+            assert_eq!(limits.max_local(BtreePageType::IndexLeaf), max_local);
+            assert_eq!(limits.max_local(BtreePageType::IndexInterior), max_local);
+            assert_eq!(limits.min_local(BtreePageType::IndexLeaf), min_local);
+            assert_eq!(limits.max_local(BtreePageType::TableLeaf), max_leaf);
+            assert_eq!(limits.min_local(BtreePageType::TableLeaf), min_local);
+        }
+
+        let limits = PayloadLimits::new(1024, 64, 32, 32);
+        assert_eq!(limits.local_size(BtreePageType::TableLeaf, 989), 989);
+        // 5000 bytes keep 103 + (5000 - 103) % 1020 on the page, leaving four full pages
+        assert_eq!(limits.local_size(BtreePageType::TableLeaf, 5000), 920);
+        assert_eq!(limits.local_size(BtreePageType::IndexLeaf, 225), 225);
+        // past the max-local size a surplus that does not fit leaves just the min-local size
+        assert_eq!(limits.local_size(BtreePageType::IndexLeaf, 1003), 103);
+        assert_eq!(limits.local_size(BtreePageType::IndexInterior, 1250), 230);
+
+        let limits = PayloadLimits::new(4096, 64, 32, 32);
+        assert_eq!(limits.local_size(BtreePageType::TableLeaf, 5000), 908);
+        assert_eq!(limits.local_size(BtreePageType::IndexLeaf, 1003), 489);
+    }
+
+    #[test]
+    fn payloads_round_trip() {
+        let mut pager = memory_pager(DEFAULT_PAGE_SIZE, AutoVacuum::None);
+        let capacity = pager.payload_limits().overflow_page_capacity();
+        for len in [0, 100, 4061, 4062, 10_000, 3 * capacity + 489, 100_000] {
+            let payload = payload(len);
+            let page_count = pager.page_count();
+            let page = leaf_with_payload(&mut pager, &payload);
+            let cell = &page.cells()[0];
+            assert_eq!(cell.payload_size, len as u64);
+            assert_eq!(cell.overflow.is_some(), len > 4061, "{}", len);
+            assert_eq!(pager.read_payload(&page, cell).unwrap().as_ref(), payload);
+
+            let spilled = len - cell.local.len();
+            let overflow_pages = spilled.div_ceil(capacity) as u32;
+            assert_eq!(pager.page_count(), page_count + overflow_pages + 1);
+        }
+        pager.commit().unwrap();
+    }
+
+    #[test]
+    fn overflow_pages_in_pointer_map() {
+        let mut pager = memory_pager(DEFAULT_PAGE_SIZE, AutoVacuum::Full);
+        let page = leaf_with_payload(&mut pager, &payload(20_000));
+        let cell = page.cells()[0].clone();
+        let first = cell.overflow.unwrap();
+        let mut number = first;
+        let mut previous = None;
+        while number != 0 {
+            if let Some(previous) = previous {
+                assert_eq!(
+                    pager.ptrmap_get(number).unwrap(),
+                    (PtrmapType::Overflow2, previous)
+                );
+            }
+            previous = Some(number);
+            number = read_page_number(pager.get(number).unwrap().data(), 0);
+        }
+        assert_eq!(
+            pager.read_payload(&page, &cell).unwrap().as_ref(),
+            payload(20_000)
+        );
+    }
+
+    #[test]
+    fn free_overflow_chain() {
+        let mut pager = memory_pager(DEFAULT_PAGE_SIZE, AutoVacuum::None);
+        let page = leaf_with_payload(&mut pager, &payload(50_000));
+        let kept = leaf_with_payload(&mut pager, &payload(9000));
+        pager.commit().unwrap();
+        assert_eq!(free_pages(&mut pager), 0);
+
+        pager.free_overflow(&page, &page.cells()[0]).unwrap();
+        let overflow_pages = (50_000 - page.cells()[0].local.len()).div_ceil(4092) as u32;
+        assert_eq!(free_pages(&mut pager), overflow_pages);
+        // a cell without overflow pages frees nothing; its own page came off the freelist
+        let small = leaf_with_payload(&mut pager, &payload(10));
+        pager.free_overflow(&small, &small.cells()[0]).unwrap();
+        assert_eq!(free_pages(&mut pager), overflow_pages - 1);
+        pager.commit().unwrap();
+
+        // other chains are untouched
+        assert_eq!(
+            pager
+                .read_payload(&kept, &kept.cells()[0])
+                .unwrap()
+                .as_ref(),
+            payload(9000)
+        );
+    }
+
+    #[test]
+    fn corrupt_chain_err() {
+        let mut pager = memory_pager(DEFAULT_PAGE_SIZE, AutoVacuum::None);
+        let page = leaf_with_payload(&mut pager, &payload(10_000));
+        let cell = page.cells()[0].clone();
+        let first = cell.overflow.unwrap();
+        let data = pager.get(first).unwrap().into_data();
+
+        for next in [0, 1, pager.page_count() + 1] {
+            let mut damaged = BytesMut::from(data.as_ref());
+            damaged[..4].copy_from_slice(&next.to_be_bytes());
+            pager.write(Page::new(first, damaged.freeze())).unwrap();
+            for result in [
+                pager.read_payload(&page, &cell).map(|_| ()),
+                pager.free_overflow(&page, &cell),
+            ] {
+                assert!(matches!(
+                    result,
+                    Err(SqliteError::Corrupt {
+                        code: ExtendedResultCode::Corrupt,
+                        ..
+                    })
+                ));
+            }
+        }
+
+        // a payload size the database could not hold
+        let mut huge = cell.clone();
+        huge.payload_size = 1 << 40;
+        assert!(pager.read_payload(&page, &huge).is_err());
+    }
+}
@@ -0,0 +1,720 @@
+use crate::btree::PayloadLimits;
+use crate::database::MIN_USABLE_PAGE_SIZE;
+use crate::errors::ExtendedResultCode;
+use crate::format::read_varint;
+use crate::storage::PageNumber;
+use crate::SqliteError;
+use bytes::Bytes;
+use std::fmt;
+use std::ops::Range;
+
+/// Offset of the first freeblock in the b-tree page header
+pub(crate) const FIRST_FREEBLOCK_OFFSET: usize = 1;
+
+/// Offset of the number of cells in the b-tree page header
+pub(crate) const CELL_COUNT_OFFSET: usize = 3;
+
+/// Offset of the start of the cell content area in the b-tree page header
+pub(crate) const CELL_CONTENT_OFFSET: usize = 5;
+
+/// Offset of the number of fragmented free bytes in the b-tree page header
+pub(crate) const FRAGMENTED_BYTES_OFFSET: usize = 7;
+
+/// Offset of the right-most child in the header of an interior b-tree page
+pub(crate) const RIGHT_CHILD_OFFSET: usize = 8;
+
+/// The fewest bytes a cell takes, so that it can become a freeblock when deleted
+pub(crate) const MIN_CELL_SIZE: usize = 4;
+
+/// The kinds of b-tree page, by the first byte of the b-tree page header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BtreePageType {
+    IndexInterior = 2,
+    TableInterior = 5,
+    IndexLeaf = 10,
+    TableLeaf = 13,
+}
+
+impl BtreePageType {
+    pub fn is_leaf(self) -> bool {
+        matches!(self, BtreePageType::IndexLeaf | BtreePageType::TableLeaf)
+    }
+
+    pub fn is_table(self) -> bool {
+        matches!(
+            self,
+            BtreePageType::TableInterior | BtreePageType::TableLeaf
+        )
+    }
+
+    /// The size of the page header, which interior pages end with the right-most child
+    pub fn header_size(self) -> usize {
+        if self.is_leaf() {
+            8
+        } else {
+            12
+        }
+    }
+}
+
+impl TryFrom<u8> for BtreePageType {
+    type Error = SqliteError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            2 => Ok(BtreePageType::IndexInterior),
+            5 => Ok(BtreePageType::TableInterior),
+            10 => Ok(BtreePageType::IndexLeaf),
+            13 => Ok(BtreePageType::TableLeaf),
+            _ => Err(SqliteError::Corrupt {
+                code: ExtendedResultCode::Corrupt,
+                message: format!("invalid b-tree page type: {}", value),
+            }),
+        }
+    }
+}
+
+/// Where the b-tree page header starts: after the database header on page 1
+pub fn btree_header_offset(number: PageNumber) -> usize {
+    if number == 1 {
+        100
+    } else {
+        0
+    }
+}
+
+pub(crate) fn read_page_number(data: &[u8], offset: usize) -> PageNumber {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u16(data: &[u8], offset: usize) -> usize {
+    u16::from_be_bytes([data[offset], data[offset + 1]]) as usize
+}
+
+/// What is wrong with a malformed b-tree page
+#[derive(Clone, Debug, PartialEq)]
+pub enum CorruptionKind {
+    /// The usable part of the page is smaller than any sqlite3 page or its own header
+    Truncated { len: usize },
+    /// The first byte of the page header is not a b-tree page type
+    PageType(u8),
+    /// The cell pointer array runs into the cell content area or off the page
+    CellPointerArray { cell_count: usize },
+    /// The cell content area starts past the usable end of the page
+    CellContentStart(usize),
+    /// A cell pointer points outside the cell content area
+    CellPointer { cell: usize, offset: usize },
+    /// A cell runs past the usable end of the page
+    CellOverflow { cell: usize, offset: usize },
+    /// A child page number is 0
+    ChildPage { cell: Option<usize> },
+    /// A freeblock lies outside the cell content area, overlaps the next one or is out of
+    /// order
+    Freeblock { offset: usize },
+    /// The free bytes the header and freeblocks add up to are more than the page has
+    FreeSpace(usize),
+}
+
+/// A malformed b-tree page, which converts into `SQLITE_CORRUPT`
+#[derive(Clone, Debug, PartialEq)]
+pub struct PageCorruption {
+    pub page: PageNumber,
+    pub kind: CorruptionKind,
+}
+
+impl fmt::Display for PageCorruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "b-tree page {}: ", self.page)?;
+        match &self.kind {
+            CorruptionKind::Truncated { len } => write!(f, "{} bytes is too small", len),
+            CorruptionKind::PageType(page_type) => write!(f, "invalid page type {}", page_type),
+            CorruptionKind::CellPointerArray { cell_count } => {
+                write!(f, "{} cell pointers overrun the cell content", cell_count)
+            }
+            CorruptionKind::CellContentStart(offset) => {
+                write!(f, "cell content starts past the page at {}", offset)
+            }
+            CorruptionKind::CellPointer { cell, offset } => {
+                write!(
+                    f,
+                    "cell {} points outside the cell content at {}",
+                    cell, offset
+                )
+            }
+            CorruptionKind::CellOverflow { cell, offset } => {
+                write!(f, "cell {} at {} runs past the page", cell, offset)
+            }
+            CorruptionKind::ChildPage { cell: Some(cell) } => {
+                write!(f, "cell {} has child page 0", cell)
+            }
+            CorruptionKind::ChildPage { cell: None } => write!(f, "right child page is 0"),
+            CorruptionKind::Freeblock { offset } => {
+                write!(f, "freeblock at {} is malformed", offset)
+            }
+            CorruptionKind::FreeSpace(free) => {
+                write!(f, "{} free bytes do not fit on the page", free)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PageCorruption {}
+
+impl From<PageCorruption> for SqliteError {
+    fn from(value: PageCorruption) -> Self {
+        SqliteError::Corrupt {
+            code: ExtendedResultCode::Corrupt,
+            message: value.to_string(),
+        }
+    }
+}
+
+/// The b-tree page header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BtreePageHeader {
+    pub page_type: BtreePageType,
+    /// Offset of the first freeblock, or 0 when there is none
+    pub first_freeblock: usize,
+    pub cell_count: usize,
+    /// Offset of the cell content area, which the header stores as 0 for 65536
+    pub cell_content_start: usize,
+    /// Free bytes in fragments of up to 3 bytes, too small to be freeblocks
+    pub fragmented_bytes: usize,
+    /// The right-most child of an interior page
+    pub right_child: Option<PageNumber>,
+}
+
+/// An unused range in the cell content area.  Freeblocks form a chain in order of offset.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Freeblock {
+    pub offset: usize,
+    pub size: usize,
+}
+
+/// A cell of a b-tree page.  Offsets are from the start of the page.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cell {
+    /// Where the cell starts, which for an interior cell is its left child page number
+    pub offset: usize,
+    /// The bytes the cell takes on the page
+    pub size: usize,
+    /// The left child of an interior cell
+    pub left_child: Option<PageNumber>,
+    /// The key of a table cell
+    pub rowid: Option<i64>,
+    /// The size of the whole payload, which interior table cells do not have
+    pub payload_size: u64,
+    /// The part of the payload kept on the page
+    pub local: Range<usize>,
+    /// The first overflow page, when the payload spills
+    pub overflow: Option<PageNumber>,
+}
+
+impl Cell {
+    /// Where the first overflow page number is, after the local payload
+    pub fn overflow_offset(&self) -> Option<usize> {
+        self.overflow.map(|_| self.local.end)
+    }
+}
+
+/// A decoded b-tree page.  Decoding checks everything the header and cell pointers say
+/// against the usable size of the page, so the cells and freeblocks it gives lie within it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BtreePage {
+    number: PageNumber,
+    data: Bytes,
+    header: BtreePageHeader,
+    cells: Vec<Cell>,
+    freeblocks: Vec<Freeblock>,
+    free_space: usize,
+}
+
+impl BtreePage {
+    /// Decode page `number` of a database with the given payload limits, which include the
+    /// usable page size.  Fails with a `PageCorruption` describing the first problem found.
+    pub fn decode(
+        number: PageNumber,
+        data: Bytes,
+        limits: &PayloadLimits,
+    ) -> Result<BtreePage, PageCorruption> {
+        let corrupt = |kind| PageCorruption { page: number, kind };
+        let usable_size = limits.usable_size();
+        if data.len() < usable_size || usable_size < MIN_USABLE_PAGE_SIZE as usize {
+            let len = data.len().min(usable_size);
+            return Err(corrupt(CorruptionKind::Truncated { len }));
+        }
+        let usable = &data[..usable_size];
+        let header_offset = btree_header_offset(number);
+        let page_type = BtreePageType::try_from(usable[header_offset])
+            .map_err(|_| corrupt(CorruptionKind::PageType(usable[header_offset])))?;
+        let cell_pointers = header_offset + page_type.header_size();
+        if cell_pointers > usable_size {
+            return Err(corrupt(CorruptionKind::Truncated { len: usable_size }));
+        }
+        let cell_count = read_u16(usable, header_offset + CELL_COUNT_OFFSET);
+        let cell_content_start = match read_u16(usable, header_offset + CELL_CONTENT_OFFSET) {
+            0 => 65536,
+            offset => offset,
+        };
+        let right_child = if page_type.is_leaf() {
+            None
+        } else {
+            match read_page_number(usable, header_offset + RIGHT_CHILD_OFFSET) {
+                0 => return Err(corrupt(CorruptionKind::ChildPage { cell: None })),
+                child => Some(child),
+            }
+        };
+        let header = BtreePageHeader {
+            page_type,
+            first_freeblock: read_u16(usable, header_offset + FIRST_FREEBLOCK_OFFSET),
+            cell_count,
+            cell_content_start,
+            fragmented_bytes: usable[header_offset + FRAGMENTED_BYTES_OFFSET] as usize,
+            right_child,
+        };
+        if cell_content_start > usable_size {
+            return Err(corrupt(CorruptionKind::CellContentStart(
+                cell_content_start,
+            )));
+        }
+        let cell_pointers_end = cell_pointers + cell_count * 2;
+        if cell_pointers_end > cell_content_start {
+            return Err(corrupt(CorruptionKind::CellPointerArray { cell_count }));
+        }
+
+        let mut cells = Vec::with_capacity(cell_count);
+        for cell in 0..cell_count {
+            let offset = read_u16(usable, cell_pointers + cell * 2);
+            if offset < cell_content_start || offset + MIN_CELL_SIZE > usable_size {
+                return Err(corrupt(CorruptionKind::CellPointer { cell, offset }));
+            }
+            let decoded = decode_cell(page_type, usable, offset, limits)
+                .ok_or_else(|| corrupt(CorruptionKind::CellOverflow { cell, offset }))?;
+            if decoded.left_child == Some(0) {
+                return Err(corrupt(CorruptionKind::ChildPage { cell: Some(cell) }));
+            }
+            cells.push(decoded);
+        }
+
+        // the freeblock chain goes up the page, each one ending before the next starts
+        let mut freeblocks = Vec::new();
+        let mut free_space = cell_content_start - cell_pointers_end + header.fragmented_bytes;
+        let mut offset = header.first_freeblock;
+        while offset != 0 {
+            if offset < cell_content_start || offset + 4 > usable_size {
+                return Err(corrupt(CorruptionKind::Freeblock { offset }));
+            }
+            let next = read_u16(usable, offset);
+            let size = read_u16(usable, offset + 2);
+            if size < 4 || offset + size > usable_size || (next != 0 && next < offset + size) {
+                return Err(corrupt(CorruptionKind::Freeblock { offset }));
+            }
+            freeblocks.push(Freeblock { offset, size });
+            free_space += size;
+            offset = next;
+        }
+        if free_space > usable_size - cell_pointers_end {
+            return Err(corrupt(CorruptionKind::FreeSpace(free_space)));
+        }
+        Ok(BtreePage {
+            number,
+            data,
+            header,
+            cells,
+            freeblocks,
+            free_space,
+        })
+    }
+
+    pub fn number(&self) -> PageNumber {
+        self.number
+    }
+
+    pub fn header(&self) -> &BtreePageHeader {
+        &self.header
+    }
+
+    pub fn page_type(&self) -> BtreePageType {
+        self.header.page_type
+    }
+
+    /// The cells in key order
+    pub fn cells(&self) -> &[Cell] {
+        &self.cells
+    }
+
+    pub fn freeblocks(&self) -> &[Freeblock] {
+        &self.freeblocks
+    }
+
+    /// The bytes a new cell and its cell pointer could take, counting freeblocks and
+    /// fragments that defragmenting the page would gather up
+    pub fn free_space(&self) -> usize {
+        self.free_space
+    }
+
+    /// Where the right-most child page number is on an interior page
+    pub fn right_child_offset(&self) -> Option<usize> {
+        self.header
+            .right_child
+            .map(|_| btree_header_offset(self.number) + RIGHT_CHILD_OFFSET)
+    }
+
+    /// The part of a cell's payload kept on the page, sharing the page's memory
+    pub fn local_payload(&self, cell: &Cell) -> Bytes {
+        self.data.slice(cell.local.clone())
+    }
+
+    pub fn data(&self) -> &Bytes {
+        &self.data
+    }
+}
+
+/// Decode the cell at `offset`, or `None` if it runs past the end of `usable`
+fn decode_cell(
+    page_type: BtreePageType,
+    usable: &[u8],
+    offset: usize,
+    limits: &PayloadLimits,
+) -> Option<Cell> {
+    let mut at = offset;
+    let mut cell = Cell {
+        offset,
+        size: 0,
+        left_child: None,
+        rowid: None,
+        payload_size: 0,
+        local: 0..0,
+        overflow: None,
+    };
+    if !page_type.is_leaf() {
+        cell.left_child = Some(read_page_number(usable, at));
+        at += 4;
+    }
+    if page_type != BtreePageType::TableInterior {
+        let (payload_size, len) = read_varint(usable.get(at..)?)?;
+        cell.payload_size = payload_size;
+        at += len;
+    }
+    if page_type.is_table() {
+        let (rowid, len) = read_varint(usable.get(at..)?)?;
+        cell.rowid = Some(rowid as i64);
+        at += len;
+    }
+    let local = limits.local_size(page_type, cell.payload_size);
+    cell.local = at..at.checked_add(local)?;
+    at = cell.local.end;
+    if (local as u64) < cell.payload_size {
+        cell.overflow = Some(read_page_number(usable.get(at..at + 4)?, 0));
+        at += 4;
+    }
+    if at > usable.len() {
+        return None;
+    }
+    cell.size = (at - offset).max(MIN_CELL_SIZE);
+    Some(cell)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{BufMut, BytesMut};
+    use proptest::prelude::*;
+
+    const PAGE_SIZE: usize = 1024;
+
+    fn limits(usable_size: usize) -> PayloadLimits {
+        PayloadLimits::new(usable_size, 64, 32, 32)
+    }
+
+    /// Page `number` holding `cells` at the given offsets, the lowest of which starts the cell
+    /// content area
+    fn btree_page(
+        number: PageNumber,
+        page_type: BtreePageType,
+        right_child: Option<PageNumber>,
+        cells: &[(usize, Vec<u8>)],
+    ) -> BytesMut {
+        let mut data = BytesMut::zeroed(PAGE_SIZE);
+        let header = btree_header_offset(number);
+        data[header] = page_type as u8;
+        data[header + 3..header + 5].copy_from_slice(&(cells.len() as u16).to_be_bytes());
+        let content = cells
+            .iter()
+            .map(|(offset, _)| *offset)
+            .min()
+            .unwrap_or(PAGE_SIZE);
+        data[header + 5..header + 7].copy_from_slice(&(content as u16).to_be_bytes());
+        if let Some(right_child) = right_child {
+            data[header + 8..header + 12].copy_from_slice(&right_child.to_be_bytes());
+        }
+        for (i, (offset, cell)) in cells.iter().enumerate() {
+            data[*offset..*offset + cell.len()].copy_from_slice(cell);
+            let at = header + page_type.header_size() + i * 2;
+            data[at..at + 2].copy_from_slice(&(*offset as u16).to_be_bytes());
+        }
+        data
+    }
+
+    /// A table leaf cell with a payload of `len` bytes of `rowid`, spilling to page 9 when it
+    /// does not fit
+    fn leaf_cell(rowid: u8, len: usize) -> Vec<u8> {
+        let mut cell = Vec::new();
+        crate::format::put_varint(&mut cell, len as u64);
+        cell.push(rowid);
+        let local = limits(PAGE_SIZE).local_size(BtreePageType::TableLeaf, len as u64);
+        cell.put_bytes(rowid, local);
+        if local < len {
+            cell.put_u32(9);
+        }
+        cell
+    }
+
+    fn decode(number: PageNumber, data: &BytesMut) -> Result<BtreePage, PageCorruption> {
+        BtreePage::decode(number, data.clone().freeze(), &limits(PAGE_SIZE))
+    }
+
+    #[test]
+    fn table_leaf_with_freeblocks() {
+        let mut data = btree_page(
+            2,
+            BtreePageType::TableLeaf,
+            None,
+            &[(900, leaf_cell(1, 10)), (1000, leaf_cell(2, 20))],
+        );
+        // freeblocks at 920 and 960, with 2 fragmented bytes
+        data[1..3].copy_from_slice(&920u16.to_be_bytes());
+        data[920..924].copy_from_slice(&[0x03, 0xc0, 0x00, 0x10]);
+        data[960..964].copy_from_slice(&[0x00, 0x00, 0x00, 0x08]);
+        data[7] = 2;
+        let page = decode(2, &data).unwrap();
+        assert_eq!(
+            page.header(),
+            &BtreePageHeader {
+                page_type: BtreePageType::TableLeaf,
+                first_freeblock: 920,
+                cell_count: 2,
+                cell_content_start: 900,
+                fragmented_bytes: 2,
+                right_child: None,
+            }
+        );
+        assert_eq!(
+            page.freeblocks(),
+            &[
+                Freeblock {
+                    offset: 920,
+                    size: 16
+                },
+                Freeblock {
+                    offset: 960,
+                    size: 8
+                }
+            ]
+        );
+        // the gap between the cell pointers and the cell content, the freeblocks and fragments
+        assert_eq!(page.free_space(), (900 - 12) + 16 + 8 + 2);
+        let cells = page.cells();
+        assert_eq!(cells[0].rowid, Some(1));
+        assert_eq!(cells[0].size, 12);
+        assert_eq!(cells[1].local, 1002..1022);
+        assert_eq!(page.local_payload(&cells[1]).as_ref(), &[2; 20]);
+        assert_eq!(cells[1].overflow, None);
+        assert_eq!(page.right_child_offset(), None);
+    }
+
+    #[test]
+    fn interior_pages() {
+        let mut cell = 7u32.to_be_bytes().to_vec();
+        cell.push(42);
+        let page = decode(
+            3,
+            &btree_page(3, BtreePageType::TableInterior, Some(8), &[(1000, cell)]),
+        )
+        .unwrap();
+        assert_eq!(page.header().right_child, Some(8));
+        assert_eq!(page.right_child_offset(), Some(RIGHT_CHILD_OFFSET));
+        let cell = &page.cells()[0];
+        assert_eq!((cell.left_child, cell.rowid), (Some(7), Some(42)));
+        assert_eq!((cell.payload_size, cell.size), (0, 5));
+
+        // an index key too big for the page keeps less of itself than a table row would
+        let local = limits(PAGE_SIZE).local_size(BtreePageType::IndexInterior, 600);
+        let mut cell = 7u32.to_be_bytes().to_vec();
+        crate::format::put_varint(&mut cell, 600);
+        cell.put_bytes(1, local);
+        cell.put_u32(11);
+        let page = decode(
+            3,
+            &btree_page(3, BtreePageType::IndexInterior, Some(8), &[(600, cell)]),
+        )
+        .unwrap();
+        let cell = &page.cells()[0];
+        assert_eq!(cell.overflow, Some(11));
+        assert_eq!(cell.overflow_offset(), Some(606 + local));
+        assert_eq!(cell.size, 4 + 2 + local + 4);
+    }
+
+    #[test]
+    fn page_one_header_offset() {
+        let mut data = btree_page(
+            1,
+            BtreePageType::TableLeaf,
+            None,
+            &[(1000, leaf_cell(1, 5))],
+        );
+        data[..16].copy_from_slice(b"SQLite format 3\0");
+        let page = decode(1, &data).unwrap();
+        assert_eq!(page.header().cell_count, 1);
+        assert_eq!(page.cells()[0].offset, 1000);
+        // read as any other page the database header is garbage
+        assert_eq!(
+            decode(2, &data).unwrap_err().kind,
+            CorruptionKind::PageType(b'S')
+        );
+    }
+
+    #[test]
+    fn content_start_of_65536() {
+        let mut data = BytesMut::zeroed(65536);
+        data[0] = BtreePageType::TableLeaf as u8;
+        let page = BtreePage::decode(2, data.clone().freeze(), &limits(65536)).unwrap();
+        assert_eq!(page.header().cell_content_start, 65536);
+        assert_eq!(page.free_space(), 65536 - 8);
+        // but not on a smaller page
+        assert_eq!(
+            BtreePage::decode(2, data.freeze(), &limits(65000))
+                .unwrap_err()
+                .kind,
+            CorruptionKind::CellContentStart(65536)
+        );
+    }
+
+    #[test]
+    fn corrupt_page_err() {
+        let valid = btree_page(
+            2,
+            BtreePageType::TableInterior,
+            Some(8),
+            &[(1000, vec![0, 0, 0, 7, 1]), (1010, vec![0, 0, 0, 6, 2])],
+        );
+        assert!(decode(2, &valid).is_ok());
+        type Damage = fn(&mut BytesMut);
+        let cases: Vec<(Damage, CorruptionKind)> = vec![
+            (|data| data[0] = 1, CorruptionKind::PageType(1)),
+            (
+                |data| data[3..5].copy_from_slice(&[2, 0]),
+                CorruptionKind::CellPointerArray { cell_count: 512 },
+            ),
+            (
+                |data| data[5..7].copy_from_slice(&[0x10, 0]),
+                CorruptionKind::CellContentStart(4096),
+            ),
+            (
+                |data| data[12..14].copy_from_slice(&[0, 20]),
+                CorruptionKind::CellPointer {
+                    cell: 0,
+                    offset: 20,
+                },
+            ),
+            (
+                |data| data[14..16].copy_from_slice(&[0x03, 0xfe]),
+                CorruptionKind::CellPointer {
+                    cell: 1,
+                    offset: 1022,
+                },
+            ),
+            (
+                |data| {
+                    data[14..16].copy_from_slice(&[0x03, 0xf8]);
+                    data[1020..1024].copy_from_slice(&[0xff; 4]);
+                },
+                CorruptionKind::CellOverflow {
+                    cell: 1,
+                    offset: 1016,
+                },
+            ),
+            (
+                |data| data[1003] = 0,
+                CorruptionKind::ChildPage { cell: Some(0) },
+            ),
+            (
+                |data| data[11] = 0,
+                CorruptionKind::ChildPage { cell: None },
+            ),
+            (
+                |data| data[1..3].copy_from_slice(&[0, 100]),
+                CorruptionKind::Freeblock { offset: 100 },
+            ),
+            (
+                // a freeblock too small to hold its own header
+                |data| {
+                    data[1..3].copy_from_slice(&[0x03, 0xf0]);
+                    data[1008..1012].copy_from_slice(&[0, 0, 0, 2]);
+                },
+                CorruptionKind::Freeblock { offset: 1008 },
+            ),
+            (
+                // freeblocks out of order
+                |data| {
+                    data[1..3].copy_from_slice(&[0x03, 0xf8]);
+                    data[1016..1020].copy_from_slice(&[0x03, 0xf0, 0, 4]);
+                },
+                CorruptionKind::Freeblock { offset: 1016 },
+            ),
+            (|data| data[7] = 60, CorruptionKind::FreeSpace(1044)),
+        ];
+        for (corrupt, kind) in cases {
+            let mut data = valid.clone();
+            corrupt(&mut data);
+            let err = decode(2, &data).unwrap_err();
+            assert_eq!(err, PageCorruption { page: 2, kind });
+            assert!(matches!(
+                SqliteError::from(err),
+                SqliteError::Corrupt {
+                    code: ExtendedResultCode::Corrupt,
+                    ..
+                }
+            ));
+        }
+        assert_eq!(
+            BtreePage::decode(2, valid.clone().freeze(), &limits(400))
+                .unwrap_err()
+                .kind,
+            CorruptionKind::Truncated { len: 400 }
+        );
+        assert_eq!(
+            BtreePage::decode(2, valid.freeze().slice(..1000), &limits(PAGE_SIZE))
+                .unwrap_err()
+                .kind,
+            CorruptionKind::Truncated { len: 1000 }
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn damaged_pages_decode_or_err(
+            number in 1u32..3,
+            damage in prop::collection::vec((0usize..PAGE_SIZE, any::<u8>()), 1..8),
+        ) {
+            let mut data = btree_page(
+                number,
+                BtreePageType::TableLeaf,
+                None,
+                &[(400, leaf_cell(1, 1500)), (900, leaf_cell(2, 30)), (1000, leaf_cell(3, 3))],
+            );
+            for (offset, byte) in damage {
+                data[offset] = byte;
+            }
+            if let Ok(page) = decode(number, &data) {
+                for cell in page.cells() {
+                    prop_assert!(cell.offset + cell.size <= PAGE_SIZE);
+                    prop_assert!(cell.local.end <= PAGE_SIZE);
+                }
+                for freeblock in page.freeblocks() {
+                    prop_assert!(freeblock.offset + freeblock.size <= PAGE_SIZE);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,747 @@
+use crate::connection::options::{CacheType, ConnectionOptions, Mode};
+use crate::connection::uri::{DatabaseUri, MEMORY_FILENAME};
+use crate::database::SqliteHeader;
+use crate::errors::{ExtendedResultCode, SqliteResult};
+use crate::storage::{
+    next_connection_id, AutoVacuum, CheckpointMode, ConnectionId, FreelistStats, JournalMode,
+    LockingMode, PageNumber, Pager, SharedCache, TableLock, VacuumOptions, WalCheckpoint,
+};
+use crate::vfs::{self, FileKind, OpenAccess, OpenFlags, Vfs, MEMORY_VFS_NAME};
+use crate::SqliteError;
+use std::sync::Arc;
+
+/// An open database
+#[derive(Debug)]
+pub struct Connection {
+    path: String,
+    options: ConnectionOptions,
+    vfs: Arc<dyn Vfs>,
+    id: ConnectionId,
+    cache: Arc<SharedCache>,
+    read_only: bool,
+}
+
+impl Connection {
+    /// Open a database by filename or `file:` URI.  Query parameters in a URI are layered on
+    /// top of `options` and may not ask for more access than `options` grants.
+    ///
+    /// * `Mode::ReadOnly` opens an existing file for reading.
+    /// * `Mode::ReadWrite` opens an existing file for reading and writing, falling back to read
+    ///   only when the file is write protected.
+    /// * `Mode::ReadWriteCreate` also creates the file, initializing it as an empty database.
+    /// * `Mode::Memory` opens an in-memory database through the memory VFS.  `:memory:` and an
+    ///   empty filename are private to the connection, while other names are shared by every
+    ///   connection that opens them until the last one closes.
+    ///
+    /// Files are opened through the VFS named by the `vfs` option, or the default VFS.  With
+    /// `CacheType::Shared`, connections in this process that open the same file share one
+    /// page cache and arbitrate access with table locks.
+    ///
+    /// A hot journal left behind by a writer that crashed is rolled back before the header is
+    /// read, unless the database is immutable.  A read only connection cannot roll it back and
+    /// fails with `SQLITE_READONLY_ROLLBACK`.
+    pub fn open(path_or_uri: &str, options: ConnectionOptions) -> SqliteResult<Connection> {
+        let (path, options) = DatabaseUri::parse_with(path_or_uri, options)?.into_parts();
+        let memory = options.mode() == &Mode::Memory || path.is_empty();
+        let vfs_name = if memory {
+            Some(MEMORY_VFS_NAME)
+        } else {
+            options.vfs()
+        };
+        let vfs = vfs::find(vfs_name).ok_or_else(|| SqliteError::Error {
+            code: ExtendedResultCode::Error,
+            message: format!("no such vfs: {}", vfs_name.unwrap_or_default()),
+        })?;
+
+        let access = match options.mode() {
+            _ if options.immutable() => OpenAccess::ReadOnly,
+            Mode::ReadOnly => OpenAccess::ReadOnly,
+            Mode::ReadWrite => OpenAccess::ReadWrite,
+            Mode::ReadWriteCreate | Mode::Memory => OpenAccess::ReadWriteCreate,
+        };
+        let mut flags = OpenFlags::new(access, FileKind::MainDb);
+        flags.delete_on_close = memory;
+        let name = if memory && path == MEMORY_FILENAME {
+            ""
+        } else {
+            path.as_str()
+        };
+        let open_pager = || {
+            let mut pager = Pager::open(vfs.clone(), name, flags, &options)?;
+            if memory {
+                pager.set_journal_mode(JournalMode::Memory)?;
+            }
+            Ok(pager)
+        };
+        let cache = if options.cache_type() == &CacheType::Shared && !name.is_empty() {
+            let key = format!("{}:{}", vfs.name(), vfs.full_pathname(name)?);
+            SharedCache::open_shared(&key, open_pager)?
+        } else {
+            SharedCache::new(open_pager()?)
+        };
+        let read_only = access == OpenAccess::ReadOnly || cache.pager().is_read_only();
+        Ok(Connection {
+            path,
+            options,
+            vfs,
+            id: next_connection_id(),
+            cache,
+            read_only,
+        })
+    }
+
+    /// The path of the database file, or the name of an in-memory database
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn options(&self) -> &ConnectionOptions {
+        &self.options
+    }
+
+    /// The database header as of the time it was last read
+    pub fn header(&self) -> SqliteHeader {
+        self.cache.pager().header().clone()
+    }
+
+    /// Whether writes are refused, either because the database was opened read only or
+    /// immutable, or because the file is write protected
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Whether this connection shares its page cache with other connections
+    pub fn is_shared_cache(&self) -> bool {
+        Arc::strong_count(&self.cache) > 1
+    }
+
+    /// Limit the page cache, following `PRAGMA cache_size`: a positive size counts pages, a
+    /// negative size counts KiB and zero restores the size suggested by the header.  With a
+    /// shared cache the limit applies to every connection sharing it.
+    pub fn set_cache_size(&self, cache_size: i64) {
+        let mut pager = self.cache.pager();
+        let cache_size = match cache_size {
+            0 => pager.header().default_page_cache_size() as i32 as i64,
+            _ => cache_size,
+        };
+        pager.set_cache_size(cache_size);
+    }
+
+    /// The most clean pages the page cache holds
+    pub fn cache_capacity(&self) -> usize {
+        self.cache.pager().cache_capacity()
+    }
+
+    /// What happens to the rollback journal when a transaction ends
+    pub fn journal_mode(&self) -> JournalMode {
+        self.cache.pager().journal_mode()
+    }
+
+    /// Change the journal mode, following `PRAGMA journal_mode`, and return the mode in
+    /// effect.  An in-memory database keeps its journal in memory, so it only switches
+    /// between `Memory` and `Off`.  Switching into or out of `Wal` rewrites the database
+    /// header.
+    pub fn set_journal_mode(&self, journal_mode: JournalMode) -> SqliteResult<JournalMode> {
+        let mut pager = self.cache.pager();
+        if !(self.is_memory() && (journal_mode.uses_file() || journal_mode == JournalMode::Wal)) {
+            pager.set_journal_mode(journal_mode)?;
+        }
+        Ok(pager.journal_mode())
+    }
+
+    /// When the locks on the database file are released
+    pub fn locking_mode(&self) -> LockingMode {
+        self.cache.pager().locking_mode()
+    }
+
+    /// Change the locking mode, following `PRAGMA locking_mode`, and return the mode in
+    /// effect.  A database that entered WAL mode in exclusive locking mode stays in it.
+    pub fn set_locking_mode(&self, locking_mode: LockingMode) -> SqliteResult<LockingMode> {
+        let mut pager = self.cache.pager();
+        pager.set_locking_mode(locking_mode)?;
+        Ok(pager.locking_mode())
+    }
+
+    /// Copy frames from the write-ahead log back into the database file, following
+    /// `sqlite3_wal_checkpoint_v2`.  Returns how many frames the log holds and how many of
+    /// them are in the database file, or `None` when the database is not in WAL mode.  Fails
+    /// with `SQLITE_BUSY` while another connection runs a checkpoint.
+    pub fn checkpoint(&self, mode: CheckpointMode) -> SqliteResult<Option<WalCheckpoint>> {
+        self.cache.pager().checkpoint(mode)
+    }
+
+    /// Frames in the log that trigger a passive checkpoint after a commit, or 0 for never
+    pub fn wal_autocheckpoint(&self) -> u32 {
+        self.cache.pager().wal_autocheckpoint()
+    }
+
+    /// Change the auto-checkpoint threshold, following `PRAGMA wal_autocheckpoint`.  With a
+    /// shared cache it applies to every connection sharing it.
+    pub fn set_wal_autocheckpoint(&self, frames: u32) {
+        self.cache.pager().set_wal_autocheckpoint(frames)
+    }
+
+    /// How many pages the database has and how many of them are free, as the freelist says
+    pub fn freelist_stats(&self) -> SqliteResult<FreelistStats> {
+        self.cache.pager().freelist_stats()
+    }
+
+    /// Whether and when free pages are given back to the file system
+    pub fn auto_vacuum(&self) -> SqliteResult<AutoVacuum> {
+        self.cache.pager().auto_vacuum()
+    }
+
+    /// Change the auto-vacuum mode, following `PRAGMA auto_vacuum`, and return the mode in
+    /// effect.  Only a database without tables can start or stop auto-vacuuming.
+    pub fn set_auto_vacuum(&self, mode: AutoVacuum) -> SqliteResult<AutoVacuum> {
+        let mut pager = self.cache.pager();
+        pager.set_auto_vacuum(mode)?;
+        pager.auto_vacuum()
+    }
+
+    /// Truncate up to `pages` free pages from the end of an auto-vacuum database, or all of
+    /// them when `pages` is 0, following `PRAGMA incremental_vacuum`.  Returns how many pages
+    /// were released.
+    pub fn incremental_vacuum(&self, pages: u32) -> SqliteResult<u32> {
+        self.cache.pager().incremental_vacuum(pages)
+    }
+
+    /// Rebuild the database, following `VACUUM`, optionally with a new page size, reserved
+    /// space or auto-vacuum mode.  Fails with `SQLITE_ERROR` within a write transaction.
+    pub fn vacuum(&self, options: VacuumOptions) -> SqliteResult<()> {
+        self.cache.pager().vacuum(options)
+    }
+
+    /// Write a rebuilt copy of the database to a new file, following `VACUUM INTO`.  The file
+    /// is opened through the same VFS and must not exist or be empty.
+    pub fn vacuum_into(&self, path: &str, options: VacuumOptions) -> SqliteResult<()> {
+        self.cache.pager().vacuum_into(path, options)
+    }
+
+    /// Lock a table, identified by its root page, against the other connections sharing the
+    /// cache.  Fails with `SQLITE_LOCKED_SHAREDCACHE` on a conflicting lock.
+    pub fn lock_table(&self, table: PageNumber, table_lock: TableLock) -> SqliteResult<()> {
+        self.cache.lock_table(self.id, table, table_lock)
+    }
+
+    /// Release every table lock this connection holds
+    pub fn unlock_tables(&self) {
+        self.cache.unlock_tables(self.id)
+    }
+
+    pub fn is_memory(&self) -> bool {
+        self.options.mode() == &Mode::Memory || self.path.is_empty()
+    }
+
+    /// The name of the VFS the database was opened through
+    pub fn vfs_name(&self) -> &str {
+        self.vfs.name()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.unlock_tables();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use tempfile::TempDir;
+
+    fn db_path(dir: &TempDir, name: &str) -> String {
+        dir.path().join(name).to_str().unwrap().to_string()
+    }
+
+    fn options(mode: Mode) -> ConnectionOptions {
+        ConnectionOptions::builder().mode(mode).build().unwrap()
+    }
+
+    #[test]
+    fn open_rwc_creates_empty_database() {
+        let dir = TempDir::new().unwrap();
+        let path = db_path(&dir, "new.db");
+        let conn = Connection::open(&path, ConnectionOptions::default()).unwrap();
+        assert!(!conn.is_read_only());
+        assert!(!conn.is_memory());
+        assert_eq!(conn.header().page_size(), crate::PageSize::Size4096);
+        assert_eq!(conn.header().size_in_pages(), 1);
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 4096);
+        assert_eq!(&bytes[0..16], b"SQLite format 3\0");
+        assert_eq!(&bytes[100..108], &[0x0d, 0, 0, 0, 0, 0x10, 0, 0]);
+
+        // reopening reads the header that was written
+        let conn = Connection::open(&path, options(Mode::ReadOnly)).unwrap();
+        assert!(conn.is_read_only());
+        assert_eq!(conn.header().file_change_counter(), 1);
+    }
+
+    #[test]
+    fn open_rwc_initializes_zero_length_file() {
+        let dir = TempDir::new().unwrap();
+        let path = db_path(&dir, "empty.db");
+        File::create(&path).unwrap();
+        Connection::open(&path, options(Mode::ReadWrite)).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4096);
+    }
+
+    #[test]
+    fn open_ro_zero_length_file_is_empty_database() {
+        let dir = TempDir::new().unwrap();
+        let path = db_path(&dir, "empty.db");
+        File::create(&path).unwrap();
+        let conn = Connection::open(&path, options(Mode::ReadOnly)).unwrap();
+        assert_eq!(conn.header().size_in_pages(), 1);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn open_missing_file_err() {
+        let dir = TempDir::new().unwrap();
+        let path = db_path(&dir, "missing.db");
+        for mode in [Mode::ReadOnly, Mode::ReadWrite] {
+            let result = Connection::open(&path, options(mode));
+            assert!(matches!(
+                result,
+                Err(SqliteError::CannotOpen {
+                    code: ExtendedResultCode::CantOpen,
+                    source: Some(_),
+                    ..
+                })
+            ));
+        }
+        assert!(!std::fs::exists(&path).unwrap());
+    }
+
+    #[test]
+    fn open_directory_err() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let result = Connection::open(path, ConnectionOptions::default());
+        assert!(matches!(
+            result,
+            Err(SqliteError::CannotOpen {
+                code: ExtendedResultCode::CantOpenIsDir,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn open_missing_parent_directory_err() {
+        let dir = TempDir::new().unwrap();
+        let path = db_path(&dir, "missing/new.db");
+        let result = Connection::open(&path, ConnectionOptions::default());
+        assert!(matches!(
+            result,
+            Err(SqliteError::CannotOpen {
+                code: ExtendedResultCode::CantOpen,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn open_not_a_database_err() {
+        let dir = TempDir::new().unwrap();
+        let path = db_path(&dir, "text.db");
+        std::fs::write(&path, b"this is not a database").unwrap();
+        let result = Connection::open(&path, ConnectionOptions::default());
+        assert!(matches!(
+            result,
+            Err(SqliteError::NotADb {
+                code: ExtendedResultCode::NotADb,
+                ..
+            })
+        ));
+
+        std::fs::write(&path, [b'x'; 4096]).unwrap();
+        let result = Connection::open(&path, ConnectionOptions::default());
+        assert!(matches!(
+            result,
+            Err(SqliteError::NotADb {
+                code: ExtendedResultCode::NotADb,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn open_uri_ok() {
+        let dir = TempDir::new().unwrap();
+        let path = db_path(&dir, "uri.db");
+        Connection::open(&path, ConnectionOptions::default()).unwrap();
+        let uri = format!("file:{}?mode=ro&cache=shared", path);
+        let conn = Connection::open(&uri, ConnectionOptions::default()).unwrap();
+        assert_eq!(conn.path(), path);
+        assert!(conn.is_read_only());
+        assert_eq!(conn.options().cache_type(), &CacheType::Shared);
+    }
+
+    #[test]
+    fn open_immutable_is_read_only() {
+        let dir = TempDir::new().unwrap();
+        let path = db_path(&dir, "immutable.db");
+        Connection::open(&path, ConnectionOptions::default()).unwrap();
+        let uri = format!("file:{}?immutable=1", path);
+        let conn = Connection::open(&uri, ConnectionOptions::default()).unwrap();
+        assert!(conn.is_read_only());
+    }
+
+    #[test]
+    fn open_memory_ok() {
+        let conn = Connection::open(":memory:", ConnectionOptions::default()).unwrap();
+        assert!(conn.is_memory());
+        assert_eq!(conn.options().mode(), &Mode::Memory);
+        assert_eq!(conn.header().page_size(), crate::PageSize::Size4096);
+    }
+
+    #[test]
+    fn journal_mode_of_file_and_memory_databases() {
+        let dir = TempDir::new().unwrap();
+        let conn = Connection::open(&db_path(&dir, "a.db"), ConnectionOptions::default()).unwrap();
+        assert_eq!(conn.journal_mode(), JournalMode::Delete);
+        assert_eq!(
+            conn.set_journal_mode(JournalMode::Persist).unwrap(),
+            JournalMode::Persist
+        );
+        assert_eq!(
+            conn.set_journal_mode(JournalMode::Wal).unwrap(),
+            JournalMode::Wal
+        );
+        drop(conn);
+        // the header remembers WAL mode
+        let conn = Connection::open(&db_path(&dir, "a.db"), ConnectionOptions::default()).unwrap();
+        assert_eq!(conn.journal_mode(), JournalMode::Wal);
+
+        let conn = Connection::open(":memory:", ConnectionOptions::default()).unwrap();
+        assert_eq!(conn.journal_mode(), JournalMode::Memory);
+        assert_eq!(
+            conn.set_journal_mode(JournalMode::Truncate).unwrap(),
+            JournalMode::Memory
+        );
+        assert_eq!(
+            conn.set_journal_mode(JournalMode::Wal).unwrap(),
+            JournalMode::Memory
+        );
+        assert_eq!(
+            conn.set_journal_mode(JournalMode::Off).unwrap(),
+            JournalMode::Off
+        );
+    }
+
+    #[test]
+    fn locking_mode() {
+        let dir = TempDir::new().unwrap();
+        let conn = Connection::open(&db_path(&dir, "a.db"), ConnectionOptions::default()).unwrap();
+        assert_eq!(conn.locking_mode(), LockingMode::Normal);
+        assert_eq!(
+            conn.set_locking_mode(LockingMode::Exclusive).unwrap(),
+            LockingMode::Exclusive
+        );
+        assert_eq!(
+            conn.set_locking_mode(LockingMode::Normal).unwrap(),
+            LockingMode::Normal
+        );
+
+        // a log entered in exclusive locking mode has a wal-index only this connection sees
+        conn.set_locking_mode(LockingMode::Exclusive).unwrap();
+        conn.set_journal_mode(JournalMode::Wal).unwrap();
+        assert_eq!(
+            conn.set_locking_mode(LockingMode::Normal).unwrap(),
+            LockingMode::Exclusive
+        );
+        assert!(!dir.path().join("a.db-shm").exists());
+    }
+
+    #[test]
+    fn checkpoint_and_autocheckpoint() {
+        let dir = TempDir::new().unwrap();
+        let path = db_path(&dir, "a.db");
+        let conn = Connection::open(&path, ConnectionOptions::default()).unwrap();
+        assert_eq!(conn.checkpoint(CheckpointMode::Passive).unwrap(), None);
+        conn.set_journal_mode(JournalMode::Wal).unwrap();
+        assert_eq!(conn.wal_autocheckpoint(), 1000);
+        conn.set_wal_autocheckpoint(4);
+        let commit = |pages: u32| {
+            let mut pager = conn.cache.pager();
+            for _ in 0..pages {
+                pager.allocate().unwrap();
+            }
+            pager.commit().unwrap();
+        };
+
+        commit(1);
+        let checkpoint = conn.checkpoint(CheckpointMode::Full).unwrap().unwrap();
+        assert_eq!(
+            checkpoint,
+            WalCheckpoint {
+                busy: false,
+                log_frames: 2,
+                checkpointed_frames: 2
+            }
+        );
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 2 * 4096);
+
+        // the commit that takes the log past the threshold copies it into the database file
+        commit(1);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 2 * 4096);
+        commit(1);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4 * 4096);
+        let checkpoint = conn.checkpoint(CheckpointMode::Truncate).unwrap().unwrap();
+        assert_eq!(checkpoint.log_frames, 0);
+        assert_eq!(
+            std::fs::metadata(db_path(&dir, "a.db-wal")).unwrap().len(),
+            0
+        );
+    }
+
+    #[test]
+    fn freelist_stats_of_other_connections_changes() {
+        let dir = TempDir::new().unwrap();
+        let path = db_path(&dir, "a.db");
+        let a = Connection::open(&path, ConnectionOptions::default()).unwrap();
+        let b = Connection::open(&path, ConnectionOptions::default()).unwrap();
+        assert_eq!(
+            b.freelist_stats().unwrap(),
+            FreelistStats {
+                database_pages: 1,
+                ..Default::default()
+            }
+        );
+        {
+            let mut pager = a.cache.pager();
+            for _ in 0..3 {
+                pager.allocate().unwrap();
+            }
+            pager.commit().unwrap();
+            pager.free_page(2).unwrap();
+            pager.free_page(3).unwrap();
+            pager.commit().unwrap();
+        }
+        let stats = b.freelist_stats().unwrap();
+        assert_eq!((stats.database_pages, stats.free_pages()), (4, 2));
+        assert_eq!(b.header().total_freelist_pages(), 2);
+    }
+
+    #[test]
+    fn auto_vacuum_shrinks_file() {
+        let dir = TempDir::new().unwrap();
+        let path = db_path(&dir, "a.db");
+        let a = Connection::open(&path, ConnectionOptions::default()).unwrap();
+        let b = Connection::open(&path, ConnectionOptions::default()).unwrap();
+        assert_eq!(
+            a.set_auto_vacuum(AutoVacuum::Incremental).unwrap(),
+            AutoVacuum::Incremental
+        );
+        assert_eq!(b.auto_vacuum().unwrap(), AutoVacuum::Incremental);
+        {
+            let mut pager = a.cache.pager();
+            for _ in 0..4 {
+                pager.allocate().unwrap();
+            }
+            pager.commit().unwrap();
+            pager.free_page(5).unwrap();
+            pager.free_page(6).unwrap();
+            pager.commit().unwrap();
+        }
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 6 * 4096);
+        assert_eq!(b.incremental_vacuum(1).unwrap(), 1);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 5 * 4096);
+        assert_eq!(a.freelist_stats().unwrap().free_pages(), 1);
+        // with tables in the database the pointer map stays
+        assert_eq!(
+            b.set_auto_vacuum(AutoVacuum::None).unwrap(),
+            AutoVacuum::Incremental
+        );
+        assert_eq!(
+            b.set_auto_vacuum(AutoVacuum::Full).unwrap(),
+            AutoVacuum::Full
+        );
+    }
+
+    #[test]
+    fn open_rolls_back_hot_journal() {
+        let dir = TempDir::new().unwrap();
+        let conn = Connection::open(&db_path(&dir, "a.db"), ConnectionOptions::default()).unwrap();
+        {
+            let mut pager = conn.cache.pager();
+            pager.allocate().unwrap();
+            pager.commit().unwrap();
+            pager.allocate().unwrap();
+            pager.update_header(|_| {}).unwrap();
+            pager.commit_phase_one().unwrap();
+            std::fs::copy(db_path(&dir, "a.db"), db_path(&dir, "crashed.db")).unwrap();
+            std::fs::copy(pager.journal_path(), db_path(&dir, "crashed.db-journal")).unwrap();
+            pager.rollback().unwrap();
+        }
+        let counter = conn.header().file_change_counter();
+
+        // an immutable database is read as it is
+        let immutable = format!("file:{}?immutable=1", db_path(&dir, "crashed.db"));
+        let conn = Connection::open(&immutable, ConnectionOptions::default()).unwrap();
+        assert_eq!(conn.header().size_in_pages(), 3);
+        drop(conn);
+
+        let conn =
+            Connection::open(&db_path(&dir, "crashed.db"), ConnectionOptions::default()).unwrap();
+        assert_eq!(conn.header().size_in_pages(), 2);
+        assert_eq!(conn.header().file_change_counter(), counter + 1);
+        assert!(!std::path::Path::new(&db_path(&dir, "crashed.db-journal")).exists());
+        assert_eq!(
+            std::fs::metadata(db_path(&dir, "crashed.db"))
+                .unwrap()
+                .len(),
+            2 * 4096
+        );
+    }
+
+    #[test]
+    fn open_named_memory_is_shared() {
+        let uri = "file:shared-memory-test?mode=memory";
+        let a = Connection::open(uri, ConnectionOptions::default()).unwrap();
+        assert!(a.is_memory());
+        assert_eq!(a.vfs_name(), crate::vfs::MEMORY_VFS_NAME);
+        let b = Connection::open(uri, ConnectionOptions::default()).unwrap();
+        assert_eq!(b.header(), a.header());
+        drop(a);
+        drop(b);
+        let vfs = crate::vfs::find(Some(crate::vfs::MEMORY_VFS_NAME)).unwrap();
+        assert!(!vfs
+            .access("shared-memory-test", crate::vfs::AccessFlags::Exists)
+            .unwrap());
+    }
+
+    #[test]
+    fn open_with_registered_vfs() {
+        #[derive(Debug)]
+        struct RamDisk(crate::vfs::MemoryVfs);
+
+        impl Vfs for RamDisk {
+            fn name(&self) -> &str {
+                "ramdisk"
+            }
+
+            fn open(
+                &self,
+                path: &str,
+                flags: OpenFlags,
+                options: &ConnectionOptions,
+            ) -> SqliteResult<Box<dyn crate::vfs::VfsFile>> {
+                self.0.open(path, flags, options)
+            }
+
+            fn delete(&self, path: &str, sync_dir: bool) -> SqliteResult<()> {
+                self.0.delete(path, sync_dir)
+            }
+
+            fn access(&self, path: &str, flags: crate::vfs::AccessFlags) -> SqliteResult<bool> {
+                self.0.access(path, flags)
+            }
+        }
+
+        vfs::register(Arc::new(RamDisk(crate::vfs::MemoryVfs::new())), false);
+        let conn =
+            Connection::open("file:ram.db?vfs=ramdisk", ConnectionOptions::default()).unwrap();
+        assert_eq!(conn.vfs_name(), "ramdisk");
+        assert!(!conn.is_memory());
+        assert_eq!(conn.header().size_in_pages(), 1);
+        vfs::unregister("ramdisk");
+    }
+
+    #[test]
+    fn open_unknown_vfs_err() {
+        let result = Connection::open("file:x.db?vfs=nope", ConnectionOptions::default());
+        assert!(matches!(
+            result,
+            Err(SqliteError::Error {
+                code: ExtendedResultCode::Error,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn shared_cache_is_shared_per_file() {
+        let dir = TempDir::new().unwrap();
+        let path = db_path(&dir, "shared.db");
+        let shared = ConnectionOptions::builder()
+            .cache_type(CacheType::Shared)
+            .build()
+            .unwrap();
+        let a = Connection::open(&path, shared.clone()).unwrap();
+        assert!(!a.is_shared_cache());
+        let b =
+            Connection::open(&format!("file:{}?cache=shared", path), Default::default()).unwrap();
+        assert!(a.is_shared_cache());
+        assert!(b.is_shared_cache());
+        let private = Connection::open(&path, ConnectionOptions::default()).unwrap();
+        assert!(!private.is_shared_cache());
+
+        a.set_cache_size(100);
+        assert_eq!(b.cache_capacity(), 100);
+        assert_eq!(private.cache_capacity(), 500);
+        b.set_cache_size(0);
+        assert_eq!(a.cache_capacity(), 500);
+
+        drop(b);
+        assert!(!a.is_shared_cache());
+    }
+
+    #[test]
+    fn shared_cache_table_locks() {
+        let uri = "file:table-locks?mode=memory&cache=shared";
+        let a = Connection::open(uri, ConnectionOptions::default()).unwrap();
+        let b = Connection::open(uri, ConnectionOptions::default()).unwrap();
+        assert!(a.is_shared_cache());
+
+        a.lock_table(2, TableLock::Read).unwrap();
+        b.lock_table(2, TableLock::Read).unwrap();
+        let result = b.lock_table(2, TableLock::Write);
+        assert!(matches!(
+            result,
+            Err(SqliteError::Locked {
+                code: ExtendedResultCode::LockedSharedCache,
+                ..
+            })
+        ));
+        a.unlock_tables();
+        b.lock_table(2, TableLock::Write).unwrap();
+        assert!(a.lock_table(2, TableLock::Read).is_err());
+
+        // closing a connection releases its locks
+        drop(b);
+        a.lock_table(2, TableLock::Write).unwrap();
+    }
+
+    #[test]
+    fn private_memory_databases_never_share() {
+        let options = ConnectionOptions::builder()
+            .cache_type(CacheType::Shared)
+            .build()
+            .unwrap();
+        let a = Connection::open(":memory:", options.clone()).unwrap();
+        let b = Connection::open(":memory:", options).unwrap();
+        assert!(!a.is_shared_cache());
+        assert!(!b.is_shared_cache());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn open_rwc_copies_mode_of_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new().unwrap();
+        let template = db_path(&dir, "template.db");
+        File::create(&template).unwrap();
+        std::fs::set_permissions(&template, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let path = db_path(&dir, "new.db");
+        let uri = format!("file:{}?modeof={}", path, template);
+        Connection::open(&uri, ConnectionOptions::default()).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -1 +1,7 @@
+mod handle;
 mod options;
+mod uri;
+
+pub use self::handle::*;
+pub use self::options::*;
+pub use self::uri::*;
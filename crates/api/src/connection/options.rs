@@ -1,46 +1,204 @@
-#[derive(Clone, Debug, PartialEq)]
+use crate::errors::ExtendedResultCode;
+use crate::SqliteError;
+
+/// Whether the page cache is private to a connection or shared with other connections to the
+/// same file in this process.  sqlite3 defaults to a private cache.
+#[derive(Clone, Debug, Default, PartialEq)]
 pub enum CacheType {
     Shared,
+    #[default]
     Private,
 }
 
-impl From<&str> for CacheType {
-    fn from(value: &str) -> Self {
+impl TryFrom<&str> for CacheType {
+    type Error = SqliteError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
         match value.to_lowercase().as_str() {
-            "shared" => CacheType::Shared,
-            "private" => CacheType::Private,
-            _ => panic!("invalid cache_type: {}", value),
+            "shared" => Ok(CacheType::Shared),
+            "private" => Ok(CacheType::Private),
+            _ => Err(SqliteError::Misuse {
+                code: ExtendedResultCode::Misuse,
+                message: format!("invalid cache_type: {}", value),
+            }),
         }
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// How the database is opened.  sqlite3 defaults to opening for reading and writing, creating
+/// the file if it does not exist.
+#[derive(Clone, Debug, Default, PartialEq)]
 pub enum Mode {
     ReadOnly,
     ReadWrite,
+    #[default]
     ReadWriteCreate,
     Memory,
 }
 
-impl From<&str> for Mode {
-    fn from(value: &str) -> Self {
+impl TryFrom<&str> for Mode {
+    type Error = SqliteError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
         match value.to_lowercase().as_str() {
-            "ro" => Mode::ReadOnly,
-            "rw" => Mode::ReadWrite,
-            "rwc" => Mode::ReadWriteCreate,
-            "memory" => Mode::Memory,
-            _ => panic!("invalid mode: {}", value),
+            "ro" => Ok(Mode::ReadOnly),
+            "rw" => Ok(Mode::ReadWrite),
+            "rwc" => Ok(Mode::ReadWriteCreate),
+            "memory" => Ok(Mode::Memory),
+            _ => Err(SqliteError::Misuse {
+                code: ExtendedResultCode::Misuse,
+                message: format!("invalid mode: {}", value),
+            }),
+        }
+    }
+}
+
+fn invalid_options(message: &str) -> SqliteError {
+    SqliteError::Misuse {
+        code: ExtendedResultCode::Misuse,
+        message: format!("invalid connection options: {}", message),
+    }
+}
+
+/// Options controlling how a database connection is opened.  These mirror the query parameters
+/// sqlite3 accepts in URI filenames and default to the same values sqlite3 uses.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConnectionOptions {
+    cache_type: CacheType,
+    immutable: bool,
+    mode: Mode,
+    mode_of: Option<String>,
+    no_lock: bool,
+    vfs: Option<String>,
+    parameters: Vec<(String, String)>,
+}
+
+impl ConnectionOptions {
+    pub fn builder() -> ConnectionOptionsBuilder {
+        ConnectionOptionsBuilder::default()
+    }
+
+    /// A builder that starts from these options
+    pub fn into_builder(self) -> ConnectionOptionsBuilder {
+        ConnectionOptionsBuilder { options: self }
+    }
+
+    pub fn cache_type(&self) -> &CacheType {
+        &self.cache_type
+    }
+
+    /// When set the database file is assumed to never change, so no locking or change
+    /// detection is done
+    pub fn immutable(&self) -> bool {
+        self.immutable
+    }
+
+    pub fn mode(&self) -> &Mode {
+        &self.mode
+    }
+
+    /// Path of a file whose permissions are copied when the database file is created
+    pub fn mode_of(&self) -> Option<&str> {
+        self.mode_of.as_deref()
+    }
+
+    /// When set the file locking protocol is skipped entirely
+    pub fn no_lock(&self) -> bool {
+        self.no_lock
+    }
+
+    /// Name of the registered VFS to open the database with, or `None` for the default
+    pub fn vfs(&self) -> Option<&str> {
+        self.vfs.as_deref()
+    }
+
+    /// Query parameters from a URI filename that are not interpreted by the library and are
+    /// passed through to the VFS, in the order they appeared
+    pub fn parameters(&self) -> &[(String, String)] {
+        &self.parameters
+    }
+
+    /// The value of a passthrough parameter, like sqlite3_uri_parameter
+    pub fn parameter(&self, name: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Check that the options do not contradict each other
+    pub fn validate(&self) -> Result<(), SqliteError> {
+        if self.mode == Mode::Memory && self.immutable {
+            return Err(invalid_options("an in-memory database cannot be immutable"));
+        }
+        if let Some(mode_of) = &self.mode_of {
+            if mode_of.is_empty() {
+                return Err(invalid_options("mode_of must name a file"));
+            }
+            if self.mode != Mode::ReadWriteCreate {
+                return Err(invalid_options(
+                    "mode_of only applies when the database may be created",
+                ));
+            }
+            if self.immutable {
+                return Err(invalid_options("an immutable database cannot be created"));
+            }
         }
+        if self.vfs.as_deref() == Some("") {
+            return Err(invalid_options("vfs must name a registered vfs"));
+        }
+        Ok(())
     }
 }
 
-// pub struct ConnectionOptions {
-//     cache_type: CacheType,
-//     immutable: bool,
-//     mode: Mode,
-//     mode_of: String,
-//     no_lock: bool,
-// }
+/// Builds a validated [`ConnectionOptions`]
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionOptionsBuilder {
+    options: ConnectionOptions,
+}
+
+impl ConnectionOptionsBuilder {
+    pub fn cache_type(mut self, cache_type: CacheType) -> Self {
+        self.options.cache_type = cache_type;
+        self
+    }
+
+    pub fn immutable(mut self, immutable: bool) -> Self {
+        self.options.immutable = immutable;
+        self
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.options.mode = mode;
+        self
+    }
+
+    pub fn mode_of(mut self, mode_of: impl Into<String>) -> Self {
+        self.options.mode_of = Some(mode_of.into());
+        self
+    }
+
+    pub fn no_lock(mut self, no_lock: bool) -> Self {
+        self.options.no_lock = no_lock;
+        self
+    }
+
+    pub fn vfs(mut self, vfs: impl Into<String>) -> Self {
+        self.options.vfs = Some(vfs.into());
+        self
+    }
+
+    /// Add a parameter that is passed through to the VFS
+    pub fn parameter(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.options.parameters.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn build(self) -> Result<ConnectionOptions, SqliteError> {
+        self.options.validate()?;
+        Ok(self.options)
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -65,15 +223,17 @@ mod tests {
             ("rWc", Mode::ReadWriteCreate),
         ];
         for case in cases {
-            let mode = Mode::from(case.0);
+            let mode = Mode::try_from(case.0).unwrap();
             assert_eq!(mode, case.1)
         }
     }
 
     #[test]
-    #[should_panic(expected = "invalid mode: will_fail")]
     fn mode_from_str_fail() {
-        let _mode = Mode::from("will_fail");
+        let result = Mode::try_from("will_fail");
+        assert!(
+            matches!(result, Err(SqliteError::Misuse { code: ExtendedResultCode::Misuse, ref message }) if message == "invalid mode: will_fail")
+        );
     }
 
     #[test]
@@ -87,14 +247,95 @@ mod tests {
             ("pRiVaTe", CacheType::Private),
         ];
         for case in cases {
-            let ct: CacheType = CacheType::from(case.0);
+            let ct = CacheType::try_from(case.0).unwrap();
             assert_eq!(ct, case.1);
         }
     }
 
     #[test]
-    #[should_panic(expected = "invalid cache_type: will_fail")]
     fn cache_type_fail() {
-        let _ct = CacheType::from("will_fail");
+        let result = CacheType::try_from("will_fail");
+        assert!(
+            matches!(result, Err(SqliteError::Misuse { code: ExtendedResultCode::Misuse, ref message }) if message == "invalid cache_type: will_fail")
+        );
+    }
+
+    #[test]
+    fn connection_options_defaults_match_sqlite3() {
+        let options = ConnectionOptions::default();
+        assert_eq!(options.cache_type(), &CacheType::Private);
+        assert!(!options.immutable());
+        assert_eq!(options.mode(), &Mode::ReadWriteCreate);
+        assert_eq!(options.mode_of(), None);
+        assert!(!options.no_lock());
+        assert_eq!(options.vfs(), None);
+        assert!(options.parameters().is_empty());
+        assert_eq!(ConnectionOptions::builder().build().unwrap(), options);
+    }
+
+    #[test]
+    fn connection_options_builder_ok() {
+        let options = ConnectionOptions::builder()
+            .cache_type(CacheType::Shared)
+            .mode(Mode::ReadOnly)
+            .immutable(true)
+            .no_lock(true)
+            .vfs("unix")
+            .parameter("psow", "0")
+            .build()
+            .unwrap();
+        assert_eq!(options.vfs(), Some("unix"));
+        assert_eq!(options.parameter("psow"), Some("0"));
+        assert_eq!(options.parameter("missing"), None);
+        assert_eq!(options.cache_type(), &CacheType::Shared);
+        assert!(options.immutable());
+        assert_eq!(options.mode(), &Mode::ReadOnly);
+        assert!(options.no_lock());
+
+        let options = ConnectionOptions::builder()
+            .mode_of("template.db")
+            .build()
+            .unwrap();
+        assert_eq!(options.mode_of(), Some("template.db"));
+
+        let options = ConnectionOptions::builder()
+            .mode(Mode::Memory)
+            .cache_type(CacheType::Shared)
+            .build()
+            .unwrap();
+        assert_eq!(options.mode(), &Mode::Memory);
+    }
+
+    #[test]
+    fn connection_options_builder_contradictions() {
+        let cases = vec![
+            ConnectionOptions::builder()
+                .mode(Mode::Memory)
+                .immutable(true),
+            ConnectionOptions::builder()
+                .mode(Mode::Memory)
+                .mode_of("template.db"),
+            ConnectionOptions::builder()
+                .mode(Mode::ReadOnly)
+                .mode_of("template.db"),
+            ConnectionOptions::builder()
+                .mode(Mode::ReadWrite)
+                .mode_of("template.db"),
+            ConnectionOptions::builder()
+                .immutable(true)
+                .mode_of("template.db"),
+            ConnectionOptions::builder().mode_of(""),
+            ConnectionOptions::builder().vfs(""),
+        ];
+        for case in cases {
+            let result = case.build();
+            assert!(matches!(
+                result,
+                Err(SqliteError::Misuse {
+                    code: ExtendedResultCode::Misuse,
+                    ..
+                })
+            ));
+        }
     }
 }
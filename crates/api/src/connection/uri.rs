@@ -0,0 +1,375 @@
+use crate::connection::options::{CacheType, ConnectionOptions, Mode};
+use crate::errors::{ExtendedResultCode, SqliteResult};
+use crate::SqliteError;
+
+/// The filename that opens a private, in-memory database
+pub const MEMORY_FILENAME: &str = ":memory:";
+
+const URI_SCHEME: &str = "file:";
+
+fn invalid_uri(message: String) -> SqliteError {
+    SqliteError::Error {
+        code: ExtendedResultCode::Error,
+        message,
+    }
+}
+
+fn from_hex(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Decode `%HH` escapes.  Unlike form encoding, `+` is left as is, matching sqlite3.
+fn percent_decode(value: &str) -> SqliteResult<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let escape = bytes
+                .get(i + 1)
+                .and_then(|hi| from_hex(*hi))
+                .zip(bytes.get(i + 2).and_then(|lo| from_hex(*lo)));
+            match escape {
+                Some((0, 0)) => {
+                    return Err(invalid_uri(format!("invalid uri escape %00 in: {}", value)))
+                }
+                Some((hi, lo)) => decoded.push(hi << 4 | lo),
+                None => return Err(invalid_uri(format!("invalid uri escape in: {}", value))),
+            }
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded)
+        .map_err(|_| invalid_uri(format!("uri is not valid utf-8: {}", value)))
+}
+
+/// Parse a boolean query parameter like sqlite3_uri_boolean: any integer, true unless it
+/// is zero, or one of the words sqlite3 knows.  Where sqlite3 falls back to the default for
+/// anything else, this is an error.
+fn parse_bool(name: &str, value: &str) -> SqliteResult<bool> {
+    if let Ok(integer) = value.parse::<i64>() {
+        return Ok(integer != 0);
+    }
+    match value.to_lowercase().as_str() {
+        "yes" | "true" | "on" => Ok(true),
+        "no" | "false" | "off" => Ok(false),
+        _ => Err(SqliteError::Misuse {
+            code: ExtendedResultCode::Misuse,
+            message: format!("invalid boolean for {}: {}", name, value),
+        }),
+    }
+}
+
+/// Orders the file access modes so a URI can be checked against the access the caller granted
+fn access_level(mode: &Mode) -> u8 {
+    match mode {
+        Mode::ReadOnly => 0,
+        Mode::ReadWrite => 1,
+        Mode::ReadWriteCreate | Mode::Memory => 2,
+    }
+}
+
+/// Split the part after `file:` into the path and the query string, applying the authority
+/// rules.  Anything after a `#` is a fragment and is ignored.
+fn split_uri(rest: &str) -> SqliteResult<(&str, &str)> {
+    let rest = rest.split('#').next().unwrap_or_default();
+    let rest = match rest.strip_prefix("//") {
+        Some(after_slashes) => {
+            let authority_end = after_slashes.find('/').unwrap_or(after_slashes.len());
+            let authority = &after_slashes[..authority_end];
+            if !authority.is_empty() && authority != "localhost" {
+                return Err(invalid_uri(format!("invalid uri authority: {}", authority)));
+            }
+            &after_slashes[authority_end..]
+        }
+        None => rest,
+    };
+    Ok(match rest.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (rest, ""),
+    })
+}
+
+/// A database filename split into the path to open and the options it requests
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatabaseUri {
+    path: String,
+    options: ConnectionOptions,
+}
+
+impl DatabaseUri {
+    /// Parse a filename using the sqlite3 defaults for anything it does not specify
+    pub fn parse(filename: &str) -> SqliteResult<DatabaseUri> {
+        DatabaseUri::parse_with(filename, ConnectionOptions::default())
+    }
+
+    /// Parse a filename, layering the query parameters of a `file:` URI on top of `options`.
+    /// A URI may not ask for more access than `options` grants.  Filenames that do not start
+    /// with `file:` are used verbatim, apart from the special `:memory:` name.
+    pub fn parse_with(filename: &str, options: ConnectionOptions) -> SqliteResult<DatabaseUri> {
+        let Some(rest) = filename.strip_prefix(URI_SCHEME) else {
+            let options = if filename == MEMORY_FILENAME {
+                options.into_builder().mode(Mode::Memory).build()?
+            } else {
+                options
+            };
+            return Ok(DatabaseUri {
+                path: String::from(filename),
+                options,
+            });
+        };
+
+        let (path, query) = split_uri(rest)?;
+        let path = percent_decode(path)?;
+        let granted = access_level(options.mode());
+        let mut builder = options.into_builder();
+        if path == MEMORY_FILENAME {
+            builder = builder.mode(Mode::Memory);
+        }
+
+        for pair in query.split('&') {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let name = percent_decode(name)?;
+            let value = percent_decode(value)?;
+            if name.is_empty() {
+                continue;
+            }
+            builder = match name.as_str() {
+                "mode" => {
+                    let mode = Mode::try_from(value.as_str())?;
+                    if access_level(&mode) > granted {
+                        return Err(SqliteError::Perm {
+                            code: ExtendedResultCode::Perm,
+                            message: format!("access mode not allowed: {}", value),
+                        });
+                    }
+                    builder.mode(mode)
+                }
+                "cache" => builder.cache_type(CacheType::try_from(value.as_str())?),
+                "immutable" => builder.immutable(parse_bool(&name, &value)?),
+                "nolock" => builder.no_lock(parse_bool(&name, &value)?),
+                "modeof" => builder.mode_of(value),
+                "vfs" => builder.vfs(value),
+                _ => builder.parameter(name, value),
+            };
+        }
+
+        Ok(DatabaseUri {
+            path,
+            options: builder.build()?,
+        })
+    }
+
+    /// The decoded path of the database file, or the name of an in-memory database
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn options(&self) -> &ConnectionOptions {
+        &self.options
+    }
+
+    pub fn into_parts(self) -> (String, ConnectionOptions) {
+        (self.path, self.options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_full_uri_ok() {
+        let uri =
+            DatabaseUri::parse("file:data.db?mode=ro&cache=shared&immutable=1&nolock=1&vfs=unix")
+                .unwrap();
+        assert_eq!(uri.path(), "data.db");
+        let options = uri.options();
+        assert_eq!(options.mode(), &Mode::ReadOnly);
+        assert_eq!(options.cache_type(), &CacheType::Shared);
+        assert!(options.immutable());
+        assert!(options.no_lock());
+        assert_eq!(options.vfs(), Some("unix"));
+        assert!(options.parameters().is_empty());
+    }
+
+    #[test]
+    fn parse_mode_of_ok() {
+        let uri = DatabaseUri::parse("file:data.db?modeof=template.db&mode=rwc").unwrap();
+        assert_eq!(uri.options().mode_of(), Some("template.db"));
+    }
+
+    #[test]
+    fn parse_plain_filename_ok() {
+        let cases = vec!["data.db", "/tmp/data.db", "data.db?mode=ro", "FILE:data.db"];
+        for case in cases {
+            let uri = DatabaseUri::parse(case).unwrap();
+            assert_eq!(uri.path(), case);
+            assert_eq!(uri.options(), &ConnectionOptions::default());
+        }
+    }
+
+    #[test]
+    fn parse_memory_ok() {
+        let cases = vec![
+            (":memory:", ":memory:"),
+            ("file::memory:", ":memory:"),
+            ("file::memory:?cache=shared", ":memory:"),
+            ("file:memdb1?mode=memory&cache=shared", "memdb1"),
+        ];
+        for case in cases {
+            let uri = DatabaseUri::parse(case.0).unwrap();
+            assert_eq!(uri.path(), case.1);
+            assert_eq!(uri.options().mode(), &Mode::Memory, "{}", case.0);
+        }
+    }
+
+    #[test]
+    fn parse_authority_ok() {
+        let cases = vec![
+            ("file:///home/fred/data.db", "/home/fred/data.db"),
+            ("file://localhost/home/fred/data.db", "/home/fred/data.db"),
+            ("file:/home/fred/data.db", "/home/fred/data.db"),
+            ("file://", ""),
+        ];
+        for case in cases {
+            let uri = DatabaseUri::parse(case.0).unwrap();
+            assert_eq!(uri.path(), case.1);
+        }
+    }
+
+    #[test]
+    fn parse_authority_err() {
+        let result = DatabaseUri::parse("file://darkstar/home/fred/data.db");
+        assert!(
+            matches!(result, Err(SqliteError::Error { code: ExtendedResultCode::Error, ref message }) if message == "invalid uri authority: darkstar")
+        );
+    }
+
+    #[test]
+    fn parse_percent_encoding_ok() {
+        let uri = DatabaseUri::parse(
+            "file:data%20base%3f.db?vfs=un%69x&na%6De=a%26b&plus=a+b#fragment&mode=ro",
+        )
+        .unwrap();
+        assert_eq!(uri.path(), "data base?.db");
+        assert_eq!(uri.options().vfs(), Some("unix"));
+        assert_eq!(uri.options().parameter("name"), Some("a&b"));
+        assert_eq!(uri.options().parameter("plus"), Some("a+b"));
+        assert_eq!(uri.options().mode(), &Mode::ReadWriteCreate);
+    }
+
+    #[test]
+    fn parse_percent_encoding_err() {
+        let cases = vec![
+            "file:data%2.db",
+            "file:data%zz.db",
+            "file:data%",
+            "file:data%00.db",
+            "file:data%ff.db",
+            "file:data.db?vfs=%g0",
+        ];
+        for case in cases {
+            let result = DatabaseUri::parse(case);
+            assert!(
+                matches!(
+                    result,
+                    Err(SqliteError::Error {
+                        code: ExtendedResultCode::Error,
+                        ..
+                    })
+                ),
+                "{}",
+                case
+            );
+        }
+    }
+
+    #[test]
+    fn parse_unknown_parameters_passthrough() {
+        let uri = DatabaseUri::parse("file:data.db?psow=0&&=ignored&flag&psow=1").unwrap();
+        assert_eq!(
+            uri.options().parameters(),
+            &[
+                (String::from("psow"), String::from("0")),
+                (String::from("flag"), String::new()),
+                (String::from("psow"), String::from("1")),
+            ]
+        );
+        assert_eq!(uri.options().parameter("psow"), Some("0"));
+    }
+
+    #[test]
+    fn parse_bad_values_err() {
+        let cases =
+            vec![
+            ("file:data.db?mode=rwx", "invalid mode: rwx"),
+            ("file:data.db?cache=public", "invalid cache_type: public"),
+            ("file:data.db?immutable=maybe", "invalid boolean for immutable: maybe"),
+            ("file:data.db?nolock=1.5", "invalid boolean for nolock: 1.5"),
+            (
+                "file::memory:?immutable=1",
+                "invalid connection options: an in-memory database cannot be immutable",
+            ),
+            (
+                "file:data.db?mode=ro&modeof=template.db",
+                "invalid connection options: mode_of only applies when the database may be created",
+            ),
+            (
+                "file:data.db?vfs=",
+                "invalid connection options: vfs must name a registered vfs",
+            ),
+        ];
+        for case in cases {
+            let result = DatabaseUri::parse(case.0);
+            assert!(
+                matches!(result, Err(SqliteError::Misuse { code: ExtendedResultCode::Misuse, ref message }) if message == case.1),
+                "{}",
+                case.0
+            );
+        }
+    }
+
+    #[test]
+    fn parse_bool_ok() {
+        let cases = vec![
+            ("1", true),
+            ("2", true),
+            ("-1", true),
+            ("0", false),
+            ("00", false),
+            ("YES", true),
+            ("on", true),
+            ("False", false),
+            ("off", false),
+        ];
+        for case in cases {
+            assert_eq!(parse_bool("nolock", case.0).unwrap(), case.1, "{}", case.0);
+        }
+    }
+
+    #[test]
+    fn parse_with_base_options() {
+        let base = ConnectionOptions::builder()
+            .mode(Mode::ReadWrite)
+            .no_lock(true)
+            .build()
+            .unwrap();
+        let uri = DatabaseUri::parse_with("file:data.db?mode=ro", base.clone()).unwrap();
+        assert_eq!(uri.options().mode(), &Mode::ReadOnly);
+        assert!(uri.options().no_lock());
+
+        let result = DatabaseUri::parse_with("file:data.db?mode=rwc", base);
+        assert!(
+            matches!(result, Err(SqliteError::Perm { code: ExtendedResultCode::Perm, ref message }) if message == "access mode not allowed: rwc")
+        );
+    }
+}
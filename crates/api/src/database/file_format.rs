@@ -1,15 +1,23 @@
+use crate::errors::ExtendedResultCode;
+use crate::SqliteError;
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum FileFormatWriteVersion {
     Legacy,
     Wal,
 }
 
-impl From<u8> for FileFormatWriteVersion {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for FileFormatWriteVersion {
+    type Error = SqliteError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            1 => FileFormatWriteVersion::Legacy,
-            2 => FileFormatWriteVersion::Wal,
-            _ => panic!("unsupported file format write version: {}", value),
+            1 => Ok(FileFormatWriteVersion::Legacy),
+            2 => Ok(FileFormatWriteVersion::Wal),
+            _ => Err(SqliteError::NotADb {
+                code: ExtendedResultCode::NotADb,
+                message: format!("unsupported file format write version: {}", value),
+            }),
         }
     }
 }
@@ -29,12 +37,17 @@ pub enum FileFormatReadVersion {
     Wal,
 }
 
-impl From<u8> for FileFormatReadVersion {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for FileFormatReadVersion {
+    type Error = SqliteError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
-            1 => FileFormatReadVersion::Legacy,
-            2 => FileFormatReadVersion::Wal,
-            _ => panic!("unsupported file format read version: {}", value),
+            1 => Ok(FileFormatReadVersion::Legacy),
+            2 => Ok(FileFormatReadVersion::Wal),
+            _ => Err(SqliteError::NotADb {
+                code: ExtendedResultCode::NotADb,
+                message: format!("unsupported file format read version: {}", value),
+            }),
         }
     }
 }
@@ -59,15 +72,20 @@ mod tests {
             (2, FileFormatWriteVersion::Wal),
         ];
         for case in cases {
-            let result = FileFormatWriteVersion::from(case.0);
+            let result = FileFormatWriteVersion::try_from(case.0).unwrap();
             assert_eq!(result, case.1);
         }
     }
 
     #[test]
-    #[should_panic(expected = "unsupported file format write version: 42")]
     fn file_format_write_version_err() {
-        let _result = FileFormatWriteVersion::from(42);
+        let cases: Vec<u8> = vec![0, 3, 42, 255];
+        for case in cases {
+            let result = FileFormatWriteVersion::try_from(case);
+            assert!(
+                matches!(result, Err(SqliteError::NotADb { code: ExtendedResultCode::NotADb, ref message }) if *message == format!("unsupported file format write version: {}", case))
+            );
+        }
     }
 
     #[test]
@@ -89,15 +107,20 @@ mod tests {
             (2, FileFormatReadVersion::Wal),
         ];
         for case in cases {
-            let result = FileFormatReadVersion::from(case.0);
+            let result = FileFormatReadVersion::try_from(case.0).unwrap();
             assert_eq!(result, case.1);
         }
     }
 
     #[test]
-    #[should_panic(expected = "unsupported file format read version: 42")]
     fn file_format_read_version_err() {
-        let _result = FileFormatReadVersion::from(42);
+        let cases: Vec<u8> = vec![0, 3, 42, 255];
+        for case in cases {
+            let result = FileFormatReadVersion::try_from(case);
+            assert!(
+                matches!(result, Err(SqliteError::NotADb { code: ExtendedResultCode::NotADb, ref message }) if *message == format!("unsupported file format read version: {}", case))
+            );
+        }
     }
 
     #[test]
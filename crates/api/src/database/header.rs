@@ -1,8 +1,102 @@
 use crate::database::file_format::{FileFormatReadVersion, FileFormatWriteVersion};
 use crate::database::page_size::PageSize;
-use crate::errors::SqliteResult;
+use crate::database::schema_format::SchemaFormat;
+use crate::database::text_encoding::TextEncoding;
+use crate::errors::{ExtendedResultCode, SqliteResult};
 use crate::SqliteError;
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+/// The magic string every database file starts with
+const MAGIC_HEADER_STRING: &str = "SQLite format 3\0";
+
+/// Total size of the database header in bytes
+pub const HEADER_SIZE: usize = 100;
+
+/// Number of bytes at offset 72 reserved for expansion, which must be zero
+const RESERVED_FOR_EXPANSION_SIZE: usize = 20;
+
+/// The version of sqlite3 whose file format this library writes, stored at offset 96
+pub(crate) const SQLITE_VERSION_NUMBER: u32 = 3046001;
+
+/// The smallest usable page size (page size less reserved space) sqlite3 will accept
+pub(crate) const MIN_USABLE_PAGE_SIZE: u32 = 480;
+
+fn corrupt_header(message: String) -> SqliteError {
+    SqliteError::Corrupt {
+        code: ExtendedResultCode::Corrupt,
+        message,
+    }
+}
+
+fn read_header_string(buf: &mut Bytes) -> Result<String, SqliteError> {
+    let magic = buf.split_to(MAGIC_HEADER_STRING.len());
+    if magic.as_ref() != MAGIC_HEADER_STRING.as_bytes() {
+        return Err(SqliteError::NotADb {
+            code: ExtendedResultCode::NotADb,
+            message: String::from("file is not a database"),
+        });
+    }
+    Ok(String::from(MAGIC_HEADER_STRING))
+}
+
+fn read_page_size(buf: &mut Bytes) -> Result<PageSize, SqliteError> {
+    PageSize::try_from(buf.get_u16())
+}
+
+fn read_file_format_write_version(buf: &mut Bytes) -> Result<FileFormatWriteVersion, SqliteError> {
+    FileFormatWriteVersion::try_from(buf.get_u8())
+}
+
+fn read_file_format_read_version(buf: &mut Bytes) -> Result<FileFormatReadVersion, SqliteError> {
+    FileFormatReadVersion::try_from(buf.get_u8())
+}
+
+fn read_page_reserved_space(buf: &mut Bytes, page_size: PageSize) -> Result<u8, SqliteError> {
+    let value = buf.get_u8();
+    let usable = u32::from(page_size) - value as u32;
+    if usable < MIN_USABLE_PAGE_SIZE {
+        return Err(corrupt_header(format!(
+            "page reserved space {} leaves a usable page size of {}",
+            value, usable
+        )));
+    }
+    Ok(value)
+}
+
+fn read_schema_format(buf: &mut Bytes) -> Result<Option<SchemaFormat>, SqliteError> {
+    match buf.get_u32() {
+        0 => Ok(None),
+        value => SchemaFormat::try_from(value).map(Some),
+    }
+}
+
+fn read_text_encoding(buf: &mut Bytes) -> Result<Option<TextEncoding>, SqliteError> {
+    match buf.get_u32() {
+        0 => Ok(None),
+        value => TextEncoding::try_from(value).map(Some),
+    }
+}
+
+fn read_reserved_for_expansion(buf: &mut Bytes) -> Result<(), SqliteError> {
+    let reserved = buf.split_to(RESERVED_FOR_EXPANSION_SIZE);
+    if reserved.iter().any(|b| *b != 0) {
+        return Err(corrupt_header(String::from(
+            "reserved for expansion bytes must be zero",
+        )));
+    }
+    Ok(())
+}
+
+fn read_payload_fraction(buf: &mut Bytes, name: &str, expected: u8) -> Result<u8, SqliteError> {
+    let value = buf.get_u8();
+    if value != expected {
+        return Err(corrupt_header(format!(
+            "invalid {} payload fraction: {}",
+            name, value
+        )));
+    }
+    Ok(value)
+}
 
 #[allow(dead_code)]
 type HeaderFieldWriter = fn(header: &SqliteHeader, bytes: &mut BytesMut) -> Result<(), SqliteError>;
@@ -78,7 +172,93 @@ fn write_size_in_pages(header: &SqliteHeader, bytes: &mut BytesMut) -> Result<()
     Ok(())
 }
 
-const WRITERS: [HeaderFieldWriter; 10] = [
+fn write_first_freelist_trunk_page(
+    header: &SqliteHeader,
+    bytes: &mut BytesMut,
+) -> Result<(), SqliteError> {
+    bytes.put_u32(header.first_freelist_trunk_page);
+    Ok(())
+}
+
+fn write_total_freelist_pages(
+    header: &SqliteHeader,
+    bytes: &mut BytesMut,
+) -> Result<(), SqliteError> {
+    bytes.put_u32(header.total_freelist_pages);
+    Ok(())
+}
+
+fn write_schema_cookie(header: &SqliteHeader, bytes: &mut BytesMut) -> Result<(), SqliteError> {
+    bytes.put_u32(header.schema_cookie);
+    Ok(())
+}
+
+fn write_schema_format(header: &SqliteHeader, bytes: &mut BytesMut) -> Result<(), SqliteError> {
+    bytes.put_u32(header.schema_format.map_or(0, |v| v.into()));
+    Ok(())
+}
+
+fn write_default_page_cache_size(
+    header: &SqliteHeader,
+    bytes: &mut BytesMut,
+) -> Result<(), SqliteError> {
+    bytes.put_u32(header.default_page_cache_size);
+    Ok(())
+}
+
+fn write_largest_root_btree_page(
+    header: &SqliteHeader,
+    bytes: &mut BytesMut,
+) -> Result<(), SqliteError> {
+    bytes.put_u32(header.largest_root_btree_page);
+    Ok(())
+}
+
+fn write_text_encoding(header: &SqliteHeader, bytes: &mut BytesMut) -> Result<(), SqliteError> {
+    bytes.put_u32(header.text_encoding.map_or(0, |v| v.into()));
+    Ok(())
+}
+
+fn write_user_version(header: &SqliteHeader, bytes: &mut BytesMut) -> Result<(), SqliteError> {
+    bytes.put_i32(header.user_version);
+    Ok(())
+}
+
+fn write_incremental_vacuum(
+    header: &SqliteHeader,
+    bytes: &mut BytesMut,
+) -> Result<(), SqliteError> {
+    bytes.put_u32(header.incremental_vacuum as u32);
+    Ok(())
+}
+
+fn write_application_id(header: &SqliteHeader, bytes: &mut BytesMut) -> Result<(), SqliteError> {
+    bytes.put_i32(header.application_id);
+    Ok(())
+}
+
+fn write_reserved_for_expansion(
+    _header: &SqliteHeader,
+    bytes: &mut BytesMut,
+) -> Result<(), SqliteError> {
+    bytes.put_bytes(0, RESERVED_FOR_EXPANSION_SIZE);
+    Ok(())
+}
+
+fn write_version_valid_for(header: &SqliteHeader, bytes: &mut BytesMut) -> Result<(), SqliteError> {
+    bytes.put_u32(header.version_valid_for);
+    Ok(())
+}
+
+fn write_sqlite_version_number(
+    header: &SqliteHeader,
+    bytes: &mut BytesMut,
+) -> Result<(), SqliteError> {
+    bytes.put_u32(header.sqlite_version_number);
+    Ok(())
+}
+
+const WRITERS: [HeaderFieldWriter; 23] = [
     write_header_string,
     write_page_size,
     write_file_format_write_version,
@@ -89,6 +269,19 @@ const WRITERS: [HeaderFieldWriter; 10] = [
     write_leaf_payload_fraction,
     write_file_change_counter,
     write_size_in_pages,
+    write_first_freelist_trunk_page,
+    write_total_freelist_pages,
+    write_schema_cookie,
+    write_schema_format,
+    write_default_page_cache_size,
+    write_largest_root_btree_page,
+    write_text_encoding,
+    write_user_version,
+    write_incremental_vacuum,
+    write_application_id,
+    write_reserved_for_expansion,
+    write_version_valid_for,
+    write_sqlite_version_number,
 ];
 
 /// Represents the header section of the database per https://sqlite.org/fileformat2.html
@@ -104,24 +297,272 @@ pub struct SqliteHeader {
     leaf_payload_fraction: u8,
     file_change_counter: u32,
     size_in_pages: u32,
+    first_freelist_trunk_page: u32,
+    total_freelist_pages: u32,
+    schema_cookie: u32,
+    /// `None` until the first schema object is created
+    schema_format: Option<SchemaFormat>,
+    default_page_cache_size: u32,
+    /// Non-zero only for auto-vacuum and incremental-vacuum databases
+    largest_root_btree_page: u32,
+    /// `None` until the first schema object is created
+    text_encoding: Option<TextEncoding>,
+    user_version: i32,
+    incremental_vacuum: bool,
+    application_id: i32,
+    version_valid_for: u32,
+    sqlite_version_number: u32,
 }
 
 impl SqliteHeader {
-    /// Given a Byte buffer, create a SqliteHeader struct
-    #[allow(dead_code)]
-    fn from_buffer(_buf: &Bytes) -> SqliteResult<SqliteHeader> {
-        todo!()
+    /// The header of a newly created, empty database.  Like sqlite3, the schema format and text
+    /// encoding are left unset until the first schema object is created.
+    pub(crate) fn new(page_size: PageSize) -> SqliteHeader {
+        SqliteHeader {
+            header: String::from(MAGIC_HEADER_STRING),
+            page_size,
+            file_format_write_version: FileFormatWriteVersion::Legacy,
+            file_format_read_version: FileFormatReadVersion::Legacy,
+            page_reserved_space: 0,
+            max_embedded_payload_fraction: 64,
+            min_embedded_payload_fraction: 32,
+            leaf_payload_fraction: 32,
+            file_change_counter: 1,
+            size_in_pages: 1,
+            first_freelist_trunk_page: 0,
+            total_freelist_pages: 0,
+            schema_cookie: 0,
+            schema_format: None,
+            default_page_cache_size: 0,
+            largest_root_btree_page: 0,
+            text_encoding: None,
+            user_version: 0,
+            incremental_vacuum: false,
+            application_id: 0,
+            version_valid_for: 1,
+            sqlite_version_number: SQLITE_VERSION_NUMBER,
+        }
+    }
+
+    /// Given a Byte buffer, create a SqliteHeader struct.  The buffer must hold at least the
+    /// 100 header bytes; anything past that is ignored.
+    pub(crate) fn from_buffer(buf: &Bytes) -> SqliteResult<SqliteHeader> {
+        if buf.len() < HEADER_SIZE {
+            return Err(corrupt_header(format!(
+                "database header is truncated: expected {} bytes, found {}",
+                HEADER_SIZE,
+                buf.len()
+            )));
+        }
+        let mut buf = buf.slice(0..HEADER_SIZE);
+
+        let header = read_header_string(&mut buf)?;
+        let page_size = read_page_size(&mut buf)?;
+        let file_format_write_version = read_file_format_write_version(&mut buf)?;
+        let file_format_read_version = read_file_format_read_version(&mut buf)?;
+        let page_reserved_space = read_page_reserved_space(&mut buf, page_size)?;
+        let max_embedded_payload_fraction = read_payload_fraction(&mut buf, "max embedded", 64)?;
+        let min_embedded_payload_fraction = read_payload_fraction(&mut buf, "min embedded", 32)?;
+        let leaf_payload_fraction = read_payload_fraction(&mut buf, "leaf", 32)?;
+        let file_change_counter = buf.get_u32();
+        let size_in_pages = buf.get_u32();
+        let first_freelist_trunk_page = buf.get_u32();
+        let total_freelist_pages = buf.get_u32();
+        let schema_cookie = buf.get_u32();
+        let schema_format = read_schema_format(&mut buf)?;
+        let default_page_cache_size = buf.get_u32();
+        let largest_root_btree_page = buf.get_u32();
+        let text_encoding = read_text_encoding(&mut buf)?;
+        let user_version = buf.get_i32();
+        let incremental_vacuum = buf.get_u32() != 0;
+        let application_id = buf.get_i32();
+        read_reserved_for_expansion(&mut buf)?;
+        let version_valid_for = buf.get_u32();
+        let sqlite_version_number = buf.get_u32();
+
+        Ok(SqliteHeader {
+            header,
+            page_size,
+            file_format_write_version,
+            file_format_read_version,
+            page_reserved_space,
+            max_embedded_payload_fraction,
+            min_embedded_payload_fraction,
+            leaf_payload_fraction,
+            file_change_counter,
+            size_in_pages,
+            first_freelist_trunk_page,
+            total_freelist_pages,
+            schema_cookie,
+            schema_format,
+            default_page_cache_size,
+            largest_root_btree_page,
+            text_encoding,
+            user_version,
+            incremental_vacuum,
+            application_id,
+            version_valid_for,
+            sqlite_version_number,
+        })
+    }
+
+    /// The in-header database size is only trusted when it is non-zero and was written by the
+    /// same transaction that last bumped the file change counter; otherwise the size has to be
+    /// computed from the size of the database file.
+    pub fn is_size_in_pages_valid(&self) -> bool {
+        self.size_in_pages != 0 && self.version_valid_for == self.file_change_counter
     }
 
     /// Given a mutable Byte buffer, write the contents of the header starting at position 0
     /// in the buffer
-    #[allow(dead_code)]
-    fn write(&self, buf: &mut BytesMut) -> SqliteResult<()> {
+    pub(crate) fn write(&self, buf: &mut BytesMut) -> SqliteResult<()> {
         for writer in WRITERS {
             writer(self, buf)?;
         }
         Ok(())
     }
+
+    pub fn page_size(&self) -> PageSize {
+        self.page_size
+    }
+
+    pub fn file_format_write_version(&self) -> FileFormatWriteVersion {
+        self.file_format_write_version
+    }
+
+    pub fn file_format_read_version(&self) -> FileFormatReadVersion {
+        self.file_format_read_version
+    }
+
+    /// Bytes at the end of every page reserved for extensions
+    pub fn page_reserved_space(&self) -> u8 {
+        self.page_reserved_space
+    }
+
+    pub fn max_embedded_payload_fraction(&self) -> u8 {
+        self.max_embedded_payload_fraction
+    }
+
+    pub fn min_embedded_payload_fraction(&self) -> u8 {
+        self.min_embedded_payload_fraction
+    }
+
+    pub fn leaf_payload_fraction(&self) -> u8 {
+        self.leaf_payload_fraction
+    }
+
+    pub fn file_change_counter(&self) -> u32 {
+        self.file_change_counter
+    }
+
+    pub fn size_in_pages(&self) -> u32 {
+        self.size_in_pages
+    }
+
+    /// The first trunk page of the freelist, or 0 when no page is free
+    pub fn first_freelist_trunk_page(&self) -> u32 {
+        self.first_freelist_trunk_page
+    }
+
+    /// Number of free pages, counting the trunk pages as well as the pages they list
+    pub fn total_freelist_pages(&self) -> u32 {
+        self.total_freelist_pages
+    }
+
+    pub fn schema_cookie(&self) -> u32 {
+        self.schema_cookie
+    }
+
+    pub fn schema_format(&self) -> Option<SchemaFormat> {
+        self.schema_format
+    }
+
+    pub fn default_page_cache_size(&self) -> u32 {
+        self.default_page_cache_size
+    }
+
+    /// The largest root page of any b-tree in an auto-vacuum database, or 0 when the database
+    /// does not auto-vacuum
+    pub fn largest_root_btree_page(&self) -> u32 {
+        self.largest_root_btree_page
+    }
+
+    pub fn text_encoding(&self) -> Option<TextEncoding> {
+        self.text_encoding
+    }
+
+    pub fn user_version(&self) -> i32 {
+        self.user_version
+    }
+
+    /// Whether an auto-vacuum database only vacuums on request rather than on every commit
+    pub fn incremental_vacuum(&self) -> bool {
+        self.incremental_vacuum
+    }
+
+    pub fn application_id(&self) -> i32 {
+        self.application_id
+    }
+
+    pub fn version_valid_for(&self) -> u32 {
+        self.version_valid_for
+    }
+
+    pub fn sqlite_version_number(&self) -> u32 {
+        self.sqlite_version_number
+    }
+
+    /// Switch between rollback journal and WAL mode, which sqlite3 records by setting both
+    /// file format versions
+    pub(crate) fn set_file_format_versions(
+        &mut self,
+        write_version: FileFormatWriteVersion,
+        read_version: FileFormatReadVersion,
+    ) {
+        self.file_format_write_version = write_version;
+        self.file_format_read_version = read_version;
+    }
+
+    pub(crate) fn set_size_in_pages(&mut self, size_in_pages: u32) {
+        self.size_in_pages = size_in_pages;
+    }
+
+    pub(crate) fn set_freelist(&mut self, first_trunk_page: u32, total_pages: u32) {
+        self.first_freelist_trunk_page = first_trunk_page;
+        self.total_freelist_pages = total_pages;
+    }
+
+    /// Set the auto-vacuum fields, which sqlite3 only changes while the database holds no
+    /// tables
+    pub(crate) fn set_auto_vacuum(&mut self, largest_root_btree_page: u32, incremental: bool) {
+        self.largest_root_btree_page = largest_root_btree_page;
+        self.incremental_vacuum = incremental;
+    }
+
+    /// The header of a copy of this database that `VACUUM` rebuilt with the given page layout.
+    /// Like sqlite3, the copy keeps the schema format, text encoding, suggested cache size,
+    /// user version and application id, has no free pages and bumps the schema cookie, since
+    /// every root page may have moved.
+    pub(crate) fn vacuumed(&self, page_size: PageSize, page_reserved_space: u8) -> SqliteHeader {
+        SqliteHeader {
+            page_size,
+            page_reserved_space,
+            size_in_pages: 1,
+            first_freelist_trunk_page: 0,
+            total_freelist_pages: 0,
+            schema_cookie: self.schema_cookie.wrapping_add(1),
+            ..self.clone()
+        }
+    }
+
+    /// Record that a transaction changed the file: bump the change counter and mark the
+    /// in-header database size as valid for this version of the file, as sqlite3 does on
+    /// every commit
+    pub(crate) fn bump_file_change_counter(&mut self) {
+        self.file_change_counter = self.file_change_counter.wrapping_add(1);
+        self.version_valid_for = self.file_change_counter;
+        self.sqlite_version_number = SQLITE_VERSION_NUMBER;
+    }
 }
 
 #[cfg(test)]
@@ -141,6 +582,18 @@ mod tests {
             leaf_payload_fraction: 32,
             file_change_counter: 0,
             size_in_pages: 1,
+            first_freelist_trunk_page: 0,
+            total_freelist_pages: 0,
+            schema_cookie: 0,
+            schema_format: Some(SchemaFormat::V4),
+            default_page_cache_size: 0,
+            largest_root_btree_page: 0,
+            text_encoding: Some(TextEncoding::UTF8),
+            user_version: 0,
+            incremental_vacuum: false,
+            application_id: 0,
+            version_valid_for: 0,
+            sqlite_version_number: 3046000,
         }
     }
 
@@ -200,8 +653,281 @@ mod tests {
         let header = test_header();
         let mut buf: BytesMut = BytesMut::with_capacity(100);
         let result = header.write(&mut buf);
-        let expected_buf_capacity = 32;
+        let expected_buf_capacity = HEADER_SIZE;
         assert!(result.is_ok());
         assert_eq!(buf.len(), expected_buf_capacity);
     }
+
+    /// First 100 bytes of a database created by sqlite3 with the default settings
+    const SQLITE3_DEFAULT_HEADER: [u8; 100] = [
+        0x53, 0x51, 0x4c, 0x69, 0x74, 0x65, 0x20, 0x66, 0x6f, 0x72, 0x6d, 0x61, 0x74, 0x20, 0x33,
+        0x00, 0x10, 0x00, 0x01, 0x01, 0x00, 0x40, 0x20, 0x20, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00,
+        0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00,
+        0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x2e, 0x8d, 0xfa,
+    ];
+
+    /// First 100 bytes of a sqlite3 database in WAL mode with a 65536 byte page size,
+    /// user_version 7 and application_id 1234
+    const SQLITE3_WAL_64K_HEADER: [u8; 100] = [
+        0x53, 0x51, 0x4c, 0x69, 0x74, 0x65, 0x20, 0x66, 0x6f, 0x72, 0x6d, 0x61, 0x74, 0x20, 0x33,
+        0x00, 0x00, 0x01, 0x02, 0x02, 0x00, 0x40, 0x20, 0x20, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00,
+        0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00,
+        0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+        0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0xd2, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x2e, 0x8d, 0xfa,
+    ];
+
+    /// First 100 bytes of a sqlite3 database with a 512 byte page size, incremental
+    /// auto-vacuum and UTF-16le text encoding
+    const SQLITE3_512_AUTOVACUUM_HEADER: [u8; 100] = [
+        0x53, 0x51, 0x4c, 0x69, 0x74, 0x65, 0x20, 0x66, 0x6f, 0x72, 0x6d, 0x61, 0x74, 0x20, 0x33,
+        0x00, 0x02, 0x00, 0x01, 0x01, 0x00, 0x40, 0x20, 0x20, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00,
+        0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00,
+        0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x02,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x2e, 0x8d, 0xfa,
+    ];
+
+    /// First 100 bytes of a sqlite3 database with default_cache_size -500, user_version -5
+    /// and application_id -7
+    const SQLITE3_NEGATIVE_VALUES_HEADER: [u8; 100] = [
+        0x53, 0x51, 0x4c, 0x69, 0x74, 0x65, 0x20, 0x66, 0x6f, 0x72, 0x6d, 0x61, 0x74, 0x20, 0x33,
+        0x00, 0x10, 0x00, 0x01, 0x01, 0x00, 0x40, 0x20, 0x20, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00,
+        0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00,
+        0x00, 0x00, 0x04, 0x00, 0x00, 0x01, 0xf4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+        0xff, 0xff, 0xff, 0xfb, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xf9, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00, 0x2e, 0x8d, 0xfa,
+    ];
+
+    /// First 100 bytes of a sqlite3 database where only the user_version has been set
+    const SQLITE3_NO_SCHEMA_HEADER: [u8; 100] = [
+        0x53, 0x51, 0x4c, 0x69, 0x74, 0x65, 0x20, 0x66, 0x6f, 0x72, 0x6d, 0x61, 0x74, 0x20, 0x33,
+        0x00, 0x10, 0x00, 0x01, 0x01, 0x00, 0x40, 0x20, 0x20, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x2e, 0x8d, 0xfa,
+    ];
+
+    #[test]
+    fn from_buffer_sqlite3_default_ok() {
+        let buf = Bytes::from_static(&SQLITE3_DEFAULT_HEADER);
+        let header = SqliteHeader::from_buffer(&buf).unwrap();
+        assert_eq!(header.header, MAGIC_HEADER_STRING);
+        assert_eq!(header.page_size, PageSize::Size4096);
+        assert_eq!(
+            header.file_format_write_version,
+            FileFormatWriteVersion::Legacy
+        );
+        assert_eq!(
+            header.file_format_read_version,
+            FileFormatReadVersion::Legacy
+        );
+        assert_eq!(header.page_reserved_space, 0);
+        assert_eq!(header.max_embedded_payload_fraction, 64);
+        assert_eq!(header.min_embedded_payload_fraction, 32);
+        assert_eq!(header.leaf_payload_fraction, 32);
+        assert_eq!(header.file_change_counter, 2);
+        assert_eq!(header.size_in_pages, 2);
+        assert_eq!(header.first_freelist_trunk_page, 0);
+        assert_eq!(header.total_freelist_pages, 0);
+        assert_eq!(header.schema_cookie, 1);
+        assert_eq!(header.schema_format, Some(SchemaFormat::V4));
+        assert_eq!(header.default_page_cache_size, 0);
+        assert_eq!(header.largest_root_btree_page, 0);
+        assert_eq!(header.text_encoding, Some(TextEncoding::UTF8));
+        assert_eq!(header.user_version, 0);
+        assert!(!header.incremental_vacuum);
+        assert_eq!(header.application_id, 0);
+        assert_eq!(header.version_valid_for, 2);
+        assert_eq!(header.sqlite_version_number, 3051002);
+        assert!(header.is_size_in_pages_valid());
+    }
+
+    #[test]
+    fn from_buffer_sqlite3_wal_64k_ok() {
+        let buf = Bytes::from_static(&SQLITE3_WAL_64K_HEADER);
+        let header = SqliteHeader::from_buffer(&buf).unwrap();
+        assert_eq!(header.page_size, PageSize::Size65536);
+        assert_eq!(
+            header.file_format_write_version,
+            FileFormatWriteVersion::Wal
+        );
+        assert_eq!(header.file_format_read_version, FileFormatReadVersion::Wal);
+        assert_eq!(header.user_version, 7);
+        assert_eq!(header.application_id, 1234);
+    }
+
+    #[test]
+    fn from_buffer_sqlite3_512_ok() {
+        let buf = Bytes::from_static(&SQLITE3_512_AUTOVACUUM_HEADER);
+        let header = SqliteHeader::from_buffer(&buf).unwrap();
+        assert_eq!(header.page_size, PageSize::Size512);
+        assert_eq!(header.size_in_pages, 3);
+        assert_eq!(header.largest_root_btree_page, 3);
+        assert_eq!(header.text_encoding, Some(TextEncoding::UTF16LE));
+        assert!(header.incremental_vacuum);
+    }
+
+    #[test]
+    fn from_buffer_sqlite3_negative_values_ok() {
+        let buf = Bytes::from_static(&SQLITE3_NEGATIVE_VALUES_HEADER);
+        let header = SqliteHeader::from_buffer(&buf).unwrap();
+        assert_eq!(header.default_page_cache_size, 500);
+        assert_eq!(header.user_version, -5);
+        assert_eq!(header.application_id, -7);
+    }
+
+    #[test]
+    fn from_buffer_sqlite3_no_schema_ok() {
+        let buf = Bytes::from_static(&SQLITE3_NO_SCHEMA_HEADER);
+        let header = SqliteHeader::from_buffer(&buf).unwrap();
+        assert_eq!(header.schema_format, None);
+        assert_eq!(header.text_encoding, None);
+        assert_eq!(header.user_version, 3);
+    }
+
+    #[test]
+    fn write_matches_sqlite3() {
+        let cases: Vec<&[u8; 100]> = vec![
+            &SQLITE3_DEFAULT_HEADER,
+            &SQLITE3_WAL_64K_HEADER,
+            &SQLITE3_512_AUTOVACUUM_HEADER,
+            &SQLITE3_NEGATIVE_VALUES_HEADER,
+            &SQLITE3_NO_SCHEMA_HEADER,
+        ];
+        for case in cases {
+            let header = SqliteHeader::from_buffer(&Bytes::copy_from_slice(case)).unwrap();
+            let mut buf = BytesMut::with_capacity(HEADER_SIZE);
+            header.write(&mut buf).unwrap();
+            assert_eq!(buf.as_ref(), &case[..]);
+        }
+    }
+
+    #[test]
+    fn new_matches_sqlite3_empty_database() {
+        let header = SqliteHeader::new(PageSize::Size4096);
+        let mut buf = BytesMut::with_capacity(HEADER_SIZE);
+        header.write(&mut buf).unwrap();
+        let mut expected = BytesMut::from(&SQLITE3_NO_SCHEMA_HEADER[..]);
+        // user_version and the version number of the library that wrote the file
+        expected[60..64].copy_from_slice(&0u32.to_be_bytes());
+        expected[96..100].copy_from_slice(&SQLITE_VERSION_NUMBER.to_be_bytes());
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn bump_file_change_counter_ok() {
+        let mut header = test_header();
+        header.set_size_in_pages(5);
+        header.bump_file_change_counter();
+        assert_eq!(header.file_change_counter, 1);
+        assert_eq!(header.version_valid_for, 1);
+        assert_eq!(header.sqlite_version_number, SQLITE_VERSION_NUMBER);
+        assert!(header.is_size_in_pages_valid());
+
+        header.file_change_counter = u32::MAX;
+        header.bump_file_change_counter();
+        assert_eq!(header.file_change_counter, 0);
+        assert_eq!(header.version_valid_for, 0);
+    }
+
+    #[test]
+    fn size_in_pages_validity() {
+        let mut header = test_header();
+        assert!(header.is_size_in_pages_valid());
+        header.file_change_counter = 3;
+        assert!(!header.is_size_in_pages_valid());
+        header.version_valid_for = 3;
+        header.size_in_pages = 0;
+        assert!(!header.is_size_in_pages_valid());
+    }
+
+    #[test]
+    fn from_buffer_ignores_trailing_bytes() {
+        let mut buf = BytesMut::from(&SQLITE3_DEFAULT_HEADER[..]);
+        buf.put_bytes(0xff, 4000);
+        let header = SqliteHeader::from_buffer(&buf.freeze()).unwrap();
+        assert_eq!(header.page_size, PageSize::Size4096);
+    }
+
+    #[test]
+    fn from_buffer_round_trip_ok() {
+        let header = test_header();
+        let mut buf: BytesMut = BytesMut::with_capacity(HEADER_SIZE);
+        header.write(&mut buf).unwrap();
+        let result = SqliteHeader::from_buffer(&buf.freeze()).unwrap();
+        assert_eq!(result, header);
+    }
+
+    #[test]
+    fn from_buffer_truncated() {
+        let cases = vec![0, 16, 32, 99];
+        for case in cases {
+            let buf = Bytes::copy_from_slice(&SQLITE3_DEFAULT_HEADER[..case]);
+            let err = SqliteHeader::from_buffer(&buf).unwrap_err();
+            assert_eq!(err.code(), ExtendedResultCode::Corrupt);
+        }
+    }
+
+    #[test]
+    fn from_buffer_bad_magic() {
+        let mut buf = BytesMut::from(&SQLITE3_DEFAULT_HEADER[..]);
+        buf[7] = b'F';
+        let err = SqliteHeader::from_buffer(&buf.freeze()).unwrap_err();
+        assert_eq!(err.code(), ExtendedResultCode::NotADb);
+    }
+
+    #[test]
+    fn from_buffer_malformed() {
+        let cases: Vec<(usize, u8)> = vec![
+            // page size of 0x1100
+            (16, 0x11),
+            // page size of 0
+            (16, 0x00),
+            (21, 65),
+            (22, 31),
+            (23, 33),
+            (59, 4),
+            (72, 1),
+            (91, 1),
+        ];
+        for case in cases {
+            let mut buf = BytesMut::from(&SQLITE3_DEFAULT_HEADER[..]);
+            buf[case.0] = case.1;
+            let err = SqliteHeader::from_buffer(&buf.freeze()).unwrap_err();
+            assert_eq!(err.code(), ExtendedResultCode::Corrupt, "offset {}", case.0);
+        }
+    }
+
+    #[test]
+    fn from_buffer_unsupported_format() {
+        let cases: Vec<(usize, u8)> = vec![(18, 3), (19, 0), (47, 5)];
+        for case in cases {
+            let mut buf = BytesMut::from(&SQLITE3_DEFAULT_HEADER[..]);
+            buf[case.0] = case.1;
+            let err = SqliteHeader::from_buffer(&buf.freeze()).unwrap_err();
+            assert_eq!(err.code(), ExtendedResultCode::NotADb, "offset {}", case.0);
+        }
+    }
+
+    #[test]
+    fn from_buffer_reserved_space_too_large() {
+        let mut buf = BytesMut::from(&SQLITE3_512_AUTOVACUUM_HEADER[..]);
+        buf[20] = 33;
+        let err = SqliteHeader::from_buffer(&buf.freeze()).unwrap_err();
+        assert_eq!(err.code(), ExtendedResultCode::Corrupt);
+
+        let mut buf = BytesMut::from(&SQLITE3_512_AUTOVACUUM_HEADER[..]);
+        buf[20] = 32;
+        let header = SqliteHeader::from_buffer(&buf.freeze()).unwrap();
+        assert_eq!(header.page_reserved_space, 32);
+    }
 }
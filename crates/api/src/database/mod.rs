@@ -3,3 +3,9 @@ mod header;
 mod page_size;
 mod schema_format;
 mod text_encoding;
+
+pub use self::file_format::*;
+pub use self::header::*;
+pub use self::page_size::*;
+pub use self::schema_format::*;
+pub use self::text_encoding::*;
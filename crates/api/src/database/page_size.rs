@@ -1,3 +1,6 @@
+use crate::errors::ExtendedResultCode;
+use crate::SqliteError;
+
 #[derive(Clone, Debug, Copy, PartialEq)]
 pub enum PageSize {
     Size512,
@@ -40,34 +43,46 @@ impl From<PageSize> for u32 {
     }
 }
 
-impl From<u32> for PageSize {
-    fn from(value: u32) -> Self {
+fn unsupported_page_size(value: u32) -> SqliteError {
+    SqliteError::Corrupt {
+        code: ExtendedResultCode::Corrupt,
+        message: format!("unsupported page size: {}", value),
+    }
+}
+
+impl TryFrom<u32> for PageSize {
+    type Error = SqliteError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
         match value {
-            512 => PageSize::Size512,
-            1024 => PageSize::Size1024,
-            2048 => PageSize::Size2048,
-            4096 => PageSize::Size4096,
-            8192 => PageSize::Size8192,
-            16384 => PageSize::Size16384,
-            32768 => PageSize::Size32768,
-            65536 => PageSize::Size65536,
-            _ => panic!("unsupported page size: {}", value),
+            512 => Ok(PageSize::Size512),
+            1024 => Ok(PageSize::Size1024),
+            2048 => Ok(PageSize::Size2048),
+            4096 => Ok(PageSize::Size4096),
+            8192 => Ok(PageSize::Size8192),
+            16384 => Ok(PageSize::Size16384),
+            32768 => Ok(PageSize::Size32768),
+            65536 => Ok(PageSize::Size65536),
+            _ => Err(unsupported_page_size(value)),
         }
     }
 }
 
-impl From<u16> for PageSize {
-    fn from(value: u16) -> Self {
+/// Converts the on-disk representation of a page size, where 65536 is stored as 1
+impl TryFrom<u16> for PageSize {
+    type Error = SqliteError;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
         match value {
-            512 => PageSize::Size512,
-            1024 => PageSize::Size1024,
-            2048 => PageSize::Size2048,
-            4096 => PageSize::Size4096,
-            8192 => PageSize::Size8192,
-            16384 => PageSize::Size16384,
-            32768 => PageSize::Size32768,
-            1 => PageSize::Size65536,
-            _ => panic!("unsupported page size: {}", value),
+            512 => Ok(PageSize::Size512),
+            1024 => Ok(PageSize::Size1024),
+            2048 => Ok(PageSize::Size2048),
+            4096 => Ok(PageSize::Size4096),
+            8192 => Ok(PageSize::Size8192),
+            16384 => Ok(PageSize::Size16384),
+            32768 => Ok(PageSize::Size32768),
+            1 => Ok(PageSize::Size65536),
+            _ => Err(unsupported_page_size(value as u32)),
         }
     }
 }
@@ -128,16 +143,20 @@ mod tests {
         ];
         for case in cases {
             let page_size: u32 = case.1;
-            let ps: PageSize = page_size.into();
+            let ps = PageSize::try_from(page_size).unwrap();
             assert_eq!(ps, case.0);
         }
     }
 
     #[test]
-    #[should_panic(expected = "unsupported page size: 42")]
     fn test_from_u32_unsupported() {
-        let page_size: u32 = 42;
-        let _ps: PageSize = page_size.into();
+        let cases: Vec<u32> = vec![0, 1, 42, 511, 65535, 131072];
+        for case in cases {
+            let result = PageSize::try_from(case);
+            assert!(
+                matches!(result, Err(SqliteError::Corrupt { code: ExtendedResultCode::Corrupt, ref message }) if *message == format!("unsupported page size: {}", case))
+            );
+        }
     }
 
     #[test]
@@ -154,15 +173,19 @@ mod tests {
         ];
         for case in cases {
             let ps = case.1;
-            let ps: PageSize = ps.into();
+            let ps = PageSize::try_from(ps).unwrap();
             assert_eq!(ps, case.0);
         }
     }
 
     #[test]
-    #[should_panic(expected = "unsupported page size: 42")]
     fn test_from_u16_unsupported() {
-        let page_size: u16 = 42;
-        let _ps: PageSize = page_size.into();
+        let cases: Vec<u16> = vec![0, 2, 42, 511, 65535];
+        for case in cases {
+            let result = PageSize::try_from(case);
+            assert!(
+                matches!(result, Err(SqliteError::Corrupt { code: ExtendedResultCode::Corrupt, ref message }) if *message == format!("unsupported page size: {}", case))
+            );
+        }
     }
 }
@@ -1,4 +1,7 @@
-#[derive(Debug, PartialEq)]
+use crate::errors::ExtendedResultCode;
+use crate::SqliteError;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub enum SchemaFormat {
     V1,
     V2,
@@ -6,14 +9,19 @@ pub enum SchemaFormat {
     V4,
 }
 
-impl From<u32> for SchemaFormat {
-    fn from(v: u32) -> Self {
+impl TryFrom<u32> for SchemaFormat {
+    type Error = SqliteError;
+
+    fn try_from(v: u32) -> Result<Self, Self::Error> {
         match v {
-            1 => SchemaFormat::V1,
-            2 => SchemaFormat::V2,
-            3 => SchemaFormat::V3,
-            4 => SchemaFormat::V4,
-            _ => panic!("unsupported schema format: {}", v),
+            1 => Ok(SchemaFormat::V1),
+            2 => Ok(SchemaFormat::V2),
+            3 => Ok(SchemaFormat::V3),
+            4 => Ok(SchemaFormat::V4),
+            _ => Err(SqliteError::NotADb {
+                code: ExtendedResultCode::NotADb,
+                message: format!("unsupported schema format: {}", v),
+            }),
         }
     }
 }
@@ -56,14 +64,19 @@ mod tests {
             (4, SchemaFormat::V4),
         ];
         for case in cases {
-            let result: SchemaFormat = case.0.into();
+            let result = SchemaFormat::try_from(case.0).unwrap();
             assert_eq!(result, case.1)
         }
     }
 
     #[test]
-    #[should_panic(expected = "unsupported schema format: 42")]
     fn schema_format_from_u32_err() {
-        let _result: SchemaFormat = 42.into();
+        let cases: Vec<u32> = vec![0, 5, 42];
+        for case in cases {
+            let result = SchemaFormat::try_from(case);
+            assert!(
+                matches!(result, Err(SqliteError::NotADb { code: ExtendedResultCode::NotADb, ref message }) if *message == format!("unsupported schema format: {}", case))
+            );
+        }
     }
 }
@@ -1,17 +1,25 @@
-#[derive(Debug, PartialEq)]
+use crate::errors::ExtendedResultCode;
+use crate::SqliteError;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub enum TextEncoding {
     UTF8,
     UTF16BE,
     UTF16LE,
 }
 
-impl From<u32> for TextEncoding {
-    fn from(value: u32) -> Self {
+impl TryFrom<u32> for TextEncoding {
+    type Error = SqliteError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
         match value {
-            1 => TextEncoding::UTF8,
-            2 => TextEncoding::UTF16LE,
-            3 => TextEncoding::UTF16BE,
-            _ => panic!("unsupported text encoding: {}", value),
+            1 => Ok(TextEncoding::UTF8),
+            2 => Ok(TextEncoding::UTF16LE),
+            3 => Ok(TextEncoding::UTF16BE),
+            _ => Err(SqliteError::Corrupt {
+                code: ExtendedResultCode::Corrupt,
+                message: format!("unsupported text encoding: {}", value),
+            }),
         }
     }
 }
@@ -38,15 +46,20 @@ mod tests {
             (3, TextEncoding::UTF16BE),
         ];
         for case in cases {
-            let result: TextEncoding = case.0.into();
+            let result = TextEncoding::try_from(case.0).unwrap();
             assert_eq!(result, case.1);
         }
     }
 
     #[test]
-    #[should_panic(expected = "unsupported text encoding: 42")]
     fn from_u32_fail() {
-        let _result: TextEncoding = 42.into();
+        let cases: Vec<u32> = vec![0, 4, 42];
+        for case in cases {
+            let result = TextEncoding::try_from(case);
+            assert!(
+                matches!(result, Err(SqliteError::Corrupt { code: ExtendedResultCode::Corrupt, ref message }) if *message == format!("unsupported text encoding: {}", case))
+            );
+        }
     }
 
     #[test]
@@ -1,8 +1,698 @@
+use std::fmt;
+
 pub type SqliteResult<T, E = SqliteError> = Result<T, E>;
 
-///Sqlite specific errors
+/// Declares a result code enum whose discriminants match the values defined in sqlite3.h,
+/// along with the C name of each code and a fallible conversion from the raw value.
+macro_rules! result_codes {
+    ($(#[$meta:meta])* $name:ident { $($variant:ident = $value:expr => $c_name:literal,)+ }) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        #[repr(i32)]
+        pub enum $name {
+            $($variant = $value,)+
+        }
+
+        impl $name {
+            /// The name of the code as spelled in sqlite3.h, e.g. `SQLITE_BUSY`
+            pub fn name(self) -> &'static str {
+                match self {
+                    $($name::$variant => $c_name,)+
+                }
+            }
+        }
+
+        impl TryFrom<i32> for $name {
+            type Error = SqliteError;
+
+            fn try_from(value: i32) -> Result<Self, SqliteError> {
+                match value {
+                    $($value => Ok($name::$variant),)+
+                    _ => Err(SqliteError::Misuse {
+                        code: ExtendedResultCode::Misuse,
+                        message: format!("unknown result code: {}", value),
+                    }),
+                }
+            }
+        }
+
+        impl From<$name> for i32 {
+            fn from(value: $name) -> Self {
+                value as i32
+            }
+        }
+    };
+}
+
+result_codes! {
+    /// Primary result codes.  These are the low 8 bits of every extended result code.
+    ResultCode {
+        Ok = 0 => "SQLITE_OK",
+        Error = 1 => "SQLITE_ERROR",
+        Internal = 2 => "SQLITE_INTERNAL",
+        Perm = 3 => "SQLITE_PERM",
+        Abort = 4 => "SQLITE_ABORT",
+        Busy = 5 => "SQLITE_BUSY",
+        Locked = 6 => "SQLITE_LOCKED",
+        NoMem = 7 => "SQLITE_NOMEM",
+        ReadOnly = 8 => "SQLITE_READONLY",
+        Interrupt = 9 => "SQLITE_INTERRUPT",
+        IoErr = 10 => "SQLITE_IOERR",
+        Corrupt = 11 => "SQLITE_CORRUPT",
+        NotFound = 12 => "SQLITE_NOTFOUND",
+        Full = 13 => "SQLITE_FULL",
+        CantOpen = 14 => "SQLITE_CANTOPEN",
+        Protocol = 15 => "SQLITE_PROTOCOL",
+        Empty = 16 => "SQLITE_EMPTY",
+        Schema = 17 => "SQLITE_SCHEMA",
+        TooBig = 18 => "SQLITE_TOOBIG",
+        Constraint = 19 => "SQLITE_CONSTRAINT",
+        Mismatch = 20 => "SQLITE_MISMATCH",
+        Misuse = 21 => "SQLITE_MISUSE",
+        NoLfs = 22 => "SQLITE_NOLFS",
+        Auth = 23 => "SQLITE_AUTH",
+        Format = 24 => "SQLITE_FORMAT",
+        Range = 25 => "SQLITE_RANGE",
+        NotADb = 26 => "SQLITE_NOTADB",
+        Notice = 27 => "SQLITE_NOTICE",
+        Warning = 28 => "SQLITE_WARNING",
+        Row = 100 => "SQLITE_ROW",
+        Done = 101 => "SQLITE_DONE",
+    }
+}
+
+result_codes! {
+    /// Extended result codes.  A code without a more specific meaning is reported with the
+    /// same value as its primary code, as sqlite3_extended_errcode does.
+    ExtendedResultCode {
+        Ok = 0 => "SQLITE_OK",
+        Error = 1 => "SQLITE_ERROR",
+        Internal = 2 => "SQLITE_INTERNAL",
+        Perm = 3 => "SQLITE_PERM",
+        Abort = 4 => "SQLITE_ABORT",
+        Busy = 5 => "SQLITE_BUSY",
+        Locked = 6 => "SQLITE_LOCKED",
+        NoMem = 7 => "SQLITE_NOMEM",
+        ReadOnly = 8 => "SQLITE_READONLY",
+        Interrupt = 9 => "SQLITE_INTERRUPT",
+        IoErr = 10 => "SQLITE_IOERR",
+        Corrupt = 11 => "SQLITE_CORRUPT",
+        NotFound = 12 => "SQLITE_NOTFOUND",
+        Full = 13 => "SQLITE_FULL",
+        CantOpen = 14 => "SQLITE_CANTOPEN",
+        Protocol = 15 => "SQLITE_PROTOCOL",
+        Empty = 16 => "SQLITE_EMPTY",
+        Schema = 17 => "SQLITE_SCHEMA",
+        TooBig = 18 => "SQLITE_TOOBIG",
+        Constraint = 19 => "SQLITE_CONSTRAINT",
+        Mismatch = 20 => "SQLITE_MISMATCH",
+        Misuse = 21 => "SQLITE_MISUSE",
+        NoLfs = 22 => "SQLITE_NOLFS",
+        Auth = 23 => "SQLITE_AUTH",
+        Format = 24 => "SQLITE_FORMAT",
+        Range = 25 => "SQLITE_RANGE",
+        NotADb = 26 => "SQLITE_NOTADB",
+        Notice = 27 => "SQLITE_NOTICE",
+        Warning = 28 => "SQLITE_WARNING",
+        Row = 100 => "SQLITE_ROW",
+        Done = 101 => "SQLITE_DONE",
+        OkLoadPermanently = 256 => "SQLITE_OK_LOAD_PERMANENTLY",
+        OkSymlink = 512 => "SQLITE_OK_SYMLINK",
+        ErrorMissingCollSeq = 257 => "SQLITE_ERROR_MISSING_COLLSEQ",
+        ErrorRetry = 513 => "SQLITE_ERROR_RETRY",
+        ErrorSnapshot = 769 => "SQLITE_ERROR_SNAPSHOT",
+        AbortRollback = 516 => "SQLITE_ABORT_ROLLBACK",
+        BusyRecovery = 261 => "SQLITE_BUSY_RECOVERY",
+        BusySnapshot = 517 => "SQLITE_BUSY_SNAPSHOT",
+        BusyTimeout = 773 => "SQLITE_BUSY_TIMEOUT",
+        LockedSharedCache = 262 => "SQLITE_LOCKED_SHAREDCACHE",
+        LockedVtab = 518 => "SQLITE_LOCKED_VTAB",
+        ReadOnlyRecovery = 264 => "SQLITE_READONLY_RECOVERY",
+        ReadOnlyCantLock = 520 => "SQLITE_READONLY_CANTLOCK",
+        ReadOnlyRollback = 776 => "SQLITE_READONLY_ROLLBACK",
+        ReadOnlyDbMoved = 1032 => "SQLITE_READONLY_DBMOVED",
+        ReadOnlyCantInit = 1288 => "SQLITE_READONLY_CANTINIT",
+        ReadOnlyDirectory = 1544 => "SQLITE_READONLY_DIRECTORY",
+        IoErrRead = 266 => "SQLITE_IOERR_READ",
+        IoErrShortRead = 522 => "SQLITE_IOERR_SHORT_READ",
+        IoErrWrite = 778 => "SQLITE_IOERR_WRITE",
+        IoErrFsync = 1034 => "SQLITE_IOERR_FSYNC",
+        IoErrDirFsync = 1290 => "SQLITE_IOERR_DIR_FSYNC",
+        IoErrTruncate = 1546 => "SQLITE_IOERR_TRUNCATE",
+        IoErrFstat = 1802 => "SQLITE_IOERR_FSTAT",
+        IoErrUnlock = 2058 => "SQLITE_IOERR_UNLOCK",
+        IoErrRdLock = 2314 => "SQLITE_IOERR_RDLOCK",
+        IoErrDelete = 2570 => "SQLITE_IOERR_DELETE",
+        IoErrBlocked = 2826 => "SQLITE_IOERR_BLOCKED",
+        IoErrNoMem = 3082 => "SQLITE_IOERR_NOMEM",
+        IoErrAccess = 3338 => "SQLITE_IOERR_ACCESS",
+        IoErrCheckReservedLock = 3594 => "SQLITE_IOERR_CHECKRESERVEDLOCK",
+        IoErrLock = 3850 => "SQLITE_IOERR_LOCK",
+        IoErrClose = 4106 => "SQLITE_IOERR_CLOSE",
+        IoErrDirClose = 4362 => "SQLITE_IOERR_DIR_CLOSE",
+        IoErrShmOpen = 4618 => "SQLITE_IOERR_SHMOPEN",
+        IoErrShmSize = 4874 => "SQLITE_IOERR_SHMSIZE",
+        IoErrShmLock = 5130 => "SQLITE_IOERR_SHMLOCK",
+        IoErrShmMap = 5386 => "SQLITE_IOERR_SHMMAP",
+        IoErrSeek = 5642 => "SQLITE_IOERR_SEEK",
+        IoErrDeleteNoEnt = 5898 => "SQLITE_IOERR_DELETE_NOENT",
+        IoErrMmap = 6154 => "SQLITE_IOERR_MMAP",
+        IoErrGetTempPath = 6410 => "SQLITE_IOERR_GETTEMPPATH",
+        IoErrConvPath = 6666 => "SQLITE_IOERR_CONVPATH",
+        IoErrVnode = 6922 => "SQLITE_IOERR_VNODE",
+        IoErrAuth = 7178 => "SQLITE_IOERR_AUTH",
+        IoErrBeginAtomic = 7434 => "SQLITE_IOERR_BEGIN_ATOMIC",
+        IoErrCommitAtomic = 7690 => "SQLITE_IOERR_COMMIT_ATOMIC",
+        IoErrRollbackAtomic = 7946 => "SQLITE_IOERR_ROLLBACK_ATOMIC",
+        IoErrData = 8202 => "SQLITE_IOERR_DATA",
+        IoErrCorruptFs = 8458 => "SQLITE_IOERR_CORRUPTFS",
+        IoErrInPage = 8714 => "SQLITE_IOERR_IN_PAGE",
+        CorruptVtab = 267 => "SQLITE_CORRUPT_VTAB",
+        CorruptSequence = 523 => "SQLITE_CORRUPT_SEQUENCE",
+        CorruptIndex = 779 => "SQLITE_CORRUPT_INDEX",
+        CantOpenNoTempDir = 270 => "SQLITE_CANTOPEN_NOTEMPDIR",
+        CantOpenIsDir = 526 => "SQLITE_CANTOPEN_ISDIR",
+        CantOpenFullPath = 782 => "SQLITE_CANTOPEN_FULLPATH",
+        CantOpenConvPath = 1038 => "SQLITE_CANTOPEN_CONVPATH",
+        CantOpenDirtyWal = 1294 => "SQLITE_CANTOPEN_DIRTYWAL",
+        CantOpenSymlink = 1550 => "SQLITE_CANTOPEN_SYMLINK",
+        ConstraintCheck = 275 => "SQLITE_CONSTRAINT_CHECK",
+        ConstraintCommitHook = 531 => "SQLITE_CONSTRAINT_COMMITHOOK",
+        ConstraintForeignKey = 787 => "SQLITE_CONSTRAINT_FOREIGNKEY",
+        ConstraintFunction = 1043 => "SQLITE_CONSTRAINT_FUNCTION",
+        ConstraintNotNull = 1299 => "SQLITE_CONSTRAINT_NOTNULL",
+        ConstraintPrimaryKey = 1555 => "SQLITE_CONSTRAINT_PRIMARYKEY",
+        ConstraintTrigger = 1811 => "SQLITE_CONSTRAINT_TRIGGER",
+        ConstraintUnique = 2067 => "SQLITE_CONSTRAINT_UNIQUE",
+        ConstraintVtab = 2323 => "SQLITE_CONSTRAINT_VTAB",
+        ConstraintRowId = 2579 => "SQLITE_CONSTRAINT_ROWID",
+        ConstraintPinned = 2835 => "SQLITE_CONSTRAINT_PINNED",
+        ConstraintDataType = 3091 => "SQLITE_CONSTRAINT_DATATYPE",
+        NoticeRecoverWal = 283 => "SQLITE_NOTICE_RECOVER_WAL",
+        NoticeRecoverRollback = 539 => "SQLITE_NOTICE_RECOVER_ROLLBACK",
+        NoticeRbu = 795 => "SQLITE_NOTICE_RBU",
+        WarningAutoIndex = 284 => "SQLITE_WARNING_AUTOINDEX",
+        AuthUser = 279 => "SQLITE_AUTH_USER",
+    }
+}
+
+impl ResultCode {
+    /// English language description of the code, matching sqlite3_errstr
+    pub fn errstr(self) -> &'static str {
+        match self {
+            ResultCode::Ok => "not an error",
+            ResultCode::Error => "SQL logic error",
+            ResultCode::Perm => "access permission denied",
+            ResultCode::Abort => "query aborted",
+            ResultCode::Busy => "database is locked",
+            ResultCode::Locked => "database table is locked",
+            ResultCode::NoMem => "out of memory",
+            ResultCode::ReadOnly => "attempt to write a readonly database",
+            ResultCode::Interrupt => "interrupted",
+            ResultCode::IoErr => "disk I/O error",
+            ResultCode::Corrupt => "database disk image is malformed",
+            ResultCode::NotFound => "unknown operation",
+            ResultCode::Full => "database or disk is full",
+            ResultCode::CantOpen => "unable to open database file",
+            ResultCode::Protocol => "locking protocol",
+            ResultCode::Schema => "database schema has changed",
+            ResultCode::TooBig => "string or blob too big",
+            ResultCode::Constraint => "constraint failed",
+            ResultCode::Mismatch => "datatype mismatch",
+            ResultCode::Misuse => "bad parameter or other API misuse",
+            ResultCode::NoLfs => "large file support is disabled",
+            ResultCode::Auth => "authorization denied",
+            ResultCode::Range => "column index out of range",
+            ResultCode::NotADb => "file is not a database",
+            ResultCode::Notice => "notification message",
+            ResultCode::Warning => "warning message",
+            ResultCode::Row => "another row available",
+            ResultCode::Done => "no more rows available",
+            ResultCode::Internal | ResultCode::Empty | ResultCode::Format => "unknown error",
+        }
+    }
+}
+
+impl ExtendedResultCode {
+    /// The primary result code this extended code refines
+    pub fn primary(self) -> ResultCode {
+        ResultCode::try_from(self as i32 & 0xff).expect("every extended code has a primary code")
+    }
+
+    /// English language description of the code, matching sqlite3_errstr
+    pub fn errstr(self) -> &'static str {
+        match self {
+            ExtendedResultCode::AbortRollback => "abort due to ROLLBACK",
+            _ => self.primary().errstr(),
+        }
+    }
+}
+
+impl fmt::Display for ResultCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl fmt::Display for ExtendedResultCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+///Sqlite specific errors, one variant per primary result code
 #[derive(Debug)]
 pub enum SqliteError {
-    Error { code: i32, message: String },
-    CannotOpen { code: i32, message: String },
+    Error {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    Internal {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    Perm {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    Abort {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    Busy {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    Locked {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    NoMem {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    ReadOnly {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    Interrupt {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    /// An operating system level I/O failure, carrying the underlying error when there is one
+    IoErr {
+        code: ExtendedResultCode,
+        message: String,
+        source: Option<std::io::Error>,
+    },
+    /// The database file, most often its header, is malformed
+    Corrupt {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    NotFound {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    Full {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    /// The database could not be opened, carrying the underlying error when there is one
+    CannotOpen {
+        code: ExtendedResultCode,
+        message: String,
+        source: Option<std::io::Error>,
+    },
+    Protocol {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    Empty {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    Schema {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    TooBig {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    Constraint {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    Mismatch {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    /// An invalid value was supplied by the caller
+    Misuse {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    NoLfs {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    Auth {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    Format {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    Range {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    /// The file is not a database or uses a format this library does not support
+    NotADb {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    Notice {
+        code: ExtendedResultCode,
+        message: String,
+    },
+    Warning {
+        code: ExtendedResultCode,
+        message: String,
+    },
+}
+
+impl SqliteError {
+    /// Build the variant matching the primary code of `code`.  Codes that are not errors
+    /// (`SQLITE_OK`, `SQLITE_ROW` and `SQLITE_DONE`) are reported as misuse.
+    pub fn new(code: ExtendedResultCode, message: impl Into<String>) -> SqliteError {
+        let message = message.into();
+        match code.primary() {
+            ResultCode::Error => SqliteError::Error { code, message },
+            ResultCode::Internal => SqliteError::Internal { code, message },
+            ResultCode::Perm => SqliteError::Perm { code, message },
+            ResultCode::Abort => SqliteError::Abort { code, message },
+            ResultCode::Busy => SqliteError::Busy { code, message },
+            ResultCode::Locked => SqliteError::Locked { code, message },
+            ResultCode::NoMem => SqliteError::NoMem { code, message },
+            ResultCode::ReadOnly => SqliteError::ReadOnly { code, message },
+            ResultCode::Interrupt => SqliteError::Interrupt { code, message },
+            ResultCode::IoErr => SqliteError::IoErr {
+                code,
+                message,
+                source: None,
+            },
+            ResultCode::Corrupt => SqliteError::Corrupt { code, message },
+            ResultCode::NotFound => SqliteError::NotFound { code, message },
+            ResultCode::Full => SqliteError::Full { code, message },
+            ResultCode::CantOpen => SqliteError::CannotOpen {
+                code,
+                message,
+                source: None,
+            },
+            ResultCode::Protocol => SqliteError::Protocol { code, message },
+            ResultCode::Empty => SqliteError::Empty { code, message },
+            ResultCode::Schema => SqliteError::Schema { code, message },
+            ResultCode::TooBig => SqliteError::TooBig { code, message },
+            ResultCode::Constraint => SqliteError::Constraint { code, message },
+            ResultCode::Mismatch => SqliteError::Mismatch { code, message },
+            ResultCode::Misuse => SqliteError::Misuse { code, message },
+            ResultCode::NoLfs => SqliteError::NoLfs { code, message },
+            ResultCode::Auth => SqliteError::Auth { code, message },
+            ResultCode::Format => SqliteError::Format { code, message },
+            ResultCode::Range => SqliteError::Range { code, message },
+            ResultCode::NotADb => SqliteError::NotADb { code, message },
+            ResultCode::Notice => SqliteError::Notice { code, message },
+            ResultCode::Warning => SqliteError::Warning { code, message },
+            ResultCode::Ok | ResultCode::Row | ResultCode::Done => SqliteError::Misuse {
+                code: ExtendedResultCode::Misuse,
+                message: format!("{} is not an error: {}", code, message),
+            },
+        }
+    }
+
+    /// An I/O error with the operating system error that caused it
+    pub fn io(
+        code: ExtendedResultCode,
+        message: impl Into<String>,
+        source: std::io::Error,
+    ) -> SqliteError {
+        match SqliteError::new(code, message) {
+            SqliteError::IoErr { code, message, .. } => SqliteError::IoErr {
+                code,
+                message,
+                source: Some(source),
+            },
+            SqliteError::CannotOpen { code, message, .. } => SqliteError::CannotOpen {
+                code,
+                message,
+                source: Some(source),
+            },
+            other => other,
+        }
+    }
+
+    /// The extended result code, equivalent to sqlite3_extended_errcode
+    pub fn code(&self) -> ExtendedResultCode {
+        match self {
+            SqliteError::Error { code, .. }
+            | SqliteError::Internal { code, .. }
+            | SqliteError::Perm { code, .. }
+            | SqliteError::Abort { code, .. }
+            | SqliteError::Busy { code, .. }
+            | SqliteError::Locked { code, .. }
+            | SqliteError::NoMem { code, .. }
+            | SqliteError::ReadOnly { code, .. }
+            | SqliteError::Interrupt { code, .. }
+            | SqliteError::IoErr { code, .. }
+            | SqliteError::Corrupt { code, .. }
+            | SqliteError::NotFound { code, .. }
+            | SqliteError::Full { code, .. }
+            | SqliteError::CannotOpen { code, .. }
+            | SqliteError::Protocol { code, .. }
+            | SqliteError::Empty { code, .. }
+            | SqliteError::Schema { code, .. }
+            | SqliteError::TooBig { code, .. }
+            | SqliteError::Constraint { code, .. }
+            | SqliteError::Mismatch { code, .. }
+            | SqliteError::Misuse { code, .. }
+            | SqliteError::NoLfs { code, .. }
+            | SqliteError::Auth { code, .. }
+            | SqliteError::Format { code, .. }
+            | SqliteError::Range { code, .. }
+            | SqliteError::NotADb { code, .. }
+            | SqliteError::Notice { code, .. }
+            | SqliteError::Warning { code, .. } => *code,
+        }
+    }
+
+    /// The primary result code, equivalent to sqlite3_errcode
+    pub fn primary_code(&self) -> ResultCode {
+        self.code().primary()
+    }
+
+    /// The error specific message, equivalent to sqlite3_errmsg
+    pub fn message(&self) -> &str {
+        match self {
+            SqliteError::Error { message, .. }
+            | SqliteError::Internal { message, .. }
+            | SqliteError::Perm { message, .. }
+            | SqliteError::Abort { message, .. }
+            | SqliteError::Busy { message, .. }
+            | SqliteError::Locked { message, .. }
+            | SqliteError::NoMem { message, .. }
+            | SqliteError::ReadOnly { message, .. }
+            | SqliteError::Interrupt { message, .. }
+            | SqliteError::IoErr { message, .. }
+            | SqliteError::Corrupt { message, .. }
+            | SqliteError::NotFound { message, .. }
+            | SqliteError::Full { message, .. }
+            | SqliteError::CannotOpen { message, .. }
+            | SqliteError::Protocol { message, .. }
+            | SqliteError::Empty { message, .. }
+            | SqliteError::Schema { message, .. }
+            | SqliteError::TooBig { message, .. }
+            | SqliteError::Constraint { message, .. }
+            | SqliteError::Mismatch { message, .. }
+            | SqliteError::Misuse { message, .. }
+            | SqliteError::NoLfs { message, .. }
+            | SqliteError::Auth { message, .. }
+            | SqliteError::Format { message, .. }
+            | SqliteError::Range { message, .. }
+            | SqliteError::NotADb { message, .. }
+            | SqliteError::Notice { message, .. }
+            | SqliteError::Warning { message, .. } => message,
+        }
+    }
+
+    /// The generic description of the error code, equivalent to sqlite3_errstr
+    pub fn errstr(&self) -> &'static str {
+        self.code().errstr()
+    }
+}
+
+impl fmt::Display for SqliteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.message().is_empty() {
+            write!(f, "{} ({})", self.errstr(), self.code())
+        } else {
+            write!(f, "{} ({})", self.message(), self.code())
+        }
+    }
+}
+
+impl std::error::Error for SqliteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SqliteError::IoErr {
+                source: Some(source),
+                ..
+            }
+            | SqliteError::CannotOpen {
+                source: Some(source),
+                ..
+            } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn extended_code_values_match_sqlite3() {
+        let cases: Vec<(ExtendedResultCode, i32)> = vec![
+            (ExtendedResultCode::Busy, 5),
+            (ExtendedResultCode::BusyRecovery, 261),
+            (ExtendedResultCode::BusySnapshot, 517),
+            (ExtendedResultCode::LockedSharedCache, 262),
+            (ExtendedResultCode::IoErrRead, 266),
+            (ExtendedResultCode::IoErrShortRead, 522),
+            (ExtendedResultCode::IoErrFsync, 1034),
+            (ExtendedResultCode::IoErrShmLock, 5130),
+            (ExtendedResultCode::CorruptIndex, 779),
+            (ExtendedResultCode::CantOpenIsDir, 526),
+            (ExtendedResultCode::ConstraintUnique, 2067),
+            (ExtendedResultCode::ConstraintDataType, 3091),
+            (ExtendedResultCode::ReadOnlyRollback, 776),
+            (ExtendedResultCode::AbortRollback, 516),
+        ];
+        for case in cases {
+            let value: i32 = case.0.into();
+            assert_eq!(value, case.1, "{}", case.0);
+            assert_eq!(ExtendedResultCode::try_from(case.1).unwrap(), case.0);
+        }
+    }
+
+    #[test]
+    fn extended_code_primary() {
+        let cases = vec![
+            (ExtendedResultCode::IoErrRead, ResultCode::IoErr),
+            (ExtendedResultCode::IoErr, ResultCode::IoErr),
+            (ExtendedResultCode::CorruptIndex, ResultCode::Corrupt),
+            (ExtendedResultCode::ConstraintUnique, ResultCode::Constraint),
+            (ExtendedResultCode::CantOpenFullPath, ResultCode::CantOpen),
+            (ExtendedResultCode::OkSymlink, ResultCode::Ok),
+            (ExtendedResultCode::Done, ResultCode::Done),
+        ];
+        for case in cases {
+            assert_eq!(case.0.primary(), case.1);
+        }
+    }
+
+    #[test]
+    fn unknown_code_err() {
+        let cases = vec![-1, 29, 99, 102, 1000];
+        for case in cases {
+            let result = ResultCode::try_from(case);
+            assert!(matches!(
+                result,
+                Err(SqliteError::Misuse {
+                    code: ExtendedResultCode::Misuse,
+                    ..
+                })
+            ));
+        }
+        assert!(ExtendedResultCode::try_from(258).is_err());
+    }
+
+    #[test]
+    fn errstr_matches_sqlite3() {
+        let cases = vec![
+            (ExtendedResultCode::Busy, "database is locked"),
+            (ExtendedResultCode::BusySnapshot, "database is locked"),
+            (ExtendedResultCode::IoErrWrite, "disk I/O error"),
+            (ExtendedResultCode::NotADb, "file is not a database"),
+            (ExtendedResultCode::AbortRollback, "abort due to ROLLBACK"),
+            (ExtendedResultCode::Internal, "unknown error"),
+            (ExtendedResultCode::Done, "no more rows available"),
+        ];
+        for case in cases {
+            assert_eq!(case.0.errstr(), case.1);
+        }
+    }
+
+    #[test]
+    fn name_matches_sqlite3() {
+        assert_eq!(ResultCode::NotADb.name(), "SQLITE_NOTADB");
+        assert_eq!(
+            ExtendedResultCode::IoErrCheckReservedLock.name(),
+            "SQLITE_IOERR_CHECKRESERVEDLOCK"
+        );
+        assert_eq!(
+            ExtendedResultCode::ConstraintPrimaryKey.to_string(),
+            "SQLITE_CONSTRAINT_PRIMARYKEY"
+        );
+    }
+
+    #[test]
+    fn new_selects_variant_from_primary_code() {
+        let err = SqliteError::new(ExtendedResultCode::BusyRecovery, "recovering");
+        assert!(matches!(
+            err,
+            SqliteError::Busy {
+                code: ExtendedResultCode::BusyRecovery,
+                ..
+            }
+        ));
+        assert_eq!(err.primary_code(), ResultCode::Busy);
+        assert_eq!(err.message(), "recovering");
+
+        let err = SqliteError::new(ExtendedResultCode::CantOpenIsDir, "is a directory");
+        assert!(matches!(err, SqliteError::CannotOpen { source: None, .. }));
+
+        let err = SqliteError::new(ExtendedResultCode::Row, "row");
+        assert_eq!(err.code(), ExtendedResultCode::Misuse);
+    }
+
+    #[test]
+    fn display() {
+        let err = SqliteError::new(ExtendedResultCode::CorruptIndex, "index t1 is corrupt");
+        assert_eq!(
+            err.to_string(),
+            "index t1 is corrupt (SQLITE_CORRUPT_INDEX)"
+        );
+        let err = SqliteError::new(ExtendedResultCode::Full, "");
+        assert_eq!(err.to_string(), "database or disk is full (SQLITE_FULL)");
+    }
+
+    #[test]
+    fn source_chaining() {
+        let io_err = std::io::Error::new(std::io::ErrorKind::NotFound, "no such file");
+        let err = SqliteError::io(ExtendedResultCode::IoErrRead, "read failed", io_err);
+        let source = err.source().unwrap();
+        assert_eq!(source.to_string(), "no such file");
+
+        let io_err = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "denied");
+        let err = SqliteError::io(ExtendedResultCode::CantOpen, "open failed", io_err);
+        assert!(err.source().is_some());
+
+        let err = SqliteError::new(ExtendedResultCode::Busy, "locked");
+        assert!(err.source().is_none());
+    }
 }
@@ -0,0 +1,334 @@
+use crate::database::SchemaFormat;
+use crate::errors::ExtendedResultCode;
+use crate::format::{Record, Value};
+use crate::SqliteError;
+use std::cmp::Ordering;
+
+/// The built-in collating sequences that order text
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Collation {
+    /// Compare the bytes of the text
+    #[default]
+    Binary,
+    /// Like `Binary`, but with the 26 upper case ASCII letters folded to lower case
+    NoCase,
+    /// Like `Binary`, but ignoring trailing spaces
+    RTrim,
+}
+
+impl Collation {
+    pub fn compare(self, a: &[u8], b: &[u8]) -> Ordering {
+        match self {
+            Collation::Binary => a.cmp(b),
+            Collation::NoCase => a
+                .iter()
+                .map(u8::to_ascii_lowercase)
+                .cmp(b.iter().map(u8::to_ascii_lowercase)),
+            Collation::RTrim => trim_spaces(a).cmp(trim_spaces(b)),
+        }
+    }
+}
+
+fn trim_spaces(text: &[u8]) -> &[u8] {
+    let len = text
+        .iter()
+        .rposition(|byte| *byte != b' ')
+        .map_or(0, |i| i + 1);
+    &text[..len]
+}
+
+impl TryFrom<&str> for Collation {
+    type Error = SqliteError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "binary" => Ok(Collation::Binary),
+            "nocase" => Ok(Collation::NoCase),
+            "rtrim" => Ok(Collation::RTrim),
+            _ => Err(SqliteError::Error {
+                code: ExtendedResultCode::Error,
+                message: format!("no such collation sequence: {}", value),
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// How a column of an index key is ordered
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyColumn {
+    pub collation: Collation,
+    pub order: SortOrder,
+}
+
+/// How the entries of an index b-tree are ordered, column by column.  Columns past the ones
+/// described, like the rowid at the end of every index entry, sort ascending in binary order.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeyInfo {
+    columns: Vec<KeyColumn>,
+}
+
+impl KeyInfo {
+    /// Descending columns are only stored in descending order from schema format 4; in older
+    /// formats sqlite3 ignores `DESC` and so does this.
+    pub fn new(columns: Vec<KeyColumn>, schema_format: SchemaFormat) -> KeyInfo {
+        let columns = if schema_format == SchemaFormat::V4 {
+            columns
+        } else {
+            columns
+                .into_iter()
+                .map(|column| KeyColumn {
+                    order: SortOrder::Asc,
+                    ..column
+                })
+                .collect()
+        };
+        KeyInfo { columns }
+    }
+
+    pub fn columns(&self) -> &[KeyColumn] {
+        &self.columns
+    }
+
+    /// Compare the index entry `record` with a key that may have fewer columns.  An entry
+    /// whose first columns equal every column of the key compares equal, and one that runs
+    /// out of columns first sorts before the key.
+    pub fn compare(&self, record: &Record, key: &[Value]) -> Ordering {
+        for (i, value) in key.iter().enumerate() {
+            let Some(column) = record.get(i) else {
+                return Ordering::Less;
+            };
+            let KeyColumn { collation, order } = self.columns.get(i).copied().unwrap_or_default();
+            let ordering = compare_values(&column, value, collation);
+            if ordering != Ordering::Equal {
+                return match order {
+                    SortOrder::Asc => ordering,
+                    SortOrder::Desc => ordering.reverse(),
+                };
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+/// Compare two values as sqlite3 sorts them: NULLs first, then numbers in numeric order,
+/// then text in the order of `collation`, then blobs in byte order
+pub fn compare_values(a: &Value, b: &Value, collation: Collation) -> Ordering {
+    match (a, b) {
+        (Value::Null, Value::Null) => Ordering::Equal,
+        (Value::Integer(a), Value::Integer(b)) => a.cmp(b),
+        (Value::Float(a), Value::Float(b)) => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+        (Value::Integer(a), Value::Float(b)) => compare_int_float(*a, *b),
+        (Value::Float(a), Value::Integer(b)) => compare_int_float(*b, *a).reverse(),
+        (Value::Text(a), Value::Text(b)) => collation.compare(a, b),
+        (Value::Blob(a), Value::Blob(b)) => a.cmp(b),
+        _ => type_rank(a).cmp(&type_rank(b)),
+    }
+}
+
+fn type_rank(value: &Value) -> u8 {
+    match value {
+        Value::Null => 0,
+        Value::Integer(_) | Value::Float(_) => 1,
+        Value::Text(_) => 2,
+        Value::Blob(_) => 3,
+    }
+}
+
+/// Compare an integer with a float exactly, where converting either to the other's type
+/// could round
+fn compare_int_float(i: i64, r: f64) -> Ordering {
+    if r.is_nan() {
+        // sqlite3 stores NaN as NULL, which sorts before any number
+        return Ordering::Greater;
+    }
+    if r < -9223372036854775808.0 {
+        return Ordering::Greater;
+    }
+    if r >= 9223372036854775808.0 {
+        return Ordering::Less;
+    }
+    let y = r as i64;
+    match i.cmp(&y) {
+        Ordering::Equal => (i as f64).partial_cmp(&r).unwrap_or(Ordering::Equal),
+        ordering => ordering,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use proptest::prelude::*;
+
+    fn text(text: &str) -> Value {
+        Value::Text(Bytes::copy_from_slice(text.as_bytes()))
+    }
+
+    fn blob(blob: &[u8]) -> Value {
+        Value::Blob(Bytes::copy_from_slice(blob))
+    }
+
+    #[test]
+    fn type_order() {
+        let ordered = [
+            Value::Null,
+            Value::Float(-1e300),
+            Value::Integer(i64::MIN),
+            Value::Integer(-1),
+            Value::Float(-0.5),
+            Value::Integer(0),
+            Value::Float(0.5),
+            Value::Integer(1),
+            Value::Float(1.5),
+            Value::Integer(i64::MAX),
+            Value::Float(9223372036854775808.0),
+            text(""),
+            text("A"),
+            text("a"),
+            text("b"),
+            blob(b""),
+            blob(b"A"),
+            blob(b"a\x00"),
+        ];
+        for (i, a) in ordered.iter().enumerate() {
+            for (j, b) in ordered.iter().enumerate() {
+                assert_eq!(
+                    compare_values(a, b, Collation::Binary),
+                    i.cmp(&j),
+                    "{:?} {:?}",
+                    a,
+                    b
+                );
+            }
+        }
+        assert_eq!(
+            compare_values(&Value::Integer(3), &Value::Float(3.0), Collation::Binary),
+            Ordering::Equal
+        );
+        // 2^53 + 1 is not a float, and does not equal the nearest one
+        let big = (1i64 << 53) + 1;
+        assert_eq!(
+            compare_values(
+                &Value::Integer(big),
+                &Value::Float(big as f64),
+                Collation::Binary
+            ),
+            Ordering::Greater
+        );
+    }
+
+    #[test]
+    fn collations() {
+        assert_eq!(Collation::try_from("NOCASE").unwrap(), Collation::NoCase);
+        assert_eq!(Collation::try_from("rtrim").unwrap(), Collation::RTrim);
+        assert!(Collation::try_from("french").is_err());
+
+        assert_eq!(Collation::Binary.compare(b"ABC", b"abc"), Ordering::Less);
+        assert_eq!(Collation::NoCase.compare(b"ABC", b"abc"), Ordering::Equal);
+        assert_eq!(Collation::NoCase.compare(b"abc", b"ABD"), Ordering::Less);
+        assert_eq!(Collation::NoCase.compare(b"_", b"a"), Ordering::Less);
+        assert_eq!(Collation::NoCase.compare(b"_", b"A"), Ordering::Less);
+        // only ASCII letters fold
+        assert_eq!(
+            Collation::NoCase.compare("É".as_bytes(), "é".as_bytes()),
+            Ordering::Less
+        );
+        assert_eq!(Collation::RTrim.compare(b"abc  ", b"abc"), Ordering::Equal);
+        assert_eq!(Collation::RTrim.compare(b" abc", b"abc"), Ordering::Less);
+        assert_eq!(
+            Collation::Binary.compare(b"abc ", b"abc"),
+            Ordering::Greater
+        );
+    }
+
+    #[test]
+    fn key_order() {
+        let key_info = KeyInfo::new(
+            vec![
+                KeyColumn {
+                    collation: Collation::NoCase,
+                    order: SortOrder::Asc,
+                },
+                KeyColumn {
+                    collation: Collation::Binary,
+                    order: SortOrder::Desc,
+                },
+            ],
+            SchemaFormat::V4,
+        );
+        let entry = Record::encode(
+            &[text("Apple"), Value::Integer(5), Value::Integer(10)],
+            SchemaFormat::V4,
+        );
+        let compare = |key: &[Value]| key_info.compare(&entry, key);
+        assert_eq!(compare(&[]), Ordering::Equal);
+        assert_eq!(compare(&[text("apple")]), Ordering::Equal);
+        assert_eq!(compare(&[text("banana")]), Ordering::Less);
+        assert_eq!(compare(&[text("APPLE"), Value::Integer(4)]), Ordering::Less);
+        assert_eq!(
+            compare(&[text("APPLE"), Value::Integer(6)]),
+            Ordering::Greater
+        );
+        // the rowid sorts ascending
+        assert_eq!(
+            compare(&[text("apple"), Value::Integer(5), Value::Integer(11)]),
+            Ordering::Less
+        );
+        // an entry with fewer columns than the key sorts first
+        let short = Record::encode(&[text("apple")], SchemaFormat::V4);
+        assert_eq!(
+            key_info.compare(&short, &[text("apple"), Value::Null]),
+            Ordering::Less
+        );
+
+        // before schema format 4 descending columns are ascending
+        let legacy = KeyInfo::new(key_info.columns().to_vec(), SchemaFormat::V1);
+        assert_eq!(legacy.columns()[1].order, SortOrder::Asc);
+        assert_eq!(
+            legacy.compare(&entry, &[text("APPLE"), Value::Integer(6)]),
+            Ordering::Less
+        );
+    }
+
+    fn value() -> impl Strategy<Value = Value> {
+        prop_oneof![
+            Just(Value::Null),
+            any::<i64>().prop_map(Value::Integer),
+            prop_oneof![any::<i64>().prop_map(|i| i as f64), -1e20f64..1e20].prop_map(Value::Float),
+            "[a-cA-C ]{0,4}".prop_map(|s| text(&s)),
+            prop::collection::vec(any::<u8>(), 0..4).prop_map(|b| blob(&b)),
+        ]
+    }
+
+    fn collation() -> impl Strategy<Value = Collation> {
+        prop_oneof![
+            Just(Collation::Binary),
+            Just(Collation::NoCase),
+            Just(Collation::RTrim)
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn comparison_is_a_total_order(
+            a in value(),
+            b in value(),
+            c in value(),
+            collation in collation(),
+        ) {
+            let ab = compare_values(&a, &b, collation);
+            prop_assert_eq!(compare_values(&b, &a, collation), ab.reverse());
+            let bc = compare_values(&b, &c, collation);
+            if ab == bc {
+                prop_assert_eq!(compare_values(&a, &c, collation), ab);
+            }
+        }
+    }
+}
@@ -0,0 +1,7 @@
+mod compare;
+mod record;
+mod varint;
+
+pub use self::compare::*;
+pub use self::record::*;
+pub use self::varint::*;
@@ -0,0 +1,484 @@
+use crate::database::SchemaFormat;
+use crate::errors::{ExtendedResultCode, SqliteResult};
+use crate::format::{get_varint, put_varint, varint_len};
+use crate::SqliteError;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+/// How a record stores a value, as the varint in the record header says.  Text and blobs
+/// carry their length in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SerialType {
+    Null,
+    Int8,
+    Int16,
+    Int24,
+    Int32,
+    Int48,
+    Int64,
+    Float,
+    /// The integer 0, stored in no bytes, from schema format 4 on
+    Zero,
+    /// The integer 1, stored in no bytes, from schema format 4 on
+    One,
+    Blob(usize),
+    Text(usize),
+}
+
+impl SerialType {
+    /// The number of bytes the value takes in the record body
+    pub fn size(self) -> usize {
+        match self {
+            SerialType::Null | SerialType::Zero | SerialType::One => 0,
+            SerialType::Int8 => 1,
+            SerialType::Int16 => 2,
+            SerialType::Int24 => 3,
+            SerialType::Int32 => 4,
+            SerialType::Int48 => 6,
+            SerialType::Int64 | SerialType::Float => 8,
+            SerialType::Blob(len) | SerialType::Text(len) => len,
+        }
+    }
+
+    /// The serial type that stores `value` in the fewest bytes, as sqlite3 picks it.  Only
+    /// schema format 4 has the constants for 0 and 1.
+    pub fn for_value(value: &Value, schema_format: SchemaFormat) -> SerialType {
+        match value {
+            Value::Null => SerialType::Null,
+            Value::Integer(0 | 1) if schema_format == SchemaFormat::V4 => {
+                if *value == Value::Integer(0) {
+                    SerialType::Zero
+                } else {
+                    SerialType::One
+                }
+            }
+            Value::Integer(value) => match *value {
+                -0x80..=0x7f => SerialType::Int8,
+                -0x8000..=0x7fff => SerialType::Int16,
+                -0x80_0000..=0x7f_ffff => SerialType::Int24,
+                -0x8000_0000..=0x7fff_ffff => SerialType::Int32,
+                -0x8000_0000_0000..=0x7fff_ffff_ffff => SerialType::Int48,
+                _ => SerialType::Int64,
+            },
+            Value::Float(_) => SerialType::Float,
+            Value::Text(text) => SerialType::Text(text.len()),
+            Value::Blob(blob) => SerialType::Blob(blob.len()),
+        }
+    }
+}
+
+impl TryFrom<u64> for SerialType {
+    type Error = SqliteError;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(SerialType::Null),
+            1 => Ok(SerialType::Int8),
+            2 => Ok(SerialType::Int16),
+            3 => Ok(SerialType::Int24),
+            4 => Ok(SerialType::Int32),
+            5 => Ok(SerialType::Int48),
+            6 => Ok(SerialType::Int64),
+            7 => Ok(SerialType::Float),
+            8 => Ok(SerialType::Zero),
+            9 => Ok(SerialType::One),
+            10 | 11 => Err(SqliteError::new(
+                ExtendedResultCode::Corrupt,
+                format!("reserved serial type: {}", value),
+            )),
+            _ => {
+                let len = usize::try_from((value - 12) / 2).map_err(|_| {
+                    SqliteError::new(
+                        ExtendedResultCode::Corrupt,
+                        format!("serial type {} is too large", value),
+                    )
+                })?;
+                if value % 2 == 0 {
+                    Ok(SerialType::Blob(len))
+                } else {
+                    Ok(SerialType::Text(len))
+                }
+            }
+        }
+    }
+}
+
+impl From<SerialType> for u64 {
+    fn from(value: SerialType) -> Self {
+        match value {
+            SerialType::Null => 0,
+            SerialType::Int8 => 1,
+            SerialType::Int16 => 2,
+            SerialType::Int24 => 3,
+            SerialType::Int32 => 4,
+            SerialType::Int48 => 5,
+            SerialType::Int64 => 6,
+            SerialType::Float => 7,
+            SerialType::Zero => 8,
+            SerialType::One => 9,
+            SerialType::Blob(len) => len as u64 * 2 + 12,
+            SerialType::Text(len) => len as u64 * 2 + 13,
+        }
+    }
+}
+
+/// A value of a record.  Text is in the database's text encoding, which need not be UTF-8.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Float(f64),
+    Text(Bytes),
+    Blob(Bytes),
+}
+
+/// A column of a decoded record: its serial type and where its value starts in the record
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Column {
+    serial_type: SerialType,
+    offset: usize,
+}
+
+/// A record, the payload of a table row or index entry: a header giving the serial type of
+/// each value, then the values in order.  Decoding reads only the header; values come out of
+/// the record's bytes when asked for, so text and blobs share the record's memory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    data: Bytes,
+    columns: Vec<Column>,
+}
+
+impl Record {
+    /// Decode the header of a record, checking that it lies within `data` and that every
+    /// value does.  Fails with `SQLITE_CORRUPT` if not.
+    pub fn decode(data: Bytes) -> SqliteResult<Record> {
+        let mut header = data.clone();
+        let header_size = get_varint(&mut header)?;
+        let header_size = usize::try_from(header_size)
+            .ok()
+            .filter(|size| (data.len() - header.len()..=data.len()).contains(size))
+            .ok_or_else(|| {
+                SqliteError::new(
+                    ExtendedResultCode::Corrupt,
+                    format!(
+                        "record header of {} bytes in a record of {}",
+                        header_size,
+                        data.len()
+                    ),
+                )
+            })?;
+        header.truncate(header_size - (data.len() - header.len()));
+
+        let mut columns = Vec::new();
+        let mut offset = header_size;
+        while header.has_remaining() {
+            let serial_type = SerialType::try_from(get_varint(&mut header)?)?;
+            columns.push(Column {
+                serial_type,
+                offset,
+            });
+            offset = offset
+                .checked_add(serial_type.size())
+                .filter(|end| *end <= data.len())
+                .ok_or_else(|| {
+                    SqliteError::new(
+                        ExtendedResultCode::Corrupt,
+                        format!(
+                            "value {} overflows a record of {} bytes",
+                            columns.len(),
+                            data.len()
+                        ),
+                    )
+                })?;
+        }
+        Ok(Record { data, columns })
+    }
+
+    /// Encode `values` as a record, storing each in the fewest bytes `schema_format` allows
+    pub fn encode(values: &[Value], schema_format: SchemaFormat) -> Record {
+        let mut buf = BytesMut::new();
+        Record::write(values, schema_format, &mut buf);
+        Record::decode(buf.freeze()).expect("an encoded record decodes")
+    }
+
+    /// Append the record of `values` to `buf`
+    pub fn write(values: &[Value], schema_format: SchemaFormat, buf: &mut BytesMut) {
+        let serial_types: Vec<u64> = values
+            .iter()
+            .map(|value| SerialType::for_value(value, schema_format).into())
+            .collect();
+        let types_len: usize = serial_types.iter().map(|t| varint_len(*t)).sum();
+        // the header size counts the varint that holds it
+        let mut header_size = types_len + 1;
+        while varint_len(header_size as u64) + types_len != header_size {
+            header_size = varint_len(header_size as u64) + types_len;
+        }
+        put_varint(buf, header_size as u64);
+        for serial_type in serial_types {
+            put_varint(buf, serial_type);
+        }
+        for value in values {
+            match value {
+                Value::Null => {}
+                Value::Integer(integer) => {
+                    let size = SerialType::for_value(value, schema_format).size();
+                    buf.put_slice(&integer.to_be_bytes()[8 - size..]);
+                }
+                Value::Float(float) => buf.put_f64(*float),
+                Value::Text(bytes) | Value::Blob(bytes) => buf.put_slice(bytes),
+            }
+        }
+    }
+
+    /// The number of values in the record
+    pub fn len(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    pub fn serial_type(&self, column: usize) -> Option<SerialType> {
+        self.columns.get(column).map(|column| column.serial_type)
+    }
+
+    /// The value of a column, or `None` past the last one
+    pub fn get(&self, column: usize) -> Option<Value> {
+        let Column {
+            serial_type,
+            offset,
+        } = *self.columns.get(column)?;
+        let mut body = &self.data[offset..offset + serial_type.size()];
+        let value = match serial_type {
+            SerialType::Null => Value::Null,
+            SerialType::Zero => Value::Integer(0),
+            SerialType::One => Value::Integer(1),
+            SerialType::Float => Value::Float(body.get_f64()),
+            SerialType::Blob(_) => Value::Blob(self.data.slice(offset..offset + body.len())),
+            SerialType::Text(_) => Value::Text(self.data.slice(offset..offset + body.len())),
+            _ => Value::Integer(body.get_int(body.len())),
+        };
+        Some(value)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = Value> + '_ {
+        (0..self.len()).map(|column| self.get(column).unwrap())
+    }
+
+    /// The encoded record
+    pub fn as_bytes(&self) -> &Bytes {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Bytes {
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::read_varint;
+    use proptest::prelude::*;
+
+    fn hex(text: &str) -> Bytes {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect::<Vec<u8>>()
+            .into()
+    }
+
+    fn text(text: &str) -> Value {
+        Value::Text(Bytes::copy_from_slice(text.as_bytes()))
+    }
+
+    fn blob(blob: &[u8]) -> Value {
+        Value::Blob(Bytes::copy_from_slice(blob))
+    }
+
+    /// Records sqlite3 wrote for rows of a table without column affinities
+    fn sqlite_records() -> Vec<(&'static str, Vec<Value>)> {
+        use Value::*;
+        vec![
+            (
+                "050008090102",
+                vec![Null, Integer(0), Integer(1), Integer(2)],
+            ),
+            (
+                "0501010202ff7f0080ff7f",
+                vec![Integer(-1), Integer(127), Integer(128), Integer(-129)],
+            ),
+            (
+                "05020303047fff0080007fffff00800000",
+                vec![
+                    Integer(32767),
+                    Integer(32768),
+                    Integer(8388607),
+                    Integer(8388608),
+                ],
+            ),
+            (
+                "05040505067fffffff0000800000007fffffffffff0000800000000000",
+                vec![
+                    Integer(2147483647),
+                    Integer(2147483648),
+                    Integer(140737488355327),
+                    Integer(140737488355328),
+                ],
+            ),
+            (
+                "050606070780000000000000007fffffffffffffff3ff80000000000008000000000000000",
+                vec![
+                    Integer(i64::MIN),
+                    Integer(i64::MAX),
+                    Float(1.5),
+                    Float(-0.0),
+                ],
+            ),
+            (
+                "050d170c1068656c6c6f00ff",
+                vec![text(""), text("hello"), blob(b""), blob(&[0x00, 0xff])],
+            ),
+            (
+                "0507191600400800000000000068c3a96c6c6f0102030405",
+                vec![Float(3.0), text("héllo"), blob(&[1, 2, 3, 4, 5]), Null],
+            ),
+        ]
+    }
+
+    #[test]
+    fn sqlite_records_round_trip() {
+        for (encoded, values) in sqlite_records() {
+            let record = Record::decode(hex(encoded)).unwrap();
+            assert_eq!(record.values().collect::<Vec<_>>(), values, "{}", encoded);
+            assert_eq!(
+                Record::encode(&values, SchemaFormat::V4).as_bytes(),
+                &hex(encoded)
+            );
+        }
+        // -0.0 keeps its sign
+        let record = Record::decode(hex(sqlite_records()[4].0)).unwrap();
+        let Some(Value::Float(zero)) = record.get(3) else {
+            panic!("not a float");
+        };
+        assert!(zero.is_sign_negative());
+    }
+
+    #[test]
+    fn long_header() {
+        // 130 columns make the header size take two bytes
+        let mut values = vec![Value::Null; 129];
+        values.push(Value::Integer(7));
+        let record = Record::encode(&values, SchemaFormat::V4);
+        assert_eq!(&record.as_bytes()[..2], &[0x81, 0x04]);
+        assert_eq!(record.as_bytes().len(), 133);
+        assert_eq!(record.len(), 130);
+        assert_eq!(record.get(129), Some(Value::Integer(7)));
+        assert_eq!(record.get(130), None);
+
+        // 127 serial type bytes need a two byte header size of 129
+        let record = Record::encode(&vec![Value::Null; 127], SchemaFormat::V4);
+        assert_eq!(&record.as_bytes()[..2], &[0x81, 0x01]);
+        assert_eq!(record.len(), 127);
+    }
+
+    #[test]
+    fn legacy_schema_format_has_no_constants() {
+        let values = [Value::Integer(0), Value::Integer(1)];
+        let record = Record::encode(&values, SchemaFormat::V1);
+        assert_eq!(record.as_bytes().as_ref(), &[3, 1, 1, 0, 1]);
+        assert_eq!(record.serial_type(0), Some(SerialType::Int8));
+        assert_eq!(record.values().collect::<Vec<_>>(), values);
+    }
+
+    #[test]
+    fn zero_copy() {
+        let data = hex("050d170c1068656c6c6f00ff");
+        let record = Record::decode(data.clone()).unwrap();
+        let Some(Value::Text(hello)) = record.get(1) else {
+            panic!("not text");
+        };
+        assert_eq!(hello.as_ref(), b"hello");
+        assert_eq!(hello.as_ptr(), data[5..].as_ptr());
+    }
+
+    #[test]
+    fn corrupt_record_err() {
+        let cases = vec![
+            ("0900", "header size past the end"),
+            ("00", "header size smaller than its own varint"),
+            ("02067fff", "value past the end"),
+            ("020a", "reserved serial type"),
+            ("028101", "serial type varint cut short by the header size"),
+            ("", "no header size at all"),
+        ];
+        for (encoded, case) in cases {
+            assert!(
+                matches!(
+                    Record::decode(hex(encoded)),
+                    Err(SqliteError::Corrupt {
+                        code: ExtendedResultCode::Corrupt,
+                        ..
+                    })
+                ),
+                "{}: {}",
+                case,
+                encoded
+            );
+        }
+    }
+
+    fn value() -> impl Strategy<Value = Value> {
+        prop_oneof![
+            Just(Value::Null),
+            any::<i64>().prop_map(Value::Integer),
+            (-70000i64..70000).prop_map(Value::Integer),
+            any::<f64>()
+                .prop_filter("NaN is not equal to itself", |f| !f.is_nan())
+                .prop_map(Value::Float),
+            ".*".prop_map(|s| Value::Text(Bytes::from(s))),
+            any::<Vec<u8>>().prop_map(|b| Value::Blob(Bytes::from(b))),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn records_round_trip(values in prop::collection::vec(value(), 0..200)) {
+            for schema_format in [SchemaFormat::V1, SchemaFormat::V4] {
+                let record = Record::encode(&values, schema_format);
+                let size: usize = values
+                    .iter()
+                    .map(|value| {
+                        let serial_type = SerialType::for_value(value, schema_format);
+                        varint_len(serial_type.into()) + serial_type.size()
+                    })
+                    .sum();
+                // everything else is the header size varint
+                let header_size = read_varint(record.as_bytes()).unwrap();
+                prop_assert_eq!(header_size.1, record.as_bytes().len() - size);
+                prop_assert_eq!(header_size.0 as usize, header_size.1 + size - values
+                    .iter()
+                    .map(|value| SerialType::for_value(value, schema_format).size())
+                    .sum::<usize>());
+                let decoded = Record::decode(record.as_bytes().clone()).unwrap();
+                prop_assert_eq!(decoded.values().collect::<Vec<_>>(), values.clone());
+            }
+        }
+
+        #[test]
+        fn serial_types_round_trip(serial_type: u64) {
+            match SerialType::try_from(serial_type) {
+                Ok(decoded) => prop_assert_eq!(u64::from(decoded), serial_type),
+                Err(_) => prop_assert!(serial_type == 10 || serial_type == 11),
+            }
+        }
+
+        #[test]
+        fn any_bytes_decode_or_err(data: Vec<u8>) {
+            if let Ok(record) = Record::decode(Bytes::from(data)) {
+                prop_assert_eq!(record.values().count(), record.len());
+            }
+        }
+    }
+}
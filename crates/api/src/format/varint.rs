@@ -0,0 +1,148 @@
+use crate::errors::{ExtendedResultCode, SqliteResult};
+use crate::SqliteError;
+use bytes::{Buf, BufMut};
+
+/// The longest a varint gets: eight bytes of 7 bits and a ninth holding a full 8 bits
+pub const MAX_VARINT_LEN: usize = 9;
+
+/// The largest value that fits in the first eight bytes of a varint
+const MAX_SHORT_VARINT: u64 = 0x00ff_ffff_ffff_ffff;
+
+/// Read the varint at the start of `data`, returning its value and length, or `None` if
+/// `data` ends first
+pub fn read_varint(data: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, byte) in data.iter().enumerate().take(MAX_VARINT_LEN) {
+        if i == MAX_VARINT_LEN - 1 {
+            return Some(((value << 8) | *byte as u64, MAX_VARINT_LEN));
+        }
+        value = (value << 7) | (*byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Take a varint off the front of `buf`.  Fails with `SQLITE_CORRUPT` if `buf` ends first.
+pub fn get_varint(buf: &mut impl Buf) -> SqliteResult<u64> {
+    let mut value = 0u64;
+    for i in 0..MAX_VARINT_LEN {
+        if !buf.has_remaining() {
+            return Err(SqliteError::Corrupt {
+                code: ExtendedResultCode::Corrupt,
+                message: format!("varint ends after {} bytes", i),
+            });
+        }
+        let byte = buf.get_u8();
+        if i == MAX_VARINT_LEN - 1 {
+            return Ok((value << 8) | byte as u64);
+        }
+        value = (value << 7) | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    Ok(value)
+}
+
+/// The number of bytes of the shortest varint for `value`
+pub fn varint_len(value: u64) -> usize {
+    if value > MAX_SHORT_VARINT {
+        return MAX_VARINT_LEN;
+    }
+    let bits = (u64::BITS - value.leading_zeros()) as usize;
+    bits.div_ceil(7).max(1)
+}
+
+/// Append the shortest varint for `value`
+pub fn put_varint(buf: &mut impl BufMut, value: u64) {
+    let len = varint_len(value);
+    if len == MAX_VARINT_LEN {
+        let high = value >> 8;
+        for i in (0..MAX_VARINT_LEN - 1).rev() {
+            buf.put_u8(((high >> (i * 7)) & 0x7f) as u8 | 0x80);
+        }
+        buf.put_u8(value as u8);
+        return;
+    }
+    for i in (0..len).rev() {
+        let continues = if i > 0 { 0x80 } else { 0 };
+        buf.put_u8(((value >> (i * 7)) & 0x7f) as u8 | continues);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{Bytes, BytesMut};
+    use proptest::prelude::*;
+
+    #[test]
+    fn encodings() {
+        for (value, encoded) in [
+            (0, vec![0x00]),
+            (127, vec![0x7f]),
+            (128, vec![0x81, 0x00]),
+            (5000, vec![0xa7, 0x08]),
+            (16383, vec![0xff, 0x7f]),
+            (16384, vec![0x81, 0x80, 0x00]),
+            (
+                MAX_SHORT_VARINT,
+                vec![0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f],
+            ),
+            (
+                MAX_SHORT_VARINT + 1,
+                vec![0x80, 0xc0, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x00],
+            ),
+            (u64::MAX, vec![0xff; 9]),
+        ] {
+            let mut buf = BytesMut::new();
+            put_varint(&mut buf, value);
+            assert_eq!(buf.as_ref(), encoded.as_slice(), "{}", value);
+            assert_eq!(varint_len(value), encoded.len());
+            assert_eq!(read_varint(&encoded), Some((value, encoded.len())));
+        }
+    }
+
+    #[test]
+    fn truncated_varint_err() {
+        assert_eq!(read_varint(&[]), None);
+        assert_eq!(read_varint(&[0x81, 0x80]), None);
+        let mut buf = Bytes::from_static(&[0x81, 0x80]);
+        assert!(matches!(
+            get_varint(&mut buf),
+            Err(SqliteError::Corrupt {
+                code: ExtendedResultCode::Corrupt,
+                ..
+            })
+        ));
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips(value: u64, rest: Vec<u8>) {
+            let mut buf = BytesMut::new();
+            put_varint(&mut buf, value);
+            prop_assert_eq!(buf.len(), varint_len(value));
+            let len = buf.len();
+            buf.extend_from_slice(&rest);
+            prop_assert_eq!(read_varint(&buf), Some((value, len)));
+            let mut buf = buf.freeze();
+            prop_assert_eq!(get_varint(&mut buf).unwrap(), value);
+            prop_assert_eq!(buf.as_ref(), rest.as_slice());
+        }
+
+        #[test]
+        fn any_bytes_read_the_same(data: Vec<u8>) {
+            let mut buf = Bytes::from(data.clone());
+            match read_varint(&data) {
+                Some((value, len)) => {
+                    prop_assert_eq!(get_varint(&mut buf).unwrap(), value);
+                    prop_assert_eq!(buf.len(), data.len() - len);
+                }
+                None => prop_assert!(get_varint(&mut buf).is_err()),
+            }
+        }
+    }
+}
@@ -1,20 +1,17 @@
+pub mod btree;
 mod connection;
 mod database;
 pub mod errors;
+pub mod format;
+pub mod storage;
+pub mod vfs;
 
+pub use self::connection::{
+    CacheType, Connection, ConnectionOptions, ConnectionOptionsBuilder, DatabaseUri, Mode,
+    MEMORY_FILENAME,
+};
+pub use self::database::{
+    FileFormatReadVersion, FileFormatWriteVersion, PageSize, SchemaFormat, SqliteHeader,
+    TextEncoding,
+};
 pub use self::errors::*;
-
-pub fn add(left: u64, right: u64) -> u64 {
-    left + right
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
-    }
-}
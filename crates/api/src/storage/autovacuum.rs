@@ -0,0 +1,658 @@
+use crate::btree::{read_page_number, BtreePage, PayloadLimits, CELL_COUNT_OFFSET};
+use crate::errors::{ExtendedResultCode, SqliteResult};
+use crate::storage::{Page, PageNumber, Pager, PtrmapType, TransactionState};
+use crate::SqliteError;
+use bytes::{Bytes, BytesMut};
+use std::collections::BTreeSet;
+
+/// Whether and when an auto-vacuum database gives free pages back to the file system, like
+/// `PRAGMA auto_vacuum`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AutoVacuum {
+    /// Free pages stay in the file for reuse
+    #[default]
+    None,
+    /// Every commit moves the free pages to the end of the file and truncates them
+    Full,
+    /// Free pages are only truncated by `incremental_vacuum`
+    Incremental,
+}
+
+impl TryFrom<&str> for AutoVacuum {
+    type Error = SqliteError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "none" | "0" => Ok(AutoVacuum::None),
+            "full" | "1" => Ok(AutoVacuum::Full),
+            "incremental" | "2" => Ok(AutoVacuum::Incremental),
+            _ => Err(SqliteError::Misuse {
+                code: ExtendedResultCode::Misuse,
+                message: format!("invalid auto_vacuum: {}", value),
+            }),
+        }
+    }
+}
+
+/// The offsets of the page numbers a b-tree page points to, with what the pages they point to
+/// are: child pages of an interior page, including the right-most child, and the first
+/// overflow page of every cell whose payload spills
+pub(crate) fn page_pointers(
+    number: PageNumber,
+    data: &Bytes,
+    limits: &PayloadLimits,
+) -> SqliteResult<Vec<(usize, PtrmapType)>> {
+    let page = BtreePage::decode(number, data.clone(), limits)?;
+    let mut pointers = Vec::new();
+    if let Some(right_child) = page.right_child_offset() {
+        pointers.push((right_child, PtrmapType::Btree));
+    }
+    for cell in page.cells() {
+        if cell.left_child.is_some() {
+            pointers.push((cell.offset, PtrmapType::Btree));
+        }
+        if let Some(overflow) = cell.overflow_offset() {
+            pointers.push((overflow, PtrmapType::Overflow1));
+        }
+    }
+    Ok(pointers)
+}
+
+impl Pager {
+    /// The auto-vacuum mode in the header, which is only current within a transaction
+    pub(crate) fn auto_vacuum_mode(&self) -> AutoVacuum {
+        match (self.has_ptrmap(), self.header().incremental_vacuum()) {
+            (false, _) => AutoVacuum::None,
+            (true, false) => AutoVacuum::Full,
+            (true, true) => AutoVacuum::Incremental,
+        }
+    }
+
+    /// The auto-vacuum mode, following `PRAGMA auto_vacuum`
+    pub fn auto_vacuum(&mut self) -> SqliteResult<AutoVacuum> {
+        if self.transaction_state() == TransactionState::None {
+            self.begin_read()?;
+            let mode = self.auto_vacuum_mode();
+            self.end_read()?;
+            return Ok(mode);
+        }
+        Ok(self.auto_vacuum_mode())
+    }
+
+    /// Change the auto-vacuum mode, in the current write transaction or in one of its own.
+    /// Like sqlite3, a database only starts or stops keeping a pointer map while it holds no
+    /// tables; otherwise the change is ignored, though `VACUUM` can still make it.  Full and
+    /// incremental auto-vacuum can be swapped at any time.
+    pub fn set_auto_vacuum(&mut self, mode: AutoVacuum) -> SqliteResult<()> {
+        self.in_write_transaction(|pager| {
+            let current = pager.auto_vacuum_mode();
+            if current == mode
+                || (current == AutoVacuum::None || mode == AutoVacuum::None) && !pager.is_empty()?
+            {
+                return Ok(());
+            }
+            pager.update_header(|header| match mode {
+                AutoVacuum::None => header.set_auto_vacuum(0, false),
+                AutoVacuum::Full | AutoVacuum::Incremental => header.set_auto_vacuum(
+                    header.largest_root_btree_page().max(1),
+                    mode == AutoVacuum::Incremental,
+                ),
+            })
+        })
+    }
+
+    /// Whether the database is a lone page 1 with an empty sqlite_schema table
+    fn is_empty(&mut self) -> SqliteResult<bool> {
+        if self.page_count() > 1 {
+            return Ok(false);
+        }
+        let page = self.get(1)?;
+        let cell_count = &page.data()[100 + CELL_COUNT_OFFSET..100 + CELL_COUNT_OFFSET + 2];
+        Ok(cell_count == [0, 0])
+    }
+
+    /// Give up to `pages` free pages back to the file system, or every free page when `pages`
+    /// is 0, like `PRAGMA incremental_vacuum`.  Pages in use past the new end of the file
+    /// move into free pages before it, and the pointers to them are updated.  Runs in the
+    /// current write transaction or in one of its own, and returns how many pages the
+    /// database shrank by, not counting pointer map pages.  Does nothing unless the database
+    /// auto-vacuums.
+    pub fn incremental_vacuum(&mut self, pages: u32) -> SqliteResult<u32> {
+        self.in_write_transaction(|pager| {
+            let free_pages = pager.header().total_freelist_pages();
+            if !pager.has_ptrmap() || free_pages == 0 {
+                return Ok(0);
+            }
+            let pages = if pages == 0 {
+                free_pages
+            } else {
+                pages.min(free_pages)
+            };
+            pager.vacuum_free_pages(pages)?;
+            Ok(pages)
+        })
+    }
+
+    /// Truncate every free page before a full auto-vacuum database commits
+    pub(crate) fn auto_vacuum_commit(&mut self) -> SqliteResult<()> {
+        let free_pages = self.header().total_freelist_pages();
+        self.vacuum_free_pages(free_pages)
+    }
+
+    /// Allocate the root page of a new b-tree.  An auto-vacuum database keeps its root pages
+    /// together at the start of the file, right after page 1 and the first pointer map page,
+    /// so the page after the current largest root is taken off the freelist or has its
+    /// contents moved out of the way, as in sqlite3.
+    pub fn allocate_root_page(&mut self) -> SqliteResult<Page> {
+        self.begin_write()?;
+        if !self.has_ptrmap() {
+            return self.allocate();
+        }
+        let ptrmap = self.ptrmap();
+        let mut root = self.header().largest_root_btree_page() + 1;
+        while ptrmap.is_reserved(root) {
+            root += 1;
+        }
+        let page = if root > self.page_count() {
+            let page = self.append_page()?;
+            // a root page can only be appended after every page before it
+            debug_assert_eq!(page.number(), root);
+            page
+        } else {
+            let mut free: BTreeSet<PageNumber> = self.drain_freelist()?.into_iter().collect();
+            let taken = free.remove(&root);
+            for number in free {
+                self.free_page(number)?;
+            }
+            if !taken {
+                let (ptrmap_type, parent) = self.ptrmap_get(root)?;
+                if matches!(ptrmap_type, PtrmapType::RootPage | PtrmapType::FreePage) {
+                    return Err(SqliteError::new(
+                        ExtendedResultCode::Corrupt,
+                        format!(
+                            "page {} after the largest root page is a {:?} page",
+                            root, ptrmap_type
+                        ),
+                    ));
+                }
+                let dest = self.allocate()?;
+                self.relocate_page(root, dest.number(), ptrmap_type, parent)?;
+            }
+            let page_size: u32 = self.page_size().into();
+            let page = Page::new(root, Bytes::from(vec![0u8; page_size as usize]));
+            self.write(page.clone())?;
+            page
+        };
+        self.ptrmap_put(root, PtrmapType::RootPage, 0)?;
+        self.update_header(|header| {
+            let incremental = header.incremental_vacuum();
+            header.set_auto_vacuum(root, incremental)
+        })?;
+        Ok(page)
+    }
+
+    /// Shrink the database by `count` free pages, moving the pages in use past the new end
+    /// into the free pages before it
+    fn vacuum_free_pages(&mut self, count: u32) -> SqliteResult<()> {
+        let ptrmap = self.ptrmap();
+        let mut free: BTreeSet<PageNumber> = self.drain_freelist()?.into_iter().collect();
+        let page_count = self.page_count();
+        let mut target = page_count;
+        let mut remaining = count;
+        while remaining > 0 {
+            if !ptrmap.is_reserved(target) {
+                remaining -= 1;
+            }
+            target -= 1;
+        }
+        // a pointer map page with no pages after it is not needed
+        while ptrmap.is_reserved(target) {
+            target -= 1;
+        }
+        for number in (target + 1..=page_count).rev() {
+            if ptrmap.is_reserved(number) || free.remove(&number) {
+                continue;
+            }
+            let (ptrmap_type, parent) = self.ptrmap_get(number)?;
+            if matches!(ptrmap_type, PtrmapType::RootPage | PtrmapType::FreePage) {
+                return Err(SqliteError::new(
+                    ExtendedResultCode::Corrupt,
+                    format!(
+                        "page {} past the end of the vacuumed database is a {:?} page",
+                        number, ptrmap_type
+                    ),
+                ));
+            }
+            let dest = match free.pop_first() {
+                Some(dest) if dest <= target => dest,
+                _ => {
+                    return Err(SqliteError::new(
+                        ExtendedResultCode::Corrupt,
+                        format!("no free page to move page {} into", number),
+                    ))
+                }
+            };
+            self.relocate_page(number, dest, ptrmap_type, parent)?;
+        }
+        self.truncate(target)?;
+        for number in free {
+            self.free_page(number)?;
+        }
+        Ok(())
+    }
+
+    /// Record b-tree page `number` as the parent of its child pages and first overflow pages
+    pub(crate) fn put_child_ptrmaps(
+        &mut self,
+        number: PageNumber,
+        data: &Bytes,
+    ) -> SqliteResult<()> {
+        for (offset, child_type) in page_pointers(number, data, &self.payload_limits())? {
+            self.ptrmap_put(read_page_number(data, offset), child_type, number)?;
+        }
+        Ok(())
+    }
+
+    /// Move the contents of page `from` into page `to`, updating the page that points to it
+    /// and the pointer map entries of the pages it points to
+    fn relocate_page(
+        &mut self,
+        from: PageNumber,
+        to: PageNumber,
+        ptrmap_type: PtrmapType,
+        parent: PageNumber,
+    ) -> SqliteResult<()> {
+        let data = self.get(from)?.into_data();
+        self.write(Page::new(to, data.clone()))?;
+        match ptrmap_type {
+            PtrmapType::Btree => self.put_child_ptrmaps(to, &data)?,
+            PtrmapType::Overflow1 | PtrmapType::Overflow2 => {
+                let next = read_page_number(&data, 0);
+                if next != 0 {
+                    self.ptrmap_put(next, PtrmapType::Overflow2, to)?;
+                }
+            }
+            PtrmapType::RootPage | PtrmapType::FreePage => unreachable!("never relocated"),
+        }
+        self.ptrmap_put(to, ptrmap_type, parent)?;
+
+        let data = self.get(parent)?.into_data();
+        let offset = if ptrmap_type == PtrmapType::Overflow2 {
+            Some(0).filter(|offset| read_page_number(&data, *offset) == from)
+        } else {
+            page_pointers(parent, &data, &self.payload_limits())?
+                .into_iter()
+                .find(|(offset, pointer_type)| {
+                    *pointer_type == ptrmap_type && read_page_number(&data, *offset) == from
+                })
+                .map(|(offset, _)| offset)
+        };
+        let Some(offset) = offset else {
+            return Err(SqliteError::new(
+                ExtendedResultCode::Corrupt,
+                format!(
+                    "page {} does not point to page {} as the pointer map says",
+                    parent, from
+                ),
+            ));
+        };
+        let mut data = BytesMut::from(data.as_ref());
+        data[offset..offset + 4].copy_from_slice(&to.to_be_bytes());
+        self.write(Page::new(parent, data.freeze()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::{BtreePageType, RIGHT_CHILD_OFFSET};
+    use crate::storage::{memory_pager, DEFAULT_PAGE_SIZE};
+    use bytes::BufMut;
+
+    const PAGE_SIZE: usize = 4096;
+
+    fn limits() -> PayloadLimits {
+        PayloadLimits::new(PAGE_SIZE, 64, 32, 32)
+    }
+
+    /// A b-tree page holding `cells`, packed at the end of the page
+    fn btree_page(
+        page_type: BtreePageType,
+        right_child: Option<PageNumber>,
+        cells: &[Vec<u8>],
+    ) -> Bytes {
+        let mut data = vec![0u8; PAGE_SIZE];
+        data[0] = page_type as u8;
+        data[3..5].copy_from_slice(&(cells.len() as u16).to_be_bytes());
+        let mut pointers = 8;
+        if let Some(right_child) = right_child {
+            data[8..12].copy_from_slice(&right_child.to_be_bytes());
+            pointers = 12;
+        }
+        let mut content = PAGE_SIZE;
+        for (i, cell) in cells.iter().enumerate() {
+            content -= cell.len();
+            data[content..content + cell.len()].copy_from_slice(cell);
+            let at = pointers + i * 2;
+            data[at..at + 2].copy_from_slice(&(content as u16).to_be_bytes());
+        }
+        data[5..7].copy_from_slice(&(content as u16).to_be_bytes());
+        Bytes::from(data)
+    }
+
+    fn interior_cell(child: PageNumber, rowid: u8) -> Vec<u8> {
+        let mut cell = child.to_be_bytes().to_vec();
+        cell.push(rowid);
+        cell
+    }
+
+    /// A table leaf cell with a 5000-byte payload, of which 908 bytes stay on the page
+    fn overflow_cell(rowid: u8, overflow: PageNumber) -> Vec<u8> {
+        let mut cell = BytesMut::new();
+        cell.put_slice(&[0x80 | (5000 >> 7) as u8, (5000 & 0x7f) as u8, rowid]);
+        cell.put_bytes(rowid, 908);
+        cell.put_u32(overflow);
+        cell.to_vec()
+    }
+
+    fn overflow_page(next: PageNumber) -> Bytes {
+        let mut data = vec![0xaau8; PAGE_SIZE];
+        data[..4].copy_from_slice(&next.to_be_bytes());
+        Bytes::from(data)
+    }
+
+    fn put(pager: &mut Pager, number: PageNumber, data: Bytes) {
+        pager.write(Page::new(number, data)).unwrap();
+    }
+
+    fn pointer(pager: &mut Pager, number: PageNumber, offset: usize) -> PageNumber {
+        read_page_number(pager.get(number).unwrap().data(), offset)
+    }
+
+    #[test]
+    fn pointers_of_btree_pages() {
+        // a table leaf: just the overflow pointer of the spilling cell
+        let page = btree_page(
+            BtreePageType::TableLeaf,
+            None,
+            &[vec![2, 1, 0, 0], overflow_cell(2, 9)],
+        );
+        let pointers = page_pointers(5, &page, &limits()).unwrap();
+        assert_eq!(pointers.len(), 1);
+        assert_eq!(pointers[0].1, PtrmapType::Overflow1);
+        assert_eq!(read_page_number(&page, pointers[0].0), 9);
+
+        // an index interior page: child pointers and the overflow of a spilling key, which
+        // keeps less of its payload on the page than a table leaf
+        let mut cell = 7u32.to_be_bytes().to_vec();
+        cell.extend_from_slice(&[0xa7, 0x08]);
+        cell.extend_from_slice(&[0u8; 908]);
+        cell.extend_from_slice(&8u32.to_be_bytes());
+        let page = btree_page(BtreePageType::IndexInterior, Some(6), &[cell]);
+        let pointers: Vec<(PageNumber, PtrmapType)> = page_pointers(5, &page, &limits())
+            .unwrap()
+            .into_iter()
+            .map(|(offset, pointer_type)| (read_page_number(&page, offset), pointer_type))
+            .collect();
+        assert_eq!(
+            pointers,
+            vec![
+                (6, PtrmapType::Btree),
+                (7, PtrmapType::Btree),
+                (8, PtrmapType::Overflow1)
+            ]
+        );
+
+        let mut page = BytesMut::from(page.as_ref());
+        page[0] = 1;
+        assert!(matches!(
+            page_pointers(5, &page.clone().freeze(), &limits()),
+            Err(SqliteError::Corrupt { .. })
+        ));
+        page[0] = BtreePageType::TableLeaf as u8;
+        page[8..10].copy_from_slice(&[0x20, 0]);
+        assert!(matches!(
+            page_pointers(5, &page.freeze(), &limits()),
+            Err(SqliteError::Corrupt { .. })
+        ));
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!(
+            AutoVacuum::try_from("INCREMENTAL").unwrap(),
+            AutoVacuum::Incremental
+        );
+        assert_eq!(AutoVacuum::try_from("1").unwrap(), AutoVacuum::Full);
+        assert!(AutoVacuum::try_from("partial").is_err());
+
+        let mut pager = memory_pager(DEFAULT_PAGE_SIZE, AutoVacuum::None);
+        assert_eq!(pager.auto_vacuum().unwrap(), AutoVacuum::None);
+        pager.set_auto_vacuum(AutoVacuum::Full).unwrap();
+        assert_eq!(pager.auto_vacuum().unwrap(), AutoVacuum::Full);
+        assert_eq!(pager.header().largest_root_btree_page(), 1);
+        pager.set_auto_vacuum(AutoVacuum::Incremental).unwrap();
+        assert_eq!(pager.auto_vacuum().unwrap(), AutoVacuum::Incremental);
+        assert!(pager.header().incremental_vacuum());
+
+        // appended pages skip the pointer map pages
+        let numbers: Vec<PageNumber> = (0..820)
+            .map(|_| pager.allocate().unwrap().number())
+            .collect();
+        assert_eq!(numbers[0], 3);
+        assert_eq!(numbers[818], 821);
+        assert_eq!(numbers[819], 823);
+        pager.commit().unwrap();
+        assert_eq!(pager.page_count(), 823);
+
+        // with pages in use the database keeps its pointer map
+        pager.set_auto_vacuum(AutoVacuum::None).unwrap();
+        assert_eq!(pager.auto_vacuum().unwrap(), AutoVacuum::Incremental);
+        pager.set_auto_vacuum(AutoVacuum::Full).unwrap();
+        assert_eq!(pager.auto_vacuum().unwrap(), AutoVacuum::Full);
+    }
+
+    #[test]
+    fn incremental_vacuum_moves_pages() {
+        let mut pager = memory_pager(DEFAULT_PAGE_SIZE, AutoVacuum::Incremental);
+        assert_eq!(pager.allocate_root_page().unwrap().number(), 3);
+        for number in 4..=13 {
+            assert_eq!(pager.allocate().unwrap().number(), number);
+        }
+        put(
+            &mut pager,
+            3,
+            btree_page(
+                BtreePageType::TableInterior,
+                Some(9),
+                &[interior_cell(8, 1), interior_cell(10, 2)],
+            ),
+        );
+        put(
+            &mut pager,
+            8,
+            btree_page(BtreePageType::TableLeaf, None, &[overflow_cell(1, 11)]),
+        );
+        put(
+            &mut pager,
+            9,
+            btree_page(BtreePageType::TableLeaf, None, &[]),
+        );
+        put(
+            &mut pager,
+            10,
+            btree_page(BtreePageType::TableLeaf, None, &[overflow_cell(2, 12)]),
+        );
+        put(&mut pager, 11, overflow_page(0));
+        put(&mut pager, 12, overflow_page(13));
+        put(&mut pager, 13, overflow_page(0));
+        for (number, ptrmap_type, parent) in [
+            (8, PtrmapType::Btree, 3),
+            (9, PtrmapType::Btree, 3),
+            (10, PtrmapType::Btree, 3),
+            (11, PtrmapType::Overflow1, 8),
+            (12, PtrmapType::Overflow1, 10),
+            (13, PtrmapType::Overflow2, 12),
+        ] {
+            pager.ptrmap_put(number, ptrmap_type, parent).unwrap();
+        }
+        for number in 4..=7 {
+            pager.free_page(number).unwrap();
+        }
+        pager.commit().unwrap();
+        assert_eq!(pager.ptrmap_get(5).unwrap(), (PtrmapType::FreePage, 0));
+
+        // the overflow chain past the new end moves into the first free pages
+        assert_eq!(pager.incremental_vacuum(3).unwrap(), 3);
+        assert_eq!(pager.page_count(), 10);
+        assert_eq!(pager.header().total_freelist_pages(), 1);
+        assert_eq!(pager.header().size_in_pages(), 10);
+        assert_eq!(pointer(&mut pager, 5, 0), 4);
+        assert_eq!(pointer(&mut pager, 4, 0), 0);
+        assert_eq!(pager.ptrmap_get(4).unwrap(), (PtrmapType::Overflow2, 5));
+        assert_eq!(pager.ptrmap_get(5).unwrap(), (PtrmapType::Overflow1, 10));
+        assert_eq!(pager.ptrmap_get(6).unwrap(), (PtrmapType::Overflow1, 8));
+        assert_eq!(pager.ptrmap_get(7).unwrap(), (PtrmapType::FreePage, 0));
+        assert_eq!(pointer(&mut pager, 8, PAGE_SIZE - 4), 6);
+        let leaf = pager.get(10).unwrap().into_data();
+
+        // then a leaf moves, taking the pointer map entry of its overflow page along
+        assert_eq!(pager.incremental_vacuum(0).unwrap(), 1);
+        assert_eq!(pager.page_count(), 9);
+        assert_eq!(pager.freelist_stats().unwrap().free_pages(), 0);
+        assert_eq!(pager.get(7).unwrap().into_data(), leaf);
+        assert_eq!(pager.ptrmap_get(7).unwrap(), (PtrmapType::Btree, 3));
+        assert_eq!(pager.ptrmap_get(5).unwrap(), (PtrmapType::Overflow1, 7));
+        let root = pager.get(3).unwrap().into_data();
+        let children: Vec<PageNumber> = page_pointers(3, &root, &limits())
+            .unwrap()
+            .into_iter()
+            .map(|(offset, _)| read_page_number(&root, offset))
+            .collect();
+        assert_eq!(children, vec![9, 8, 7]);
+        assert_eq!(pager.incremental_vacuum(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn full_auto_vacuum_truncates_on_commit() {
+        let mut pager = memory_pager(DEFAULT_PAGE_SIZE, AutoVacuum::Full);
+        pager.allocate_root_page().unwrap();
+        for _ in 4..=6 {
+            pager.allocate().unwrap();
+        }
+        put(
+            &mut pager,
+            3,
+            btree_page(BtreePageType::TableInterior, Some(6), &[]),
+        );
+        put(
+            &mut pager,
+            6,
+            btree_page(BtreePageType::TableLeaf, None, &[]),
+        );
+        pager.ptrmap_put(6, PtrmapType::Btree, 3).unwrap();
+        pager.commit().unwrap();
+        assert_eq!(pager.page_count(), 6);
+
+        pager.free_page(4).unwrap();
+        pager.free_page(5).unwrap();
+        pager.commit().unwrap();
+        assert_eq!(pager.page_count(), 4);
+        assert_eq!(pager.header().size_in_pages(), 4);
+        assert_eq!(pager.header().total_freelist_pages(), 0);
+        assert_eq!(pointer(&mut pager, 3, RIGHT_CHILD_OFFSET), 4);
+        assert_eq!(pager.ptrmap_get(4).unwrap(), (PtrmapType::Btree, 3));
+
+        // freeing every page but the root drops the pointer map page too
+        pager.free_page(4).unwrap();
+        pager.free_page(3).unwrap();
+        pager.commit().unwrap();
+        assert_eq!(pager.page_count(), 1);
+    }
+
+    #[test]
+    fn rollback_restores_vacuumed_pages() {
+        let mut pager = memory_pager(DEFAULT_PAGE_SIZE, AutoVacuum::Incremental);
+        pager.allocate_root_page().unwrap();
+        pager.allocate().unwrap();
+        pager.commit().unwrap();
+        pager.free_page(4).unwrap();
+        pager.commit().unwrap();
+        pager.begin_write().unwrap();
+        assert_eq!(pager.incremental_vacuum(0).unwrap(), 1);
+        assert_eq!(pager.page_count(), 3);
+        pager.rollback().unwrap();
+        assert_eq!(pager.page_count(), 4);
+        assert_eq!(pager.header().total_freelist_pages(), 1);
+    }
+
+    #[test]
+    fn root_pages_stay_together() {
+        let mut pager = memory_pager(DEFAULT_PAGE_SIZE, AutoVacuum::Incremental);
+        pager.allocate_root_page().unwrap();
+        assert_eq!(pager.allocate().unwrap().number(), 4);
+        put(
+            &mut pager,
+            3,
+            btree_page(BtreePageType::TableInterior, Some(4), &[]),
+        );
+        put(
+            &mut pager,
+            4,
+            btree_page(BtreePageType::TableLeaf, None, &[]),
+        );
+        pager.ptrmap_put(4, PtrmapType::Btree, 3).unwrap();
+        pager.commit().unwrap();
+
+        // the leaf in the way moves to a new page
+        let root = pager.allocate_root_page().unwrap();
+        assert_eq!(root.number(), 4);
+        assert!(pager.get(4).unwrap().data().iter().all(|b| *b == 0));
+        assert_eq!(pointer(&mut pager, 3, RIGHT_CHILD_OFFSET), 5);
+        assert_eq!(pager.ptrmap_get(5).unwrap(), (PtrmapType::Btree, 3));
+        assert_eq!(pager.ptrmap_get(4).unwrap(), (PtrmapType::RootPage, 0));
+        assert_eq!(pager.header().largest_root_btree_page(), 4);
+
+        // a free page in the way comes off the freelist
+        pager.allocate().unwrap();
+        pager.allocate().unwrap();
+        pager.commit().unwrap();
+        pager.free_page(5).unwrap();
+        pager.free_page(7).unwrap();
+        pager.commit().unwrap();
+        assert_eq!(pager.allocate_root_page().unwrap().number(), 5);
+        assert_eq!(pager.header().largest_root_btree_page(), 5);
+        assert_eq!(pager.ptrmap_get(5).unwrap(), (PtrmapType::RootPage, 0));
+        assert_eq!(pager.freelist_stats().unwrap().free_pages(), 1);
+        assert_eq!(pager.ptrmap_get(7).unwrap(), (PtrmapType::FreePage, 0));
+    }
+
+    #[test]
+    fn corrupt_pointer_map_err() {
+        let mut pager = memory_pager(DEFAULT_PAGE_SIZE, AutoVacuum::Incremental);
+        pager.allocate_root_page().unwrap();
+        pager.allocate().unwrap();
+        pager.allocate().unwrap();
+        put(
+            &mut pager,
+            3,
+            btree_page(BtreePageType::TableLeaf, None, &[]),
+        );
+        put(
+            &mut pager,
+            5,
+            btree_page(BtreePageType::TableLeaf, None, &[]),
+        );
+        // page 5 claims page 3 points to it, which it does not
+        pager.ptrmap_put(5, PtrmapType::Btree, 3).unwrap();
+        pager.commit().unwrap();
+        pager.free_page(4).unwrap();
+        pager.commit().unwrap();
+        assert!(matches!(
+            pager.incremental_vacuum(0),
+            Err(SqliteError::Corrupt { .. })
+        ));
+        assert_eq!(pager.transaction_state(), TransactionState::None);
+        assert_eq!(pager.page_count(), 5);
+    }
+}
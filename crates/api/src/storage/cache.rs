@@ -0,0 +1,259 @@
+use crate::storage::PageNumber;
+use bytes::Bytes;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// The cache size sqlite3 uses when the header does not suggest one: 2000 KiB
+pub const DEFAULT_CACHE_SIZE: i64 = -2000;
+
+/// sqlite3 never shrinks a page cache below this many pages
+const MIN_CACHE_PAGES: usize = 10;
+
+/// The number of pages a cache size allows, following `PRAGMA cache_size`: a positive size
+/// counts pages, a negative size counts KiB and zero means the default
+pub fn cache_capacity(cache_size: i64, page_size: u32) -> usize {
+    let cache_size = if cache_size == 0 {
+        DEFAULT_CACHE_SIZE
+    } else {
+        cache_size
+    };
+    let pages = if cache_size > 0 {
+        cache_size as usize
+    } else {
+        (cache_size.unsigned_abs() as usize * 1024) / page_size as usize
+    };
+    pages.max(MIN_CACHE_PAGES)
+}
+
+#[derive(Debug)]
+struct CachedPage {
+    data: Bytes,
+    last_used: u64,
+}
+
+/// A bounded cache of page contents with least-recently-used eviction.  Dirty pages are pinned:
+/// they are never evicted, so the cache may grow past its capacity until they are cleaned.
+#[derive(Debug)]
+pub struct PageCache {
+    capacity: usize,
+    pages: HashMap<PageNumber, CachedPage>,
+    /// Clean pages by the tick they were last used, oldest first
+    lru: BTreeMap<u64, PageNumber>,
+    dirty: BTreeSet<PageNumber>,
+    tick: u64,
+}
+
+impl PageCache {
+    pub fn new(capacity: usize) -> PageCache {
+        PageCache {
+            capacity: capacity.max(1),
+            pages: HashMap::new(),
+            lru: BTreeMap::new(),
+            dirty: BTreeSet::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Change the capacity, evicting clean pages if the cache is now over it
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        self.evict();
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    pub fn contains(&self, number: PageNumber) -> bool {
+        self.pages.contains_key(&number)
+    }
+
+    pub fn is_dirty(&self, number: PageNumber) -> bool {
+        self.dirty.contains(&number)
+    }
+
+    /// Dirty page numbers in ascending order
+    pub fn dirty_pages(&self) -> impl Iterator<Item = PageNumber> + '_ {
+        self.dirty.iter().copied()
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// Look up a page, marking it as the most recently used
+    pub fn get(&mut self, number: PageNumber) -> Option<Bytes> {
+        let tick = self.next_tick();
+        let page = self.pages.get_mut(&number)?;
+        if !self.dirty.contains(&number) {
+            self.lru.remove(&page.last_used);
+            self.lru.insert(tick, number);
+        }
+        page.last_used = tick;
+        Some(page.data.clone())
+    }
+
+    /// Look up a page without changing its position in the eviction order
+    pub fn peek(&self, number: PageNumber) -> Option<&Bytes> {
+        self.pages.get(&number).map(|page| &page.data)
+    }
+
+    fn put(&mut self, number: PageNumber, data: Bytes, dirty: bool) {
+        let tick = self.next_tick();
+        if let Some(old) = self.pages.insert(
+            number,
+            CachedPage {
+                data,
+                last_used: tick,
+            },
+        ) {
+            self.lru.remove(&old.last_used);
+        }
+        if dirty {
+            self.dirty.insert(number);
+        } else if !self.dirty.contains(&number) {
+            self.lru.insert(tick, number);
+        }
+        self.evict();
+    }
+
+    /// Cache a page as read from the file
+    pub fn insert(&mut self, number: PageNumber, data: Bytes) {
+        self.put(number, data, false)
+    }
+
+    /// Cache a changed page, pinning it until [`PageCache::clear_dirty`]
+    pub fn insert_dirty(&mut self, number: PageNumber, data: Bytes) {
+        self.put(number, data, true)
+    }
+
+    /// Mark every dirty page clean, making them candidates for eviction again
+    pub fn clear_dirty(&mut self) {
+        for number in std::mem::take(&mut self.dirty) {
+            let tick = self.next_tick();
+            if let Some(page) = self.pages.get_mut(&number) {
+                page.last_used = tick;
+                self.lru.insert(tick, number);
+            }
+        }
+        self.evict();
+    }
+
+    /// Drop a page, dirty or not
+    pub fn remove(&mut self, number: PageNumber) {
+        if let Some(page) = self.pages.remove(&number) {
+            self.lru.remove(&page.last_used);
+        }
+        self.dirty.remove(&number);
+    }
+
+    /// Drop every page
+    pub fn clear(&mut self) {
+        self.pages.clear();
+        self.lru.clear();
+        self.dirty.clear();
+    }
+
+    fn evict(&mut self) {
+        while self.pages.len() > self.capacity {
+            let Some((_, number)) = self.lru.pop_first() else {
+                // everything left is pinned
+                return;
+            };
+            self.pages.remove(&number);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(fill: u8) -> Bytes {
+        Bytes::from(vec![fill; 16])
+    }
+
+    #[test]
+    fn cache_capacity_ok() {
+        let cases = vec![
+            (0, 4096, 500),
+            (-2000, 4096, 500),
+            (-2000, 1024, 2000),
+            (100, 4096, 100),
+            (1, 4096, 10),
+            (-1, 65536, 10),
+        ];
+        for case in cases {
+            assert_eq!(cache_capacity(case.0, case.1), case.2, "{:?}", case);
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = PageCache::new(3);
+        for number in 1..=3 {
+            cache.insert(number, page(number as u8));
+        }
+        // touch 1 so 2 becomes the oldest
+        assert_eq!(cache.get(1), Some(page(1)));
+        cache.insert(4, page(4));
+        assert_eq!(cache.len(), 3);
+        assert!(!cache.contains(2));
+        assert!(cache.contains(1));
+        assert!(cache.contains(3));
+        assert!(cache.contains(4));
+    }
+
+    #[test]
+    fn dirty_pages_are_pinned() {
+        let mut cache = PageCache::new(2);
+        cache.insert_dirty(1, page(1));
+        cache.insert_dirty(2, page(2));
+        cache.insert(3, page(3));
+        // the clean page is evicted straight away
+        assert!(!cache.contains(3));
+        cache.insert_dirty(3, page(3));
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.dirty_pages().collect::<Vec<_>>(), vec![1, 2, 3]);
+
+        cache.clear_dirty();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.dirty_pages().count(), 0);
+        // pages were cleaned in order, so the lowest went first
+        assert!(!cache.contains(1));
+    }
+
+    #[test]
+    fn rewriting_clean_page_as_dirty_pins_it() {
+        let mut cache = PageCache::new(1);
+        cache.insert(1, page(1));
+        cache.insert_dirty(1, page(9));
+        cache.insert(2, page(2));
+        assert_eq!(cache.peek(1), Some(&page(9)));
+        assert!(cache.is_dirty(1));
+        assert!(!cache.contains(2));
+    }
+
+    #[test]
+    fn shrinking_capacity_evicts() {
+        let mut cache = PageCache::new(5);
+        for number in 1..=5 {
+            cache.insert(number, page(number as u8));
+        }
+        cache.set_capacity(2);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains(4));
+        assert!(cache.contains(5));
+        cache.remove(5);
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,401 @@
+use crate::errors::{ExtendedResultCode, SqliteResult};
+use crate::storage::{Page, PageNumber, Pager, PtrmapType, TransactionState};
+use crate::SqliteError;
+use bytes::{Buf, Bytes, BytesMut};
+
+/// Leaves a trunk page has room for: the page less the next trunk pointer and the leaf count
+pub fn max_trunk_leaves(usable_size: usize) -> usize {
+    usable_size / 4 - 2
+}
+
+/// Leaves sqlite3 lists on a trunk page before it starts a new one.  It stops six short of the
+/// room there is, because versions before 3.6.0 reported the last entries as corruption.
+fn trunk_fill_limit(usable_size: usize) -> usize {
+    usable_size / 4 - 8
+}
+
+/// A trunk page of the freelist.  The free pages of a database form a chain of trunk pages
+/// starting from the header, each listing free leaf pages after a pointer to the next trunk
+/// and the number of leaves, all as big-endian 32-bit integers.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FreelistTrunk {
+    /// The next trunk page, or 0 for the last one
+    pub next: PageNumber,
+    /// Free pages whose contents no longer matter
+    pub leaves: Vec<PageNumber>,
+}
+
+impl FreelistTrunk {
+    /// Parse a trunk page, checking that the leaf count fits in the usable part of the page
+    pub fn from_page(page: &Page, usable_size: usize) -> SqliteResult<FreelistTrunk> {
+        let mut data = page.data().slice(..);
+        let next = data.get_u32();
+        let count = data.get_u32() as usize;
+        if count > max_trunk_leaves(usable_size) {
+            return Err(SqliteError::new(
+                ExtendedResultCode::Corrupt,
+                format!(
+                    "freelist trunk page {} lists {} leaves",
+                    page.number(),
+                    count
+                ),
+            ));
+        }
+        let leaves = (0..count).map(|_| data.get_u32()).collect();
+        Ok(FreelistTrunk { next, leaves })
+    }
+
+    /// Write the trunk over the start of `data`, leaving the bytes past the last leaf as they
+    /// are
+    pub fn write(&self, data: &mut [u8]) {
+        data[0..4].copy_from_slice(&self.next.to_be_bytes());
+        data[4..8].copy_from_slice(&(self.leaves.len() as u32).to_be_bytes());
+        for (i, leaf) in self.leaves.iter().enumerate() {
+            data[8 + i * 4..12 + i * 4].copy_from_slice(&leaf.to_be_bytes());
+        }
+    }
+}
+
+/// How the pages of a database are used, for capacity planning
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FreelistStats {
+    /// Pages in the database, free or not
+    pub database_pages: u32,
+    /// Free pages that hold part of the freelist
+    pub trunk_pages: u32,
+    /// Free pages listed on the trunk pages
+    pub leaf_pages: u32,
+}
+
+impl FreelistStats {
+    /// Pages that can be reused without growing the file, as `PRAGMA freelist_count` counts
+    pub fn free_pages(&self) -> u32 {
+        self.trunk_pages + self.leaf_pages
+    }
+}
+
+impl Pager {
+    fn read_trunk(&mut self, number: PageNumber) -> SqliteResult<(Page, FreelistTrunk)> {
+        if number > self.page_count() {
+            return Err(SqliteError::new(
+                ExtendedResultCode::Corrupt,
+                format!(
+                    "freelist trunk page {} is past the end of the database",
+                    number
+                ),
+            ));
+        }
+        let page = self.get(number)?;
+        let trunk = FreelistTrunk::from_page(&page, self.usable_size())?;
+        Ok((page, trunk))
+    }
+
+    fn write_trunk(&mut self, page: Page, trunk: &FreelistTrunk) -> SqliteResult<()> {
+        let number = page.number();
+        let mut data = BytesMut::from(page.into_data().as_ref());
+        trunk.write(&mut data);
+        self.write(Page::new(number, data.freeze()))
+    }
+
+    /// Take a page off the freelist and zero it, or `None` when no page is free.  Like
+    /// sqlite3, this hands out the first leaf of the first trunk, moving the last leaf into
+    /// its place, and the trunk itself once it lists no leaves.
+    pub(crate) fn allocate_free_page(&mut self) -> SqliteResult<Option<Page>> {
+        let free_pages = self.header().total_freelist_pages();
+        let first_trunk = self.header().first_freelist_trunk_page();
+        if free_pages == 0 || first_trunk == 0 {
+            return Ok(None);
+        }
+        let (page, mut trunk) = self.read_trunk(first_trunk)?;
+        let (number, first_trunk) = if trunk.leaves.is_empty() {
+            (first_trunk, trunk.next)
+        } else {
+            let leaf = trunk.leaves.swap_remove(0);
+            if leaf < 2 || leaf > self.page_count() {
+                return Err(SqliteError::new(
+                    ExtendedResultCode::Corrupt,
+                    format!(
+                        "freelist leaf page {} is out of range for a database of {} pages",
+                        leaf,
+                        self.page_count()
+                    ),
+                ));
+            }
+            self.write_trunk(page, &trunk)?;
+            (leaf, first_trunk)
+        };
+        self.update_header(|header| header.set_freelist(first_trunk, free_pages - 1))?;
+        let page_size: u32 = self.page_size().into();
+        let page = Page::new(number, Bytes::from(vec![0u8; page_size as usize]));
+        self.write(page.clone())?;
+        Ok(Some(page))
+    }
+
+    /// Put a page on the freelist, as a leaf of the first trunk when it has room and as the
+    /// new first trunk otherwise.  The page keeps its size and contents until it is reused.
+    /// In an auto-vacuum database the pointer map records the page as free.
+    pub fn free_page(&mut self, number: PageNumber) -> SqliteResult<()> {
+        self.begin_write()?;
+        if number < 2 || number > self.page_count() {
+            return Err(SqliteError::new(
+                ExtendedResultCode::Corrupt,
+                format!(
+                    "cannot free page {} of a database of {} pages",
+                    number,
+                    self.page_count()
+                ),
+            ));
+        }
+        if self.has_ptrmap() {
+            self.ptrmap_put(number, PtrmapType::FreePage, 0)?;
+        }
+        let free_pages = self.header().total_freelist_pages() + 1;
+        let first_trunk = self.header().first_freelist_trunk_page();
+        if first_trunk != 0 {
+            let (page, mut trunk) = self.read_trunk(first_trunk)?;
+            if trunk.leaves.len() < trunk_fill_limit(self.usable_size()) {
+                trunk.leaves.push(number);
+                self.write_trunk(page, &trunk)?;
+                return self.update_header(|header| header.set_freelist(first_trunk, free_pages));
+            }
+        }
+        let page = self.get(number)?;
+        let trunk = FreelistTrunk {
+            next: first_trunk,
+            leaves: Vec::new(),
+        };
+        self.write_trunk(page, &trunk)?;
+        self.update_header(|header| header.set_freelist(number, free_pages))
+    }
+
+    /// Empty the freelist, returning every page it held, trunks included, in no particular
+    /// order
+    pub(crate) fn drain_freelist(&mut self) -> SqliteResult<Vec<PageNumber>> {
+        self.begin_write()?;
+        let free_pages = self.header().total_freelist_pages();
+        let mut pages = Vec::with_capacity(free_pages as usize);
+        let mut next = self.header().first_freelist_trunk_page();
+        while next != 0 && pages.len() < free_pages as usize {
+            let (_, trunk) = self.read_trunk(next)?;
+            pages.push(next);
+            for leaf in &trunk.leaves {
+                if *leaf < 2 || *leaf > self.page_count() {
+                    return Err(SqliteError::new(
+                        ExtendedResultCode::Corrupt,
+                        format!(
+                            "freelist leaf page {} is out of range for a database of {} pages",
+                            leaf,
+                            self.page_count()
+                        ),
+                    ));
+                }
+            }
+            pages.extend(trunk.leaves);
+            next = trunk.next;
+        }
+        if next != 0 || pages.len() != free_pages as usize {
+            return Err(SqliteError::new(
+                ExtendedResultCode::Corrupt,
+                format!(
+                    "the freelist holds {} pages but the header counts {}",
+                    pages.len(),
+                    free_pages
+                ),
+            ));
+        }
+        self.update_header(|header| header.set_freelist(0, 0))?;
+        Ok(pages)
+    }
+
+    /// Walk the freelist, failing with `SQLITE_CORRUPT` when it does not add up to the count
+    /// in the header
+    pub fn freelist_stats(&mut self) -> SqliteResult<FreelistStats> {
+        if self.transaction_state() == TransactionState::None {
+            // the header is only current within a transaction
+            self.begin_read()?;
+            let stats = self.freelist_stats();
+            self.end_read()?;
+            return stats;
+        }
+        let free_pages = self.header().total_freelist_pages();
+        let mut stats = FreelistStats {
+            database_pages: self.page_count(),
+            ..Default::default()
+        };
+        let mut next = self.header().first_freelist_trunk_page();
+        while next != 0 {
+            if stats.free_pages() >= free_pages {
+                break;
+            }
+            let (_, trunk) = self.read_trunk(next)?;
+            stats.trunk_pages += 1;
+            stats.leaf_pages += trunk.leaves.len() as u32;
+            next = trunk.next;
+        }
+        if next != 0 || stats.free_pages() != free_pages {
+            return Err(SqliteError::new(
+                ExtendedResultCode::Corrupt,
+                format!(
+                    "the freelist holds {} pages but the header counts {}",
+                    stats.free_pages(),
+                    free_pages
+                ),
+            ));
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::ConnectionOptions;
+    use crate::vfs::{FileKind, MemoryVfs, OpenAccess, OpenFlags};
+    use std::sync::Arc;
+
+    fn pager_with_pages(count: u32) -> Pager {
+        let mut pager = Pager::open(
+            Arc::new(MemoryVfs::new()),
+            "",
+            OpenFlags::new(OpenAccess::ReadWriteCreate, FileKind::MainDb),
+            &ConnectionOptions::default(),
+        )
+        .unwrap();
+        for _ in 1..count {
+            let page = pager.allocate().unwrap();
+            let fill = page.number() as u8;
+            pager
+                .write(Page::new(page.number(), Bytes::from(vec![fill; 4096])))
+                .unwrap();
+        }
+        pager.commit().unwrap();
+        pager
+    }
+
+    #[test]
+    fn trunk_layout() {
+        let trunk = FreelistTrunk {
+            next: 7,
+            leaves: vec![3, 0x01020304],
+        };
+        let mut data = vec![0xffu8; 512];
+        trunk.write(&mut data);
+        assert_eq!(
+            &data[..16],
+            &[0, 0, 0, 7, 0, 0, 0, 2, 0, 0, 0, 3, 1, 2, 3, 4]
+        );
+        assert_eq!(data[16], 0xff);
+        let page = Page::new(5, Bytes::from(data));
+        assert_eq!(FreelistTrunk::from_page(&page, 512).unwrap(), trunk);
+
+        let mut data = vec![0u8; 512];
+        data[4..8].copy_from_slice(&127u32.to_be_bytes());
+        let page = Page::new(5, Bytes::from(data));
+        assert!(matches!(
+            FreelistTrunk::from_page(&page, 512),
+            Err(SqliteError::Corrupt { .. })
+        ));
+        assert_eq!(max_trunk_leaves(512), 126);
+    }
+
+    #[test]
+    fn freed_pages_are_reused_before_growing() {
+        let mut pager = pager_with_pages(5);
+        pager.free_page(3).unwrap();
+        pager.free_page(4).unwrap();
+        pager.free_page(5).unwrap();
+        pager.commit().unwrap();
+        // the first freed page became the trunk listing the others
+        assert_eq!(pager.header().first_freelist_trunk_page(), 3);
+        assert_eq!(pager.header().total_freelist_pages(), 3);
+        let trunk = FreelistTrunk::from_page(&pager.get(3).unwrap(), 4096).unwrap();
+        assert_eq!(trunk.leaves, vec![4, 5]);
+        // freed pages keep their contents
+        assert!(pager.get(4).unwrap().data().iter().all(|b| *b == 4));
+        assert_eq!(
+            pager.freelist_stats().unwrap(),
+            FreelistStats {
+                database_pages: 5,
+                trunk_pages: 1,
+                leaf_pages: 2
+            }
+        );
+
+        let numbers: Vec<PageNumber> = (0..4)
+            .map(|_| {
+                let page = pager.allocate().unwrap();
+                assert!(page.data().iter().all(|b| *b == 0));
+                page.number()
+            })
+            .collect();
+        assert_eq!(numbers, vec![4, 5, 3, 6]);
+        assert!(pager.get(3).unwrap().data().iter().all(|b| *b == 0));
+        pager.commit().unwrap();
+        assert_eq!(pager.page_count(), 6);
+        assert_eq!(pager.header().first_freelist_trunk_page(), 0);
+        assert_eq!(pager.freelist_stats().unwrap().free_pages(), 0);
+    }
+
+    #[test]
+    fn full_trunk_starts_a_new_one() {
+        let mut pager = pager_with_pages(1);
+        let limit = trunk_fill_limit(pager.usable_size()) as u32;
+        for _ in 0..limit + 2 {
+            pager.allocate().unwrap();
+        }
+        for number in 2..=limit + 3 {
+            pager.free_page(number).unwrap();
+        }
+        pager.commit().unwrap();
+        let stats = pager.freelist_stats().unwrap();
+        assert_eq!((stats.trunk_pages, stats.leaf_pages), (2, limit));
+        assert_eq!(pager.header().first_freelist_trunk_page(), limit + 3);
+        let trunk = FreelistTrunk::from_page(&pager.get(limit + 3).unwrap(), 4096).unwrap();
+        assert_eq!(
+            trunk,
+            FreelistTrunk {
+                next: 2,
+                leaves: vec![]
+            }
+        );
+
+        // the empty trunk goes first, then the leaves of the next one
+        assert_eq!(pager.allocate().unwrap().number(), limit + 3);
+        assert_eq!(pager.header().first_freelist_trunk_page(), 2);
+        assert_eq!(pager.allocate().unwrap().number(), 3);
+    }
+
+    #[test]
+    fn rollback_restores_freelist() {
+        let mut pager = pager_with_pages(3);
+        pager.free_page(2).unwrap();
+        pager.rollback().unwrap();
+        assert_eq!(pager.header().total_freelist_pages(), 0);
+        assert!(pager.get(2).unwrap().data().iter().all(|b| *b == 2));
+    }
+
+    #[test]
+    fn corrupt_freelist_err() {
+        let mut pager = pager_with_pages(3);
+        for number in [0, 1, 4] {
+            assert!(matches!(
+                pager.free_page(number),
+                Err(SqliteError::Corrupt { .. })
+            ));
+        }
+        // a header counting more pages than the freelist holds
+        pager.free_page(2).unwrap();
+        pager
+            .update_header(|header| header.set_freelist(2, 2))
+            .unwrap();
+        assert!(matches!(
+            pager.freelist_stats(),
+            Err(SqliteError::Corrupt { .. })
+        ));
+        // a trunk pointing past the end of the database
+        pager
+            .update_header(|header| header.set_freelist(9, 1))
+            .unwrap();
+        assert!(matches!(pager.allocate(), Err(SqliteError::Corrupt { .. })));
+    }
+}
@@ -0,0 +1,618 @@
+use crate::connection::ConnectionOptions;
+use crate::database::{SqliteHeader, HEADER_SIZE};
+use crate::errors::{ExtendedResultCode, SqliteResult};
+use crate::storage::PageNumber;
+use crate::vfs::{
+    AccessFlags, FileKind, LockLevel, OpenAccess, OpenFlags, SyncFlags, Vfs, VfsFile,
+};
+use crate::SqliteError;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::HashSet;
+use std::hash::{BuildHasher, Hasher, RandomState};
+
+/// The first 8 bytes of a journal header once its records are safely on disk
+pub const JOURNAL_MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+
+/// The journal header fields sqlite3 reads: magic, record count, nonce, initial database
+/// size, sector size and page size
+const JOURNAL_HEADER_FIELDS_SIZE: usize = 28;
+
+/// A record count meaning the count was never filled in and the records run to the end of
+/// the file
+const RECORD_COUNT_UNKNOWN: u32 = 0xffffffff;
+
+/// Suffix appended to the database path to name its rollback journal
+pub const JOURNAL_SUFFIX: &str = "-journal";
+
+/// What happens to the rollback journal at the end of a transaction, like
+/// `PRAGMA journal_mode`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum JournalMode {
+    /// Delete the journal file
+    #[default]
+    Delete,
+    /// Truncate the journal file to zero bytes
+    Truncate,
+    /// Leave the journal file and overwrite its header with zeros
+    Persist,
+    /// Keep the journal in memory.  Rollback works, but a crash during commit can corrupt the
+    /// database.
+    Memory,
+    /// Keep no journal at all; rollback only discards pages that were never written
+    Off,
+    /// Append changes to a write-ahead log instead of journaling original pages
+    Wal,
+}
+
+impl TryFrom<&str> for JournalMode {
+    type Error = SqliteError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "delete" => Ok(JournalMode::Delete),
+            "truncate" => Ok(JournalMode::Truncate),
+            "persist" => Ok(JournalMode::Persist),
+            "memory" => Ok(JournalMode::Memory),
+            "off" => Ok(JournalMode::Off),
+            "wal" => Ok(JournalMode::Wal),
+            _ => Err(SqliteError::Misuse {
+                code: ExtendedResultCode::Misuse,
+                message: format!("invalid journal_mode: {}", value),
+            }),
+        }
+    }
+}
+
+impl JournalMode {
+    /// Whether the mode writes a rollback journal file
+    pub fn uses_file(self) -> bool {
+        matches!(
+            self,
+            JournalMode::Delete | JournalMode::Truncate | JournalMode::Persist
+        )
+    }
+}
+
+/// The checksum of a journal record: the nonce plus every 200th byte of the page, counting
+/// down from near its end
+pub fn journal_checksum(nonce: u32, data: &[u8]) -> u32 {
+    let mut checksum = nonce;
+    let mut i = data.len() as isize - 200;
+    while i > 0 {
+        checksum = checksum.wrapping_add(data[i as usize] as u32);
+        i -= 200;
+    }
+    checksum
+}
+
+/// A random nonce for a new journal header, also used for WAL salts
+pub(crate) fn random_nonce() -> u32 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default(),
+    );
+    hasher.finish() as u32
+}
+
+/// The header that starts each segment of a journal.  Headers are padded to the sector size,
+/// and page records follow.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JournalHeader {
+    pub record_count: u32,
+    pub nonce: u32,
+    /// Size of the database in pages when the transaction started
+    pub initial_size: u32,
+    pub sector_size: u32,
+    pub page_size: u32,
+}
+
+impl JournalHeader {
+    /// Write the header fields.  Without `magic` the magic and record count are zero, marking a
+    /// journal whose records may not be on disk yet and which must not be played back.
+    pub fn write(&self, buf: &mut BytesMut, magic: bool) {
+        if magic {
+            buf.put_slice(&JOURNAL_MAGIC);
+            buf.put_u32(self.record_count);
+        } else {
+            buf.put_bytes(0, JOURNAL_MAGIC.len() + 4);
+        }
+        buf.put_u32(self.nonce);
+        buf.put_u32(self.initial_size);
+        buf.put_u32(self.sector_size);
+        buf.put_u32(self.page_size);
+    }
+
+    /// Parse a header, or `None` when the magic is missing
+    pub fn from_buffer(buf: &Bytes) -> Option<JournalHeader> {
+        if buf.len() < JOURNAL_HEADER_FIELDS_SIZE || buf[..8] != JOURNAL_MAGIC {
+            return None;
+        }
+        let mut buf = buf.slice(8..);
+        Some(JournalHeader {
+            record_count: buf.get_u32(),
+            nonce: buf.get_u32(),
+            initial_size: buf.get_u32(),
+            sector_size: buf.get_u32(),
+            page_size: buf.get_u32(),
+        })
+    }
+}
+
+/// Sector sizes outside what sqlite3 accepts are clamped the way it clamps them
+fn clamp_sector_size(sector_size: u32) -> u32 {
+    match sector_size {
+        0..32 => 512,
+        32..=0x10000 => sector_size,
+        _ => 0x10000,
+    }
+}
+
+/// A rollback journal being written for the current transaction.  Before a page that existed
+/// when the transaction started is first changed, its original image is appended so that a
+/// crash part way through the commit can be undone.
+#[derive(Debug)]
+pub struct Journal {
+    file: Box<dyn VfsFile>,
+    header: JournalHeader,
+    /// Where the next record goes
+    offset: u64,
+    journaled: HashSet<PageNumber>,
+}
+
+impl Journal {
+    /// Create or overwrite the journal and write its header with the magic zeroed
+    pub fn create(
+        vfs: &dyn Vfs,
+        path: &str,
+        options: &ConnectionOptions,
+        initial_size: u32,
+        page_size: u32,
+    ) -> SqliteResult<Journal> {
+        let mut file = vfs.open(
+            path,
+            OpenFlags::new(OpenAccess::ReadWriteCreate, FileKind::MainJournal),
+            options,
+        )?;
+        let sector_size = clamp_sector_size(file.sector_size());
+        let header = JournalHeader {
+            record_count: 0,
+            nonce: random_nonce(),
+            initial_size,
+            sector_size,
+            page_size,
+        };
+        let mut buf = BytesMut::with_capacity(sector_size as usize);
+        header.write(&mut buf, false);
+        buf.resize(sector_size as usize, 0);
+        file.write(&buf, 0)?;
+        Ok(Journal {
+            file,
+            offset: sector_size as u64,
+            header,
+            journaled: HashSet::new(),
+        })
+    }
+
+    pub fn header(&self) -> &JournalHeader {
+        &self.header
+    }
+
+    /// Whether a page needs its original image journaled before it is changed
+    pub fn needs(&self, number: PageNumber) -> bool {
+        number <= self.header.initial_size && !self.journaled.contains(&number)
+    }
+
+    /// Append the original image of a page
+    pub fn append(&mut self, number: PageNumber, data: &[u8]) -> SqliteResult<()> {
+        if !self.needs(number) {
+            return Ok(());
+        }
+        let mut buf = BytesMut::with_capacity(data.len() + 8);
+        buf.put_u32(number);
+        buf.put_slice(data);
+        buf.put_u32(journal_checksum(self.header.nonce, data));
+        self.file.write(&buf, self.offset)?;
+        self.offset += buf.len() as u64;
+        self.header.record_count += 1;
+        self.journaled.insert(number);
+        Ok(())
+    }
+
+    /// Make the journal safe to play back: sync the records, then write the magic and record
+    /// count and sync again, so a journal with a valid header always has its records on disk
+    pub fn sync(&mut self) -> SqliteResult<()> {
+        self.file.sync(SyncFlags::Full)?;
+        let mut buf = BytesMut::with_capacity(12);
+        buf.put_slice(&JOURNAL_MAGIC);
+        buf.put_u32(self.header.record_count);
+        self.file.write(&buf, 0)?;
+        self.file.sync(SyncFlags::Full)
+    }
+
+    /// End the transaction according to the journal mode, so the journal can no longer be
+    /// played back
+    pub fn finish(self, mode: JournalMode, vfs: &dyn Vfs, path: &str) -> SqliteResult<()> {
+        finish_journal(self.file, mode, vfs, path)
+    }
+
+    /// The open journal file, for playing it back
+    pub fn file_mut(&mut self) -> &mut dyn VfsFile {
+        self.file.as_mut()
+    }
+}
+
+fn finish_journal(
+    mut file: Box<dyn VfsFile>,
+    mode: JournalMode,
+    vfs: &dyn Vfs,
+    path: &str,
+) -> SqliteResult<()> {
+    match mode {
+        JournalMode::Truncate => {
+            file.truncate(0)?;
+            file.sync(SyncFlags::Full)
+        }
+        JournalMode::Persist => {
+            file.write(&[0u8; JOURNAL_HEADER_FIELDS_SIZE], 0)?;
+            file.sync(SyncFlags::DataOnly)
+        }
+        JournalMode::Delete => {
+            drop(file);
+            vfs.delete(path, false)
+        }
+        JournalMode::Memory | JournalMode::Off | JournalMode::Wal => Ok(()),
+    }
+}
+
+/// Whether the journal at `path` is hot: it exists, it has a header, the database is not empty
+/// and no connection holds the reserved lock that would mean a writer is still using it.  The
+/// caller holds a shared lock on `db`.
+pub fn is_hot_journal(vfs: &dyn Vfs, path: &str, db: &dyn VfsFile) -> SqliteResult<bool> {
+    if !vfs.access(path, AccessFlags::Exists)? || db.check_reserved_lock()? || db.file_size()? == 0
+    {
+        return Ok(false);
+    }
+    let mut journal = match vfs.open(
+        path,
+        OpenFlags::new(OpenAccess::ReadOnly, FileKind::MainJournal),
+        &ConnectionOptions::default(),
+    ) {
+        Ok(journal) => journal,
+        // deleted since it was checked
+        Err(SqliteError::CannotOpen { .. }) => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    let mut first = [0u8];
+    Ok(journal.read(&mut first, 0)? == 1 && first[0] != 0)
+}
+
+/// Roll back a transaction that a crashed writer left behind, as sqlite3 does before reading
+/// a database.  With a shared lock held on `db`, checks for a hot journal at `path` and, if
+/// there is one, takes an exclusive lock, plays the journal back, bumps the file change counter
+/// so other connections drop their cached pages and finishes the journal according to `mode`.
+/// Returns whether a journal was rolled back, holding a shared lock again either way.
+pub fn recover_hot_journal(
+    vfs: &dyn Vfs,
+    path: &str,
+    options: &ConnectionOptions,
+    db: &mut dyn VfsFile,
+    mode: JournalMode,
+) -> SqliteResult<bool> {
+    if !is_hot_journal(vfs, path, db)? {
+        return Ok(false);
+    }
+    if db.is_read_only() {
+        return Err(SqliteError::ReadOnly {
+            code: ExtendedResultCode::ReadOnlyRollback,
+            message: String::from("attempt to write a readonly database"),
+        });
+    }
+    if let Err(err) = db.lock(LockLevel::Exclusive) {
+        db.unlock(LockLevel::Shared)?;
+        return Err(err);
+    }
+    let result = rollback_hot_journal(vfs, path, options, db, mode);
+    db.unlock(LockLevel::Shared)?;
+    result
+}
+
+fn rollback_hot_journal(
+    vfs: &dyn Vfs,
+    path: &str,
+    options: &ConnectionOptions,
+    db: &mut dyn VfsFile,
+    mode: JournalMode,
+) -> SqliteResult<bool> {
+    // another connection may have rolled it back while this one waited for the lock
+    if !vfs.access(path, AccessFlags::Exists)? {
+        return Ok(false);
+    }
+    let mut journal = vfs.open(
+        path,
+        OpenFlags::new(OpenAccess::ReadWrite, FileKind::MainJournal),
+        options,
+    )?;
+    if playback(journal.as_mut(), db)?.is_some() {
+        let mut buf = vec![0u8; HEADER_SIZE];
+        db.read(&mut buf, 0)?;
+        // a database that is not one is left for the next read of its header to report
+        if let Ok(mut header) = SqliteHeader::from_buffer(&Bytes::from(buf)) {
+            header.bump_file_change_counter();
+            let mut buf = BytesMut::with_capacity(HEADER_SIZE);
+            header.write(&mut buf)?;
+            db.write(&buf, 0)?;
+            db.sync(SyncFlags::Full)?;
+        }
+    }
+    // a journal left in a file based mode is cleaned up even when this connection keeps its
+    // journal in memory
+    let mode = if mode.uses_file() {
+        mode
+    } else {
+        JournalMode::Delete
+    };
+    finish_journal(journal, mode, vfs, path)?;
+    Ok(true)
+}
+
+/// Copy the original page images in a journal back into the database and truncate it to its
+/// size before the transaction, as sqlite3 does when rolling back.  Records are played back
+/// until one fails its checksum.  Returns the restored size in pages, or `None` when the
+/// journal has no valid header and there is nothing to undo.
+pub fn playback(journal: &mut dyn VfsFile, db: &mut dyn VfsFile) -> SqliteResult<Option<u32>> {
+    let journal_size = journal.file_size()?;
+    let mut header_offset = 0u64;
+    let mut restored_size = None;
+    let mut page_size = 0u64;
+    'segments: while header_offset < journal_size {
+        let mut buf = vec![0u8; JOURNAL_HEADER_FIELDS_SIZE];
+        journal.read(&mut buf, header_offset)?;
+        let Some(header) = JournalHeader::from_buffer(&Bytes::from(buf)) else {
+            break;
+        };
+        if header.page_size < 512 || header.page_size > 65536 || !header.page_size.is_power_of_two()
+        {
+            break;
+        }
+        let sector_size = clamp_sector_size(header.sector_size) as u64;
+        let record_size = header.page_size as u64 + 8;
+        let mut offset = header_offset + sector_size;
+        let record_count = if header.record_count == RECORD_COUNT_UNKNOWN {
+            ((journal_size.saturating_sub(offset)) / record_size) as u32
+        } else {
+            header.record_count
+        };
+        restored_size.get_or_insert(header.initial_size);
+        page_size = header.page_size as u64;
+
+        let mut record = vec![0u8; record_size as usize];
+        for _ in 0..record_count {
+            if journal.read(&mut record, offset)? < record.len() {
+                break 'segments;
+            }
+            offset += record_size;
+            let mut fields = &record[..];
+            let number = fields.get_u32();
+            let data = &fields[..header.page_size as usize];
+            let checksum = (&fields[header.page_size as usize..]).get_u32();
+            if number == 0 || checksum != journal_checksum(header.nonce, data) {
+                break 'segments;
+            }
+            if number <= header.initial_size {
+                db.write(data, (number as u64 - 1) * header.page_size as u64)?;
+            }
+        }
+        // the next segment starts on a sector boundary
+        header_offset = offset.div_ceil(sector_size) * sector_size;
+    }
+    if let Some(size) = restored_size {
+        db.truncate(size as u64 * page_size)?;
+        db.sync(SyncFlags::Full)?;
+    }
+    Ok(restored_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryVfs;
+
+    fn open(vfs: &MemoryVfs, path: &str) -> Box<dyn VfsFile> {
+        vfs.open(
+            path,
+            OpenFlags::new(OpenAccess::ReadWriteCreate, FileKind::MainDb),
+            &ConnectionOptions::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn journal_mode_from_str() {
+        let cases = vec![
+            ("delete", JournalMode::Delete),
+            ("TRUNCATE", JournalMode::Truncate),
+            ("Persist", JournalMode::Persist),
+            ("memory", JournalMode::Memory),
+            ("off", JournalMode::Off),
+            ("WAL", JournalMode::Wal),
+        ];
+        for case in cases {
+            assert_eq!(JournalMode::try_from(case.0).unwrap(), case.1);
+        }
+        assert!(matches!(
+            JournalMode::try_from("wall"),
+            Err(SqliteError::Misuse { .. })
+        ));
+    }
+
+    #[test]
+    fn checksum_samples_every_200th_byte() {
+        let mut data = vec![0u8; 1024];
+        assert_eq!(journal_checksum(7, &data), 7);
+        // sampled offsets for 1024 bytes are 824, 624, 424, 224 and 24
+        for i in [824, 624, 424, 224] {
+            data[i] = 1;
+        }
+        data[124] = 100;
+        data[1023] = 100;
+        assert_eq!(journal_checksum(7, &data), 11);
+        assert_eq!(journal_checksum(u32::MAX, &data), 3);
+    }
+
+    #[test]
+    fn header_layout() {
+        let header = JournalHeader {
+            record_count: 3,
+            nonce: 0x01020304,
+            initial_size: 5,
+            sector_size: 4096,
+            page_size: 1024,
+        };
+        let mut buf = BytesMut::new();
+        header.write(&mut buf, true);
+        assert_eq!(
+            buf.as_ref(),
+            &[
+                0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7, 0, 0, 0, 3, 1, 2, 3, 4, 0, 0, 0, 5,
+                0, 0, 0x10, 0, 0, 0, 4, 0
+            ]
+        );
+        assert_eq!(
+            JournalHeader::from_buffer(&buf.freeze()),
+            Some(header.clone())
+        );
+
+        let mut buf = BytesMut::new();
+        header.write(&mut buf, false);
+        assert!(buf[..12].iter().all(|b| *b == 0));
+        assert_eq!(JournalHeader::from_buffer(&buf.freeze()), None);
+    }
+
+    #[test]
+    fn journal_and_playback() {
+        let vfs = MemoryVfs::new();
+        let options = ConnectionOptions::default();
+        let mut db = open(&vfs, "db");
+        for number in 1..=3u8 {
+            db.write(&[number; 512], (number as u64 - 1) * 512).unwrap();
+        }
+
+        let mut journal = Journal::create(&vfs, "db-journal", &options, 3, 512).unwrap();
+        journal.append(2, &[2; 512]).unwrap();
+        // pages are journaled once, and pages past the initial size not at all
+        journal.append(2, &[9; 512]).unwrap();
+        journal.append(4, &[4; 512]).unwrap();
+        assert_eq!(journal.header().record_count, 1);
+        assert!(!journal.needs(2));
+        assert!(journal.needs(1));
+
+        // nothing to play back until the journal is synced
+        assert_eq!(playback(journal.file_mut(), db.as_mut()).unwrap(), None);
+        journal.sync().unwrap();
+
+        db.write(&[7; 512], 512).unwrap();
+        db.write(&[4; 512], 3 * 512).unwrap();
+        assert_eq!(playback(journal.file_mut(), db.as_mut()).unwrap(), Some(3));
+        assert_eq!(db.file_size().unwrap(), 3 * 512);
+        let mut page = [0u8; 512];
+        db.read(&mut page, 512).unwrap();
+        assert_eq!(page, [2; 512]);
+    }
+
+    #[test]
+    fn playback_stops_at_bad_checksum() {
+        let vfs = MemoryVfs::new();
+        let options = ConnectionOptions::default();
+        let mut db = open(&vfs, "db");
+        db.write(&[0; 1024], 0).unwrap();
+        let mut journal = Journal::create(&vfs, "db-journal", &options, 2, 512).unwrap();
+        journal.append(1, &[1; 512]).unwrap();
+        journal.append(2, &[2; 512]).unwrap();
+        journal.sync().unwrap();
+        // corrupt a sampled byte of the second record
+        let sector = journal.header().sector_size as u64;
+        journal
+            .file_mut()
+            .write(&[0xff], sector + 520 + 4 + 312)
+            .unwrap();
+
+        playback(journal.file_mut(), db.as_mut()).unwrap();
+        let mut page = [0u8; 512];
+        db.read(&mut page, 0).unwrap();
+        assert_eq!(page, [1; 512]);
+        db.read(&mut page, 512).unwrap();
+        assert_eq!(page, [0; 512]);
+    }
+
+    #[test]
+    fn finish_by_mode() {
+        let vfs = MemoryVfs::new();
+        let options = ConnectionOptions::default();
+        for mode in [
+            JournalMode::Delete,
+            JournalMode::Truncate,
+            JournalMode::Persist,
+        ] {
+            let mut journal = Journal::create(&vfs, "db-journal", &options, 1, 512).unwrap();
+            journal.append(1, &[1; 512]).unwrap();
+            journal.sync().unwrap();
+            journal.finish(mode, &vfs, "db-journal").unwrap();
+            let exists = vfs
+                .access("db-journal", crate::vfs::AccessFlags::Exists)
+                .unwrap();
+            assert_eq!(exists, mode != JournalMode::Delete, "{:?}", mode);
+            if exists {
+                let mut journal = open(&vfs, "db-journal");
+                let mut header = vec![0u8; JOURNAL_HEADER_FIELDS_SIZE];
+                journal.read(&mut header, 0).unwrap();
+                assert_eq!(JournalHeader::from_buffer(&Bytes::from(header)), None);
+                if mode == JournalMode::Truncate {
+                    assert_eq!(journal.file_size().unwrap(), 0);
+                }
+                vfs.delete("db-journal", false).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn hot_journal_detection() {
+        let vfs = MemoryVfs::new();
+        let options = ConnectionOptions::default();
+        let mut db = open(&vfs, "db");
+        let mut writer = open(&vfs, "db");
+        assert!(!is_hot_journal(&vfs, "db-journal", db.as_ref()).unwrap());
+
+        let mut journal = Journal::create(&vfs, "db-journal", &options, 1, 512).unwrap();
+        journal.append(1, &[1; 512]).unwrap();
+        // an empty database has nothing to roll back
+        assert!(!is_hot_journal(&vfs, "db-journal", db.as_ref()).unwrap());
+        db.write(&[0; 512], 0).unwrap();
+        // nor does a journal whose header was never written
+        assert!(!is_hot_journal(&vfs, "db-journal", db.as_ref()).unwrap());
+        journal.sync().unwrap();
+        assert!(is_hot_journal(&vfs, "db-journal", db.as_ref()).unwrap());
+
+        // a writer still holding its reserved lock is using the journal
+        writer.lock(LockLevel::Reserved).unwrap();
+        assert!(!is_hot_journal(&vfs, "db-journal", db.as_ref()).unwrap());
+        writer.unlock(LockLevel::None).unwrap();
+
+        db.lock(LockLevel::Shared).unwrap();
+        assert!(recover_hot_journal(
+            &vfs,
+            "db-journal",
+            &options,
+            db.as_mut(),
+            JournalMode::Delete
+        )
+        .unwrap());
+        assert_eq!(db.lock_level(), LockLevel::Shared);
+        assert!(!vfs.access("db-journal", AccessFlags::Exists).unwrap());
+        let mut page = [0u8; 512];
+        db.read(&mut page, 0).unwrap();
+        assert_eq!(page, [1; 512]);
+    }
+}
@@ -0,0 +1,23 @@
+mod autovacuum;
+mod cache;
+mod freelist;
+mod journal;
+mod page;
+mod pager;
+mod ptrmap;
+mod shared_cache;
+mod vacuum;
+mod wal;
+mod wal_index;
+
+pub use self::autovacuum::*;
+pub use self::cache::*;
+pub use self::freelist::*;
+pub use self::journal::*;
+pub use self::page::*;
+pub use self::pager::*;
+pub use self::ptrmap::*;
+pub use self::shared_cache::*;
+pub use self::vacuum::*;
+pub use self::wal::*;
+pub use self::wal_index::*;
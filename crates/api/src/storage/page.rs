@@ -0,0 +1,61 @@
+use crate::database::HEADER_SIZE;
+use bytes::Bytes;
+
+/// 1-based number of a page in the database file
+pub type PageNumber = u32;
+
+/// A copy of one database page.  Pages are immutable; to change one, build the new contents
+/// and hand them back to the pager.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Page {
+    number: PageNumber,
+    data: Bytes,
+}
+
+impl Page {
+    pub fn new(number: PageNumber, data: Bytes) -> Page {
+        Page { number, data }
+    }
+
+    pub fn number(&self) -> PageNumber {
+        self.number
+    }
+
+    /// The full contents of the page, including the database header on page 1
+    pub fn data(&self) -> &Bytes {
+        &self.data
+    }
+
+    pub fn into_data(self) -> Bytes {
+        self.data
+    }
+
+    /// Offset of the page content: page 1 starts with the 100 byte database header
+    pub fn header_offset(&self) -> usize {
+        if self.number == 1 {
+            HEADER_SIZE
+        } else {
+            0
+        }
+    }
+
+    /// The page contents following the database header, if any
+    pub fn content(&self) -> Bytes {
+        self.data.slice(self.header_offset()..)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_offset_ok() {
+        let cases = vec![(1, 100), (2, 0), (42, 0)];
+        for case in cases {
+            let page = Page::new(case.0, Bytes::from(vec![0u8; 512]));
+            assert_eq!(page.header_offset(), case.1);
+            assert_eq!(page.content().len(), 512 - case.1);
+        }
+    }
+}
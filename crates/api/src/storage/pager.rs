@@ -0,0 +1,1746 @@
+use crate::connection::ConnectionOptions;
+use crate::database::{
+    FileFormatReadVersion, FileFormatWriteVersion, PageSize, SqliteHeader, HEADER_SIZE,
+};
+use crate::errors::{ExtendedResultCode, SqliteResult};
+use crate::storage::{
+    cache_capacity, pending_byte_page, playback, recover_hot_journal, AutoVacuum, CheckpointMode,
+    Journal, JournalMode, Page, PageCache, PageNumber, Wal, WalCheckpoint,
+    DEFAULT_WAL_AUTOCHECKPOINT, JOURNAL_SUFFIX, WAL_SUFFIX,
+};
+use crate::vfs::{HeapShm, LockLevel, MemoryVfs, OpenAccess, OpenFlags, SyncFlags, Vfs, VfsFile};
+use crate::SqliteError;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::sync::Arc;
+
+/// Page size sqlite3 uses for new databases
+pub const DEFAULT_PAGE_SIZE: PageSize = PageSize::Size4096;
+
+/// The b-tree page type of the table leaf that holds the empty sqlite_schema table
+const TABLE_LEAF_PAGE_TYPE: u8 = 0x0d;
+
+/// Page 1 of an empty database: the header followed by an empty sqlite_schema table leaf
+fn empty_database(page_size: PageSize) -> SqliteResult<(SqliteHeader, Bytes)> {
+    let header = SqliteHeader::new(page_size);
+    let page = empty_page_one(&header)?;
+    Ok((header, page))
+}
+
+/// Page 1 of an empty database with the given header
+pub(crate) fn empty_page_one(header: &SqliteHeader) -> SqliteResult<Bytes> {
+    let size: u32 = header.page_size().into();
+    let mut page = BytesMut::with_capacity(size as usize);
+    header.write(&mut page)?;
+    page.put_u8(TABLE_LEAF_PAGE_TYPE);
+    // first freeblock and number of cells
+    page.put_u16(0);
+    page.put_u16(0);
+    // start of the cell content area, where 0 means 65536
+    page.put_u16(size as u16);
+    // fragmented free bytes
+    page.put_u8(0);
+    page.resize(size as usize, 0);
+    Ok(page.freeze())
+}
+
+/// How far into a transaction a pager is
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransactionState {
+    /// No lock is held, outside exclusive locking mode, and cached pages may be stale
+    None,
+    /// A shared lock is held so the database cannot change underneath
+    Read,
+    /// A reserved or stronger lock is held and changes are being made
+    Write,
+}
+
+/// When a pager releases its locks on the database file, like `PRAGMA locking_mode`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LockingMode {
+    /// Release the locks at the end of every transaction
+    #[default]
+    Normal,
+    /// Keep the strongest lock taken until the locking mode changes back, so once this pager
+    /// has written no other connection can read.  A database entering WAL mode in this
+    /// locking mode keeps its wal-index on the heap instead of in shared memory.
+    Exclusive,
+}
+
+impl TryFrom<&str> for LockingMode {
+    type Error = SqliteError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "normal" => Ok(LockingMode::Normal),
+            "exclusive" => Ok(LockingMode::Exclusive),
+            _ => Err(SqliteError::Misuse {
+                code: ExtendedResultCode::Misuse,
+                message: format!("invalid locking_mode: {}", value),
+            }),
+        }
+    }
+}
+
+/// Reads and writes fixed-size pages of a database file.  Pages are handed out by 1-based page
+/// number and changes are held as dirty pages until they are committed.
+///
+/// Changes are made in transactions that follow sqlite3's rollback journal protocol: before a
+/// page is first changed its original image goes to the `-journal` file, and at commit the
+/// journal is synced before any page of the database file is overwritten.  A crash part way
+/// through a commit leaves a journal that either engine can use to restore the database.
+///
+/// A database whose header says it is in WAL mode is used through its write-ahead log
+/// instead: commits append the dirty pages to the `-wal` file and leave the database file
+/// alone, and reads prefer the newest copy of a page in the log.  Connections find frames
+/// through the wal-index the VFS shares between them, or through a private one on the heap
+/// when the VFS has no shared memory or the pager is in exclusive locking mode, in which case
+/// the pager keeps an exclusive lock on the database file.  Checkpoints copy the frames back
+/// into the database file, on request or once a commit leaves the log longer than the
+/// auto-checkpoint threshold.
+#[derive(Debug)]
+pub struct Pager {
+    vfs: Arc<dyn Vfs>,
+    path: String,
+    options: ConnectionOptions,
+    file: Box<dyn VfsFile>,
+    header: SqliteHeader,
+    read_only: bool,
+    page_count: u32,
+    cache: PageCache,
+    journal_mode: JournalMode,
+    locking_mode: LockingMode,
+    state: TransactionState,
+    journal: Option<Journal>,
+    /// The header and page count when the write transaction started, restored by a rollback
+    original: Option<(SqliteHeader, u32)>,
+    /// Whether the commit has started overwriting the database file
+    db_written: bool,
+    /// The write-ahead log, in WAL mode
+    wal: Option<Wal>,
+    /// Frames in the log that trigger a passive checkpoint after a commit, or 0 for never
+    wal_autocheckpoint: u32,
+    /// Bumped whenever pages this pager handed out may have changed
+    change_count: u64,
+}
+
+impl Pager {
+    /// Open a pager over a database file, reading the header to learn the page size.  An empty
+    /// file is initialized as an empty database unless the pager, or the file, is read only.
+    /// The header is read under a shared lock, so this fails with `SQLITE_BUSY` while another
+    /// connection is writing.
+    pub fn open(
+        vfs: Arc<dyn Vfs>,
+        path: &str,
+        flags: OpenFlags,
+        options: &ConnectionOptions,
+    ) -> SqliteResult<Pager> {
+        let mut file = vfs.open(path, flags, options)?;
+        let read_only = flags.access == OpenAccess::ReadOnly || file.is_read_only();
+        let journal_mode = if path.is_empty() {
+            JournalMode::Memory
+        } else {
+            JournalMode::default()
+        };
+        file.lock(LockLevel::Shared)?;
+        recover(vfs.as_ref(), path, options, file.as_mut(), journal_mode)?;
+        let file_size = file.file_size()?;
+        let (header, page_count, page_one) = if file_size == 0 {
+            let (header, page) = empty_database(DEFAULT_PAGE_SIZE)?;
+            (header, 1, Some(page))
+        } else {
+            let header = read_header(file.as_mut())?;
+            let page_count = page_count(&header, file_size);
+            (header, page_count, None)
+        };
+        let mut pager = Pager {
+            vfs,
+            path: String::from(path),
+            options: options.clone(),
+            file,
+            cache: Pager::new_cache(&header),
+            header,
+            read_only,
+            page_count,
+            journal_mode,
+            locking_mode: LockingMode::default(),
+            state: TransactionState::Read,
+            journal: None,
+            original: None,
+            db_written: false,
+            wal: None,
+            wal_autocheckpoint: DEFAULT_WAL_AUTOCHECKPOINT,
+            change_count: 0,
+        };
+        if let Some(page) = page_one {
+            if read_only {
+                pager.cache.insert(1, page);
+            } else {
+                // a new database has nothing to roll back to, so it needs no journal
+                pager.file.lock(LockLevel::Exclusive)?;
+                pager.cache.insert_dirty(1, page);
+                pager.write_dirty_pages()?;
+            }
+        }
+        if pager.header.file_format_read_version() == FileFormatReadVersion::Wal
+            && !pager.path.is_empty()
+        {
+            pager.open_wal()?;
+            pager.refresh(true)?;
+        }
+        pager.end_read()?;
+        Ok(pager)
+    }
+
+    fn open_wal(&mut self) -> SqliteResult<()> {
+        let shm = match self.locking_mode {
+            LockingMode::Normal => self.file.shm_open()?,
+            LockingMode::Exclusive => None,
+        };
+        let private_index = shm.is_none();
+        if private_index {
+            // nobody else can see the wal-index, so nobody else may use the database
+            self.file.lock(LockLevel::Exclusive)?;
+        }
+        let wal = Wal::open(
+            self.vfs.as_ref(),
+            &self.wal_path(),
+            &self.options,
+            self.read_only,
+            shm.unwrap_or_else(|| Box::new(HeapShm::new())),
+            private_index,
+        )?;
+        self.wal = Some(wal);
+        self.journal_mode = JournalMode::Wal;
+        Ok(())
+    }
+
+    /// A cache sized by the header's suggested cache size
+    fn new_cache(header: &SqliteHeader) -> PageCache {
+        let cache_size = header.default_page_cache_size() as i32 as i64;
+        PageCache::new(cache_capacity(cache_size, header.page_size().into()))
+    }
+
+    /// Limit the page cache, following `PRAGMA cache_size`: a positive size counts pages, a
+    /// negative size counts KiB and zero restores the default
+    pub fn set_cache_size(&mut self, cache_size: i64) {
+        self.cache
+            .set_capacity(cache_capacity(cache_size, self.page_size().into()));
+    }
+
+    /// The most pages the cache holds, not counting dirty pages
+    pub fn cache_capacity(&self) -> usize {
+        self.cache.capacity()
+    }
+
+    pub fn journal_mode(&self) -> JournalMode {
+        self.journal_mode
+    }
+
+    /// Change what happens to the journal when a transaction ends.  The mode cannot change
+    /// during a write transaction.
+    ///
+    /// Switching to or from `JournalMode::Wal` rewrites the file format versions in the header
+    /// in a transaction of its own.  A database leaves WAL mode once no other connection uses
+    /// it, after a checkpoint copies every frame into the database file, and a private
+    /// in-memory database stays out of it.
+    pub fn set_journal_mode(&mut self, journal_mode: JournalMode) -> SqliteResult<()> {
+        if self.state == TransactionState::Write {
+            return Err(SqliteError::Error {
+                code: ExtendedResultCode::Error,
+                message: String::from("cannot change journal mode from within a transaction"),
+            });
+        }
+        match (self.wal.is_some(), journal_mode == JournalMode::Wal) {
+            (false, true) if self.path.is_empty() => Ok(()),
+            (false, true) => {
+                self.update_header(|header| {
+                    header.set_file_format_versions(
+                        FileFormatWriteVersion::Wal,
+                        FileFormatReadVersion::Wal,
+                    )
+                })?;
+                self.commit()?;
+                self.open_wal()
+            }
+            (true, false) => self.leave_wal(journal_mode),
+            _ => {
+                self.journal_mode = journal_mode;
+                Ok(())
+            }
+        }
+    }
+
+    fn leave_wal(&mut self, journal_mode: JournalMode) -> SqliteResult<()> {
+        self.begin_write()?;
+        // no other connection may be using the log when it goes away
+        let checkpoint = self.file.lock(LockLevel::Exclusive).and_then(|()| {
+            let wal = self.wal.as_mut().expect("in WAL mode");
+            wal.checkpoint(self.file.as_mut(), CheckpointMode::Passive)
+        });
+        match checkpoint {
+            Ok(checkpoint) if checkpoint.checkpointed_frames == checkpoint.log_frames => {}
+            Ok(_) => {
+                self.rollback()?;
+                return Err(SqliteError::Busy {
+                    code: ExtendedResultCode::Busy,
+                    message: String::from("database is locked"),
+                });
+            }
+            Err(err) => {
+                self.rollback()?;
+                return Err(err);
+            }
+        }
+        let wal = self.wal.take().expect("in WAL mode");
+        let closed = wal.close(self.vfs.as_ref(), true);
+        self.rollback()?;
+        closed?;
+        self.journal_mode = journal_mode;
+        self.update_header(|header| {
+            header.set_file_format_versions(
+                FileFormatWriteVersion::Legacy,
+                FileFormatReadVersion::Legacy,
+            )
+        })?;
+        self.commit()
+    }
+
+    /// Copy frames from the log back into the database file, see [`Wal::checkpoint`].
+    /// Returns `None` when the database is not in WAL mode, and fails with `SQLITE_LOCKED`
+    /// during a transaction.
+    pub fn checkpoint(&mut self, mode: CheckpointMode) -> SqliteResult<Option<WalCheckpoint>> {
+        if self.wal.is_none() {
+            return Ok(None);
+        }
+        if self.state != TransactionState::None {
+            return Err(SqliteError::Locked {
+                code: ExtendedResultCode::Locked,
+                message: String::from("database table is locked"),
+            });
+        }
+        self.check_writable()?;
+        // the lock on the database file keeps the log from being deleted underneath
+        self.begin_read()?;
+        let checkpoint = match self.wal.as_mut() {
+            Some(wal) => wal
+                .end_read()
+                .and_then(|()| wal.checkpoint(self.file.as_mut(), mode))
+                .map(Some),
+            None => Ok(None),
+        };
+        self.end_read()?;
+        checkpoint
+    }
+
+    /// Frames in the log that trigger a passive checkpoint after a commit
+    pub fn wal_autocheckpoint(&self) -> u32 {
+        self.wal_autocheckpoint
+    }
+
+    /// Change the auto-checkpoint threshold, following `PRAGMA wal_autocheckpoint`, where 0
+    /// turns automatic checkpoints off
+    pub fn set_wal_autocheckpoint(&mut self, frames: u32) {
+        self.wal_autocheckpoint = frames;
+    }
+
+    pub fn locking_mode(&self) -> LockingMode {
+        self.locking_mode
+    }
+
+    /// Change when locks on the database file are released.  Leaving exclusive locking mode
+    /// releases them once no transaction is open.  A pager whose wal-index is on the heap
+    /// stays in exclusive locking mode, as other connections could not see its log.
+    pub fn set_locking_mode(&mut self, locking_mode: LockingMode) -> SqliteResult<()> {
+        if self.wal.as_ref().is_some_and(Wal::has_private_index) {
+            return Ok(());
+        }
+        self.locking_mode = locking_mode;
+        if locking_mode == LockingMode::Normal && self.state == TransactionState::None {
+            self.file.unlock(LockLevel::None)?;
+        }
+        Ok(())
+    }
+
+    /// Path of the rollback journal
+    pub fn journal_path(&self) -> String {
+        format!("{}{}", self.path, JOURNAL_SUFFIX)
+    }
+
+    /// Path of the write-ahead log
+    pub fn wal_path(&self) -> String {
+        format!("{}{}", self.path, WAL_SUFFIX)
+    }
+
+    /// The write-ahead log, when the database is in WAL mode
+    pub fn wal(&self) -> Option<&Wal> {
+        self.wal.as_ref()
+    }
+
+    /// The VFS the database file was opened through
+    pub(crate) fn vfs(&self) -> Arc<dyn Vfs> {
+        self.vfs.clone()
+    }
+
+    pub fn transaction_state(&self) -> TransactionState {
+        self.state
+    }
+
+    /// The database header as of the last change made through this pager
+    pub fn header(&self) -> &SqliteHeader {
+        &self.header
+    }
+
+    pub fn page_size(&self) -> PageSize {
+        self.header.page_size()
+    }
+
+    /// The page size less the bytes reserved at the end of every page
+    pub fn usable_size(&self) -> usize {
+        let page_size: u32 = self.page_size().into();
+        page_size as usize - self.header.page_reserved_space() as usize
+    }
+
+    /// Number of pages in the database, including pages allocated but not yet committed
+    pub fn page_count(&self) -> u32 {
+        self.page_count
+    }
+
+    /// A counter that moves whenever a page may have changed: when one is written, the
+    /// database shrinks or is replaced, a transaction rolls back or another connection's
+    /// changes are picked up.  Cursors compare it to tell when their pages are stale.
+    pub fn change_count(&self) -> u64 {
+        self.change_count
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn is_dirty(&self, number: PageNumber) -> bool {
+        self.cache.is_dirty(number)
+    }
+
+    /// Page numbers with changes that have not been committed, in ascending order
+    pub fn dirty_pages(&self) -> impl Iterator<Item = PageNumber> + '_ {
+        self.cache.dirty_pages()
+    }
+
+    fn check_page_number(&self, number: PageNumber) -> SqliteResult<()> {
+        if number == 0 || number > self.page_count {
+            return Err(SqliteError::Corrupt {
+                code: ExtendedResultCode::Corrupt,
+                message: format!(
+                    "page {} is out of range for a database of {} pages",
+                    number, self.page_count
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    fn check_writable(&self) -> SqliteResult<()> {
+        if self.read_only {
+            return Err(SqliteError::ReadOnly {
+                code: ExtendedResultCode::ReadOnly,
+                message: String::from("attempt to write a readonly database"),
+            });
+        }
+        Ok(())
+    }
+
+    /// Start a read transaction by taking a shared lock.  If another connection changed the
+    /// database since this pager last looked, the cache is dropped.
+    pub fn begin_read(&mut self) -> SqliteResult<()> {
+        if self.state != TransactionState::None {
+            return Ok(());
+        }
+        let level = match self.wal.as_ref() {
+            Some(wal) if wal.has_private_index() => LockLevel::Exclusive,
+            _ => LockLevel::Shared,
+        };
+        self.file.lock(level)?;
+        let recovered = recover(
+            self.vfs.as_ref(),
+            &self.path,
+            &self.options,
+            self.file.as_mut(),
+            self.journal_mode,
+        );
+        if let Err(err) = recovered.and_then(|recovered| self.refresh(recovered)) {
+            if let Some(wal) = self.wal.as_mut() {
+                let _ = wal.end_read();
+            }
+            let _ = self.release_lock();
+            return Err(err);
+        }
+        self.state = TransactionState::Read;
+        Ok(())
+    }
+
+    /// Re-read the header and drop cached pages if the file change counter moved, or always
+    /// when `force` is set.  In WAL mode this starts a read transaction on the log and the
+    /// cache is dropped when the log holds new transactions.
+    fn refresh(&mut self, force: bool) -> SqliteResult<()> {
+        let file_size = self.file.file_size()?;
+        if let Some(wal) = self.wal.as_mut() {
+            if !wal.begin_read()? && !force {
+                return Ok(());
+            }
+            self.cache.clear();
+            self.change_count += 1;
+            let header = SqliteHeader::from_buffer(&self.read_page(1)?)?;
+            if header.file_format_read_version() != FileFormatReadVersion::Wal {
+                // another connection took the database out of WAL mode
+                if let Some(wal) = self.wal.take() {
+                    wal.close(self.vfs.as_ref(), false)?;
+                }
+                self.journal_mode = JournalMode::default();
+                return self.refresh(true);
+            }
+            let db_size = self.wal.as_ref().and_then(Wal::db_size);
+            self.page_count = db_size.unwrap_or_else(|| page_count(&header, file_size));
+            self.header = header;
+            return Ok(());
+        }
+        if file_size == 0 {
+            // a read only pager over an empty file keeps its empty database
+            return Ok(());
+        }
+        let header = read_header(self.file.as_mut())?;
+        if force
+            || header.file_change_counter() != self.header.file_change_counter()
+            || header.page_size() != self.header.page_size()
+        {
+            if header.file_format_read_version() == FileFormatReadVersion::Wal
+                && !self.path.is_empty()
+            {
+                // another connection switched the database to WAL mode
+                self.open_wal()?;
+                return self.refresh(true);
+            }
+            self.cache.clear();
+            self.change_count += 1;
+            self.page_count = page_count(&header, file_size);
+            self.header = header;
+        }
+        Ok(())
+    }
+
+    /// End a read transaction, releasing the shared lock unless in exclusive locking mode.
+    /// Does nothing during a write transaction.
+    pub fn end_read(&mut self) -> SqliteResult<()> {
+        if self.state == TransactionState::Read {
+            if let Some(wal) = self.wal.as_mut() {
+                wal.end_read()?;
+            }
+            self.release_lock()?;
+            self.state = TransactionState::None;
+        }
+        Ok(())
+    }
+
+    /// Drop the lock on the database file at the end of a transaction
+    fn release_lock(&mut self) -> SqliteResult<()> {
+        match self.locking_mode {
+            LockingMode::Normal => self.file.unlock(LockLevel::None),
+            LockingMode::Exclusive => Ok(()),
+        }
+    }
+
+    /// Start a write transaction by taking a reserved lock, failing with `SQLITE_BUSY` if
+    /// another connection is writing.  Pages written outside a transaction start one.
+    ///
+    /// In WAL mode the write lock of the wal-index keeps writers apart instead, and a read
+    /// transaction whose snapshot another connection has since committed past fails with
+    /// `SQLITE_BUSY_SNAPSHOT`.
+    pub fn begin_write(&mut self) -> SqliteResult<()> {
+        self.check_writable()?;
+        if self.state == TransactionState::Write {
+            return Ok(());
+        }
+        let was_reading = self.state == TransactionState::Read;
+        self.begin_read()?;
+        let locked = match self.wal.as_mut() {
+            Some(wal) => wal.begin_write(),
+            None => self.file.lock(LockLevel::Reserved),
+        };
+        if let Err(err) = locked {
+            if !was_reading {
+                self.end_read()?;
+            }
+            return Err(err);
+        }
+        self.original = Some((self.header.clone(), self.page_count));
+        self.state = TransactionState::Write;
+        Ok(())
+    }
+
+    /// Read a page from the file.  Pages past the end of the file read as zeros, as they do
+    /// in sqlite3.
+    fn read_page(&mut self, number: PageNumber) -> SqliteResult<Bytes> {
+        let page_size: u32 = self.page_size().into();
+        let mut buf = vec![0u8; page_size as usize];
+        if let Some(wal) = self.wal.as_mut() {
+            if let Some(frame) = wal.find_frame(number)? {
+                wal.read_frame(frame, &mut buf)?;
+                return Ok(Bytes::from(buf));
+            }
+        }
+        let offset = (number as u64 - 1) * page_size as u64;
+        self.file.read(&mut buf, offset)?;
+        Ok(Bytes::from(buf))
+    }
+
+    /// Fetch a page by its 1-based page number.  Outside a transaction the page is read under
+    /// a shared lock that is released again.
+    pub fn get(&mut self, number: PageNumber) -> SqliteResult<Page> {
+        if self.state == TransactionState::None {
+            self.begin_read()?;
+            let page = self.fetch(number);
+            self.end_read()?;
+            return page;
+        }
+        self.fetch(number)
+    }
+
+    fn fetch(&mut self, number: PageNumber) -> SqliteResult<Page> {
+        self.check_page_number(number)?;
+        if let Some(data) = self.cache.get(number) {
+            return Ok(Page::new(number, data));
+        }
+        let data = self.read_page(number)?;
+        self.cache.insert(number, data.clone());
+        Ok(Page::new(number, data))
+    }
+
+    /// Save the original image of a page to the journal before it is first changed
+    fn journal_page(&mut self, number: PageNumber) -> SqliteResult<()> {
+        if matches!(self.journal_mode, JournalMode::Off | JournalMode::Wal)
+            || self.cache.is_dirty(number)
+        {
+            return Ok(());
+        }
+        if self.journal.is_none() {
+            let (_, initial_size) = self.original.as_ref().expect("in a write transaction");
+            let journal = if self.journal_mode.uses_file() {
+                Journal::create(
+                    self.vfs.as_ref(),
+                    &self.journal_path(),
+                    &self.options,
+                    *initial_size,
+                    self.page_size().into(),
+                )?
+            } else {
+                // a private file of the memory VFS
+                Journal::create(
+                    &MemoryVfs::new(),
+                    "",
+                    &self.options,
+                    *initial_size,
+                    self.page_size().into(),
+                )?
+            };
+            self.journal = Some(journal);
+        }
+        if !self.journal.as_ref().is_some_and(|j| j.needs(number)) {
+            return Ok(());
+        }
+        let data = match self.cache.peek(number) {
+            Some(data) => data.clone(),
+            None => self.read_page(number)?,
+        };
+        self.journal
+            .as_mut()
+            .expect("journal was opened")
+            .append(number, &data)
+    }
+
+    /// Replace the contents of a page.  The change is held until the transaction commits.
+    /// Writing page 1 also replaces the database header, which must be valid.
+    pub fn write(&mut self, page: Page) -> SqliteResult<()> {
+        self.begin_write()?;
+        self.check_page_number(page.number())?;
+        let page_size: u32 = self.page_size().into();
+        if page.data().len() != page_size as usize {
+            return Err(SqliteError::Misuse {
+                code: ExtendedResultCode::Misuse,
+                message: format!(
+                    "page {} is {} bytes but the page size is {}",
+                    page.number(),
+                    page.data().len(),
+                    page_size
+                ),
+            });
+        }
+        let header = if page.number() == 1 {
+            let header = SqliteHeader::from_buffer(page.data())?;
+            if header.page_size() != self.page_size() {
+                return Err(SqliteError::Misuse {
+                    code: ExtendedResultCode::Misuse,
+                    message: String::from("the page size cannot be changed by writing page 1"),
+                });
+            }
+            Some(header)
+        } else {
+            None
+        };
+        let number = page.number();
+        self.journal_page(number)?;
+        if let Some(header) = header {
+            self.header = header;
+        }
+        self.cache.insert_dirty(number, page.into_data());
+        self.change_count += 1;
+        Ok(())
+    }
+
+    /// Allocate a zeroed page, reusing a page from the freelist before appending one to the
+    /// end of the database
+    pub fn allocate(&mut self) -> SqliteResult<Page> {
+        self.begin_write()?;
+        match self.allocate_free_page()? {
+            Some(page) => Ok(page),
+            None => self.append_page(),
+        }
+    }
+
+    /// Add a zeroed page to the end of the database.  Like sqlite3, this skips the page
+    /// holding the pending byte and, in an auto-vacuum database, adds the pointer map pages
+    /// the new page falls under.
+    pub(crate) fn append_page(&mut self) -> SqliteResult<Page> {
+        self.begin_write()?;
+        let page_size: u32 = self.page_size().into();
+        let ptrmap = self.has_ptrmap().then(|| self.ptrmap());
+        let data = Bytes::from(vec![0u8; page_size as usize]);
+        loop {
+            let number = self.page_count + 1;
+            self.page_count = number;
+            if number == pending_byte_page(page_size) {
+                continue;
+            }
+            self.journal_page(number)?;
+            self.cache.insert_dirty(number, data.clone());
+            if !ptrmap.is_some_and(|ptrmap| ptrmap.is_map_page(number)) {
+                return Ok(Page::new(number, data));
+            }
+        }
+    }
+
+    /// Shrink the database to `page_count` pages.  The pages past the end are dropped from the
+    /// file when the transaction commits.
+    pub(crate) fn truncate(&mut self, page_count: u32) -> SqliteResult<()> {
+        self.begin_write()?;
+        for number in page_count + 1..=self.page_count {
+            self.journal_page(number)?;
+            self.cache.remove(number);
+        }
+        self.page_count = page_count;
+        self.change_count += 1;
+        Ok(())
+    }
+
+    /// Replace the whole database, page size included, with `page_count` pages produced by
+    /// `page`, in the current write transaction.  Every page is journaled first, so rolling
+    /// back or playing back a hot journal restores the database as it was.  The page size
+    /// cannot change in WAL mode, where every frame of the log has the same size.
+    pub(crate) fn replace_database(
+        &mut self,
+        page_count: u32,
+        mut page: impl FnMut(PageNumber) -> SqliteResult<Bytes>,
+    ) -> SqliteResult<()> {
+        self.begin_write()?;
+        let page_one = page(1)?;
+        let header = SqliteHeader::from_buffer(&page_one)?;
+        if self.wal.is_some() && header.page_size() != self.page_size() {
+            return Err(SqliteError::Misuse {
+                code: ExtendedResultCode::Misuse,
+                message: String::from("the page size cannot change in WAL mode"),
+            });
+        }
+        for number in 1..=self.page_count {
+            self.journal_page(number)?;
+        }
+        self.cache.clear();
+        self.change_count += 1;
+        self.header = header;
+        self.page_count = page_count;
+        self.cache.insert_dirty(1, page_one);
+        let pending_byte_page = pending_byte_page(self.page_size().into());
+        for number in 2..=page_count {
+            if number != pending_byte_page {
+                self.cache.insert_dirty(number, page(number)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `update` in the current write transaction, or in one of its own that commits when
+    /// it succeeds and rolls back when it or the commit fails
+    pub(crate) fn in_write_transaction<T>(
+        &mut self,
+        update: impl FnOnce(&mut Pager) -> SqliteResult<T>,
+    ) -> SqliteResult<T> {
+        if self.state == TransactionState::Write {
+            return update(self);
+        }
+        self.begin_write()?;
+        let result = update(self).and_then(|value| {
+            self.commit()?;
+            Ok(value)
+        });
+        if result.is_err() {
+            self.rollback()?;
+        }
+        result
+    }
+
+    /// Change header fields, rewriting the first 100 bytes of page 1
+    pub fn update_header(&mut self, update: impl FnOnce(&mut SqliteHeader)) -> SqliteResult<()> {
+        self.begin_write()?;
+        let mut header = self.header.clone();
+        update(&mut header);
+        let page = self.get(1)?;
+        let mut data = BytesMut::from(page.data().as_ref());
+        let mut header_bytes = BytesMut::with_capacity(HEADER_SIZE);
+        header.write(&mut header_bytes)?;
+        data[..HEADER_SIZE].copy_from_slice(&header_bytes);
+        self.write(Page::new(1, data.freeze()))
+    }
+
+    /// Commit the write transaction: the first phase of sqlite3's two phase commit.  Bumps the
+    /// file change counter, syncs the journal, takes an exclusive lock and writes and syncs
+    /// every dirty page.  A crash after this leaves a hot journal that undoes the commit.
+    /// A full auto-vacuum database first moves its free pages to the end and drops them.
+    pub fn commit_phase_one(&mut self) -> SqliteResult<()> {
+        if self.state != TransactionState::Write || self.cache.dirty_pages().next().is_none() {
+            return Ok(());
+        }
+        if self.header.total_freelist_pages() > 0 && self.auto_vacuum_mode() == AutoVacuum::Full {
+            self.auto_vacuum_commit()?;
+        }
+        let page_count = self.page_count;
+        if self.wal.is_some() {
+            return self.commit_to_wal();
+        }
+        self.update_header(|header| {
+            header.set_size_in_pages(page_count);
+            header.bump_file_change_counter();
+        })?;
+        if let Some(journal) = self.journal.as_mut() {
+            journal.sync()?;
+        }
+        self.file.lock(LockLevel::Exclusive)?;
+        self.db_written = true;
+        let page_size: u32 = self.page_size().into();
+        let file_size = self.file.file_size()?;
+        if file_size > page_count as u64 * page_size as u64 {
+            self.file.truncate(page_count as u64 * page_size as u64)?;
+        }
+        self.write_dirty_pages()
+    }
+
+    /// Append the dirty pages to the log as one transaction.  Like sqlite3, the change counter
+    /// is only bumped when page 1 is written anyway, which it is whenever the size changes.
+    fn commit_to_wal(&mut self) -> SqliteResult<()> {
+        let page_count = self.page_count;
+        if self.cache.is_dirty(1) || self.header.size_in_pages() != page_count {
+            self.update_header(|header| {
+                header.set_size_in_pages(page_count);
+                header.bump_file_change_counter();
+            })?;
+        }
+        let pages: Vec<(PageNumber, Bytes)> = self
+            .cache
+            .dirty_pages()
+            .filter(|number| *number <= page_count)
+            .map(|number| {
+                let data = self.cache.peek(number).expect("dirty pages are pinned");
+                (number, data.clone())
+            })
+            .collect();
+        self.wal
+            .as_mut()
+            .expect("in WAL mode")
+            .append(&pages, page_count)?;
+        self.cache.clear_dirty();
+        Ok(())
+    }
+
+    /// The second phase of the commit: finish the journal so it can no longer be played back,
+    /// which is the moment the transaction becomes durable, and release the locks
+    pub fn commit_phase_two(&mut self) -> SqliteResult<()> {
+        if self.state != TransactionState::Write {
+            return Ok(());
+        }
+        if let Some(journal) = self.journal.take() {
+            journal.finish(self.journal_mode, self.vfs.as_ref(), &self.journal_path())?;
+        }
+        self.end_write()?;
+        let frames = self.wal.as_ref().map_or(0, Wal::max_frame);
+        if self.wal_autocheckpoint > 0 && frames >= self.wal_autocheckpoint {
+            // the transaction is committed whatever happens to the checkpoint, and readers
+            // that hold it up are waited out by a later commit, as in sqlite3
+            let _ = self.checkpoint(CheckpointMode::Passive);
+        }
+        Ok(())
+    }
+
+    /// Commit the write transaction, writing every dirty page back to the file.  Like a
+    /// sqlite3 commit, this bumps the file change counter and records the database size in
+    /// the header.
+    pub fn commit(&mut self) -> SqliteResult<()> {
+        self.commit_phase_one()?;
+        self.commit_phase_two()
+    }
+
+    /// Abandon the write transaction.  Dirty pages are dropped and, if the commit had already
+    /// started overwriting the database file, the journal is played back.
+    pub fn rollback(&mut self) -> SqliteResult<()> {
+        if self.state != TransactionState::Write {
+            return self.end_read();
+        }
+        let (header, page_count) = self.original.take().expect("in a write transaction");
+        self.change_count += 1;
+        if self.db_written {
+            self.cache.clear();
+            match self.journal.as_mut() {
+                Some(journal) => {
+                    playback(journal.file_mut(), self.file.as_mut())?;
+                    self.header = header;
+                    self.page_count = page_count;
+                }
+                None => {
+                    // without a journal the changes stay, so pick up whatever reached the file
+                    let file_size = self.file.file_size()?;
+                    self.header = read_header(self.file.as_mut())?;
+                    self.page_count = self::page_count(&self.header, file_size);
+                }
+            }
+        } else {
+            let dirty: Vec<PageNumber> = self.cache.dirty_pages().collect();
+            for number in dirty {
+                self.cache.remove(number);
+            }
+            self.header = header;
+            self.page_count = page_count;
+        }
+        if let Some(journal) = self.journal.take() {
+            journal.finish(self.journal_mode, self.vfs.as_ref(), &self.journal_path())?;
+        }
+        self.end_write()
+    }
+
+    fn end_write(&mut self) -> SqliteResult<()> {
+        if let Some(wal) = self.wal.as_mut() {
+            wal.end_write()?;
+        }
+        self.original = None;
+        self.db_written = false;
+        self.state = TransactionState::Read;
+        self.end_read()
+    }
+
+    fn write_dirty_pages(&mut self) -> SqliteResult<()> {
+        let page_size: u32 = self.header.page_size().into();
+        for number in self.cache.dirty_pages() {
+            let offset = (number as u64 - 1) * page_size as u64;
+            let data = self.cache.peek(number).expect("dirty pages are pinned");
+            self.file.write(data, offset)?;
+        }
+        self.cache.clear_dirty();
+        self.file.sync(SyncFlags::Full)
+    }
+}
+
+impl Drop for Pager {
+    fn drop(&mut self) {
+        let _ = self.rollback();
+    }
+}
+
+/// Roll back a hot journal left by a crashed writer.  Private in-memory databases and immutable
+/// files have no journal to recover.
+fn recover(
+    vfs: &dyn Vfs,
+    path: &str,
+    options: &ConnectionOptions,
+    file: &mut dyn VfsFile,
+    journal_mode: JournalMode,
+) -> SqliteResult<bool> {
+    if path.is_empty() || options.immutable() {
+        return Ok(false);
+    }
+    let journal_path = format!("{}{}", path, JOURNAL_SUFFIX);
+    recover_hot_journal(vfs, &journal_path, options, file, journal_mode)
+}
+
+/// Read and parse the database header at the start of the file
+fn read_header(file: &mut dyn VfsFile) -> SqliteResult<SqliteHeader> {
+    let mut buf = vec![0u8; HEADER_SIZE];
+    if file.read(&mut buf, 0)? < HEADER_SIZE {
+        return Err(SqliteError::NotADb {
+            code: ExtendedResultCode::NotADb,
+            message: String::from("file is not a database"),
+        });
+    }
+    SqliteHeader::from_buffer(&Bytes::from(buf))
+}
+
+/// The database size from the header when it is valid, otherwise from the file size
+fn page_count(header: &SqliteHeader, file_size: u64) -> u32 {
+    let page_size: u32 = header.page_size().into();
+    if header.is_size_in_pages_valid() {
+        header.size_in_pages()
+    } else {
+        file_size.div_ceil(page_size as u64) as u32
+    }
+}
+
+/// Open the database `path` of `vfs` for tests, creating it if it does not exist
+#[cfg(test)]
+pub(crate) fn test_pager(vfs: Arc<dyn Vfs>, path: &str) -> Pager {
+    use crate::vfs::FileKind;
+    Pager::open(
+        vfs,
+        path,
+        OpenFlags::new(OpenAccess::ReadWriteCreate, FileKind::MainDb),
+        &ConnectionOptions::default(),
+    )
+    .unwrap()
+}
+
+/// An empty in-memory database for tests, with pages of `page_size` and `auto_vacuum` set
+#[cfg(test)]
+pub(crate) fn memory_pager(page_size: PageSize, auto_vacuum: AutoVacuum) -> Pager {
+    let mut pager = test_pager(Arc::new(MemoryVfs::new()), "");
+    if page_size != pager.page_size() {
+        pager
+            .vacuum(crate::storage::VacuumOptions {
+                page_size: Some(page_size),
+                ..Default::default()
+            })
+            .unwrap();
+    }
+    if auto_vacuum != AutoVacuum::None {
+        pager.set_auto_vacuum(auto_vacuum).unwrap();
+    }
+    pager
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::JOURNAL_MAGIC;
+    use crate::vfs::{FileKind, UnixVfs};
+    use std::path::{Path, PathBuf};
+    use tempfile::NamedTempFile;
+
+    fn open_file(file: &NamedTempFile) -> Box<dyn VfsFile> {
+        UnixVfs::new()
+            .open(
+                file.path().to_str().unwrap(),
+                OpenFlags::new(OpenAccess::ReadWrite, FileKind::MainDb),
+                &ConnectionOptions::default(),
+            )
+            .unwrap()
+    }
+
+    fn open_pager(path: impl AsRef<Path>, access: OpenAccess) -> SqliteResult<Pager> {
+        Pager::open(
+            Arc::new(UnixVfs::new()),
+            path.as_ref().to_str().unwrap(),
+            OpenFlags::new(access, FileKind::MainDb),
+            &ConnectionOptions::default(),
+        )
+    }
+
+    fn filled_page(number: PageNumber, page_size: usize, fill: u8) -> Page {
+        Page::new(number, Bytes::from(vec![fill; page_size]))
+    }
+
+    #[test]
+    fn open_empty_file_initializes_database() {
+        let tmp = NamedTempFile::new().unwrap();
+        let pager = open_pager(&tmp, OpenAccess::ReadWrite).unwrap();
+        assert_eq!(pager.page_size(), PageSize::Size4096);
+        assert_eq!(pager.usable_size(), 4096);
+        assert_eq!(pager.page_count(), 1);
+        assert_eq!(std::fs::metadata(tmp.path()).unwrap().len(), 4096);
+    }
+
+    #[test]
+    fn open_empty_file_read_only_is_not_written() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut pager = open_pager(&tmp, OpenAccess::ReadOnly).unwrap();
+        assert_eq!(pager.page_count(), 1);
+        assert_eq!(pager.get(1).unwrap().data()[100], TABLE_LEAF_PAGE_TYPE);
+        assert_eq!(std::fs::metadata(tmp.path()).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn allocate_write_commit_and_reopen() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut pager = open_pager(&tmp, OpenAccess::ReadWrite).unwrap();
+        for fill in 2..=4u8 {
+            let page = pager.allocate().unwrap();
+            assert_eq!(page.number(), fill as u32);
+            assert!(pager.is_dirty(page.number()));
+            pager.write(filled_page(page.number(), 4096, fill)).unwrap();
+        }
+        assert_eq!(pager.dirty_pages().collect::<Vec<_>>(), vec![2, 3, 4]);
+        pager.commit().unwrap();
+        assert_eq!(pager.dirty_pages().count(), 0);
+        assert_eq!(pager.header().size_in_pages(), 4);
+        assert_eq!(pager.header().file_change_counter(), 2);
+
+        let mut pager = open_pager(&tmp, OpenAccess::ReadOnly).unwrap();
+        assert_eq!(pager.page_count(), 4);
+        assert_eq!(pager.header().version_valid_for(), 2);
+        for number in 2..=4u32 {
+            let page = pager.get(number).unwrap();
+            assert!(page.data().iter().all(|b| *b == number as u8));
+            assert_eq!(page.header_offset(), 0);
+        }
+        assert_eq!(pager.get(1).unwrap().header_offset(), 100);
+    }
+
+    #[test]
+    fn get_out_of_range_err() {
+        let mut pager = memory_pager(DEFAULT_PAGE_SIZE, AutoVacuum::None);
+        for number in [0, 2, 100] {
+            let result = pager.get(number);
+            assert!(matches!(
+                result,
+                Err(SqliteError::Corrupt {
+                    code: ExtendedResultCode::Corrupt,
+                    ..
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn write_wrong_size_err() {
+        let mut pager = memory_pager(DEFAULT_PAGE_SIZE, AutoVacuum::None);
+        pager.allocate().unwrap();
+        let result = pager.write(filled_page(2, 512, 0));
+        assert!(matches!(
+            result,
+            Err(SqliteError::Misuse {
+                code: ExtendedResultCode::Misuse,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn write_read_only_err() {
+        let tmp = NamedTempFile::new().unwrap();
+        open_pager(&tmp, OpenAccess::ReadWrite).unwrap();
+        let mut pager = open_pager(&tmp, OpenAccess::ReadOnly).unwrap();
+        let page = pager.get(1).unwrap();
+        let result = pager.write(page);
+        assert!(matches!(
+            result,
+            Err(SqliteError::ReadOnly {
+                code: ExtendedResultCode::ReadOnly,
+                ..
+            })
+        ));
+        assert!(pager.allocate().is_err());
+    }
+
+    #[test]
+    fn write_page_one_updates_header() {
+        let mut pager = memory_pager(DEFAULT_PAGE_SIZE, AutoVacuum::None);
+        pager
+            .update_header(|header| header.set_size_in_pages(7))
+            .unwrap();
+        assert_eq!(pager.header().size_in_pages(), 7);
+        let page = pager.get(1).unwrap();
+        assert_eq!(&page.data()[28..32], &7u32.to_be_bytes());
+
+        let mut data = BytesMut::from(page.data().as_ref());
+        data[0] = b'X';
+        let result = pager.write(Page::new(1, data.freeze()));
+        assert!(matches!(result, Err(SqliteError::NotADb { .. })));
+    }
+
+    #[test]
+    fn page_size_65536_and_reserved_space() {
+        let tmp = NamedTempFile::new().unwrap();
+        let (header, _) = empty_database(PageSize::Size65536).unwrap();
+        let mut buf = BytesMut::with_capacity(65536);
+        header.write(&mut buf).unwrap();
+        // page size is encoded as 1
+        assert_eq!(&buf[16..18], &[0, 1]);
+        buf[20] = 40;
+        buf.resize(65536 * 2, 0);
+        std::fs::write(tmp.path(), &buf).unwrap();
+
+        let mut pager = open_pager(&tmp, OpenAccess::ReadWrite).unwrap();
+        assert_eq!(pager.page_size(), PageSize::Size65536);
+        assert_eq!(pager.usable_size(), 65536 - 40);
+        // the header says 1 page but the change counter and version-valid-for match, so the
+        // header wins over the file size
+        assert_eq!(pager.page_count(), 1);
+        assert_eq!(pager.get(1).unwrap().data().len(), 65536);
+    }
+
+    #[test]
+    fn page_count_from_file_size_when_header_stale() {
+        let tmp = NamedTempFile::new().unwrap();
+        let (header, page) = empty_database(PageSize::Size512).unwrap();
+        let mut buf = BytesMut::from(page.as_ref());
+        // an older writer bumped the change counter without updating the size
+        buf[24..28].copy_from_slice(&(header.file_change_counter() + 1).to_be_bytes());
+        buf.resize(512 * 3, 0);
+        std::fs::write(tmp.path(), &buf).unwrap();
+
+        let mut pager = open_pager(&tmp, OpenAccess::ReadOnly).unwrap();
+        assert_eq!(pager.page_count(), 3);
+        assert_eq!(pager.get(3).unwrap().data().len(), 512);
+    }
+
+    #[test]
+    fn memory_pager_keeps_pages() {
+        let mut pager = memory_pager(DEFAULT_PAGE_SIZE, AutoVacuum::None);
+        let page = pager.allocate().unwrap();
+        pager.write(filled_page(page.number(), 4096, 9)).unwrap();
+        pager.commit().unwrap();
+        assert!(pager.get(2).unwrap().data().iter().all(|b| *b == 9));
+        assert_eq!(pager.header().size_in_pages(), 2);
+    }
+
+    #[test]
+    fn evicted_pages_are_read_back() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut pager = open_pager(&tmp, OpenAccess::ReadWrite).unwrap();
+        assert_eq!(pager.cache_capacity(), 500);
+        pager.set_cache_size(10);
+        assert_eq!(pager.cache_capacity(), 10);
+        for fill in 2..=40u8 {
+            let page = pager.allocate().unwrap();
+            pager.write(filled_page(page.number(), 4096, fill)).unwrap();
+        }
+        // dirty pages stay cached past the capacity until they are committed
+        assert_eq!(pager.dirty_pages().count(), 39);
+        pager.commit().unwrap();
+        for number in 2..=40u32 {
+            let page = pager.get(number).unwrap();
+            assert!(page.data().iter().all(|b| *b == number as u8));
+        }
+    }
+
+    #[test]
+    fn open_while_writer_holds_pending_is_busy() {
+        let tmp = NamedTempFile::new().unwrap();
+        open_pager(&tmp, OpenAccess::ReadWrite).unwrap();
+        let mut writer = open_file(&tmp);
+        writer.lock(LockLevel::Pending).unwrap();
+        let result = open_pager(&tmp, OpenAccess::ReadOnly);
+        assert!(matches!(
+            result,
+            Err(SqliteError::Busy {
+                code: ExtendedResultCode::Busy,
+                ..
+            })
+        ));
+        writer.unlock(LockLevel::None).unwrap();
+        let pager = open_pager(&tmp, OpenAccess::ReadOnly).unwrap();
+        assert_eq!(pager.page_count(), 1);
+        // the lock taken to read the header is released again
+        writer.lock(LockLevel::Exclusive).unwrap();
+    }
+
+    #[test]
+    fn truncated_header_err() {
+        let tmp = NamedTempFile::new().unwrap();
+        std::fs::write(tmp.path(), b"SQLite format 3\0").unwrap();
+        let result = open_pager(&tmp, OpenAccess::ReadWrite);
+        assert!(matches!(result, Err(SqliteError::NotADb { .. })));
+    }
+
+    fn fill_pages(pager: &mut Pager, count: u32, fill: u8) {
+        while pager.page_count() < count + 1 {
+            pager.allocate().unwrap();
+        }
+        for number in 2..=count + 1 {
+            pager.write(filled_page(number, 4096, fill)).unwrap();
+        }
+    }
+
+    fn assert_filled(pager: &mut Pager, count: u32, fill: u8) {
+        for number in 2..=count + 1 {
+            assert!(pager.get(number).unwrap().data().iter().all(|b| *b == fill));
+        }
+    }
+
+    #[test]
+    fn commit_deletes_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.db");
+        let mut pager = open_pager(&path, OpenAccess::ReadWriteCreate).unwrap();
+        let journal_path = pager.journal_path();
+        fill_pages(&mut pager, 2, 1);
+        pager.commit().unwrap();
+        assert!(!Path::new(&journal_path).exists());
+
+        fill_pages(&mut pager, 2, 2);
+        assert_eq!(pager.transaction_state(), TransactionState::Write);
+        let journal = std::fs::read(&journal_path).unwrap();
+        // the header stays zeroed until the journal is synced
+        assert!(journal[..8].iter().all(|b| *b == 0));
+        pager.commit().unwrap();
+        assert_eq!(pager.transaction_state(), TransactionState::None);
+        assert!(!Path::new(&journal_path).exists());
+
+        let mut pager = open_pager(&path, OpenAccess::ReadOnly).unwrap();
+        assert_filled(&mut pager, 2, 2);
+    }
+
+    #[test]
+    fn rollback_restores_pages_and_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.db");
+        let mut pager = open_pager(&path, OpenAccess::ReadWriteCreate).unwrap();
+        fill_pages(&mut pager, 2, 1);
+        pager.commit().unwrap();
+        let header = pager.header().clone();
+
+        fill_pages(&mut pager, 4, 2);
+        assert_eq!(pager.page_count(), 5);
+        pager.rollback().unwrap();
+        assert_eq!(pager.page_count(), 3);
+        assert_eq!(pager.header(), &header);
+        assert_eq!(pager.dirty_pages().count(), 0);
+        assert_filled(&mut pager, 2, 1);
+        assert!(!Path::new(&pager.journal_path()).exists());
+    }
+
+    /// Commit a transaction and crash during a second one that grows the database from 4 to
+    /// 5 pages, leaving `crashed.db` and its hot journal.  Returns the path and the committed
+    /// contents.
+    fn crash_during_commit(dir: &Path) -> (PathBuf, Vec<u8>) {
+        let path = dir.join("a.db");
+        let mut pager = open_pager(&path, OpenAccess::ReadWriteCreate).unwrap();
+        fill_pages(&mut pager, 3, 1);
+        pager.commit().unwrap();
+        let committed = std::fs::read(&path).unwrap();
+
+        fill_pages(&mut pager, 4, 2);
+        pager.commit_phase_one().unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 5 * 4096);
+
+        // what a crash right now would leave on disk
+        let crashed = dir.join("crashed.db");
+        std::fs::copy(&path, &crashed).unwrap();
+        std::fs::copy(pager.journal_path(), dir.join("crashed.db-journal")).unwrap();
+
+        pager.rollback().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), committed);
+        assert_filled(&mut pager, 3, 1);
+        assert_eq!(pager.page_count(), 4);
+        (crashed, committed)
+    }
+
+    #[test]
+    fn crash_after_phase_one_leaves_hot_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let (crashed, committed) = crash_during_commit(dir.path());
+        let journal_path = format!("{}-journal", crashed.to_str().unwrap());
+        let journal = std::fs::read(&journal_path).unwrap();
+        assert_eq!(&journal[..8], &JOURNAL_MAGIC);
+
+        let vfs = UnixVfs::new();
+        let options = ConnectionOptions::default();
+        let flags = OpenFlags::new(OpenAccess::ReadWrite, FileKind::MainDb);
+        let mut db = vfs
+            .open(crashed.to_str().unwrap(), flags, &options)
+            .unwrap();
+        let mut journal = vfs.open(&journal_path, flags, &options).unwrap();
+        assert_eq!(playback(journal.as_mut(), db.as_mut()).unwrap(), Some(4));
+        assert_eq!(std::fs::read(&crashed).unwrap(), committed);
+    }
+
+    #[test]
+    fn open_rolls_back_hot_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let (crashed, committed) = crash_during_commit(dir.path());
+        let journal_path = format!("{}-journal", crashed.to_str().unwrap());
+
+        // a read only connection cannot roll back
+        assert!(matches!(
+            open_pager(&crashed, OpenAccess::ReadOnly),
+            Err(SqliteError::ReadOnly {
+                code: ExtendedResultCode::ReadOnlyRollback,
+                ..
+            })
+        ));
+        assert!(Path::new(&journal_path).exists());
+
+        let mut pager = open_pager(&crashed, OpenAccess::ReadWrite).unwrap();
+        assert!(!Path::new(&journal_path).exists());
+        assert_eq!(pager.page_count(), 4);
+        assert_filled(&mut pager, 3, 1);
+        let restored = std::fs::read(&crashed).unwrap();
+        assert_eq!(restored.len(), committed.len());
+        // only the change counter differs
+        assert_eq!(&restored[100..], &committed[100..]);
+        let header = SqliteHeader::from_buffer(&Bytes::from(committed)).unwrap();
+        assert_eq!(
+            pager.header().file_change_counter(),
+            header.file_change_counter() + 1
+        );
+        assert!(pager.header().is_size_in_pages_valid());
+    }
+
+    #[test]
+    fn begin_read_rolls_back_hot_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let (crashed, _) = crash_during_commit(dir.path());
+        let journal_path = format!("{}-journal", crashed.to_str().unwrap());
+        let path = dir.path().join("b.db");
+        std::fs::copy(&crashed, &path).unwrap();
+        let mut pager = open_pager(&path, OpenAccess::ReadWrite).unwrap();
+        pager.set_journal_mode(JournalMode::Memory).unwrap();
+        assert_filled(&mut pager, 4, 2);
+
+        // the crash happens while this pager holds no lock
+        std::fs::copy(&journal_path, format!("{}-journal", path.to_str().unwrap())).unwrap();
+        assert_eq!(pager.page_count(), 5);
+        pager.begin_read().unwrap();
+        assert_eq!(pager.page_count(), 4);
+        assert_filled(&mut pager, 3, 1);
+        // the journal is deleted even though this pager keeps its own in memory
+        assert!(!Path::new(&format!("{}-journal", path.to_str().unwrap())).exists());
+    }
+
+    #[test]
+    fn journal_modes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.db");
+        let mut pager = open_pager(&path, OpenAccess::ReadWriteCreate).unwrap();
+        let journal_path = pager.journal_path();
+
+        pager.set_journal_mode(JournalMode::Truncate).unwrap();
+        fill_pages(&mut pager, 1, 1);
+        pager.commit().unwrap();
+        assert_eq!(std::fs::metadata(&journal_path).unwrap().len(), 0);
+
+        pager.set_journal_mode(JournalMode::Persist).unwrap();
+        fill_pages(&mut pager, 1, 2);
+        pager.commit().unwrap();
+        let journal = std::fs::read(&journal_path).unwrap();
+        assert!(journal.len() > 4096);
+        assert!(journal[..28].iter().all(|b| *b == 0));
+
+        // a persisted journal is reused by the next transaction
+        fill_pages(&mut pager, 1, 3);
+        pager.rollback().unwrap();
+        assert_filled(&mut pager, 1, 2);
+
+        std::fs::remove_file(&journal_path).unwrap();
+        for mode in [JournalMode::Memory, JournalMode::Off] {
+            pager.set_journal_mode(mode).unwrap();
+            fill_pages(&mut pager, 1, 4);
+            pager.commit().unwrap();
+            assert!(!Path::new(&journal_path).exists());
+        }
+
+        fill_pages(&mut pager, 1, 5);
+        assert!(matches!(
+            pager.set_journal_mode(JournalMode::Delete),
+            Err(SqliteError::Error { .. })
+        ));
+    }
+
+    #[test]
+    fn memory_journal_rolls_back_written_pages() {
+        let mut pager = memory_pager(DEFAULT_PAGE_SIZE, AutoVacuum::None);
+        assert_eq!(pager.journal_mode(), JournalMode::Memory);
+        fill_pages(&mut pager, 2, 1);
+        pager.commit().unwrap();
+        fill_pages(&mut pager, 2, 2);
+        pager.commit_phase_one().unwrap();
+        pager.rollback().unwrap();
+        assert_filled(&mut pager, 2, 1);
+    }
+
+    #[test]
+    fn commit_waits_for_readers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.db");
+        let mut writer = open_pager(&path, OpenAccess::ReadWriteCreate).unwrap();
+        let mut reader = open_pager(&path, OpenAccess::ReadOnly).unwrap();
+        let mut other = open_pager(&path, OpenAccess::ReadWrite).unwrap();
+        reader.begin_read().unwrap();
+        fill_pages(&mut writer, 1, 1);
+        assert!(matches!(
+            writer.commit(),
+            Err(SqliteError::Busy {
+                code: ExtendedResultCode::Busy,
+                ..
+            })
+        ));
+        // only one writer at a time
+        assert!(other.begin_write().is_err());
+        reader.end_read().unwrap();
+        writer.commit().unwrap();
+
+        // the reader notices the change and drops its stale pages
+        assert_eq!(reader.page_count(), 1);
+        reader.begin_read().unwrap();
+        assert_eq!(reader.page_count(), 2);
+        assert_filled(&mut reader, 1, 1);
+    }
+
+    #[test]
+    fn wal_commits_leave_database_file_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.db");
+        let mut pager = open_pager(&path, OpenAccess::ReadWriteCreate).unwrap();
+        fill_pages(&mut pager, 2, 1);
+        pager.commit().unwrap();
+        pager.set_journal_mode(JournalMode::Wal).unwrap();
+        assert_eq!(pager.journal_mode(), JournalMode::Wal);
+        assert_eq!(
+            pager.header().file_format_write_version(),
+            FileFormatWriteVersion::Wal
+        );
+        let before = std::fs::read(&path).unwrap();
+        assert_eq!(before[18..20], [2, 2]);
+
+        fill_pages(&mut pager, 3, 2);
+        pager.commit().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+        assert!(!Path::new(&pager.journal_path()).exists());
+        // pages 2 to 4 and page 1 for the new size
+        assert_eq!(pager.wal().unwrap().max_frame(), 4);
+        assert_filled(&mut pager, 3, 2);
+
+        let mut pager = open_pager(&path, OpenAccess::ReadOnly).unwrap();
+        assert_eq!(pager.journal_mode(), JournalMode::Wal);
+        assert_eq!(pager.page_count(), 4);
+        assert_eq!(pager.header().size_in_pages(), 4);
+        assert_filled(&mut pager, 3, 2);
+    }
+
+    #[test]
+    fn wal_rollback_discards_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.db");
+        let mut pager = open_pager(&path, OpenAccess::ReadWriteCreate).unwrap();
+        pager.set_journal_mode(JournalMode::Wal).unwrap();
+        fill_pages(&mut pager, 2, 1);
+        pager.commit().unwrap();
+        fill_pages(&mut pager, 3, 2);
+        pager.rollback().unwrap();
+        assert_eq!(pager.page_count(), 3);
+        assert_filled(&mut pager, 2, 1);
+        assert_eq!(pager.wal().unwrap().max_frame(), 3);
+    }
+
+    #[test]
+    fn wal_readers_keep_their_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.db");
+        let mut writer = open_pager(&path, OpenAccess::ReadWriteCreate).unwrap();
+        writer.set_journal_mode(JournalMode::Wal).unwrap();
+        fill_pages(&mut writer, 1, 1);
+        writer.commit().unwrap();
+
+        let mut reader = open_pager(&path, OpenAccess::ReadWrite).unwrap();
+        reader.begin_read().unwrap();
+        assert_filled(&mut reader, 1, 1);
+        // readers do not block a WAL commit
+        fill_pages(&mut writer, 2, 2);
+        writer.commit().unwrap();
+        assert_eq!(reader.page_count(), 2);
+        assert_filled(&mut reader, 1, 1);
+        assert!(matches!(
+            reader.begin_write(),
+            Err(SqliteError::Busy {
+                code: ExtendedResultCode::BusySnapshot,
+                ..
+            })
+        ));
+        assert_eq!(reader.transaction_state(), TransactionState::Read);
+        reader.end_read().unwrap();
+
+        reader.begin_read().unwrap();
+        assert_eq!(reader.page_count(), 3);
+        assert_filled(&mut reader, 2, 2);
+        reader.begin_write().unwrap();
+        assert!(matches!(
+            writer.begin_write(),
+            Err(SqliteError::Busy {
+                code: ExtendedResultCode::Busy,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn other_connection_switching_to_wal_is_noticed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.db");
+        let mut a = open_pager(&path, OpenAccess::ReadWriteCreate).unwrap();
+        let mut b = open_pager(&path, OpenAccess::ReadWrite).unwrap();
+        b.set_journal_mode(JournalMode::Wal).unwrap();
+        fill_pages(&mut b, 1, 7);
+        b.commit().unwrap();
+
+        assert_eq!(a.journal_mode(), JournalMode::Delete);
+        a.begin_read().unwrap();
+        assert_eq!(a.journal_mode(), JournalMode::Wal);
+        assert_eq!(a.page_count(), 2);
+        assert_filled(&mut a, 1, 7);
+    }
+
+    #[test]
+    fn leave_wal_after_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.db");
+        let mut pager = open_pager(&path, OpenAccess::ReadWriteCreate).unwrap();
+        pager.set_journal_mode(JournalMode::Wal).unwrap();
+        pager.set_journal_mode(JournalMode::Truncate).unwrap();
+        assert_eq!(pager.journal_mode(), JournalMode::Truncate);
+        assert!(!Path::new(&pager.wal_path()).exists());
+        assert_eq!(
+            pager.header().file_format_read_version(),
+            FileFormatReadVersion::Legacy
+        );
+        assert_eq!(std::fs::read(&path).unwrap()[18..20], [1, 1]);
+
+        pager.set_journal_mode(JournalMode::Wal).unwrap();
+        fill_pages(&mut pager, 1, 1);
+        pager.commit().unwrap();
+        // not while another connection uses the log
+        let mut reader = open_pager(&path, OpenAccess::ReadOnly).unwrap();
+        reader.begin_read().unwrap();
+        assert!(matches!(
+            pager.set_journal_mode(JournalMode::Delete),
+            Err(SqliteError::Busy { .. })
+        ));
+        assert_eq!(pager.journal_mode(), JournalMode::Wal);
+        reader.end_read().unwrap();
+
+        pager.set_journal_mode(JournalMode::Delete).unwrap();
+        assert!(!Path::new(&pager.wal_path()).exists());
+        let mut pager = open_pager(&path, OpenAccess::ReadOnly).unwrap();
+        assert_eq!(pager.journal_mode(), JournalMode::Delete);
+        assert_eq!(pager.page_count(), 2);
+        assert_filled(&mut pager, 1, 1);
+
+        // private in-memory databases have no log
+        let mut pager = memory_pager(DEFAULT_PAGE_SIZE, AutoVacuum::None);
+        pager.set_journal_mode(JournalMode::Wal).unwrap();
+        assert_eq!(pager.journal_mode(), JournalMode::Memory);
+    }
+
+    #[test]
+    fn wal_index_is_in_shm_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.db");
+        let shm_path = dir.path().join("a.db-shm");
+        let mut writer = open_pager(&path, OpenAccess::ReadWriteCreate).unwrap();
+        writer.set_journal_mode(JournalMode::Wal).unwrap();
+        assert!(!writer.wal().unwrap().has_private_index());
+        fill_pages(&mut writer, 2, 3);
+        writer.commit().unwrap();
+        // the first header copy records the committed frames
+        let shm = std::fs::read(&shm_path).unwrap();
+        assert_eq!(shm[16..20], 3u32.to_ne_bytes());
+
+        let mut reader = open_pager(&path, OpenAccess::ReadWrite).unwrap();
+        assert_eq!(reader.page_count(), 3);
+        assert_filled(&mut reader, 2, 3);
+    }
+
+    #[test]
+    fn exclusive_locking_mode_keeps_locks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.db");
+        let mut a = open_pager(&path, OpenAccess::ReadWriteCreate).unwrap();
+        let mut b = open_pager(&path, OpenAccess::ReadWrite).unwrap();
+        a.set_locking_mode(LockingMode::Exclusive).unwrap();
+        a.begin_read().unwrap();
+        a.end_read().unwrap();
+        // a shared lock still lets others read
+        b.get(1).unwrap();
+        fill_pages(&mut a, 1, 4);
+        a.commit().unwrap();
+        assert!(matches!(b.begin_read(), Err(SqliteError::Busy { .. })));
+        a.set_locking_mode(LockingMode::Normal).unwrap();
+        b.begin_read().unwrap();
+        assert_filled(&mut b, 1, 4);
+    }
+
+    #[test]
+    fn exclusive_locking_mode_wal_uses_private_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.db");
+        let mut pager = open_pager(&path, OpenAccess::ReadWriteCreate).unwrap();
+        pager.set_locking_mode(LockingMode::Exclusive).unwrap();
+        pager.set_journal_mode(JournalMode::Wal).unwrap();
+        assert!(pager.wal().unwrap().has_private_index());
+        fill_pages(&mut pager, 2, 5);
+        pager.commit().unwrap();
+        assert!(!dir.path().join("a.db-shm").exists());
+        assert!(matches!(
+            open_pager(&path, OpenAccess::ReadWrite),
+            Err(SqliteError::Busy { .. })
+        ));
+        // others could not see the log, so the locks stay
+        pager.set_locking_mode(LockingMode::Normal).unwrap();
+        assert_eq!(pager.locking_mode(), LockingMode::Exclusive);
+        assert_filled(&mut pager, 2, 5);
+
+        // the next connection rebuilds a shared wal-index from the log
+        drop(pager);
+        let mut pager = open_pager(&path, OpenAccess::ReadWrite).unwrap();
+        assert!(!pager.wal().unwrap().has_private_index());
+        assert_eq!(pager.page_count(), 3);
+        assert_filled(&mut pager, 2, 5);
+    }
+
+    #[test]
+    fn checkpoint_updates_database_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.db");
+        let mut pager = open_pager(&path, OpenAccess::ReadWriteCreate).unwrap();
+        pager.set_journal_mode(JournalMode::Wal).unwrap();
+        pager.set_wal_autocheckpoint(0);
+        fill_pages(&mut pager, 3, 6);
+        pager.commit().unwrap();
+        let before = std::fs::read(&path).unwrap();
+        let header = SqliteHeader::from_buffer(&Bytes::from(before)).unwrap();
+        assert_eq!(header.size_in_pages(), 1);
+
+        pager.begin_read().unwrap();
+        assert!(matches!(
+            pager.checkpoint(CheckpointMode::Passive),
+            Err(SqliteError::Locked { .. })
+        ));
+        pager.end_read().unwrap();
+        let checkpoint = pager.checkpoint(CheckpointMode::Passive).unwrap().unwrap();
+        assert_eq!(checkpoint.checkpointed_frames, 4);
+        let after = std::fs::read(&path).unwrap();
+        assert_eq!(after.len(), 4 * 4096);
+        let header = SqliteHeader::from_buffer(&Bytes::from(after)).unwrap();
+        assert_eq!(header.size_in_pages(), 4);
+        assert_eq!(
+            header.file_change_counter(),
+            pager.header().file_change_counter()
+        );
+        assert_filled(&mut pager, 3, 6);
+
+        // a read only connection cannot checkpoint
+        let mut reader = open_pager(&path, OpenAccess::ReadOnly).unwrap();
+        assert!(matches!(
+            reader.checkpoint(CheckpointMode::Passive),
+            Err(SqliteError::ReadOnly { .. })
+        ));
+    }
+}
@@ -0,0 +1,192 @@
+use crate::errors::{ExtendedResultCode, SqliteResult};
+use crate::storage::{Page, PageNumber, Pager};
+use crate::vfs::PENDING_BYTE;
+use crate::SqliteError;
+use bytes::BytesMut;
+
+/// Size of a pointer map entry: the page type and the parent page number
+const PTRMAP_ENTRY_SIZE: usize = 5;
+
+/// The page holding the byte sqlite3 locks to signal a pending lock.  It is never used, so the
+/// locks never cover page contents.
+pub fn pending_byte_page(page_size: u32) -> PageNumber {
+    (PENDING_BYTE as u64 / page_size as u64) as PageNumber + 1
+}
+
+/// What a page is used for, as recorded in the pointer map of an auto-vacuum database
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PtrmapType {
+    /// The root page of a b-tree, which has no parent
+    RootPage = 1,
+    /// A page on the freelist
+    FreePage = 2,
+    /// The first overflow page of a cell, whose parent is the b-tree page holding the cell
+    Overflow1 = 3,
+    /// A later overflow page, whose parent is the overflow page before it
+    Overflow2 = 4,
+    /// A b-tree page other than the root, whose parent is the b-tree page above it
+    Btree = 5,
+}
+
+impl TryFrom<u8> for PtrmapType {
+    type Error = SqliteError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(PtrmapType::RootPage),
+            2 => Ok(PtrmapType::FreePage),
+            3 => Ok(PtrmapType::Overflow1),
+            4 => Ok(PtrmapType::Overflow2),
+            5 => Ok(PtrmapType::Btree),
+            _ => Err(SqliteError::Corrupt {
+                code: ExtendedResultCode::Corrupt,
+                message: format!("invalid pointer map entry type: {}", value),
+            }),
+        }
+    }
+}
+
+/// The layout of the pointer map in an auto-vacuum database.  Page 2 is the first pointer map
+/// page and each one holds a 5-byte entry for every page that follows it up to the next.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ptrmap {
+    page_size: u32,
+    usable_size: usize,
+}
+
+impl Ptrmap {
+    pub fn new(page_size: u32, usable_size: usize) -> Ptrmap {
+        Ptrmap {
+            page_size,
+            usable_size,
+        }
+    }
+
+    /// Entries on each pointer map page
+    pub fn entries_per_page(&self) -> u32 {
+        (self.usable_size / PTRMAP_ENTRY_SIZE) as u32
+    }
+
+    /// The pointer map page holding the entry for `page`, or `page` itself when it is a
+    /// pointer map page
+    pub fn map_page(&self, page: PageNumber) -> PageNumber {
+        let pages_per_map = self.entries_per_page() + 1;
+        let map_page = (page - 2) / pages_per_map * pages_per_map + 2;
+        if map_page == pending_byte_page(self.page_size) {
+            map_page + 1
+        } else {
+            map_page
+        }
+    }
+
+    pub fn is_map_page(&self, page: PageNumber) -> bool {
+        page >= 2 && self.map_page(page) == page
+    }
+
+    /// Pages that hold neither content nor free space: pointer map pages and the pending byte
+    /// page
+    pub fn is_reserved(&self, page: PageNumber) -> bool {
+        self.is_map_page(page) || page == pending_byte_page(self.page_size)
+    }
+
+    /// Offset of the entry for `page` in its pointer map page
+    fn entry_offset(&self, page: PageNumber) -> SqliteResult<usize> {
+        let map_page = self.map_page(page);
+        if page < 3 || page <= map_page {
+            return Err(SqliteError::Corrupt {
+                code: ExtendedResultCode::Corrupt,
+                message: format!("page {} has no pointer map entry", page),
+            });
+        }
+        Ok(PTRMAP_ENTRY_SIZE * (page - map_page - 1) as usize)
+    }
+}
+
+impl Pager {
+    /// The pointer map layout, which only means something in an auto-vacuum database
+    pub fn ptrmap(&self) -> Ptrmap {
+        Ptrmap::new(self.page_size().into(), self.usable_size())
+    }
+
+    /// Whether the database keeps a pointer map, as it does in either auto-vacuum mode
+    pub fn has_ptrmap(&self) -> bool {
+        self.header().largest_root_btree_page() != 0
+    }
+
+    /// The type and parent of `page` recorded in the pointer map
+    pub fn ptrmap_get(&mut self, page: PageNumber) -> SqliteResult<(PtrmapType, PageNumber)> {
+        let ptrmap = self.ptrmap();
+        let offset = ptrmap.entry_offset(page)?;
+        let map = self.get(ptrmap.map_page(page))?;
+        let entry = &map.data()[offset..offset + PTRMAP_ENTRY_SIZE];
+        let parent = u32::from_be_bytes(entry[1..].try_into().unwrap());
+        Ok((PtrmapType::try_from(entry[0])?, parent))
+    }
+
+    /// Record the type and parent of `page` in the pointer map.  The b-tree layer calls this
+    /// for every page it links into a tree; freeing a page records it as free.
+    pub fn ptrmap_put(
+        &mut self,
+        page: PageNumber,
+        ptrmap_type: PtrmapType,
+        parent: PageNumber,
+    ) -> SqliteResult<()> {
+        let ptrmap = self.ptrmap();
+        let offset = ptrmap.entry_offset(page)?;
+        let map = self.get(ptrmap.map_page(page))?;
+        let mut entry = [0u8; PTRMAP_ENTRY_SIZE];
+        entry[0] = ptrmap_type as u8;
+        entry[1..].copy_from_slice(&parent.to_be_bytes());
+        if map.data()[offset..offset + PTRMAP_ENTRY_SIZE] == entry {
+            return Ok(());
+        }
+        let number = map.number();
+        let mut data = BytesMut::from(map.into_data().as_ref());
+        data[offset..offset + PTRMAP_ENTRY_SIZE].copy_from_slice(&entry);
+        self.write(Page::new(number, data.freeze()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_pages() {
+        let ptrmap = Ptrmap::new(1024, 1024);
+        assert_eq!(ptrmap.entries_per_page(), 204);
+        assert!(!ptrmap.is_map_page(1));
+        assert!(ptrmap.is_map_page(2));
+        assert_eq!(ptrmap.map_page(3), 2);
+        assert_eq!(ptrmap.map_page(206), 2);
+        assert!(ptrmap.is_map_page(207));
+        assert_eq!(ptrmap.map_page(208), 207);
+        assert_eq!(ptrmap.entry_offset(3).unwrap(), 0);
+        assert_eq!(ptrmap.entry_offset(208).unwrap(), 0);
+        assert_eq!(ptrmap.entry_offset(206).unwrap(), 203 * 5);
+        assert!(ptrmap.entry_offset(207).is_err());
+
+        // reserved space shrinks the map, and the pending byte page is skipped
+        let ptrmap = Ptrmap::new(65536, 65536 - 36);
+        assert_eq!(ptrmap.entries_per_page(), 13100);
+        assert_eq!(pending_byte_page(65536), 16385);
+        assert!(ptrmap.is_reserved(16385));
+        assert!(!ptrmap.is_map_page(16385));
+        let ptrmap = Ptrmap::new(1024, 1024);
+        assert_eq!(pending_byte_page(1024), 1048577);
+        // 1048577 - 2 is a multiple of the 205 pages each map page covers, so the map page
+        // that would be the pending byte page comes right after it
+        assert!(!ptrmap.is_map_page(1048577));
+        assert!(ptrmap.is_map_page(1048578));
+        assert_eq!(ptrmap.map_page(1048579), 1048578);
+    }
+
+    #[test]
+    fn entry_types() {
+        for value in 1..=5u8 {
+            assert_eq!(PtrmapType::try_from(value).unwrap() as u8, value);
+        }
+        assert!(PtrmapType::try_from(0).is_err());
+        assert!(PtrmapType::try_from(6).is_err());
+    }
+}
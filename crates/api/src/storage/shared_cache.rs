@@ -0,0 +1,184 @@
+use crate::errors::{ExtendedResultCode, SqliteResult};
+use crate::storage::{PageNumber, Pager};
+use crate::SqliteError;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, Weak};
+
+/// Identifies a connection among those sharing a cache
+pub type ConnectionId = u64;
+
+/// A fresh id for a connection
+pub fn next_connection_id() -> ConnectionId {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A lock on one table of a shared cache, identified by its root page
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TableLock {
+    Read,
+    Write,
+}
+
+/// The table locks held by connections sharing a cache.  As in sqlite3's shared-cache mode,
+/// any number of connections may read a table, but a connection may only write a table that
+/// no other connection holds a lock on.
+#[derive(Debug, Default)]
+pub struct TableLocks {
+    locks: Vec<(ConnectionId, PageNumber, TableLock)>,
+}
+
+impl TableLocks {
+    /// The strongest lock `connection` holds on `table`
+    pub fn held(&self, connection: ConnectionId, table: PageNumber) -> Option<TableLock> {
+        self.locks
+            .iter()
+            .filter(|(c, t, _)| *c == connection && *t == table)
+            .map(|(_, _, lock)| *lock)
+            .max_by_key(|lock| *lock == TableLock::Write)
+    }
+
+    /// Take a lock on a table, failing with `SQLITE_LOCKED_SHAREDCACHE` when another
+    /// connection holds a conflicting one
+    pub fn lock(
+        &mut self,
+        connection: ConnectionId,
+        table: PageNumber,
+        lock: TableLock,
+    ) -> SqliteResult<()> {
+        let conflict = self.locks.iter().any(|(c, t, held)| {
+            *c != connection
+                && *t == table
+                && (lock == TableLock::Write || *held == TableLock::Write)
+        });
+        if conflict {
+            return Err(SqliteError::Locked {
+                code: ExtendedResultCode::LockedSharedCache,
+                message: format!("database table is locked: table with root page {}", table),
+            });
+        }
+        match self.held(connection, table) {
+            Some(TableLock::Write) => {}
+            Some(TableLock::Read) if lock == TableLock::Read => {}
+            Some(TableLock::Read) => {
+                self.locks
+                    .retain(|(c, t, _)| !(*c == connection && *t == table));
+                self.locks.push((connection, table, lock));
+            }
+            None => self.locks.push((connection, table, lock)),
+        }
+        Ok(())
+    }
+
+    /// Release every table lock held by `connection`, as happens when its transaction ends
+    pub fn unlock_all(&mut self, connection: ConnectionId) {
+        self.locks.retain(|(c, _, _)| *c != connection);
+    }
+}
+
+/// A pager together with the table locks of the connections using it.  A connection with a
+/// private cache is the only user of its `SharedCache`; with `CacheType::Shared`, every
+/// connection in the process that opens the same file uses the same one.
+#[derive(Debug)]
+pub struct SharedCache {
+    pager: Mutex<Pager>,
+    table_locks: Mutex<TableLocks>,
+}
+
+impl SharedCache {
+    pub fn new(pager: Pager) -> Arc<SharedCache> {
+        Arc::new(SharedCache {
+            pager: Mutex::new(pager),
+            table_locks: Mutex::new(TableLocks::default()),
+        })
+    }
+
+    /// Find the cache registered under `key`, or open a pager and register a new one.  The
+    /// cache is unregistered once the last connection using it closes.
+    pub fn open_shared(
+        key: &str,
+        open: impl FnOnce() -> SqliteResult<Pager>,
+    ) -> SqliteResult<Arc<SharedCache>> {
+        static CACHES: OnceLock<Mutex<HashMap<String, Weak<SharedCache>>>> = OnceLock::new();
+        let mut caches = lock(CACHES.get_or_init(Default::default));
+        caches.retain(|_, cache| cache.strong_count() > 0);
+        if let Some(cache) = caches.get(key).and_then(Weak::upgrade) {
+            return Ok(cache);
+        }
+        let cache = SharedCache::new(open()?);
+        caches.insert(String::from(key), Arc::downgrade(&cache));
+        Ok(cache)
+    }
+
+    pub fn pager(&self) -> MutexGuard<'_, Pager> {
+        lock(&self.pager)
+    }
+
+    pub fn lock_table(
+        &self,
+        connection: ConnectionId,
+        table: PageNumber,
+        table_lock: TableLock,
+    ) -> SqliteResult<()> {
+        lock(&self.table_locks).lock(connection, table, table_lock)
+    }
+
+    pub fn unlock_tables(&self, connection: ConnectionId) {
+        lock(&self.table_locks).unlock_all(connection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_locked(result: SqliteResult<()>) -> bool {
+        matches!(
+            result,
+            Err(SqliteError::Locked {
+                code: ExtendedResultCode::LockedSharedCache,
+                ..
+            })
+        )
+    }
+
+    #[test]
+    fn readers_share_a_table() {
+        let mut locks = TableLocks::default();
+        locks.lock(1, 2, TableLock::Read).unwrap();
+        locks.lock(2, 2, TableLock::Read).unwrap();
+        assert!(is_locked(locks.lock(2, 2, TableLock::Write)));
+        // a different table is unaffected
+        locks.lock(2, 3, TableLock::Write).unwrap();
+        assert!(is_locked(locks.lock(1, 3, TableLock::Read)));
+        locks.unlock_all(2);
+        locks.lock(1, 3, TableLock::Read).unwrap();
+        locks.lock(1, 2, TableLock::Write).unwrap();
+    }
+
+    #[test]
+    fn upgrade_and_reentry() {
+        let mut locks = TableLocks::default();
+        locks.lock(1, 2, TableLock::Read).unwrap();
+        locks.lock(1, 2, TableLock::Write).unwrap();
+        assert_eq!(locks.held(1, 2), Some(TableLock::Write));
+        // asking for a weaker lock keeps the stronger one
+        locks.lock(1, 2, TableLock::Read).unwrap();
+        assert_eq!(locks.held(1, 2), Some(TableLock::Write));
+        assert!(is_locked(locks.lock(2, 2, TableLock::Read)));
+        locks.unlock_all(1);
+        assert_eq!(locks.held(1, 2), None);
+    }
+
+    #[test]
+    fn connection_ids_are_unique() {
+        let a = next_connection_id();
+        let b = next_connection_id();
+        assert_ne!(a, b);
+    }
+}
@@ -0,0 +1,722 @@
+use crate::btree::{
+    btree_header_offset, write_btree_page, BtreePage, BtreePageType, MAX_BTREE_DEPTH,
+};
+use crate::connection::ConnectionOptions;
+use crate::database::{
+    FileFormatReadVersion, FileFormatWriteVersion, PageSize, SchemaFormat, MIN_USABLE_PAGE_SIZE,
+};
+use crate::errors::{ExtendedResultCode, SqliteResult};
+use crate::format::{put_varint, Record, Value};
+use crate::storage::{
+    empty_page_one, AutoVacuum, JournalMode, PageNumber, Pager, TransactionState,
+};
+use crate::vfs::{FileKind, MemoryVfs, OpenAccess, OpenFlags};
+use crate::SqliteError;
+use bytes::Bytes;
+use std::sync::Arc;
+
+/// The column of a sqlite_schema row holding the root page
+const SCHEMA_ROOT_PAGE_COLUMN: usize = 3;
+
+/// How `VACUUM` lays out the rebuilt database.  Settings left as `None` stay as they are.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VacuumOptions {
+    /// The page size, like `PRAGMA page_size` before a `VACUUM`.  A database in WAL mode keeps
+    /// its page size, as in sqlite3.
+    pub page_size: Option<PageSize>,
+    /// Bytes reserved at the end of every page for extensions
+    pub page_reserved_space: Option<u8>,
+    /// Whether the rebuilt database auto-vacuums, which a database holding tables can only
+    /// change this way
+    pub auto_vacuum: Option<AutoVacuum>,
+}
+
+/// An entry of a b-tree: a table row with its rowid, or an index key
+pub(crate) struct BtreeEntry {
+    pub(crate) rowid: Option<i64>,
+    pub(crate) payload: Vec<u8>,
+}
+
+/// The root page a sqlite_schema row names, which is 0 for views and triggers
+fn schema_root_page(record: &[u8]) -> SqliteResult<PageNumber> {
+    let record = Record::decode(Bytes::copy_from_slice(record))?;
+    let root = match record.get(SCHEMA_ROOT_PAGE_COLUMN) {
+        Some(Value::Null) => 0,
+        Some(Value::Integer(root)) => root,
+        _ => -1,
+    };
+    PageNumber::try_from(root).map_err(|_| {
+        SqliteError::new(
+            ExtendedResultCode::Corrupt,
+            format!(
+                "sqlite_schema row has root page {:?}",
+                record.get(SCHEMA_ROOT_PAGE_COLUMN)
+            ),
+        )
+    })
+}
+
+/// The sqlite_schema row with its root page replaced by `root`
+fn with_root_page(
+    record: &[u8],
+    root: PageNumber,
+    schema_format: SchemaFormat,
+) -> SqliteResult<Vec<u8>> {
+    let mut values: Vec<Value> = Record::decode(Bytes::copy_from_slice(record))?
+        .values()
+        .collect();
+    values[SCHEMA_ROOT_PAGE_COLUMN] = Value::Integer(root as i64);
+    Ok(Record::encode(&values, schema_format).into_bytes().to_vec())
+}
+
+/// A cell of a b-tree being built, without the left child page number of interior cells
+struct BuiltCell {
+    child: PageNumber,
+    body: Vec<u8>,
+    rowid: Option<i64>,
+}
+
+/// The cells of the page being filled at one level of a b-tree being built
+#[derive(Default)]
+struct Level {
+    cells: Vec<BuiltCell>,
+    /// Bytes the cells and their cell pointers take
+    used: usize,
+}
+
+/// Builds a b-tree bottom up from entries in key order, filling each page before starting
+/// the next.  When a page is full it is written out and the divider between it and the next
+/// page goes up a level: a copy of the last rowid in a table, or the last entry itself in an
+/// index, where interior cells hold entries that are in no leaf.
+pub(crate) struct BtreeBuilder {
+    root: PageNumber,
+    table: bool,
+    levels: Vec<Level>,
+}
+
+impl BtreeBuilder {
+    pub(crate) fn new(root: PageNumber, table: bool) -> BtreeBuilder {
+        BtreeBuilder {
+            root,
+            table,
+            levels: vec![Level::default()],
+        }
+    }
+
+    fn page_type(&self, level: usize) -> BtreePageType {
+        match (self.table, level == 0) {
+            (true, true) => BtreePageType::TableLeaf,
+            (true, false) => BtreePageType::TableInterior,
+            (false, true) => BtreePageType::IndexLeaf,
+            (false, false) => BtreePageType::IndexInterior,
+        }
+    }
+
+    /// The space a cell takes on a page: sqlite3 never makes a cell smaller than 4 bytes
+    fn cell_size(level: usize, cell: &BuiltCell) -> usize {
+        let size = cell.body.len() + if level > 0 { 4 } else { 0 };
+        size.max(4) + 2
+    }
+
+    pub(crate) fn add(&mut self, pager: &mut Pager, entry: BtreeEntry) -> SqliteResult<()> {
+        let mut body = Vec::new();
+        put_varint(&mut body, entry.payload.len() as u64);
+        if let Some(rowid) = entry.rowid {
+            put_varint(&mut body, rowid as u64);
+        }
+        pager.write_payload(self.page_type(0), &entry.payload, &mut body)?;
+        let cell = BuiltCell {
+            child: 0,
+            body,
+            rowid: entry.rowid,
+        };
+        self.push(pager, 0, cell)
+    }
+
+    fn push(&mut self, pager: &mut Pager, level: usize, cell: BuiltCell) -> SqliteResult<()> {
+        if level == self.levels.len() {
+            self.levels.push(Level::default());
+        }
+        let size = BtreeBuilder::cell_size(level, &cell);
+        let capacity = pager.usable_size() - self.page_type(level).header_size();
+        if self.levels[level].used + size > capacity {
+            let (number, divider) = self.flush(pager, level)?;
+            self.push(
+                pager,
+                level + 1,
+                BuiltCell {
+                    child: number,
+                    ..divider
+                },
+            )?;
+        }
+        self.levels[level].used += size;
+        self.levels[level].cells.push(cell);
+        Ok(())
+    }
+
+    /// Write out the full page at `level`, returning its page number and the divider that
+    /// goes up a level
+    fn flush(&mut self, pager: &mut Pager, level: usize) -> SqliteResult<(PageNumber, BuiltCell)> {
+        let mut cells = std::mem::take(&mut self.levels[level].cells);
+        self.levels[level].used = 0;
+        let (divider, right_child) = if level == 0 && self.table {
+            let rowid = cells.last().and_then(|cell| cell.rowid).unwrap_or_default();
+            let mut body = Vec::new();
+            put_varint(&mut body, rowid as u64);
+            let divider = BuiltCell {
+                child: 0,
+                body,
+                rowid: Some(rowid),
+            };
+            (divider, None)
+        } else {
+            // the last cell goes up, leaving its child as the right-most child
+            let last = cells.pop().expect("a full page has cells");
+            let right_child = (level > 0).then_some(last.child);
+            (last, right_child)
+        };
+        let number = pager.allocate()?.number();
+        self.write_page(pager, number, level, &cells, right_child)?;
+        Ok((number, divider))
+    }
+
+    /// Write out the pages still being filled, the top one as the root
+    pub(crate) fn finish(mut self, pager: &mut Pager) -> SqliteResult<()> {
+        let top = self.levels.len() - 1;
+        let mut right_child = None;
+        for level in 0..top {
+            let cells = std::mem::take(&mut self.levels[level].cells);
+            let number = pager.allocate()?.number();
+            self.write_page(pager, number, level, &cells, right_child)?;
+            right_child = Some(number);
+        }
+        let cells = std::mem::take(&mut self.levels[top].cells);
+        let root_capacity = pager.usable_size()
+            - btree_header_offset(self.root)
+            - self.page_type(top).header_size();
+        if self.levels[top].used <= root_capacity {
+            return self.write_page(pager, self.root, top, &cells, right_child);
+        }
+        // only page 1 can be too small, and like sqlite3 its cells then go down to a new
+        // page that the root points to
+        let number = pager.allocate()?.number();
+        self.write_page(pager, number, top, &cells, right_child)?;
+        self.write_page(pager, self.root, top + 1, &[], Some(number))
+    }
+
+    fn write_page(
+        &self,
+        pager: &mut Pager,
+        number: PageNumber,
+        level: usize,
+        cells: &[BuiltCell],
+        right_child: Option<PageNumber>,
+    ) -> SqliteResult<()> {
+        let cells: Vec<Vec<u8>> = cells
+            .iter()
+            .map(|cell| {
+                let mut bytes = Vec::with_capacity(4 + cell.body.len());
+                if level > 0 {
+                    bytes.extend_from_slice(&cell.child.to_be_bytes());
+                }
+                bytes.extend_from_slice(&cell.body);
+                bytes
+            })
+            .collect();
+        write_btree_page(pager, number, self.page_type(level), &cells, right_child)
+    }
+}
+
+impl Pager {
+    /// Visit the entries of the b-tree rooted at page `number` in key order
+    fn walk_btree(
+        &mut self,
+        number: PageNumber,
+        depth: usize,
+        visit: &mut dyn FnMut(BtreeEntry) -> SqliteResult<()>,
+    ) -> SqliteResult<()> {
+        if depth > MAX_BTREE_DEPTH {
+            return Err(SqliteError::new(
+                ExtendedResultCode::Corrupt,
+                format!(
+                    "b-tree page {} is more than {} levels deep",
+                    number, MAX_BTREE_DEPTH
+                ),
+            ));
+        }
+        let data = self.get(number)?.into_data();
+        let page = BtreePage::decode(number, data, &self.payload_limits())?;
+        for cell in page.cells() {
+            if let Some(child) = cell.left_child {
+                self.walk_btree(child, depth + 1, visit)?;
+            }
+            if page.page_type() != BtreePageType::TableInterior {
+                let payload = self.read_payload(&page, cell)?.to_vec();
+                visit(BtreeEntry {
+                    rowid: cell.rowid,
+                    payload,
+                })?;
+            }
+        }
+        if let Some(right_child) = page.header().right_child {
+            self.walk_btree(right_child, depth + 1, visit)?;
+        }
+        Ok(())
+    }
+
+    /// Copy every b-tree into a new in-memory database with the layout `options` asks for,
+    /// returning its pager in the write transaction that built it
+    fn rebuild(&mut self, options: VacuumOptions) -> SqliteResult<Pager> {
+        let page_size = options.page_size.unwrap_or(self.page_size());
+        let page_reserved_space = options
+            .page_reserved_space
+            .unwrap_or(self.header().page_reserved_space());
+        let size: u32 = page_size.into();
+        if size.saturating_sub(page_reserved_space as u32) < MIN_USABLE_PAGE_SIZE {
+            return Err(SqliteError::Misuse {
+                code: ExtendedResultCode::Misuse,
+                message: format!(
+                    "reserving {} bytes of {} byte pages leaves too few usable bytes",
+                    page_reserved_space, size
+                ),
+            });
+        }
+        let auto_vacuum = options.auto_vacuum.unwrap_or(self.auto_vacuum_mode());
+        let mut header = self.header().vacuumed(page_size, page_reserved_space);
+        header.set_auto_vacuum(
+            (auto_vacuum != AutoVacuum::None) as u32,
+            auto_vacuum == AutoVacuum::Incremental,
+        );
+
+        let mut rebuilt = Pager::open(
+            Arc::new(MemoryVfs::new()),
+            "",
+            OpenFlags::new(OpenAccess::ReadWriteCreate, FileKind::MainDb),
+            &ConnectionOptions::default(),
+        )?;
+        rebuilt.set_journal_mode(JournalMode::Off)?;
+        rebuilt.replace_database(1, |_| empty_page_one(&header))?;
+
+        // sqlite_schema names the root page of every other b-tree, which all move
+        let mut schema = Vec::new();
+        self.walk_btree(1, 0, &mut |entry| {
+            schema.push(entry);
+            Ok(())
+        })?;
+        for entry in schema.iter_mut() {
+            let root = schema_root_page(&entry.payload)?;
+            if root == 0 {
+                continue;
+            }
+            let new_root = rebuilt.allocate_root_page()?.number();
+            entry.payload = with_root_page(
+                &entry.payload,
+                new_root,
+                header.schema_format().unwrap_or(SchemaFormat::V4),
+            )?;
+            let page_type = self.get(root)?.data()[btree_header_offset(root)];
+            let table = BtreePageType::try_from(page_type)?.is_table();
+            let mut builder = BtreeBuilder::new(new_root, table);
+            self.walk_btree(root, 0, &mut |entry| builder.add(&mut rebuilt, entry))?;
+            builder.finish(&mut rebuilt)?;
+        }
+        let mut builder = BtreeBuilder::new(1, true);
+        for entry in schema {
+            builder.add(&mut rebuilt, entry)?;
+        }
+        builder.finish(&mut rebuilt)?;
+        Ok(rebuilt)
+    }
+
+    /// Rebuild the database, like `VACUUM`: every b-tree is copied into a fresh database that
+    /// then replaces this one, so the pages of each b-tree end up full and in order and no
+    /// page is free.  The page size, reserved space and auto-vacuum mode can change on the
+    /// way.  Runs in a write transaction of its own, which commits like any other, and fails
+    /// with `SQLITE_ERROR` within a write transaction.
+    pub fn vacuum(&mut self, options: VacuumOptions) -> SqliteResult<()> {
+        if self.transaction_state() == TransactionState::Write {
+            return Err(SqliteError::Error {
+                code: ExtendedResultCode::Error,
+                message: String::from("cannot VACUUM from within a transaction"),
+            });
+        }
+        self.in_write_transaction(|pager| {
+            let mut options = options;
+            if pager.wal().is_some() {
+                options.page_size = None;
+            }
+            let mut rebuilt = pager.rebuild(options)?;
+            pager.replace_database(rebuilt.page_count(), |number| {
+                Ok(rebuilt.get(number)?.into_data())
+            })
+        })
+    }
+
+    /// Write a rebuilt copy of the database to a new file at `path`, like `VACUUM INTO`,
+    /// leaving this database alone.  The copy is a rollback journal database even if this one
+    /// is in WAL mode.  Fails with `SQLITE_ERROR` if the file exists and is not empty.
+    pub fn vacuum_into(&mut self, path: &str, options: VacuumOptions) -> SqliteResult<()> {
+        let flags = OpenFlags::new(OpenAccess::ReadWriteCreate, FileKind::MainDb);
+        let output_options = ConnectionOptions::default();
+        if self.vfs().open(path, flags, &output_options)?.file_size()? > 0 {
+            return Err(SqliteError::Error {
+                code: ExtendedResultCode::Error,
+                message: String::from("output file already exists"),
+            });
+        }
+        // the copy comes from one snapshot
+        let reading = self.transaction_state() == TransactionState::None;
+        if reading {
+            self.begin_read()?;
+        }
+        let rebuilt = self.rebuild(options);
+        if reading {
+            self.end_read()?;
+        }
+        let mut rebuilt = rebuilt?;
+        rebuilt.update_header(|header| {
+            header.set_file_format_versions(
+                FileFormatWriteVersion::Legacy,
+                FileFormatReadVersion::Legacy,
+            )
+        })?;
+
+        let mut output = Pager::open(self.vfs(), path, flags, &output_options)?;
+        // a new file has nothing to roll back to
+        output.set_journal_mode(JournalMode::Off)?;
+        output.in_write_transaction(|output| {
+            output.replace_database(rebuilt.page_count(), |number| {
+                Ok(rebuilt.get(number)?.into_data())
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{memory_pager, test_pager, Page, PtrmapType, DEFAULT_PAGE_SIZE};
+    use crate::vfs::Vfs;
+
+    /// A record of integers and texts, texts given as `Err`
+    fn record(values: &[Result<i64, &str>]) -> Vec<u8> {
+        let values: Vec<Value> = values
+            .iter()
+            .map(|value| match value {
+                Ok(integer) => Value::Integer(*integer),
+                Err(text) => Value::Text(Bytes::copy_from_slice(text.as_bytes())),
+            })
+            .collect();
+        Record::encode(&values, SchemaFormat::V4)
+            .into_bytes()
+            .to_vec()
+    }
+
+    fn schema_row(kind: &str, name: &str, root: PageNumber) -> Vec<u8> {
+        record(&[
+            Err(kind),
+            Err(name),
+            Err(name),
+            Ok(root as i64),
+            Err("CREATE ..."),
+        ])
+    }
+
+    /// Rows `0..rows` of a table where every seventh row spills to overflow pages, and an
+    /// index on it
+    fn rows(rows: i64) -> Vec<BtreeEntry> {
+        (0..rows)
+            .map(|rowid| BtreeEntry {
+                rowid: Some(rowid),
+                payload: vec![rowid as u8; if rowid % 7 == 0 { 5000 } else { 40 }],
+            })
+            .collect()
+    }
+
+    fn index(keys: i64) -> Vec<BtreeEntry> {
+        (0..keys)
+            .map(|key| BtreeEntry {
+                rowid: None,
+                payload: record(&[Ok(key), Err("key")]),
+            })
+            .collect()
+    }
+
+    /// Fill the empty database with a table `t`, an index `i` and a view, leaving some free
+    /// pages behind
+    fn fill(pager: &mut Pager) {
+        pager
+            .in_write_transaction(|pager| {
+                let mut schema = Vec::new();
+                for (name, table, entries) in [("t", true, rows(500)), ("i", false, index(2000))] {
+                    let root = pager.allocate_root_page()?.number();
+                    let kind = if table { "table" } else { "index" };
+                    schema.push(BtreeEntry {
+                        rowid: Some(schema.len() as i64 + 1),
+                        payload: schema_row(kind, name, root),
+                    });
+                    let mut builder = BtreeBuilder::new(root, table);
+                    for entry in entries {
+                        builder.add(pager, entry)?;
+                    }
+                    builder.finish(pager)?;
+                }
+                schema.push(BtreeEntry {
+                    rowid: Some(3),
+                    payload: schema_row("view", "v", 0),
+                });
+                let mut builder = BtreeBuilder::new(1, true);
+                for entry in schema {
+                    builder.add(pager, entry)?;
+                }
+                builder.finish(pager)?;
+                for _ in 0..10 {
+                    let number = pager.append_page()?.number();
+                    pager.free_page(number)?;
+                }
+                // user_version 7 and application_id 99
+                let mut data = pager.get(1)?.data().to_vec();
+                data[60..64].copy_from_slice(&7i32.to_be_bytes());
+                data[68..72].copy_from_slice(&99i32.to_be_bytes());
+                pager.write(Page::new(1, Bytes::from(data)))
+            })
+            .unwrap();
+    }
+
+    type Entries = Vec<(Option<i64>, Vec<u8>)>;
+
+    fn entries(pager: &mut Pager, root: PageNumber) -> Entries {
+        let mut entries = Vec::new();
+        pager
+            .walk_btree(root, 0, &mut |entry| {
+                entries.push((entry.rowid, entry.payload));
+                Ok(())
+            })
+            .unwrap();
+        entries
+    }
+
+    /// The root page of each schema entry with the entries of its b-tree
+    fn contents(pager: &mut Pager) -> Vec<(PageNumber, Entries)> {
+        let mut schema = Vec::new();
+        pager
+            .walk_btree(1, 0, &mut |entry| {
+                schema.push(entry);
+                Ok(())
+            })
+            .unwrap();
+        let mut contents = Vec::new();
+        for entry in schema {
+            let root = schema_root_page(&entry.payload).unwrap();
+            let entries = if root == 0 {
+                Vec::new()
+            } else {
+                entries(pager, root)
+            };
+            contents.push((root, entries));
+        }
+        contents
+    }
+
+    fn without_roots(contents: Vec<(PageNumber, Entries)>) -> Vec<Entries> {
+        contents.into_iter().map(|(_, entries)| entries).collect()
+    }
+
+    #[test]
+    fn root_page_column() {
+        let row = schema_row("table", "t", 2);
+        assert_eq!(schema_root_page(&row).unwrap(), 2);
+        for root in [0, 5, 300, 70000, 0x0100_0000] {
+            let rewritten = with_root_page(&row, root, SchemaFormat::V4).unwrap();
+            assert_eq!(schema_root_page(&rewritten).unwrap(), root);
+            let rewritten = Record::decode(Bytes::from(rewritten)).unwrap();
+            assert_eq!(rewritten.len(), 5);
+            assert_eq!(
+                rewritten.get(4),
+                Some(Value::Text(Bytes::from_static(b"CREATE ...")))
+            );
+        }
+        assert!(matches!(
+            schema_root_page(&record(&[Err("table"), Err("t"), Err("t"), Ok(-1)])),
+            Err(SqliteError::Corrupt { .. })
+        ));
+        assert!(matches!(
+            schema_root_page(&row[..row.len() - 1]),
+            Err(SqliteError::Corrupt { .. })
+        ));
+    }
+
+    #[test]
+    fn vacuum_changes_page_size() {
+        let mut pager = memory_pager(DEFAULT_PAGE_SIZE, AutoVacuum::None);
+        fill(&mut pager);
+        let before = contents(&mut pager);
+        let page_count = pager.page_count();
+        assert_eq!(pager.freelist_stats().unwrap().free_pages(), 10);
+
+        pager
+            .vacuum(VacuumOptions {
+                page_size: Some(PageSize::Size1024),
+                page_reserved_space: Some(16),
+                auto_vacuum: None,
+            })
+            .unwrap();
+        assert_eq!(pager.page_size(), PageSize::Size1024);
+        assert_eq!(pager.header().page_reserved_space(), 16);
+        assert_eq!(pager.header().user_version(), 7);
+        assert_eq!(pager.header().application_id(), 99);
+        assert_eq!(pager.freelist_stats().unwrap().free_pages(), 0);
+        assert!(pager.page_count() > page_count);
+        assert_eq!(
+            without_roots(contents(&mut pager)),
+            without_roots(before.clone())
+        );
+
+        // and back, which leaves the database as small as it was without its free pages
+        pager
+            .vacuum(VacuumOptions {
+                page_size: Some(PageSize::Size4096),
+                page_reserved_space: Some(0),
+                auto_vacuum: None,
+            })
+            .unwrap();
+        assert_eq!(pager.page_count(), page_count - 10);
+        assert_eq!(contents(&mut pager), before);
+    }
+
+    #[test]
+    fn vacuum_switches_auto_vacuum() {
+        let mut pager = memory_pager(DEFAULT_PAGE_SIZE, AutoVacuum::None);
+        fill(&mut pager);
+        let before = without_roots(contents(&mut pager));
+        pager
+            .vacuum(VacuumOptions {
+                auto_vacuum: Some(AutoVacuum::Incremental),
+                ..VacuumOptions::default()
+            })
+            .unwrap();
+        assert_eq!(pager.auto_vacuum().unwrap(), AutoVacuum::Incremental);
+        let after = contents(&mut pager);
+        let roots: Vec<_> = after.iter().map(|(root, _)| *root).collect();
+        // the pointer map takes page 2
+        assert_eq!(roots, vec![3, 4, 0]);
+        assert_eq!(pager.header().largest_root_btree_page(), 4);
+        for root in [3, 4] {
+            assert_eq!(pager.ptrmap_get(root).unwrap(), (PtrmapType::RootPage, 0));
+        }
+        assert_eq!(without_roots(after), before);
+
+        // every page is where the pointer map says, so pages can move
+        let page_count = pager.page_count();
+        pager.free_page(page_count - 1).unwrap();
+        pager.commit().unwrap();
+        assert_eq!(pager.incremental_vacuum(0).unwrap(), 1);
+
+        pager
+            .vacuum(VacuumOptions {
+                auto_vacuum: Some(AutoVacuum::None),
+                ..VacuumOptions::default()
+            })
+            .unwrap();
+        assert_eq!(pager.auto_vacuum().unwrap(), AutoVacuum::None);
+        assert_eq!(pager.header().largest_root_btree_page(), 0);
+    }
+
+    #[test]
+    fn rollback_restores_page_size() {
+        let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+        let mut pager = test_pager(vfs.clone(), "a.db");
+        fill(&mut pager);
+        let before = contents(&mut pager);
+        let page_count = pager.page_count();
+        pager.begin_write().unwrap();
+        let mut rebuilt = pager
+            .rebuild(VacuumOptions {
+                page_size: Some(PageSize::Size512),
+                ..VacuumOptions::default()
+            })
+            .unwrap();
+        pager
+            .replace_database(rebuilt.page_count(), |number| {
+                Ok(rebuilt.get(number)?.into_data())
+            })
+            .unwrap();
+        assert_eq!(pager.page_size(), PageSize::Size512);
+        pager.rollback().unwrap();
+        assert_eq!(pager.page_size(), PageSize::Size4096);
+        assert_eq!(pager.page_count(), page_count);
+        assert_eq!(contents(&mut pager), before);
+        let mut other = test_pager(vfs, "a.db");
+        assert_eq!(contents(&mut other), before);
+    }
+
+    #[test]
+    fn vacuum_into_leaves_source() {
+        let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+        let mut pager = test_pager(vfs.clone(), "a.db");
+        fill(&mut pager);
+        let before = contents(&mut pager);
+        let page_count = pager.page_count();
+        let options = VacuumOptions {
+            page_size: Some(PageSize::Size2048),
+            ..VacuumOptions::default()
+        };
+        pager.vacuum_into("b.db", options).unwrap();
+        assert_eq!(pager.page_count(), page_count);
+        assert_eq!(pager.page_size(), PageSize::Size4096);
+        assert_eq!(contents(&mut pager), before);
+
+        let mut copy = test_pager(vfs.clone(), "b.db");
+        assert_eq!(copy.page_size(), PageSize::Size2048);
+        assert_eq!(copy.header().user_version(), 7);
+        assert_eq!(copy.freelist_stats().unwrap().free_pages(), 0);
+        assert_eq!(without_roots(contents(&mut copy)), without_roots(before));
+
+        assert!(matches!(
+            pager.vacuum_into("b.db", options),
+            Err(SqliteError::Error { .. })
+        ));
+    }
+
+    #[test]
+    fn vacuum_err() {
+        let mut pager = memory_pager(DEFAULT_PAGE_SIZE, AutoVacuum::None);
+        fill(&mut pager);
+        let before = contents(&mut pager);
+        assert!(matches!(
+            pager.vacuum(VacuumOptions {
+                page_size: Some(PageSize::Size512),
+                page_reserved_space: Some(40),
+                auto_vacuum: None,
+            }),
+            Err(SqliteError::Misuse { .. })
+        ));
+        pager.begin_write().unwrap();
+        assert!(matches!(
+            pager.vacuum(VacuumOptions::default()),
+            Err(SqliteError::Error { .. })
+        ));
+        pager.rollback().unwrap();
+
+        // a corrupt b-tree leaves the database as it was
+        let index_root = before[1].0;
+        pager
+            .in_write_transaction(|pager| {
+                let mut data = pager.get(index_root)?.data().to_vec();
+                data[0] = 1;
+                pager.write(Page::new(index_root, Bytes::from(data)))
+            })
+            .unwrap();
+        assert!(matches!(
+            pager.vacuum(VacuumOptions {
+                page_size: Some(PageSize::Size1024),
+                ..VacuumOptions::default()
+            }),
+            Err(SqliteError::Corrupt { .. })
+        ));
+        assert_eq!(pager.page_size(), PageSize::Size4096);
+        assert_eq!(pager.transaction_state(), TransactionState::None);
+        assert_eq!(entries(&mut pager, before[0].0), before[0].1);
+    }
+}
@@ -0,0 +1,1298 @@
+use crate::connection::ConnectionOptions;
+use crate::errors::{ExtendedResultCode, SqliteResult};
+use crate::storage::{
+    random_nonce, wal_read_lock, PageNumber, WalIndex, WalIndexHeader, READMARK_NOT_USED,
+    WAL_CKPT_LOCK, WAL_NREADER, WAL_WRITE_LOCK,
+};
+use crate::vfs::{
+    AccessFlags, FileKind, OpenAccess, OpenFlags, Shm, ShmLockKind, SyncFlags, Vfs, VfsFile,
+};
+use crate::SqliteError;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// WAL magic when the checksums are computed on little-endian words
+pub const WAL_MAGIC_LE: u32 = 0x377f0682;
+
+/// WAL magic when the checksums are computed on big-endian words
+pub const WAL_MAGIC_BE: u32 = 0x377f0683;
+
+/// The only WAL format version sqlite3 has defined
+pub const WAL_FORMAT_VERSION: u32 = 3007000;
+
+pub const WAL_HEADER_SIZE: usize = 32;
+
+pub const WAL_FRAME_HEADER_SIZE: usize = 24;
+
+/// Suffix appended to the database path to name its write-ahead log
+pub const WAL_SUFFIX: &str = "-wal";
+
+/// Frames in the log that trigger a checkpoint after a commit, as in sqlite3
+pub const DEFAULT_WAL_AUTOCHECKPOINT: u32 = 1000;
+
+/// The cumulative checksum of the WAL format.  `data` is read as pairs of 32-bit words in the
+/// given byte order and its length must be a multiple of 8.
+pub fn wal_checksum(big_endian: bool, data: &[u8], initial: (u32, u32)) -> (u32, u32) {
+    debug_assert!(data.len() % 8 == 0);
+    let (mut s0, mut s1) = initial;
+    for words in data.chunks_exact(8) {
+        let (x0, x1) = if big_endian {
+            (
+                u32::from_be_bytes(words[..4].try_into().unwrap()),
+                u32::from_be_bytes(words[4..].try_into().unwrap()),
+            )
+        } else {
+            (
+                u32::from_le_bytes(words[..4].try_into().unwrap()),
+                u32::from_le_bytes(words[4..].try_into().unwrap()),
+            )
+        };
+        s0 = s0.wrapping_add(x0).wrapping_add(s1);
+        s1 = s1.wrapping_add(x1).wrapping_add(s0);
+    }
+    (s0, s1)
+}
+
+/// The 32-byte header at the start of a WAL file
+#[derive(Clone, Debug, PartialEq)]
+pub struct WalHeader {
+    /// Byte order of the words the checksums are computed on
+    pub big_endian: bool,
+    pub page_size: u32,
+    /// Incremented each time the WAL is restarted by a checkpoint
+    pub checkpoint_sequence: u32,
+    /// Copied into every frame; frames with other salts are left over from before a restart
+    pub salt: (u32, u32),
+    pub checksum: (u32, u32),
+}
+
+impl WalHeader {
+    /// A header for a new WAL, using checksums in the native byte order as sqlite3 does
+    pub fn new(page_size: u32, checkpoint_sequence: u32, salt: (u32, u32)) -> WalHeader {
+        let mut header = WalHeader {
+            big_endian: cfg!(target_endian = "big"),
+            page_size,
+            checkpoint_sequence,
+            salt,
+            checksum: (0, 0),
+        };
+        let mut buf = BytesMut::with_capacity(WAL_HEADER_SIZE);
+        header.write(&mut buf);
+        header.checksum = wal_checksum(header.big_endian, &buf[..24], (0, 0));
+        header
+    }
+
+    pub fn write(&self, buf: &mut BytesMut) {
+        buf.put_u32(if self.big_endian {
+            WAL_MAGIC_BE
+        } else {
+            WAL_MAGIC_LE
+        });
+        buf.put_u32(WAL_FORMAT_VERSION);
+        buf.put_u32(self.page_size);
+        buf.put_u32(self.checkpoint_sequence);
+        buf.put_u32(self.salt.0);
+        buf.put_u32(self.salt.1);
+        buf.put_u32(self.checksum.0);
+        buf.put_u32(self.checksum.1);
+    }
+
+    /// Parse a header, or `None` when the magic, version, page size or checksum is wrong
+    pub fn from_buffer(buf: &[u8]) -> Option<WalHeader> {
+        if buf.len() < WAL_HEADER_SIZE {
+            return None;
+        }
+        let mut fields = &buf[..WAL_HEADER_SIZE];
+        let big_endian = match fields.get_u32() {
+            WAL_MAGIC_LE => false,
+            WAL_MAGIC_BE => true,
+            _ => return None,
+        };
+        if fields.get_u32() != WAL_FORMAT_VERSION {
+            return None;
+        }
+        let header = WalHeader {
+            big_endian,
+            page_size: fields.get_u32(),
+            checkpoint_sequence: fields.get_u32(),
+            salt: (fields.get_u32(), fields.get_u32()),
+            checksum: (fields.get_u32(), fields.get_u32()),
+        };
+        let valid_page_size =
+            (512..=65536).contains(&header.page_size) && header.page_size.is_power_of_two();
+        if !valid_page_size || header.checksum != wal_checksum(big_endian, &buf[..24], (0, 0)) {
+            return None;
+        }
+        Some(header)
+    }
+
+    /// Size of one frame: its header and a page
+    pub fn frame_size(&self) -> u64 {
+        WAL_FRAME_HEADER_SIZE as u64 + self.page_size as u64
+    }
+
+    /// Offset of a frame, counting from 1
+    pub fn frame_offset(&self, frame: u32) -> u64 {
+        frame_offset(self.page_size, frame)
+    }
+}
+
+/// Offset of a frame, counting from 1, in a log of pages of `page_size`
+fn frame_offset(page_size: u32, frame: u32) -> u64 {
+    let frame_size = WAL_FRAME_HEADER_SIZE as u64 + page_size as u64;
+    WAL_HEADER_SIZE as u64 + (frame as u64 - 1) * frame_size
+}
+
+/// The 24-byte header in front of each page in the WAL
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameHeader {
+    pub page_number: PageNumber,
+    /// For the last frame of a transaction, the database size in pages after the commit;
+    /// otherwise zero
+    pub db_size: u32,
+    pub salt: (u32, u32),
+    /// Cumulative over every frame header and page up to and including this frame
+    pub checksum: (u32, u32),
+}
+
+impl FrameHeader {
+    /// The header for `data`, chaining the checksum on from the previous frame or the WAL
+    /// header
+    pub fn new(
+        wal: &WalHeader,
+        page_number: PageNumber,
+        db_size: u32,
+        data: &[u8],
+        previous: (u32, u32),
+    ) -> FrameHeader {
+        let mut fields = BytesMut::with_capacity(8);
+        fields.put_u32(page_number);
+        fields.put_u32(db_size);
+        let checksum = wal_checksum(wal.big_endian, &fields, previous);
+        FrameHeader {
+            page_number,
+            db_size,
+            salt: wal.salt,
+            checksum: wal_checksum(wal.big_endian, data, checksum),
+        }
+    }
+
+    pub fn is_commit(&self) -> bool {
+        self.db_size != 0
+    }
+
+    pub fn write(&self, buf: &mut BytesMut) {
+        buf.put_u32(self.page_number);
+        buf.put_u32(self.db_size);
+        buf.put_u32(self.salt.0);
+        buf.put_u32(self.salt.1);
+        buf.put_u32(self.checksum.0);
+        buf.put_u32(self.checksum.1);
+    }
+
+    pub fn from_buffer(mut buf: &[u8]) -> FrameHeader {
+        FrameHeader {
+            page_number: buf.get_u32(),
+            db_size: buf.get_u32(),
+            salt: (buf.get_u32(), buf.get_u32()),
+            checksum: (buf.get_u32(), buf.get_u32()),
+        }
+    }
+
+    /// Whether this frame follows `previous` in the WAL with the given header: the salts match
+    /// and the checksum covers `data`
+    pub fn is_valid(&self, wal: &WalHeader, data: &[u8], previous: (u32, u32)) -> bool {
+        self.page_number != 0
+            && self.salt == wal.salt
+            && FrameHeader::new(wal, self.page_number, self.db_size, data, previous).checksum
+                == self.checksum
+    }
+}
+
+/// The committed frames a read transaction sees
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WalSnapshot {
+    /// The last frame of the last transaction in the snapshot, or 0 for none
+    pub max_frame: u32,
+    /// Database size in pages as of that transaction
+    pub db_size: u32,
+}
+
+/// How hard a checkpoint tries, like the modes of `PRAGMA wal_checkpoint`.  Nothing waits for
+/// other connections: whatever they hold makes the checkpoint report that it was busy.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CheckpointMode {
+    /// Copy the frames no reader still needs, without taking the write lock
+    #[default]
+    Passive,
+    /// Take the write lock so no transaction is appended meanwhile, and copy every frame
+    Full,
+    /// Like `Full`, then make sure no reader uses the log so the next writer starts it over
+    Restart,
+    /// Like `Restart`, then start the log over right away and truncate it to zero bytes
+    Truncate,
+}
+
+impl TryFrom<&str> for CheckpointMode {
+    type Error = SqliteError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "passive" => Ok(CheckpointMode::Passive),
+            "full" => Ok(CheckpointMode::Full),
+            "restart" => Ok(CheckpointMode::Restart),
+            "truncate" => Ok(CheckpointMode::Truncate),
+            _ => Err(SqliteError::Misuse {
+                code: ExtendedResultCode::Misuse,
+                message: format!("invalid checkpoint mode: {}", value),
+            }),
+        }
+    }
+}
+
+/// What a checkpoint did, like the row `PRAGMA wal_checkpoint` returns
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WalCheckpoint {
+    /// Whether another connection kept the checkpoint from doing all its mode asks for
+    pub busy: bool,
+    /// Committed frames in the log
+    pub log_frames: u32,
+    /// Frames copied into the database file, by this checkpoint or an earlier one
+    pub checkpointed_frames: u32,
+}
+
+fn busy(code: ExtendedResultCode) -> SqliteError {
+    SqliteError::Busy {
+        code,
+        message: String::from("database is locked"),
+    }
+}
+
+/// Attempts at starting a read transaction before giving up with `SQLITE_PROTOCOL`, as in
+/// sqlite3
+const READ_ATTEMPTS: u32 = 100;
+
+/// The write-ahead log of a database in WAL mode.  Committed transactions are appended to the
+/// `-wal` file as frames, each holding a page, and readers look for the newest copy of a page
+/// in the log before reading the database file.
+///
+/// The frames are found through the [`WalIndex`], which every connection to the database
+/// shares.  Frames are only trusted up to the last commit frame whose salts and cumulative
+/// checksum are valid, so a transaction that is still being written, or was cut short by a
+/// crash, is never seen.  Each read transaction works from the wal-index header as it was
+/// when the transaction started and holds a read lock on a read mark no smaller than its last
+/// frame, so frames appended afterwards stay invisible to it and no checkpoint copies them
+/// into the database under it.
+#[derive(Debug)]
+pub struct Wal {
+    path: String,
+    /// `None` when the log does not exist and this connection cannot create it
+    file: Option<Box<dyn VfsFile>>,
+    index: WalIndex,
+    /// Whether the wal-index is on the heap where only this connection sees it
+    private_index: bool,
+    /// The wal-index header as of the current, or the last, read transaction
+    header: WalIndexHeader,
+    /// The read mark whose lock the read transaction holds
+    read_lock: Option<usize>,
+    /// The first frame the read transaction needs, as earlier ones were already copied into
+    /// the database when it started
+    min_frame: u32,
+    /// Whether this connection holds the write lock
+    writing: bool,
+    /// The checkpoint sequence of the log header as of the last recovery
+    checkpoint_sequence: u32,
+}
+
+impl Wal {
+    /// Open the log at `path`, creating it unless `read_only`, with its wal-index in `shm`.  A
+    /// read only connection reads a missing log as an empty one.
+    pub fn open(
+        vfs: &dyn Vfs,
+        path: &str,
+        options: &ConnectionOptions,
+        read_only: bool,
+        shm: Box<dyn Shm>,
+        private_index: bool,
+    ) -> SqliteResult<Wal> {
+        let file = if read_only {
+            if vfs.access(path, AccessFlags::Exists)? {
+                Some(vfs.open(
+                    path,
+                    OpenFlags::new(OpenAccess::ReadOnly, FileKind::Wal),
+                    options,
+                )?)
+            } else {
+                None
+            }
+        } else {
+            Some(vfs.open(
+                path,
+                OpenFlags::new(OpenAccess::ReadWriteCreate, FileKind::Wal),
+                options,
+            )?)
+        };
+        Ok(Wal {
+            path: String::from(path),
+            file,
+            index: WalIndex::new(shm),
+            private_index,
+            header: WalIndexHeader::default(),
+            read_lock: None,
+            min_frame: 1,
+            writing: false,
+            checkpoint_sequence: 0,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The wal-index header as of the current, or the last, read transaction
+    pub fn header(&self) -> &WalIndexHeader {
+        &self.header
+    }
+
+    /// Whether the wal-index is on the heap where other connections cannot see it, so the
+    /// database file must stay locked exclusively
+    pub fn has_private_index(&self) -> bool {
+        self.private_index
+    }
+
+    /// Number of committed frames in the log as of the current, or the last, read
+    /// transaction
+    pub fn max_frame(&self) -> u32 {
+        self.header.max_frame
+    }
+
+    /// Take a wal-index lock, returning `false` when another connection holds a conflicting
+    /// one
+    fn try_lock(&mut self, slot: usize, n: usize, kind: ShmLockKind) -> SqliteResult<bool> {
+        match self.index.lock(slot, n, kind) {
+            Ok(()) => Ok(true),
+            Err(SqliteError::Busy { .. }) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Start a read transaction on the newest committed transaction in the log.  Returns
+    /// whether the log changed since the last read transaction started.
+    pub fn begin_read(&mut self) -> SqliteResult<bool> {
+        let previous = self.header;
+        self.start_read(false)?;
+        Ok(self.header != previous)
+    }
+
+    /// Take a read lock, retrying while other connections get in the way.  With `use_log` the
+    /// read lock is always on a read mark, even when the database file holds every frame.
+    fn start_read(&mut self, use_log: bool) -> SqliteResult<()> {
+        for attempt in 0..READ_ATTEMPTS {
+            if attempt > 5 {
+                std::thread::sleep(Duration::from_micros(u64::from(attempt * attempt)));
+            }
+            if let Some(read_lock) = self.try_begin_read(use_log)? {
+                self.read_lock = Some(read_lock);
+                return Ok(());
+            }
+        }
+        Err(SqliteError::Protocol {
+            code: ExtendedResultCode::Protocol,
+            message: String::from("locking protocol"),
+        })
+    }
+
+    /// One attempt at a read lock.  `None` means another connection changed the wal-index or
+    /// held a lock in the meantime and the attempt should be repeated.
+    fn try_begin_read(&mut self, use_log: bool) -> SqliteResult<Option<usize>> {
+        let Some(header) = self.read_index_header()? else {
+            return Ok(None);
+        };
+        if !use_log && header.max_frame == self.index.backfill()? {
+            // the database file holds everything, so the log can be ignored
+            if !self.try_lock(wal_read_lock(0), 1, ShmLockKind::Shared)? {
+                return Ok(None);
+            }
+            if !self.index.header_unchanged(&header)? {
+                self.index.unlock(wal_read_lock(0), 1)?;
+                return Ok(None);
+            }
+            self.header = header;
+            return Ok(Some(0));
+        }
+
+        let (mut mark, mut reader) = (0, 0);
+        for i in 1..WAL_NREADER {
+            let read_mark = self.index.read_mark(i)?;
+            if read_mark > mark && read_mark <= header.max_frame {
+                (mark, reader) = (read_mark, i);
+            }
+        }
+        if mark < header.max_frame || reader == 0 {
+            for i in 1..WAL_NREADER {
+                if self.try_lock(wal_read_lock(i), 1, ShmLockKind::Exclusive)? {
+                    self.index.set_read_mark(i, header.max_frame)?;
+                    self.index.unlock(wal_read_lock(i), 1)?;
+                    (mark, reader) = (header.max_frame, i);
+                    break;
+                }
+            }
+        }
+        if reader == 0 || !self.try_lock(wal_read_lock(reader), 1, ShmLockKind::Shared)? {
+            return Ok(None);
+        }
+        self.min_frame = self.index.backfill()? + 1;
+        // the mark may have moved, or a writer committed, before the lock was taken
+        if self.index.read_mark(reader)? != mark || !self.index.header_unchanged(&header)? {
+            self.index.unlock(wal_read_lock(reader), 1)?;
+            return Ok(None);
+        }
+        self.header = header;
+        Ok(Some(reader))
+    }
+
+    /// The wal-index header, rebuilding the wal-index from the log when it is not valid.
+    /// `None` when another connection holds the locks needed to rebuild it.
+    fn read_index_header(&mut self) -> SqliteResult<Option<WalIndexHeader>> {
+        if let Some(header) = self.index.read_header()? {
+            return Ok(Some(header));
+        }
+        if !self.writing && !self.try_lock(WAL_WRITE_LOCK, 1, ShmLockKind::Exclusive)? {
+            return Ok(None);
+        }
+        // another connection may have rebuilt it before the lock was granted
+        let header = match self.index.read_header() {
+            Ok(Some(header)) => Ok(Some(header)),
+            Ok(None) => self.recover(),
+            Err(err) => Err(err),
+        };
+        if !self.writing {
+            self.index.unlock(WAL_WRITE_LOCK, 1)?;
+        }
+        header
+    }
+
+    /// Rebuild the wal-index by reading the log, as after a crash or when the first
+    /// connection opens the database.  The caller holds the write lock.
+    fn recover(&mut self) -> SqliteResult<Option<WalIndexHeader>> {
+        if !self.try_lock(WAL_CKPT_LOCK, 2, ShmLockKind::Exclusive)? {
+            return Ok(None);
+        }
+        let header = self.rebuild_index();
+        self.index.unlock(WAL_CKPT_LOCK, 2)?;
+        header.map(Some)
+    }
+
+    fn rebuild_index(&mut self) -> SqliteResult<WalIndexHeader> {
+        let mut header = WalIndexHeader::default();
+        if let Some(file) = self.file.as_mut() {
+            let file_size = file.file_size()?;
+            let mut buf = vec![0u8; WAL_HEADER_SIZE];
+            file.read(&mut buf, 0)?;
+            if let Some(log_header) = WalHeader::from_buffer(&buf) {
+                header.big_endian = log_header.big_endian;
+                header.page_size = log_header.page_size;
+                header.salt = log_header.salt;
+                header.frame_checksum = log_header.checksum;
+                self.checkpoint_sequence = log_header.checkpoint_sequence;
+
+                let frames =
+                    file_size.saturating_sub(WAL_HEADER_SIZE as u64) / log_header.frame_size();
+                let mut buf = vec![0u8; log_header.frame_size() as usize];
+                let mut checksum = log_header.checksum;
+                let mut pending = Vec::new();
+                for frame in 1..=frames as u32 {
+                    file.read(&mut buf, log_header.frame_offset(frame))?;
+                    let (frame_header, data) = buf.split_at(WAL_FRAME_HEADER_SIZE);
+                    let frame_header = FrameHeader::from_buffer(frame_header);
+                    if !frame_header.is_valid(&log_header, data, checksum) {
+                        break;
+                    }
+                    checksum = frame_header.checksum;
+                    pending.push(frame_header.page_number);
+                    if frame_header.is_commit() {
+                        for (i, page) in pending.drain(..).enumerate() {
+                            let frame = header.max_frame + 1 + i as u32;
+                            self.index.append(frame, page, header.max_frame)?;
+                        }
+                        header.max_frame = frame;
+                        header.db_size = frame_header.db_size;
+                        header.frame_checksum = checksum;
+                    }
+                }
+            }
+        }
+        self.index.write_header(&mut header)?;
+        self.index.set_backfill(0)?;
+        self.index.set_backfill_attempted(header.max_frame)?;
+        self.index.set_read_mark(0, 0)?;
+        for i in 1..WAL_NREADER {
+            if self.try_lock(wal_read_lock(i), 1, ShmLockKind::Exclusive)? {
+                let mark = if i == 1 && header.max_frame > 0 {
+                    header.max_frame
+                } else {
+                    READMARK_NOT_USED
+                };
+                self.index.set_read_mark(i, mark)?;
+                self.index.unlock(wal_read_lock(i), 1)?;
+            }
+        }
+        Ok(header)
+    }
+
+    /// End the read transaction, releasing its read lock
+    pub fn end_read(&mut self) -> SqliteResult<()> {
+        if let Some(read_lock) = self.read_lock.take() {
+            self.index.unlock(wal_read_lock(read_lock), 1)?;
+        }
+        Ok(())
+    }
+
+    /// The snapshot of the current read transaction
+    pub fn snapshot(&self) -> Option<WalSnapshot> {
+        self.read_lock.map(|_| WalSnapshot {
+            max_frame: self.header.max_frame,
+            db_size: self.header.db_size,
+        })
+    }
+
+    /// Take the write lock during a read transaction.  Fails with `SQLITE_BUSY` while another
+    /// connection is writing, and with `SQLITE_BUSY_SNAPSHOT` when another connection
+    /// committed since the read transaction started, as it would have to write on top of a
+    /// transaction it never saw.
+    pub fn begin_write(&mut self) -> SqliteResult<()> {
+        debug_assert!(self.read_lock.is_some());
+        if self.writing {
+            return Ok(());
+        }
+        self.index.lock(WAL_WRITE_LOCK, 1, ShmLockKind::Exclusive)?;
+        if !self.index.header_unchanged(&self.header)? {
+            self.index.unlock(WAL_WRITE_LOCK, 1)?;
+            return Err(busy(ExtendedResultCode::BusySnapshot));
+        }
+        self.writing = true;
+        Ok(())
+    }
+
+    /// Release the write lock
+    pub fn end_write(&mut self) -> SqliteResult<()> {
+        if std::mem::take(&mut self.writing) {
+            self.index.unlock(WAL_WRITE_LOCK, 1)?;
+        }
+        Ok(())
+    }
+
+    /// Database size in pages as of the snapshot, when the log holds any transaction
+    pub fn db_size(&self) -> Option<u32> {
+        self.snapshot()
+            .filter(|snapshot| snapshot.max_frame > 0)
+            .map(|snapshot| snapshot.db_size)
+    }
+
+    /// The newest frame holding `page` in the snapshot
+    pub fn find_frame(&mut self, page: PageNumber) -> SqliteResult<Option<u32>> {
+        match self.read_lock {
+            Some(read_lock) if read_lock > 0 => {
+                self.index
+                    .find_frame(page, self.min_frame, self.header.max_frame)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Read the page held in a frame
+    pub fn read_frame(&mut self, frame: u32, buf: &mut [u8]) -> SqliteResult<()> {
+        let offset = frame_offset(self.header.page_size, frame) + WAL_FRAME_HEADER_SIZE as u64;
+        let file = self.file.as_mut().expect("frames imply a file");
+        file.read(buf, offset)?;
+        Ok(())
+    }
+
+    /// Append a transaction: a frame for each page, the last one marked as the commit with the
+    /// new database size, then sync the log and publish the frames in the wal-index.  The
+    /// caller holds the write lock.  An empty log gets a header first, with fresh salts unless
+    /// a checkpoint restarted it.
+    pub fn append(&mut self, pages: &[(PageNumber, Bytes)], db_size: u32) -> SqliteResult<()> {
+        if pages.is_empty() {
+            return Ok(());
+        }
+        let page_size = pages[0].1.len() as u32;
+        if self.file.is_none() {
+            return Err(SqliteError::ReadOnly {
+                code: ExtendedResultCode::ReadOnly,
+                message: String::from("attempt to write a readonly database"),
+            });
+        }
+        debug_assert!(self.writing);
+        if self.read_lock == Some(0) {
+            // every frame is in the database file, so the log starts over unless a reader
+            // still uses it
+            if self.index.backfill()? > 0
+                && self.try_lock(wal_read_lock(1), WAL_NREADER - 1, ShmLockKind::Exclusive)?
+            {
+                let mut header = self.header;
+                let restarted = self.restart(&mut header);
+                self.index.unlock(wal_read_lock(1), WAL_NREADER - 1)?;
+                restarted?;
+                self.header = header;
+            }
+            // the new frames are only read through a read mark
+            self.end_read()?;
+            self.start_read(true)?;
+        }
+
+        let mut header = self.header;
+        let file = self.file.as_mut().expect("checked above");
+        let log_header = if header.max_frame == 0 {
+            let salt = match self.checkpoint_sequence {
+                0 => (random_nonce(), random_nonce()),
+                _ => header.salt,
+            };
+            let log_header = WalHeader::new(page_size, self.checkpoint_sequence, salt);
+            let mut buf = BytesMut::with_capacity(WAL_HEADER_SIZE);
+            log_header.write(&mut buf);
+            file.write(&buf, 0)?;
+            header.big_endian = log_header.big_endian;
+            header.page_size = page_size;
+            header.salt = salt;
+            header.frame_checksum = log_header.checksum;
+            log_header
+        } else {
+            let mut buf = [0u8; WAL_HEADER_SIZE];
+            file.read(&mut buf, 0)?;
+            WalHeader::from_buffer(&buf)
+                .filter(|log_header| {
+                    log_header.salt == header.salt && log_header.page_size == page_size
+                })
+                .ok_or_else(|| SqliteError::Corrupt {
+                    code: ExtendedResultCode::Corrupt,
+                    message: String::from("the log header does not match the wal-index"),
+                })?
+        };
+
+        let mut buf = BytesMut::with_capacity(pages.len() * log_header.frame_size() as usize);
+        let mut checksum = header.frame_checksum;
+        for (i, (page, data)) in pages.iter().enumerate() {
+            let commit_size = if i == pages.len() - 1 { db_size } else { 0 };
+            let frame_header = FrameHeader::new(&log_header, *page, commit_size, data, checksum);
+            frame_header.write(&mut buf);
+            buf.put_slice(data);
+            checksum = frame_header.checksum;
+        }
+        file.write(&buf, log_header.frame_offset(header.max_frame + 1))?;
+        file.sync(SyncFlags::Full)?;
+
+        for (i, (page, _)) in pages.iter().enumerate() {
+            let frame = header.max_frame + 1 + i as u32;
+            self.index.append(frame, *page, header.max_frame)?;
+        }
+        header.max_frame += pages.len() as u32;
+        header.db_size = db_size;
+        header.frame_checksum = checksum;
+        header.change = header.change.wrapping_add(1);
+        self.index.write_header(&mut header)?;
+        self.header = header;
+        Ok(())
+    }
+
+    /// Start the log over from its first frame, as sqlite3 does: the next frame written goes
+    /// after a new log header whose salts invalidate the frames left in the file.  The caller
+    /// holds the write lock, or is the writer, and every read lock but the first.
+    fn restart(&mut self, header: &mut WalIndexHeader) -> SqliteResult<()> {
+        self.checkpoint_sequence = self.checkpoint_sequence.wrapping_add(1);
+        header.max_frame = 0;
+        header.salt = (header.salt.0.wrapping_add(1), random_nonce());
+        self.index.write_header(header)?;
+        self.index.set_backfill(0)?;
+        self.index.set_backfill_attempted(0)?;
+        self.index.set_read_mark(1, 0)?;
+        for i in 2..WAL_NREADER {
+            self.index.set_read_mark(i, READMARK_NOT_USED)?;
+        }
+        Ok(())
+    }
+
+    /// Copy frames back into the database file `db`, the newest frame of each page, as far as
+    /// no reader still needs the pages the frames replace.  Fails with `SQLITE_BUSY` while
+    /// another connection runs a checkpoint.
+    ///
+    /// Other modes than `Passive` need the write lock; when another connection is writing the
+    /// checkpoint carries on as a passive one and reports that it was busy.  They also report
+    /// busy when a reader kept frames out of the database file, and `Restart` and `Truncate`
+    /// when a reader still uses the log after every frame was copied.
+    ///
+    /// The snapshot of this connection's read transaction is left alone, so the caller should
+    /// not be in one unless it is writing and has seen every frame.
+    pub fn checkpoint(
+        &mut self,
+        db: &mut dyn VfsFile,
+        mode: CheckpointMode,
+    ) -> SqliteResult<WalCheckpoint> {
+        if !self.try_lock(WAL_CKPT_LOCK, 1, ShmLockKind::Exclusive)? {
+            return Err(busy(ExtendedResultCode::Busy));
+        }
+        let locked = mode != CheckpointMode::Passive
+            && !self.writing
+            && self.try_lock(WAL_WRITE_LOCK, 1, ShmLockKind::Exclusive)?;
+        self.writing |= locked;
+        let effective = if self.writing {
+            mode
+        } else {
+            CheckpointMode::Passive
+        };
+        let result = self.backfill(db, effective);
+        if locked {
+            self.end_write()?;
+        }
+        self.index.unlock(WAL_CKPT_LOCK, 1)?;
+        let mut checkpoint = result?;
+        checkpoint.busy |= effective != mode;
+        Ok(checkpoint)
+    }
+
+    fn backfill(
+        &mut self,
+        db: &mut dyn VfsFile,
+        mode: CheckpointMode,
+    ) -> SqliteResult<WalCheckpoint> {
+        let Some(mut header) = self.read_index_header()? else {
+            return Err(busy(ExtendedResultCode::Busy));
+        };
+        let mut backfill = self.index.backfill()?;
+        if header.max_frame > backfill {
+            // frames past the mark of a reader may replace pages its snapshot still reads from
+            // the database file
+            let mut safe_frame = header.max_frame;
+            for i in 1..WAL_NREADER {
+                let mark = self.index.read_mark(i)?;
+                if safe_frame > mark {
+                    if self.try_lock(wal_read_lock(i), 1, ShmLockKind::Exclusive)? {
+                        let mark = if i == 1 {
+                            safe_frame
+                        } else {
+                            READMARK_NOT_USED
+                        };
+                        self.index.set_read_mark(i, mark)?;
+                        self.index.unlock(wal_read_lock(i), 1)?;
+                    } else {
+                        safe_frame = mark;
+                    }
+                }
+            }
+            // readers ignoring the log read every page from the database file
+            if backfill < safe_frame
+                && self.try_lock(wal_read_lock(0), 1, ShmLockKind::Exclusive)?
+            {
+                let copied = self.copy_frames(db, &header, backfill, safe_frame);
+                self.index.unlock(wal_read_lock(0), 1)?;
+                copied?;
+                backfill = safe_frame;
+            }
+        }
+
+        let mut busy = false;
+        if mode != CheckpointMode::Passive {
+            if backfill < header.max_frame {
+                busy = true;
+            } else if matches!(mode, CheckpointMode::Restart | CheckpointMode::Truncate) {
+                if self.try_lock(wal_read_lock(1), WAL_NREADER - 1, ShmLockKind::Exclusive)? {
+                    let restarted = if mode == CheckpointMode::Truncate {
+                        self.restart(&mut header).and_then(|()| {
+                            backfill = 0;
+                            self.file.as_mut().map_or(Ok(()), |file| file.truncate(0))
+                        })
+                    } else {
+                        Ok(())
+                    };
+                    self.index.unlock(wal_read_lock(1), WAL_NREADER - 1)?;
+                    restarted?;
+                } else {
+                    busy = true;
+                }
+            }
+        }
+        Ok(WalCheckpoint {
+            busy,
+            log_frames: header.max_frame,
+            checkpointed_frames: backfill,
+        })
+    }
+
+    /// Write the pages of the frames after `from` up to `to` to the database file, syncing
+    /// the log first and the database file after, then record them as copied.  Pages past the
+    /// end of the database are dropped and, once every frame is copied, the file is truncated
+    /// to the size of the database.
+    fn copy_frames(
+        &mut self,
+        db: &mut dyn VfsFile,
+        header: &WalIndexHeader,
+        from: u32,
+        to: u32,
+    ) -> SqliteResult<()> {
+        let mut pages = BTreeMap::new();
+        for frame in from + 1..=to {
+            let page = self
+                .index
+                .frame_page(frame)?
+                .ok_or_else(|| SqliteError::Corrupt {
+                    code: ExtendedResultCode::Corrupt,
+                    message: format!("frame {} is missing from the wal-index", frame),
+                })?;
+            pages.insert(page, frame);
+        }
+        self.index.set_backfill_attempted(to)?;
+
+        let file = self.file.as_mut().expect("frames imply a file");
+        file.sync(SyncFlags::Full)?;
+        let page_size = header.page_size as u64;
+        let mut buf = vec![0u8; header.page_size as usize];
+        for (page, frame) in pages {
+            if page > header.db_size {
+                continue;
+            }
+            let offset = frame_offset(header.page_size, frame) + WAL_FRAME_HEADER_SIZE as u64;
+            file.read(&mut buf, offset)?;
+            db.write(&buf, (page as u64 - 1) * page_size)?;
+        }
+        let db_size = header.db_size as u64 * page_size;
+        if to == header.max_frame && db.file_size()? > db_size {
+            db.truncate(db_size)?;
+        }
+        db.sync(SyncFlags::Full)?;
+        self.index.set_backfill(to)
+    }
+
+    /// Release the locks and close the wal-index.  With `delete` the log is deleted too, along
+    /// with the file behind the wal-index unless another connection in this process uses it;
+    /// the caller makes sure no other connection is using the log.
+    pub fn close(mut self, vfs: &dyn Vfs, delete: bool) -> SqliteResult<()> {
+        self.end_write()?;
+        self.end_read()?;
+        self.file = None;
+        self.index.close(delete)?;
+        if delete {
+            match vfs.delete(&self.path, false) {
+                Err(SqliteError::IoErr {
+                    code: ExtendedResultCode::IoErrDeleteNoEnt,
+                    ..
+                })
+                | Ok(()) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::{HeapShm, MemoryVfs};
+
+    fn page(fill: u8) -> Bytes {
+        Bytes::from(vec![fill; 512])
+    }
+
+    /// A connection's log sharing the wal-index in `shm`
+    fn open_with(vfs: &MemoryVfs, shm: &HeapShm) -> Wal {
+        let options = ConnectionOptions::default();
+        Wal::open(vfs, "db-wal", &options, false, Box::new(shm.share()), false).unwrap()
+    }
+
+    /// Commit `pages` from a new read transaction
+    fn commit(wal: &mut Wal, pages: &[(PageNumber, Bytes)], db_size: u32) {
+        wal.begin_read().unwrap();
+        wal.begin_write().unwrap();
+        wal.append(pages, db_size).unwrap();
+        wal.end_write().unwrap();
+        wal.end_read().unwrap();
+    }
+
+    fn open_db(vfs: &MemoryVfs) -> Box<dyn VfsFile> {
+        let flags = OpenFlags::new(OpenAccess::ReadWriteCreate, FileKind::MainDb);
+        vfs.open("db", flags, &ConnectionOptions::default())
+            .unwrap()
+    }
+
+    fn checkpoint(wal: &mut Wal, db: &mut Box<dyn VfsFile>, mode: CheckpointMode) -> WalCheckpoint {
+        wal.checkpoint(db.as_mut(), mode).unwrap()
+    }
+
+    fn is_busy(result: SqliteResult<()>, expected: ExtendedResultCode) -> bool {
+        matches!(result, Err(SqliteError::Busy { code, .. }) if code == expected)
+    }
+
+    #[test]
+    fn checksum_in_both_byte_orders() {
+        let data = [1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0];
+        // s0 = 0 + 1 + 0 = 1, s1 = 0 + 2 + 1 = 3, s0 = 1 + 3 + 3 = 7, s1 = 3 + 4 + 7 = 14
+        assert_eq!(wal_checksum(false, &data, (0, 0)), (7, 14));
+        assert_eq!(wal_checksum(true, &data, (0, 0)), (0x07000000, 0x0e000000));
+        // the checksum is cumulative
+        let half = wal_checksum(false, &data[..8], (0, 0));
+        assert_eq!(wal_checksum(false, &data[8..], half), (7, 14));
+    }
+
+    #[test]
+    fn header_layout() {
+        let header = WalHeader::new(4096, 3, (0x11223344, 0x55667788));
+        let mut buf = BytesMut::new();
+        header.write(&mut buf);
+        assert_eq!(buf.len(), WAL_HEADER_SIZE);
+        let magic = if cfg!(target_endian = "big") {
+            WAL_MAGIC_BE
+        } else {
+            WAL_MAGIC_LE
+        };
+        assert_eq!(&buf[..4], &magic.to_be_bytes());
+        assert_eq!(&buf[4..8], &WAL_FORMAT_VERSION.to_be_bytes());
+        assert_eq!(&buf[8..12], &4096u32.to_be_bytes());
+        assert_eq!(&buf[12..16], &3u32.to_be_bytes());
+        assert_eq!(
+            &buf[16..24],
+            &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88]
+        );
+        assert_eq!(WalHeader::from_buffer(&buf), Some(header));
+
+        buf[9] ^= 1;
+        assert_eq!(WalHeader::from_buffer(&buf), None);
+    }
+
+    #[test]
+    fn append_and_read_frames() {
+        let vfs = MemoryVfs::new();
+        let shm = HeapShm::new();
+        let mut wal = open_with(&vfs, &shm);
+        wal.begin_read().unwrap();
+        assert_eq!(wal.db_size(), None);
+        wal.begin_write().unwrap();
+        wal.append(&[(1, page(1)), (3, page(3))], 3).unwrap();
+        wal.append(&[(3, page(4))], 3).unwrap();
+        assert_eq!(wal.max_frame(), 3);
+        assert_eq!(wal.find_frame(3).unwrap(), Some(3));
+        assert_eq!(wal.find_frame(1).unwrap(), Some(1));
+        assert_eq!(wal.find_frame(2).unwrap(), None);
+        let mut buf = [0u8; 512];
+        wal.read_frame(3, &mut buf).unwrap();
+        assert_eq!(buf, [4; 512]);
+        wal.end_write().unwrap();
+
+        // another connection finds the frames through the shared wal-index
+        let mut other = open_with(&vfs, &shm);
+        assert!(other.begin_read().unwrap());
+        assert_eq!(other.snapshot(), wal.snapshot());
+        assert_eq!(other.db_size(), Some(3));
+        assert_eq!(other.find_frame(3).unwrap(), Some(3));
+        assert_eq!(other.header(), wal.header());
+        other.end_read().unwrap();
+        assert!(!other.begin_read().unwrap());
+    }
+
+    #[test]
+    fn snapshot_hides_later_commits() {
+        let vfs = MemoryVfs::new();
+        let shm = HeapShm::new();
+        let mut writer = open_with(&vfs, &shm);
+        let mut reader = open_with(&vfs, &shm);
+        commit(&mut writer, &[(1, page(1))], 1);
+        reader.begin_read().unwrap();
+        commit(&mut writer, &[(1, page(2)), (2, page(2))], 2);
+
+        assert_eq!(reader.find_frame(1).unwrap(), Some(1));
+        assert_eq!(reader.find_frame(2).unwrap(), None);
+        assert_eq!(reader.db_size(), Some(1));
+        assert!(is_busy(
+            reader.begin_write(),
+            ExtendedResultCode::BusySnapshot
+        ));
+        reader.end_read().unwrap();
+        assert!(reader.begin_read().unwrap());
+        assert_eq!(reader.find_frame(2).unwrap(), Some(3));
+        reader.begin_write().unwrap();
+    }
+
+    #[test]
+    fn one_writer_at_a_time() {
+        let vfs = MemoryVfs::new();
+        let shm = HeapShm::new();
+        let mut a = open_with(&vfs, &shm);
+        let mut b = open_with(&vfs, &shm);
+        a.begin_read().unwrap();
+        b.begin_read().unwrap();
+        a.begin_write().unwrap();
+        assert!(is_busy(b.begin_write(), ExtendedResultCode::Busy));
+        a.end_write().unwrap();
+        b.begin_write().unwrap();
+    }
+
+    #[test]
+    fn readers_take_read_marks() {
+        let vfs = MemoryVfs::new();
+        let shm = HeapShm::new();
+        let mut writer = open_with(&vfs, &shm);
+        let mut first = open_with(&vfs, &shm);
+        let mut second = open_with(&vfs, &shm);
+
+        // an empty log is read from the database file alone
+        first.begin_read().unwrap();
+        assert_eq!(first.read_lock, Some(0));
+        first.end_read().unwrap();
+
+        commit(&mut writer, &[(1, page(1))], 1);
+        first.begin_read().unwrap();
+        let mark = first.read_lock.unwrap();
+        assert!(mark > 0);
+        assert_eq!(first.index.read_mark(mark).unwrap(), 1);
+
+        // a later snapshot needs a mark of its own, since the first one is in use
+        commit(&mut writer, &[(2, page(2))], 2);
+        second.begin_read().unwrap();
+        let other_mark = second.read_lock.unwrap();
+        assert_ne!(other_mark, mark);
+        assert_eq!(second.index.read_mark(other_mark).unwrap(), 2);
+        assert_eq!(first.index.read_mark(mark).unwrap(), 1);
+        assert_eq!(first.find_frame(2).unwrap(), None);
+        assert_eq!(second.find_frame(2).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn recovery_rebuilds_the_index() {
+        let vfs = MemoryVfs::new();
+        let mut wal = open_with(&vfs, &HeapShm::new());
+        commit(&mut wal, &[(1, page(1)), (2, page(2))], 2);
+        commit(&mut wal, &[(2, page(3))], 2);
+
+        // a connection starting from an empty wal-index reads the log
+        let mut other = open_with(&vfs, &HeapShm::new());
+        assert!(other.begin_read().unwrap());
+        assert_eq!(other.max_frame(), 3);
+        assert_eq!(other.db_size(), Some(2));
+        assert_eq!(other.find_frame(2).unwrap(), Some(3));
+        assert_eq!(other.header().salt, wal.header().salt);
+        assert_eq!(other.header().frame_checksum, wal.header().frame_checksum);
+        assert_eq!(other.index.backfill().unwrap(), 0);
+        assert_eq!(other.index.read_mark(0).unwrap(), 0);
+        assert_eq!(other.index.read_mark(1).unwrap(), 3);
+        assert_eq!(other.index.read_mark(2).unwrap(), READMARK_NOT_USED);
+
+        // and appends after the recovered frames
+        other.begin_write().unwrap();
+        other.append(&[(1, page(5))], 2).unwrap();
+        other.end_write().unwrap();
+        other.end_read().unwrap();
+        let mut third = open_with(&vfs, &HeapShm::new());
+        third.begin_read().unwrap();
+        assert_eq!(third.find_frame(1).unwrap(), Some(4));
+    }
+
+    #[test]
+    fn uncommitted_and_corrupt_frames_are_ignored() {
+        let vfs = MemoryVfs::new();
+        let mut wal = open_with(&vfs, &HeapShm::new());
+        commit(&mut wal, &[(1, page(1))], 1);
+        commit(&mut wal, &[(2, page(2)), (3, page(3))], 3);
+        let mut buf = [0u8; WAL_HEADER_SIZE];
+        let mut file = vfs
+            .open(
+                "db-wal",
+                OpenFlags::new(OpenAccess::ReadWrite, FileKind::Wal),
+                &ConnectionOptions::default(),
+            )
+            .unwrap();
+        file.read(&mut buf, 0).unwrap();
+        let header = WalHeader::from_buffer(&buf).unwrap();
+
+        // a torn write in the last transaction hides all of it
+        file.write(&[0xff], header.frame_offset(3) + 100).unwrap();
+        let mut other = open_with(&vfs, &HeapShm::new());
+        other.begin_read().unwrap();
+        assert_eq!(other.max_frame(), 1);
+        assert_eq!(other.db_size(), Some(1));
+
+        // frames of a transaction with no commit frame are never seen
+        let mut buf = BytesMut::new();
+        let checksum = other.header().frame_checksum;
+        let frame = FrameHeader::new(&header, 2, 0, &page(2), checksum);
+        frame.write(&mut buf);
+        buf.put_slice(&page(2));
+        file.truncate(header.frame_offset(2)).unwrap();
+        file.write(&buf, header.frame_offset(2)).unwrap();
+        let mut other = open_with(&vfs, &HeapShm::new());
+        other.begin_read().unwrap();
+        assert_eq!(other.max_frame(), 1);
+        // a frame chained from the wrong checksum is invalid
+        assert!(!frame.is_valid(&header, &page(2), header.checksum));
+    }
+
+    #[test]
+    fn read_only_without_log() {
+        let vfs = MemoryVfs::new();
+        let options = ConnectionOptions::default();
+        let shm = Box::new(HeapShm::new());
+        let mut wal = Wal::open(&vfs, "db-wal", &options, true, shm, true).unwrap();
+        wal.begin_read().unwrap();
+        assert_eq!(wal.find_frame(1).unwrap(), None);
+        wal.begin_write().unwrap();
+        assert!(matches!(
+            wal.append(&[(1, page(1))], 1),
+            Err(SqliteError::ReadOnly { .. })
+        ));
+    }
+
+    #[test]
+    fn checkpoint_copies_newest_frames() {
+        let vfs = MemoryVfs::new();
+        let mut db = open_db(&vfs);
+        let mut wal = open_with(&vfs, &HeapShm::new());
+        commit(&mut wal, &[(1, page(1)), (2, page(2)), (3, page(3))], 3);
+        commit(&mut wal, &[(2, page(4))], 3);
+        let result = checkpoint(&mut wal, &mut db, CheckpointMode::Passive);
+        assert_eq!(
+            result,
+            WalCheckpoint {
+                busy: false,
+                log_frames: 4,
+                checkpointed_frames: 4
+            }
+        );
+        let mut buf = [0u8; 512];
+        db.read(&mut buf, 512).unwrap();
+        assert_eq!(buf, [4; 512]);
+        assert_eq!(db.file_size().unwrap(), 3 * 512);
+
+        // a smaller database is truncated once every frame is copied
+        commit(&mut wal, &[(1, page(5))], 2);
+        checkpoint(&mut wal, &mut db, CheckpointMode::Passive);
+        assert_eq!(db.file_size().unwrap(), 2 * 512);
+
+        // readers starting now need nothing from the log
+        wal.begin_read().unwrap();
+        assert_eq!(wal.read_lock, Some(0));
+        assert_eq!(wal.find_frame(1).unwrap(), None);
+    }
+
+    #[test]
+    fn checkpoint_respects_read_marks() {
+        let vfs = MemoryVfs::new();
+        let shm = HeapShm::new();
+        let mut db = open_db(&vfs);
+        let mut writer = open_with(&vfs, &shm);
+        let mut reader = open_with(&vfs, &shm);
+        commit(&mut writer, &[(1, page(1))], 1);
+        reader.begin_read().unwrap();
+        commit(&mut writer, &[(1, page(2))], 1);
+
+        // frame 2 replaces a page the reader's snapshot still reads
+        let result = checkpoint(&mut writer, &mut db, CheckpointMode::Passive);
+        assert_eq!((result.busy, result.checkpointed_frames), (false, 1));
+        let result = checkpoint(&mut writer, &mut db, CheckpointMode::Full);
+        assert_eq!((result.busy, result.checkpointed_frames), (true, 1));
+        let mut buf = [0u8; 512];
+        db.read(&mut buf, 0).unwrap();
+        assert_eq!(buf, [1; 512]);
+
+        // a reader whose snapshot has every frame does not hold the checkpoint up, but it
+        // still uses the log, so the log cannot restart under it
+        reader.end_read().unwrap();
+        reader.begin_read().unwrap();
+        let result = checkpoint(&mut writer, &mut db, CheckpointMode::Full);
+        assert_eq!((result.busy, result.checkpointed_frames), (false, 2));
+        let result = checkpoint(&mut writer, &mut db, CheckpointMode::Restart);
+        assert!(result.busy);
+        reader.end_read().unwrap();
+        let result = checkpoint(&mut writer, &mut db, CheckpointMode::Restart);
+        assert_eq!(
+            result,
+            WalCheckpoint {
+                busy: false,
+                log_frames: 2,
+                checkpointed_frames: 2
+            }
+        );
+    }
+
+    #[test]
+    fn checkpoint_while_another_connection_writes() {
+        let vfs = MemoryVfs::new();
+        let shm = HeapShm::new();
+        let mut db = open_db(&vfs);
+        let mut writer = open_with(&vfs, &shm);
+        let mut other = open_with(&vfs, &shm);
+        commit(&mut writer, &[(1, page(1))], 1);
+        writer.begin_read().unwrap();
+        writer.begin_write().unwrap();
+
+        // without the write lock a full checkpoint is only a passive one
+        let result = checkpoint(&mut other, &mut db, CheckpointMode::Full);
+        assert_eq!((result.busy, result.checkpointed_frames), (true, 1));
+        assert_eq!(other.header(), &WalIndexHeader::default());
+        let result = checkpoint(&mut other, &mut db, CheckpointMode::Passive);
+        assert!(!result.busy);
+    }
+
+    #[test]
+    fn restarted_log_starts_over() {
+        let vfs = MemoryVfs::new();
+        let shm = HeapShm::new();
+        let mut db = open_db(&vfs);
+        let mut wal = open_with(&vfs, &shm);
+        commit(&mut wal, &[(1, page(1)), (2, page(2))], 2);
+        let salt = wal.header().salt;
+        checkpoint(&mut wal, &mut db, CheckpointMode::Restart);
+        assert_eq!(wal.max_frame(), 2);
+
+        // the next writer starts over from the first frame with new salts
+        commit(&mut wal, &[(2, page(3))], 2);
+        assert_eq!(wal.max_frame(), 1);
+        assert_eq!(wal.header().salt.0, salt.0.wrapping_add(1));
+        let mut buf = [0u8; WAL_HEADER_SIZE];
+        wal.file.as_mut().unwrap().read(&mut buf, 0).unwrap();
+        let log_header = WalHeader::from_buffer(&buf).unwrap();
+        assert_eq!(log_header.checkpoint_sequence, 1);
+        assert_eq!(log_header.salt, wal.header().salt);
+
+        // recovery only finds the frames written since
+        let mut other = open_with(&vfs, &HeapShm::new());
+        other.begin_read().unwrap();
+        assert_eq!(other.max_frame(), 1);
+        assert_eq!(other.find_frame(2).unwrap(), Some(1));
+        assert_eq!(other.find_frame(1).unwrap(), None);
+
+        // truncating restarts the log right away, once no reader uses it
+        let mut reader = open_with(&vfs, &shm);
+        reader.begin_read().unwrap();
+        let result = checkpoint(&mut wal, &mut db, CheckpointMode::Truncate);
+        assert!(result.busy);
+        reader.end_read().unwrap();
+        let result = checkpoint(&mut wal, &mut db, CheckpointMode::Truncate);
+        assert_eq!(result, WalCheckpoint::default());
+        assert_eq!(wal.file.as_ref().unwrap().file_size().unwrap(), 0);
+        commit(&mut wal, &[(1, page(4))], 2);
+        assert_eq!(wal.max_frame(), 1);
+        assert_eq!(wal.find_frame(2).unwrap(), None);
+    }
+
+    #[test]
+    fn second_checkpoint_is_busy() {
+        let vfs = MemoryVfs::new();
+        let shm = HeapShm::new();
+        let mut db = open_db(&vfs);
+        let mut wal = open_with(&vfs, &shm);
+        let mut other = open_with(&vfs, &shm);
+        other
+            .index
+            .lock(WAL_CKPT_LOCK, 1, ShmLockKind::Exclusive)
+            .unwrap();
+        assert!(is_busy(
+            wal.checkpoint(db.as_mut(), CheckpointMode::Passive)
+                .map(|_| ()),
+            ExtendedResultCode::Busy
+        ));
+        assert_eq!(
+            CheckpointMode::try_from("TRUNCATE").unwrap(),
+            CheckpointMode::Truncate
+        );
+        assert!(CheckpointMode::try_from("eager").is_err());
+    }
+}
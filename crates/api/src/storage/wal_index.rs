@@ -0,0 +1,462 @@
+use crate::errors::{ExtendedResultCode, SqliteResult};
+use crate::storage::{wal_checksum, PageNumber, WAL_FORMAT_VERSION};
+use crate::vfs::{Shm, ShmLockKind, ShmRegion, SHM_REGION_SIZE};
+use crate::SqliteError;
+
+/// Number of read marks, and of read locks
+pub const WAL_NREADER: usize = 5;
+
+/// Held exclusively by the one connection appending to the log
+pub const WAL_WRITE_LOCK: usize = 0;
+
+/// Held exclusively while a checkpoint runs
+pub const WAL_CKPT_LOCK: usize = 1;
+
+/// Held exclusively while the wal-index is rebuilt from the log
+pub const WAL_RECOVER_LOCK: usize = 2;
+
+/// The lock guarding read mark `i`; read lock 0 means the reader ignores the log
+pub const fn wal_read_lock(i: usize) -> usize {
+    3 + i
+}
+
+/// A read mark no reader uses
+pub const READMARK_NOT_USED: u32 = 0xffffffff;
+
+/// Size of one copy of the wal-index header
+const HEADER_COPY_SIZE: usize = 48;
+
+/// The header copies are followed by the checkpoint information
+const CKPT_INFO_OFFSET: usize = 2 * HEADER_COPY_SIZE;
+
+const BACKFILL_OFFSET: usize = CKPT_INFO_OFFSET;
+
+const READ_MARK_OFFSET: usize = CKPT_INFO_OFFSET + 4;
+
+const BACKFILL_ATTEMPTED_OFFSET: usize = CKPT_INFO_OFFSET + 32;
+
+/// The header copies and checkpoint information at the start of the first region
+const WALINDEX_HEADER_SIZE: usize = 136;
+
+/// Frames indexed by each hash table, whose page numbers fill the first half of a region
+const HASHTABLE_NPAGE: usize = 4096;
+
+/// Frames indexed by the first hash table, which shares its region with the header
+const HASHTABLE_NPAGE_ONE: usize = HASHTABLE_NPAGE - WALINDEX_HEADER_SIZE / 4;
+
+/// Slots in each hash table, filling the second half of a region
+const HASHTABLE_NSLOT: usize = 2 * HASHTABLE_NPAGE;
+
+/// Offset of the hash slots in a region
+const HASHTABLE_OFFSET: usize = HASHTABLE_NPAGE * 4;
+
+/// The hash table indexing `frame`
+fn frame_table(frame: u32) -> usize {
+    (frame as usize + HASHTABLE_NPAGE - HASHTABLE_NPAGE_ONE - 1) / HASHTABLE_NPAGE
+}
+
+fn hash_slot(page: PageNumber) -> usize {
+    (page as usize).wrapping_mul(383) & (HASHTABLE_NSLOT - 1)
+}
+
+fn corrupt() -> SqliteError {
+    SqliteError::Corrupt {
+        code: ExtendedResultCode::Corrupt,
+        message: String::from("wal-index hash table is corrupt"),
+    }
+}
+
+/// The header of the wal-index, describing the log as of the last commit.  sqlite3 keeps two
+/// copies in native byte order: writers update the second copy first and readers only trust
+/// the header when both copies match and the checksum is valid.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WalIndexHeader {
+    /// Incremented by every commit
+    pub change: u32,
+    /// Whether the header was ever written; a zeroed wal-index needs recovery
+    pub is_init: bool,
+    /// Byte order of the words the log checksums are computed on
+    pub big_endian: bool,
+    pub page_size: u32,
+    /// The last frame of the last committed transaction, or 0 for none
+    pub max_frame: u32,
+    /// Database size in pages after the last committed transaction
+    pub db_size: u32,
+    /// The checksum of frame `max_frame`, or of the log header when there are no frames
+    pub frame_checksum: (u32, u32),
+    /// The salts of the log header
+    pub salt: (u32, u32),
+    pub checksum: (u32, u32),
+}
+
+impl WalIndexHeader {
+    fn to_bytes(self) -> [u8; HEADER_COPY_SIZE] {
+        let mut buf = [0u8; HEADER_COPY_SIZE];
+        buf[0..4].copy_from_slice(&WAL_FORMAT_VERSION.to_ne_bytes());
+        buf[8..12].copy_from_slice(&self.change.to_ne_bytes());
+        buf[12] = u8::from(self.is_init);
+        buf[13] = u8::from(self.big_endian);
+        // 65536 does not fit, so the size is stored with its bit 16 moved to bit 0
+        let page_size = ((self.page_size & 0xff00) | (self.page_size >> 16)) as u16;
+        buf[14..16].copy_from_slice(&page_size.to_ne_bytes());
+        buf[16..20].copy_from_slice(&self.max_frame.to_ne_bytes());
+        buf[20..24].copy_from_slice(&self.db_size.to_ne_bytes());
+        buf[24..28].copy_from_slice(&self.frame_checksum.0.to_ne_bytes());
+        buf[28..32].copy_from_slice(&self.frame_checksum.1.to_ne_bytes());
+        // the salts are copied from the log header as they are
+        buf[32..36].copy_from_slice(&self.salt.0.to_be_bytes());
+        buf[36..40].copy_from_slice(&self.salt.1.to_be_bytes());
+        buf[40..44].copy_from_slice(&self.checksum.0.to_ne_bytes());
+        buf[44..48].copy_from_slice(&self.checksum.1.to_ne_bytes());
+        buf
+    }
+
+    fn from_bytes(buf: &[u8; HEADER_COPY_SIZE]) -> WalIndexHeader {
+        let word = |at: usize| u32::from_ne_bytes(buf[at..at + 4].try_into().unwrap());
+        let page_size = u16::from_ne_bytes([buf[14], buf[15]]) as u32;
+        WalIndexHeader {
+            change: word(8),
+            is_init: buf[12] != 0,
+            big_endian: buf[13] != 0,
+            page_size: (page_size & 0xfe00) + ((page_size & 1) << 16),
+            max_frame: word(16),
+            db_size: word(20),
+            frame_checksum: (word(24), word(28)),
+            salt: (
+                u32::from_be_bytes(buf[32..36].try_into().unwrap()),
+                u32::from_be_bytes(buf[36..40].try_into().unwrap()),
+            ),
+            checksum: (word(40), word(44)),
+        }
+    }
+
+    /// The checksum of the fields before it, computed on native words
+    fn compute_checksum(&self) -> (u32, u32) {
+        wal_checksum(cfg!(target_endian = "big"), &self.to_bytes()[..40], (0, 0))
+    }
+}
+
+/// The wal-index: an index from page numbers to the frames holding them, kept in memory
+/// shared by every connection to the database with the same layout as sqlite3.  The first
+/// region starts with the [`WalIndexHeader`] copies and the checkpoint information: how many
+/// frames were copied back into the database and the read marks, the largest frame each
+/// reader may read.  Each region then holds a table of the page in every frame it indexes,
+/// followed by a hash table over those entries.
+#[derive(Debug)]
+pub struct WalIndex {
+    shm: Box<dyn Shm>,
+    regions: Vec<ShmRegion>,
+}
+
+impl WalIndex {
+    pub fn new(shm: Box<dyn Shm>) -> WalIndex {
+        WalIndex {
+            shm,
+            regions: Vec::new(),
+        }
+    }
+
+    fn region(&mut self, region: usize, extend: bool) -> SqliteResult<Option<ShmRegion>> {
+        while self.regions.len() <= region {
+            match self.shm.map(self.regions.len(), extend)? {
+                Some(mapped) => self.regions.push(mapped),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(self.regions[region].clone()))
+    }
+
+    fn first_region(&mut self) -> SqliteResult<ShmRegion> {
+        Ok(self.region(0, true)?.expect("extended"))
+    }
+
+    pub fn lock(&mut self, slot: usize, n: usize, kind: ShmLockKind) -> SqliteResult<()> {
+        self.shm.lock(slot, n, kind)
+    }
+
+    pub fn unlock(&mut self, slot: usize, n: usize) -> SqliteResult<()> {
+        self.shm.unlock(slot, n)
+    }
+
+    /// Close the shared memory, see [`Shm::unmap`]
+    pub fn close(self, delete: bool) -> SqliteResult<()> {
+        self.shm.unmap(delete)
+    }
+
+    fn header_copy(&mut self, copy: usize) -> SqliteResult<[u8; HEADER_COPY_SIZE]> {
+        let mut buf = [0u8; HEADER_COPY_SIZE];
+        self.first_region()?.read(copy * HEADER_COPY_SIZE, &mut buf);
+        Ok(buf)
+    }
+
+    /// The header, or `None` when the copies differ because a writer is updating them, or the
+    /// wal-index was never initialized or is corrupt, and the log needs recovery
+    pub fn read_header(&mut self) -> SqliteResult<Option<WalIndexHeader>> {
+        let first = self.header_copy(0)?;
+        self.shm.barrier();
+        let second = self.header_copy(1)?;
+        if first != second {
+            return Ok(None);
+        }
+        let header = WalIndexHeader::from_bytes(&first);
+        if !header.is_init || header.checksum != header.compute_checksum() {
+            return Ok(None);
+        }
+        Ok(Some(header))
+    }
+
+    /// Whether the first header copy still matches `header`
+    pub fn header_unchanged(&mut self, header: &WalIndexHeader) -> SqliteResult<bool> {
+        Ok(self.header_copy(0)? == header.to_bytes())
+    }
+
+    /// Write both copies of the header, setting its checksum.  The caller holds the write
+    /// lock.
+    pub fn write_header(&mut self, header: &mut WalIndexHeader) -> SqliteResult<()> {
+        header.is_init = true;
+        header.checksum = header.compute_checksum();
+        let bytes = header.to_bytes();
+        let region = self.first_region()?;
+        region.write(HEADER_COPY_SIZE, &bytes);
+        self.shm.barrier();
+        region.write(0, &bytes);
+        Ok(())
+    }
+
+    /// Number of frames a checkpoint has copied back into the database
+    pub fn backfill(&mut self) -> SqliteResult<u32> {
+        Ok(self.first_region()?.load_u32(BACKFILL_OFFSET))
+    }
+
+    pub fn set_backfill(&mut self, frames: u32) -> SqliteResult<()> {
+        self.first_region()?.store_u32(BACKFILL_OFFSET, frames);
+        Ok(())
+    }
+
+    pub fn set_backfill_attempted(&mut self, frames: u32) -> SqliteResult<()> {
+        self.first_region()?
+            .store_u32(BACKFILL_ATTEMPTED_OFFSET, frames);
+        Ok(())
+    }
+
+    /// The largest frame a reader holding read lock `i` may read
+    pub fn read_mark(&mut self, i: usize) -> SqliteResult<u32> {
+        Ok(self.first_region()?.load_u32(READ_MARK_OFFSET + i * 4))
+    }
+
+    pub fn set_read_mark(&mut self, i: usize, frame: u32) -> SqliteResult<()> {
+        self.first_region()?
+            .store_u32(READ_MARK_OFFSET + i * 4, frame);
+        Ok(())
+    }
+
+    /// The region of a hash table, where its page numbers start and the frame before its
+    /// first
+    fn table(
+        &mut self,
+        table: usize,
+        extend: bool,
+    ) -> SqliteResult<Option<(ShmRegion, usize, u32)>> {
+        let Some(region) = self.region(table, extend)? else {
+            return Ok(None);
+        };
+        Ok(Some(if table == 0 {
+            (region, WALINDEX_HEADER_SIZE, 0)
+        } else {
+            let zero = HASHTABLE_NPAGE_ONE + (table - 1) * HASHTABLE_NPAGE;
+            (region, 0, zero as u32)
+        }))
+    }
+
+    /// Record that `frame` holds `page`.  Entries past `max_frame`, the last committed frame,
+    /// are left over from a transaction that never committed and are dropped first.
+    pub fn append(&mut self, frame: u32, page: PageNumber, max_frame: u32) -> SqliteResult<()> {
+        let (region, pages, zero) = self.table(frame_table(frame), true)?.expect("extended");
+        let index = (frame - zero) as usize;
+        if index == 1 {
+            region.zero(pages, SHM_REGION_SIZE - pages);
+        }
+        if region.load_u32(pages + (index - 1) * 4) != 0 {
+            self.truncate(max_frame)?;
+        }
+        let mut slot = hash_slot(page);
+        let mut collisions = index;
+        while region.load_u16(HASHTABLE_OFFSET + slot * 2) != 0 {
+            if collisions == 0 {
+                return Err(corrupt());
+            }
+            collisions -= 1;
+            slot = (slot + 1) & (HASHTABLE_NSLOT - 1);
+        }
+        region.store_u32(pages + (index - 1) * 4, page);
+        region.store_u16(HASHTABLE_OFFSET + slot * 2, index as u16);
+        Ok(())
+    }
+
+    /// Drop the entries of frames after `max_frame` from its hash table
+    fn truncate(&mut self, max_frame: u32) -> SqliteResult<()> {
+        if max_frame == 0 {
+            return Ok(());
+        }
+        let Some((region, pages, zero)) = self.table(frame_table(max_frame), false)? else {
+            return Ok(());
+        };
+        let limit = (max_frame - zero) as usize;
+        for slot in 0..HASHTABLE_NSLOT {
+            if region.load_u16(HASHTABLE_OFFSET + slot * 2) as usize > limit {
+                region.store_u16(HASHTABLE_OFFSET + slot * 2, 0);
+            }
+        }
+        let end = pages + limit * 4;
+        region.zero(end, HASHTABLE_OFFSET - end);
+        Ok(())
+    }
+
+    /// The page held in `frame`
+    pub fn frame_page(&mut self, frame: u32) -> SqliteResult<Option<PageNumber>> {
+        let Some((region, pages, zero)) = self.table(frame_table(frame), false)? else {
+            return Ok(None);
+        };
+        Ok(Some(
+            region.load_u32(pages + (frame - zero - 1) as usize * 4),
+        ))
+    }
+
+    /// The newest frame holding `page` between `min_frame` and `max_frame`
+    pub fn find_frame(
+        &mut self,
+        page: PageNumber,
+        min_frame: u32,
+        max_frame: u32,
+    ) -> SqliteResult<Option<u32>> {
+        if max_frame < min_frame.max(1) {
+            return Ok(None);
+        }
+        for table in (frame_table(min_frame.max(1))..=frame_table(max_frame)).rev() {
+            let Some((region, pages, zero)) = self.table(table, false)? else {
+                continue;
+            };
+            let mut found = None;
+            let mut slot = hash_slot(page);
+            let mut collisions = HASHTABLE_NSLOT;
+            loop {
+                let index = region.load_u16(HASHTABLE_OFFSET + slot * 2) as u32;
+                if index == 0 {
+                    break;
+                }
+                let frame = index + zero;
+                if (min_frame..=max_frame).contains(&frame)
+                    && region.load_u32(pages + (index as usize - 1) * 4) == page
+                {
+                    found = Some(frame);
+                }
+                if collisions == 0 {
+                    return Err(corrupt());
+                }
+                collisions -= 1;
+                slot = (slot + 1) & (HASHTABLE_NSLOT - 1);
+            }
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::HeapShm;
+
+    fn index() -> WalIndex {
+        WalIndex::new(Box::new(HeapShm::new()))
+    }
+
+    #[test]
+    fn header_layout_and_copies() {
+        let mut index = index();
+        assert_eq!(index.read_header().unwrap(), None);
+        let mut header = WalIndexHeader {
+            change: 3,
+            page_size: 65536,
+            max_frame: 9,
+            db_size: 4,
+            frame_checksum: (1, 2),
+            salt: (0x01020304, 0x05060708),
+            ..Default::default()
+        };
+        index.write_header(&mut header).unwrap();
+        assert!(header.is_init);
+        assert_eq!(index.read_header().unwrap(), Some(header));
+
+        let region = index.first_region().unwrap();
+        let mut bytes = [0u8; HEADER_COPY_SIZE];
+        region.read(0, &mut bytes);
+        assert_eq!(&bytes[0..4], &WAL_FORMAT_VERSION.to_ne_bytes());
+        assert_eq!(&bytes[14..16], &1u16.to_ne_bytes());
+        assert_eq!(&bytes[32..40], &[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        // a writer part way through updating the copies
+        region.store_u32(HEADER_COPY_SIZE + 16, 10);
+        assert_eq!(index.read_header().unwrap(), None);
+        // both copies agree but the checksum does not
+        region.store_u32(16, 10);
+        assert_eq!(index.read_header().unwrap(), None);
+        assert!(!index.header_unchanged(&header).unwrap());
+    }
+
+    #[test]
+    fn checkpoint_info_layout() {
+        let mut index = index();
+        index.set_backfill(5).unwrap();
+        index.set_read_mark(0, 0).unwrap();
+        index.set_read_mark(4, READMARK_NOT_USED).unwrap();
+        index.set_backfill_attempted(6).unwrap();
+        let region = index.first_region().unwrap();
+        assert_eq!(region.load_u32(96), 5);
+        assert_eq!(region.load_u32(116), READMARK_NOT_USED);
+        assert_eq!(region.load_u32(128), 6);
+        assert_eq!(index.read_mark(4).unwrap(), READMARK_NOT_USED);
+    }
+
+    #[test]
+    fn frames_across_hash_tables() {
+        let mut index = index();
+        let frames = HASHTABLE_NPAGE_ONE as u32 + HASHTABLE_NPAGE as u32 + 10;
+        for frame in 1..=frames {
+            index.append(frame, frame % 100 + 1, frame - 1).unwrap();
+        }
+        assert_eq!(frame_table(HASHTABLE_NPAGE_ONE as u32), 0);
+        assert_eq!(frame_table(HASHTABLE_NPAGE_ONE as u32 + 1), 1);
+        assert_eq!(index.frame_page(4062).unwrap(), Some(4062 % 100 + 1));
+        assert_eq!(index.frame_page(frames).unwrap(), Some(frames % 100 + 1));
+        // the newest frame within the range wins
+        assert_eq!(index.find_frame(1, 1, frames).unwrap(), Some(8100));
+        assert_eq!(index.find_frame(1, 1, 8099).unwrap(), Some(8000));
+        assert_eq!(index.find_frame(1, 1, 99).unwrap(), None);
+        assert_eq!(index.find_frame(1, 200, 250).unwrap(), Some(200));
+        assert_eq!(index.find_frame(1, 201, 250).unwrap(), None);
+        assert_eq!(index.find_frame(101, 1, frames).unwrap(), None);
+        // the first hash table uses the page numbers after the header
+        let region = index.first_region().unwrap();
+        assert_eq!(region.load_u32(WALINDEX_HEADER_SIZE), 2);
+    }
+
+    #[test]
+    fn uncommitted_entries_are_replaced() {
+        let mut index = index();
+        index.append(1, 7, 0).unwrap();
+        index.append(2, 8, 1).unwrap();
+        index.append(3, 9, 1).unwrap();
+        // frames 2 and 3 never committed; a new transaction reuses frame 2
+        index.append(2, 5, 1).unwrap();
+        assert_eq!(index.find_frame(8, 1, 3).unwrap(), None);
+        assert_eq!(index.find_frame(9, 1, 3).unwrap(), None);
+        assert_eq!(index.find_frame(5, 1, 2).unwrap(), Some(2));
+        assert_eq!(index.find_frame(7, 1, 2).unwrap(), Some(1));
+        // reusing frame 1 clears the whole table
+        index.append(1, 5, 0).unwrap();
+        assert_eq!(index.find_frame(7, 1, 2).unwrap(), None);
+    }
+}
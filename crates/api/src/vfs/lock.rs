@@ -0,0 +1,251 @@
+use crate::errors::{ExtendedResultCode, SqliteResult};
+use crate::SqliteError;
+
+/// The file locks of the sqlite3 locking protocol, from weakest to strongest
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockLevel {
+    #[default]
+    None,
+    /// Any number of connections may read
+    Shared,
+    /// One connection intends to write; readers may still come and go
+    Reserved,
+    /// One connection is waiting for readers to finish so it can write; no new readers
+    Pending,
+    /// One connection is writing and nobody else may read
+    Exclusive,
+}
+
+pub(crate) fn busy() -> SqliteError {
+    SqliteError::Busy {
+        code: ExtendedResultCode::Busy,
+        message: String::from("database is locked"),
+    }
+}
+
+/// The locks a process holds on a file as seen by other processes.  [`LockState`] calls these
+/// as the combined lock of every handle in the process changes; each returns `SQLITE_BUSY`
+/// when another process holds a conflicting lock.
+pub(crate) trait ProcessLock {
+    fn acquire_shared(&mut self) -> SqliteResult<()>;
+
+    fn acquire_reserved(&mut self) -> SqliteResult<()>;
+
+    fn acquire_pending(&mut self) -> SqliteResult<()>;
+
+    fn acquire_exclusive(&mut self) -> SqliteResult<()>;
+
+    /// Drop back to a shared lock from a reserved or stronger one
+    fn downgrade(&mut self, from: LockLevel) -> SqliteResult<()>;
+
+    fn release(&mut self) -> SqliteResult<()>;
+}
+
+/// For files no other process can see
+impl ProcessLock for () {
+    fn acquire_shared(&mut self) -> SqliteResult<()> {
+        Ok(())
+    }
+
+    fn acquire_reserved(&mut self) -> SqliteResult<()> {
+        Ok(())
+    }
+
+    fn acquire_pending(&mut self) -> SqliteResult<()> {
+        Ok(())
+    }
+
+    fn acquire_exclusive(&mut self) -> SqliteResult<()> {
+        Ok(())
+    }
+
+    fn downgrade(&mut self, _from: LockLevel) -> SqliteResult<()> {
+        Ok(())
+    }
+
+    fn release(&mut self) -> SqliteResult<()> {
+        Ok(())
+    }
+}
+
+/// The locks held on one file by all handles in this process.  Any number of handles may hold
+/// a shared lock, while at most one handle, the writer, holds a reserved or stronger lock.
+#[derive(Debug, Default)]
+pub(crate) struct LockState {
+    shared: usize,
+    writer: LockLevel,
+}
+
+impl LockState {
+    /// Move a handle holding `current` up to `level`.  On failure `current` reflects what was
+    /// obtained, which may be `Pending` when readers prevented an exclusive lock.
+    pub(crate) fn lock(&mut self, current: &mut LockLevel, level: LockLevel) -> SqliteResult<()> {
+        self.lock_with(current, level, &mut ())
+    }
+
+    /// Like [`LockState::lock`], also taking the process level locks from `process`
+    pub(crate) fn lock_with(
+        &mut self,
+        current: &mut LockLevel,
+        level: LockLevel,
+        process: &mut impl ProcessLock,
+    ) -> SqliteResult<()> {
+        if level <= *current {
+            return Ok(());
+        }
+        if *current == LockLevel::None {
+            if self.writer >= LockLevel::Pending {
+                return Err(busy());
+            }
+            if self.shared == 0 {
+                process.acquire_shared()?;
+            }
+            self.shared += 1;
+            *current = LockLevel::Shared;
+        }
+        if level == LockLevel::Shared {
+            return Ok(());
+        }
+        if *current == LockLevel::Shared {
+            if self.writer != LockLevel::None {
+                return Err(busy());
+            }
+            process.acquire_reserved()?;
+            self.writer = LockLevel::Reserved;
+            *current = LockLevel::Reserved;
+        }
+        if level == LockLevel::Reserved {
+            return Ok(());
+        }
+        if *current == LockLevel::Reserved {
+            process.acquire_pending()?;
+            self.writer = LockLevel::Pending;
+            *current = LockLevel::Pending;
+        }
+        if level == LockLevel::Pending {
+            return Ok(());
+        }
+        if self.shared > 1 {
+            return Err(busy());
+        }
+        process.acquire_exclusive()?;
+        self.writer = LockLevel::Exclusive;
+        *current = LockLevel::Exclusive;
+        Ok(())
+    }
+
+    /// Move a handle holding `current` down to `level`
+    pub(crate) fn unlock(&mut self, current: &mut LockLevel, level: LockLevel) {
+        // the no-op process lock cannot fail
+        let _ = self.unlock_with(current, level, &mut ());
+    }
+
+    /// Like [`LockState::unlock`], also releasing the process level locks through `process`
+    pub(crate) fn unlock_with(
+        &mut self,
+        current: &mut LockLevel,
+        level: LockLevel,
+        process: &mut impl ProcessLock,
+    ) -> SqliteResult<()> {
+        if level >= *current {
+            return Ok(());
+        }
+        let from = *current;
+        if from > LockLevel::Shared {
+            self.writer = LockLevel::None;
+            *current = LockLevel::Shared;
+            process.downgrade(from)?;
+        }
+        if level == LockLevel::None {
+            self.shared -= 1;
+            *current = LockLevel::None;
+            if self.shared == 0 {
+                process.release()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_reserved(&self) -> bool {
+        self.writer != LockLevel::None
+    }
+
+    /// Whether no handle holds any lock
+    pub(crate) fn is_unlocked(&self) -> bool {
+        self.shared == 0 && self.writer == LockLevel::None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_busy(result: SqliteResult<()>) -> bool {
+        matches!(
+            result,
+            Err(SqliteError::Busy {
+                code: ExtendedResultCode::Busy,
+                ..
+            })
+        )
+    }
+
+    #[test]
+    fn shared_locks_coexist() {
+        let mut state = LockState::default();
+        let mut a = LockLevel::None;
+        let mut b = LockLevel::None;
+        state.lock(&mut a, LockLevel::Shared).unwrap();
+        state.lock(&mut b, LockLevel::Shared).unwrap();
+        assert_eq!(a, LockLevel::Shared);
+        assert_eq!(b, LockLevel::Shared);
+        assert!(!state.is_reserved());
+        state.unlock(&mut a, LockLevel::None);
+        state.unlock(&mut b, LockLevel::None);
+        assert!(state.is_unlocked());
+    }
+
+    #[test]
+    fn single_reserved_lock() {
+        let mut state = LockState::default();
+        let mut a = LockLevel::None;
+        let mut b = LockLevel::None;
+        state.lock(&mut a, LockLevel::Reserved).unwrap();
+        state.lock(&mut b, LockLevel::Shared).unwrap();
+        assert!(state.is_reserved());
+        assert!(is_busy(state.lock(&mut b, LockLevel::Reserved)));
+        assert_eq!(b, LockLevel::Shared);
+        state.unlock(&mut a, LockLevel::Shared);
+        state.lock(&mut b, LockLevel::Reserved).unwrap();
+    }
+
+    #[test]
+    fn exclusive_waits_for_readers_in_pending() {
+        let mut state = LockState::default();
+        let mut writer = LockLevel::None;
+        let mut reader = LockLevel::None;
+        let mut late = LockLevel::None;
+        state.lock(&mut reader, LockLevel::Shared).unwrap();
+        state.lock(&mut writer, LockLevel::Reserved).unwrap();
+        assert!(is_busy(state.lock(&mut writer, LockLevel::Exclusive)));
+        assert_eq!(writer, LockLevel::Pending);
+        // pending keeps new readers out
+        assert!(is_busy(state.lock(&mut late, LockLevel::Shared)));
+        state.unlock(&mut reader, LockLevel::None);
+        state.lock(&mut writer, LockLevel::Exclusive).unwrap();
+        assert_eq!(writer, LockLevel::Exclusive);
+        state.unlock(&mut writer, LockLevel::Shared);
+        state.lock(&mut late, LockLevel::Shared).unwrap();
+    }
+
+    #[test]
+    fn none_to_exclusive() {
+        let mut state = LockState::default();
+        let mut a = LockLevel::None;
+        state.lock(&mut a, LockLevel::Exclusive).unwrap();
+        assert_eq!(a, LockLevel::Exclusive);
+        state.unlock(&mut a, LockLevel::None);
+        assert_eq!(a, LockLevel::None);
+        assert!(state.is_unlocked());
+    }
+}
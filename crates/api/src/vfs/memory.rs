@@ -0,0 +1,339 @@
+use crate::connection::ConnectionOptions;
+use crate::errors::{ExtendedResultCode, SqliteResult};
+use crate::vfs::{
+    AccessFlags, HeapShm, LockLevel, LockState, OpenAccess, OpenFlags, Shm, SyncFlags, Vfs,
+    VfsFile, MEMORY_VFS_NAME,
+};
+use crate::SqliteError;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[derive(Debug, Default)]
+struct MemoryFileData {
+    content: Vec<u8>,
+    locks: LockState,
+    /// The wal-index of the file, shared by every handle to it
+    shm: HeapShm,
+}
+
+type SharedFile = Arc<Mutex<MemoryFileData>>;
+
+type FileTable = Arc<Mutex<HashMap<String, SharedFile>>>;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A VFS that keeps files in memory.  An empty path opens a private file that no other handle
+/// can see; any other path names a file shared by every handle that opens it, which lives
+/// until it is deleted or, when opened with `delete_on_close`, until its last handle closes.
+#[derive(Debug, Default)]
+pub struct MemoryVfs {
+    files: FileTable,
+}
+
+impl MemoryVfs {
+    pub fn new() -> MemoryVfs {
+        MemoryVfs::default()
+    }
+}
+
+impl Vfs for MemoryVfs {
+    fn name(&self) -> &str {
+        MEMORY_VFS_NAME
+    }
+
+    fn open(
+        &self,
+        path: &str,
+        flags: OpenFlags,
+        options: &ConnectionOptions,
+    ) -> SqliteResult<Box<dyn VfsFile>> {
+        let no_lock = options.no_lock();
+        let read_only = flags.access == OpenAccess::ReadOnly;
+        if path.is_empty() {
+            return Ok(Box::new(MemoryFile {
+                name: None,
+                data: SharedFile::default(),
+                files: self.files.clone(),
+                lock: LockLevel::None,
+                read_only,
+                no_lock,
+                delete_on_close: true,
+            }));
+        }
+
+        let mut files = lock(&self.files);
+        let data = match files.get(path) {
+            Some(data) => data.clone(),
+            None if flags.access == OpenAccess::ReadWriteCreate => {
+                let data = SharedFile::default();
+                files.insert(String::from(path), data.clone());
+                data
+            }
+            None => {
+                return Err(SqliteError::CannotOpen {
+                    code: ExtendedResultCode::CantOpen,
+                    message: format!("unable to open database file: {}", path),
+                    source: None,
+                })
+            }
+        };
+        Ok(Box::new(MemoryFile {
+            name: Some(String::from(path)),
+            data,
+            files: self.files.clone(),
+            lock: LockLevel::None,
+            read_only,
+            no_lock,
+            delete_on_close: flags.delete_on_close,
+        }))
+    }
+
+    fn delete(&self, path: &str, _sync_dir: bool) -> SqliteResult<()> {
+        match lock(&self.files).remove(path) {
+            Some(_) => Ok(()),
+            None => Err(SqliteError::IoErr {
+                code: ExtendedResultCode::IoErrDeleteNoEnt,
+                message: format!("no such file: {}", path),
+                source: None,
+            }),
+        }
+    }
+
+    fn access(&self, path: &str, _flags: AccessFlags) -> SqliteResult<bool> {
+        Ok(lock(&self.files).contains_key(path))
+    }
+}
+
+/// A handle to a file of the [`MemoryVfs`]
+#[derive(Debug)]
+pub struct MemoryFile {
+    /// `None` for a private file
+    name: Option<String>,
+    data: SharedFile,
+    files: FileTable,
+    lock: LockLevel,
+    read_only: bool,
+    no_lock: bool,
+    delete_on_close: bool,
+}
+
+impl MemoryFile {
+    fn check_writable(&self) -> SqliteResult<()> {
+        if self.read_only {
+            return Err(SqliteError::ReadOnly {
+                code: ExtendedResultCode::ReadOnly,
+                message: String::from("attempt to write a readonly database"),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl VfsFile for MemoryFile {
+    fn read(&mut self, buf: &mut [u8], offset: u64) -> SqliteResult<usize> {
+        let data = lock(&self.data);
+        let start = (offset as usize).min(data.content.len());
+        let end = (start + buf.len()).min(data.content.len());
+        let n = end - start;
+        buf[..n].copy_from_slice(&data.content[start..end]);
+        buf[n..].fill(0);
+        Ok(n)
+    }
+
+    fn write(&mut self, buf: &[u8], offset: u64) -> SqliteResult<()> {
+        self.check_writable()?;
+        let mut data = lock(&self.data);
+        let start = offset as usize;
+        let end = start + buf.len();
+        if data.content.len() < end {
+            data.content.resize(end, 0);
+        }
+        data.content[start..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn truncate(&mut self, size: u64) -> SqliteResult<()> {
+        self.check_writable()?;
+        let mut data = lock(&self.data);
+        if (size as usize) < data.content.len() {
+            data.content.truncate(size as usize);
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self, _flags: SyncFlags) -> SqliteResult<()> {
+        Ok(())
+    }
+
+    fn file_size(&self) -> SqliteResult<u64> {
+        Ok(lock(&self.data).content.len() as u64)
+    }
+
+    fn lock(&mut self, level: LockLevel) -> SqliteResult<()> {
+        if self.no_lock {
+            self.lock = self.lock.max(level);
+            return Ok(());
+        }
+        lock(&self.data).locks.lock(&mut self.lock, level)
+    }
+
+    fn unlock(&mut self, level: LockLevel) -> SqliteResult<()> {
+        if self.no_lock {
+            self.lock = self.lock.min(level);
+            return Ok(());
+        }
+        lock(&self.data).locks.unlock(&mut self.lock, level);
+        Ok(())
+    }
+
+    fn check_reserved_lock(&self) -> SqliteResult<bool> {
+        Ok(!self.no_lock && lock(&self.data).locks.is_reserved())
+    }
+
+    fn lock_level(&self) -> LockLevel {
+        self.lock
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn shm_open(&mut self) -> SqliteResult<Option<Box<dyn Shm>>> {
+        Ok(Some(Box::new(lock(&self.data).shm.share())))
+    }
+}
+
+impl Drop for MemoryFile {
+    fn drop(&mut self) {
+        let _ = self.unlock(LockLevel::None);
+        let Some(name) = self.name.as_ref() else {
+            return;
+        };
+        if self.delete_on_close {
+            let mut files = lock(&self.files);
+            // the table holds one reference and this handle the other
+            if files
+                .get(name)
+                .is_some_and(|data| Arc::ptr_eq(data, &self.data) && Arc::strong_count(data) == 2)
+            {
+                files.remove(name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flags(access: OpenAccess) -> OpenFlags {
+        OpenFlags::new(access, crate::vfs::FileKind::MainDb)
+    }
+
+    #[test]
+    fn read_write_truncate() {
+        let vfs = MemoryVfs::new();
+        let options = ConnectionOptions::default();
+        let mut file = vfs
+            .open("a.db", flags(OpenAccess::ReadWriteCreate), &options)
+            .unwrap();
+        file.write(b"hello", 3).unwrap();
+        assert_eq!(file.file_size().unwrap(), 8);
+
+        let mut buf = [0xffu8; 10];
+        assert_eq!(file.read(&mut buf, 0).unwrap(), 8);
+        assert_eq!(&buf, b"\0\0\0hello\0\0");
+        assert_eq!(file.read(&mut buf, 100).unwrap(), 0);
+
+        file.truncate(4).unwrap();
+        assert_eq!(file.file_size().unwrap(), 4);
+        // truncating never grows the file
+        file.truncate(10).unwrap();
+        assert_eq!(file.file_size().unwrap(), 4);
+    }
+
+    #[test]
+    fn named_files_are_shared() {
+        let vfs = MemoryVfs::new();
+        let options = ConnectionOptions::default();
+        let mut a = vfs
+            .open("shared.db", flags(OpenAccess::ReadWriteCreate), &options)
+            .unwrap();
+        let mut b = vfs
+            .open("shared.db", flags(OpenAccess::ReadOnly), &options)
+            .unwrap();
+        a.write(b"abc", 0).unwrap();
+        let mut buf = [0u8; 3];
+        b.read(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"abc");
+        assert!(b.write(b"x", 0).is_err());
+
+        a.lock(LockLevel::Reserved).unwrap();
+        assert!(b.check_reserved_lock().unwrap());
+        b.lock(LockLevel::Shared).unwrap();
+        assert!(a.lock(LockLevel::Exclusive).is_err());
+        drop(b);
+        a.lock(LockLevel::Exclusive).unwrap();
+    }
+
+    #[test]
+    fn private_files_are_not_shared() {
+        let vfs = MemoryVfs::new();
+        let options = ConnectionOptions::default();
+        let mut a = vfs
+            .open("", flags(OpenAccess::ReadWriteCreate), &options)
+            .unwrap();
+        let b = vfs
+            .open("", flags(OpenAccess::ReadWriteCreate), &options)
+            .unwrap();
+        a.write(b"abc", 0).unwrap();
+        assert_eq!(b.file_size().unwrap(), 0);
+        assert!(!vfs.access("", AccessFlags::Exists).unwrap());
+    }
+
+    #[test]
+    fn open_missing_without_create_err() {
+        let vfs = MemoryVfs::new();
+        let result = vfs.open(
+            "missing.db",
+            flags(OpenAccess::ReadWrite),
+            &ConnectionOptions::default(),
+        );
+        assert!(matches!(
+            result,
+            Err(SqliteError::CannotOpen {
+                code: ExtendedResultCode::CantOpen,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn delete_and_delete_on_close() {
+        let vfs = MemoryVfs::new();
+        let options = ConnectionOptions::default();
+        vfs.open("kept.db", flags(OpenAccess::ReadWriteCreate), &options)
+            .unwrap();
+        assert!(vfs.access("kept.db", AccessFlags::Exists).unwrap());
+        vfs.delete("kept.db", false).unwrap();
+        assert!(!vfs.access("kept.db", AccessFlags::Exists).unwrap());
+        assert!(matches!(
+            vfs.delete("kept.db", false),
+            Err(SqliteError::IoErr {
+                code: ExtendedResultCode::IoErrDeleteNoEnt,
+                ..
+            })
+        ));
+
+        let mut temp = flags(OpenAccess::ReadWriteCreate);
+        temp.delete_on_close = true;
+        let a = vfs.open("temp.db", temp, &options).unwrap();
+        let b = vfs.open("temp.db", temp, &options).unwrap();
+        drop(a);
+        assert!(vfs.access("temp.db", AccessFlags::Exists).unwrap());
+        drop(b);
+        assert!(!vfs.access("temp.db", AccessFlags::Exists).unwrap());
+    }
+}
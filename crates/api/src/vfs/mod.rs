@@ -0,0 +1,232 @@
+mod lock;
+mod memory;
+mod shm;
+mod unix;
+
+pub use self::lock::*;
+pub use self::memory::*;
+pub use self::shm::*;
+pub use self::unix::*;
+
+use crate::connection::ConnectionOptions;
+use crate::errors::SqliteResult;
+use std::fmt::Debug;
+use std::sync::{Arc, OnceLock, RwLock};
+
+/// The access a file is opened with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpenAccess {
+    ReadOnly,
+    ReadWrite,
+    /// Read and write, creating the file if it does not exist
+    ReadWriteCreate,
+}
+
+/// What a file is used for.  A VFS may treat these differently, e.g. by skipping syncs for
+/// temporary files.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileKind {
+    MainDb,
+    MainJournal,
+    Wal,
+    TempDb,
+}
+
+/// Flags passed to [`Vfs::open`], mirroring the `SQLITE_OPEN_*` flags
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OpenFlags {
+    pub access: OpenAccess,
+    pub kind: FileKind,
+    /// Remove the file once the last handle to it is closed
+    pub delete_on_close: bool,
+}
+
+impl OpenFlags {
+    pub fn new(access: OpenAccess, kind: FileKind) -> OpenFlags {
+        OpenFlags {
+            access,
+            kind,
+            delete_on_close: false,
+        }
+    }
+}
+
+/// The question asked by [`Vfs::access`], mirroring the `SQLITE_ACCESS_*` flags
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessFlags {
+    Exists,
+    ReadWrite,
+    Read,
+}
+
+/// How thoroughly [`VfsFile::sync`] flushes, mirroring the `SQLITE_SYNC_*` flags
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncFlags {
+    Normal,
+    Full,
+    /// Only the file content needs to reach the disk, not its metadata
+    DataOnly,
+}
+
+/// A virtual file system, modeled on `sqlite3_vfs`.  Implementations must be safe to share
+/// between threads since one instance serves every connection that uses it.
+pub trait Vfs: Debug + Send + Sync {
+    /// The name the VFS is registered and selected under, e.g. with `vfs=` in a URI
+    fn name(&self) -> &str;
+
+    /// Open a file.  `options` carries the connection's URI parameters, including ones the
+    /// library does not interpret, so a VFS can be configured per connection.
+    fn open(
+        &self,
+        path: &str,
+        flags: OpenFlags,
+        options: &ConnectionOptions,
+    ) -> SqliteResult<Box<dyn VfsFile>>;
+
+    /// Delete a file, optionally syncing the directory that held it
+    fn delete(&self, path: &str, sync_dir: bool) -> SqliteResult<()>;
+
+    fn access(&self, path: &str, flags: AccessFlags) -> SqliteResult<bool>;
+
+    /// The canonical form of `path`, used to decide whether two connections open the same file
+    fn full_pathname(&self, path: &str) -> SqliteResult<String> {
+        Ok(String::from(path))
+    }
+}
+
+/// An open file, modeled on `sqlite3_io_methods`
+pub trait VfsFile: Debug + Send {
+    /// Fill `buf` from `offset`, returning the number of bytes that were in the file.  As in
+    /// sqlite3, the rest of a short read is filled with zeros.
+    fn read(&mut self, buf: &mut [u8], offset: u64) -> SqliteResult<usize>;
+
+    fn write(&mut self, buf: &[u8], offset: u64) -> SqliteResult<()>;
+
+    fn truncate(&mut self, size: u64) -> SqliteResult<()>;
+
+    fn sync(&mut self, flags: SyncFlags) -> SqliteResult<()>;
+
+    fn file_size(&self) -> SqliteResult<u64>;
+
+    /// Raise the lock on the file, failing with `SQLITE_BUSY` when another connection holds a
+    /// conflicting lock
+    fn lock(&mut self, level: LockLevel) -> SqliteResult<()>;
+
+    /// Lower the lock on the file to `level`, which is either `Shared` or `None`
+    fn unlock(&mut self, level: LockLevel) -> SqliteResult<()>;
+
+    /// Whether any connection holds a reserved or higher lock on the file
+    fn check_reserved_lock(&self) -> SqliteResult<bool>;
+
+    /// The lock this handle currently holds
+    fn lock_level(&self) -> LockLevel;
+
+    /// Whether the file was opened, or fell back to being opened, read only
+    fn is_read_only(&self) -> bool;
+
+    /// The smallest unit the device writes atomically, which pads the rollback journal header
+    fn sector_size(&self) -> u32 {
+        DEFAULT_SECTOR_SIZE
+    }
+
+    /// Open the shared memory for the wal-index of this database file.  `None` means the VFS
+    /// has none, so connections cannot share a database in WAL mode and each keeps its
+    /// wal-index on the heap while holding an exclusive lock on the file.
+    fn shm_open(&mut self) -> SqliteResult<Option<Box<dyn Shm>>> {
+        Ok(None)
+    }
+}
+
+/// The sector size sqlite3 assumes on unix
+pub const DEFAULT_SECTOR_SIZE: u32 = 4096;
+
+/// Name of the VFS backed by the local file system
+pub const UNIX_VFS_NAME: &str = "unix";
+
+/// Name of the VFS that keeps files in memory
+pub const MEMORY_VFS_NAME: &str = "memdb";
+
+fn registry() -> &'static RwLock<Vec<Arc<dyn Vfs>>> {
+    static REGISTRY: OnceLock<RwLock<Vec<Arc<dyn Vfs>>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        RwLock::new(vec![
+            Arc::new(UnixVfs::new()) as Arc<dyn Vfs>,
+            Arc::new(MemoryVfs::new()),
+        ])
+    })
+}
+
+/// Register a VFS, replacing any registered under the same name.  The default VFS is used
+/// by connections that do not name one.
+pub fn register(vfs: Arc<dyn Vfs>, make_default: bool) {
+    let mut registry = registry().write().unwrap_or_else(|e| e.into_inner());
+    registry.retain(|v| v.name() != vfs.name());
+    if make_default {
+        registry.insert(0, vfs);
+    } else {
+        registry.push(vfs);
+    }
+}
+
+/// Remove a VFS from the registry.  Connections already using it are unaffected.
+pub fn unregister(name: &str) {
+    let mut registry = registry().write().unwrap_or_else(|e| e.into_inner());
+    registry.retain(|v| v.name() != name);
+}
+
+/// Look up a VFS by name, or the default VFS when `name` is `None`
+pub fn find(name: Option<&str>) -> Option<Arc<dyn Vfs>> {
+    let registry = registry().read().unwrap_or_else(|e| e.into_inner());
+    match name {
+        Some(name) => registry.iter().find(|v| v.name() == name).cloned(),
+        None => registry.first().cloned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_vfs_registered() {
+        assert_eq!(find(Some(MEMORY_VFS_NAME)).unwrap().name(), MEMORY_VFS_NAME);
+        assert_eq!(find(Some(UNIX_VFS_NAME)).unwrap().name(), UNIX_VFS_NAME);
+        assert!(find(None).is_some());
+        assert!(find(Some("missing")).is_none());
+    }
+
+    #[test]
+    fn register_and_unregister() {
+        #[derive(Debug)]
+        struct Named(MemoryVfs);
+
+        impl Vfs for Named {
+            fn name(&self) -> &str {
+                "test-register"
+            }
+
+            fn open(
+                &self,
+                path: &str,
+                flags: OpenFlags,
+                options: &ConnectionOptions,
+            ) -> SqliteResult<Box<dyn VfsFile>> {
+                self.0.open(path, flags, options)
+            }
+
+            fn delete(&self, path: &str, sync_dir: bool) -> SqliteResult<()> {
+                self.0.delete(path, sync_dir)
+            }
+
+            fn access(&self, path: &str, flags: AccessFlags) -> SqliteResult<bool> {
+                self.0.access(path, flags)
+            }
+        }
+
+        register(Arc::new(Named(MemoryVfs::new())), false);
+        assert_eq!(find(Some("test-register")).unwrap().name(), "test-register");
+        assert_ne!(find(None).unwrap().name(), "test-register");
+        unregister("test-register");
+        assert!(find(Some("test-register")).is_none());
+    }
+}
@@ -0,0 +1,381 @@
+use crate::errors::SqliteResult;
+use crate::vfs::lock::busy;
+use std::any::Any;
+use std::fmt::{Debug, Formatter};
+use std::ptr::NonNull;
+use std::sync::atomic::{fence, AtomicU16, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Size of the regions the wal-index shared memory is mapped in, as in sqlite3
+pub const SHM_REGION_SIZE: usize = 32768;
+
+/// Number of wal-index locks: write, checkpoint, recover and five read locks
+pub const SHM_NLOCK: usize = 8;
+
+/// Offset of the byte in the `-shm` file locked for the first wal-index lock; the others
+/// follow it
+pub const SHM_LOCK_OFFSET: u64 = 120;
+
+/// The byte every process with the shared memory open holds a shared lock on.  The first one
+/// to open it gets an exclusive lock instead and resets whatever a crashed process left.
+pub const SHM_DMS_BYTE: u64 = SHM_LOCK_OFFSET + SHM_NLOCK as u64;
+
+/// Suffix appended to the database path to name the file behind the wal-index
+pub const SHM_SUFFIX: &str = "-shm";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShmLockKind {
+    Shared,
+    Exclusive,
+}
+
+/// One region of shared memory, accessed a word at a time with atomic loads and stores since
+/// other connections, in this process or others, read and write it concurrently.  Clones
+/// share the memory, which stays mapped while any of them lives.
+#[derive(Clone)]
+pub struct ShmRegion {
+    ptr: NonNull<u8>,
+    _owner: Arc<dyn Any + Send + Sync>,
+}
+
+// SAFETY: the memory is only accessed through atomics and `_owner` keeps it alive
+unsafe impl Send for ShmRegion {}
+unsafe impl Sync for ShmRegion {}
+
+impl Debug for ShmRegion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShmRegion").field("ptr", &self.ptr).finish()
+    }
+}
+
+impl ShmRegion {
+    /// A region of `SHM_REGION_SIZE` bytes at `ptr`, kept alive by `owner`
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for reads and writes of `SHM_REGION_SIZE` bytes, aligned to 8 bytes,
+    /// for as long as `owner` lives.
+    pub unsafe fn new(ptr: NonNull<u8>, owner: Arc<dyn Any + Send + Sync>) -> ShmRegion {
+        debug_assert_eq!(ptr.as_ptr() as usize % 8, 0);
+        ShmRegion { ptr, _owner: owner }
+    }
+
+    /// A zeroed region on the heap
+    pub fn heap() -> ShmRegion {
+        let words: Arc<[AtomicU64]> = (0..SHM_REGION_SIZE / 8)
+            .map(|_| AtomicU64::new(0))
+            .collect();
+        let ptr = NonNull::new(words.as_ptr() as *mut u8).expect("allocations are not null");
+        // SAFETY: the words are owned by the region and atomics may be accessed as bytes
+        unsafe { ShmRegion::new(ptr, Arc::new(words)) }
+    }
+
+    fn at<T>(&self, offset: usize) -> &T {
+        let size = std::mem::size_of::<T>();
+        assert!(offset % size == 0 && offset + size <= SHM_REGION_SIZE);
+        // SAFETY: in bounds and aligned as checked above, and only used as an atomic
+        unsafe { &*(self.ptr.as_ptr().add(offset) as *const T) }
+    }
+
+    pub fn load_u32(&self, offset: usize) -> u32 {
+        self.at::<AtomicU32>(offset).load(Ordering::Relaxed)
+    }
+
+    pub fn store_u32(&self, offset: usize, value: u32) {
+        self.at::<AtomicU32>(offset).store(value, Ordering::Relaxed)
+    }
+
+    pub fn load_u16(&self, offset: usize) -> u16 {
+        self.at::<AtomicU16>(offset).load(Ordering::Relaxed)
+    }
+
+    pub fn store_u16(&self, offset: usize, value: u16) {
+        self.at::<AtomicU16>(offset).store(value, Ordering::Relaxed)
+    }
+
+    /// Copy out bytes in native word order.  `offset` and the length must be multiples of 4.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) {
+        for (i, word) in buf.chunks_exact_mut(4).enumerate() {
+            word.copy_from_slice(&self.load_u32(offset + i * 4).to_ne_bytes());
+        }
+    }
+
+    /// Copy in bytes in native word order.  `offset` and the length must be multiples of 4.
+    pub fn write(&self, offset: usize, buf: &[u8]) {
+        for (i, word) in buf.chunks_exact(4).enumerate() {
+            self.store_u32(offset + i * 4, u32::from_ne_bytes(word.try_into().unwrap()));
+        }
+    }
+
+    /// Zero `len` bytes from `offset`, both multiples of 4
+    pub fn zero(&self, offset: usize, len: usize) {
+        for word in (offset..offset + len).step_by(4) {
+            self.store_u32(word, 0);
+        }
+    }
+}
+
+/// The shared memory holding the wal-index of a database, modeled on the `xShm*` methods of
+/// `sqlite3_io_methods`.  Each connection has its own handle, and the locks it holds are
+/// released when the handle is dropped.
+pub trait Shm: Debug + Send {
+    /// Region `region`, created zeroed when `extend` is set; `None` when it does not exist
+    /// yet and `extend` is not set
+    fn map(&mut self, region: usize, extend: bool) -> SqliteResult<Option<ShmRegion>>;
+
+    /// Lock `n` consecutive wal-index locks from `slot`, failing with `SQLITE_BUSY` when
+    /// another connection holds a conflicting lock
+    fn lock(&mut self, slot: usize, n: usize, kind: ShmLockKind) -> SqliteResult<()>;
+
+    /// Release whatever this handle holds of `n` consecutive locks from `slot`
+    fn unlock(&mut self, slot: usize, n: usize) -> SqliteResult<()>;
+
+    /// Make the writes before the barrier visible to other connections before any after it
+    fn barrier(&self) {
+        fence(Ordering::SeqCst);
+    }
+
+    /// Close the handle, also removing the file behind the memory when `delete` is set and no
+    /// other connection in this process has it open
+    fn unmap(self: Box<Self>, delete: bool) -> SqliteResult<()>;
+}
+
+/// The wal-index locks one handle holds, a bit per lock
+#[derive(Debug, Default)]
+pub(crate) struct ShmHeld {
+    shared: u8,
+    exclusive: u8,
+}
+
+/// The wal-index locks of a process as seen by other processes, taken by [`ShmLocks`] as the
+/// combined locks of the handles in the process change.  Locking returns `SQLITE_BUSY` when
+/// another process holds a conflicting lock.
+pub(crate) trait ShmProcessLock {
+    fn lock(&mut self, slot: usize, n: usize, kind: ShmLockKind) -> SqliteResult<()>;
+
+    fn unlock(&mut self, slot: usize, n: usize) -> SqliteResult<()>;
+}
+
+/// For memory no other process can see
+impl ShmProcessLock for () {
+    fn lock(&mut self, _slot: usize, _n: usize, _kind: ShmLockKind) -> SqliteResult<()> {
+        Ok(())
+    }
+
+    fn unlock(&mut self, _slot: usize, _n: usize) -> SqliteResult<()> {
+        Ok(())
+    }
+}
+
+/// The wal-index locks held by all handles in this process.  Any number of handles may share
+/// a lock, while an exclusive lock shuts out every other handle.
+#[derive(Debug, Default)]
+pub(crate) struct ShmLocks {
+    shared: [usize; SHM_NLOCK],
+    exclusive: u8,
+}
+
+fn slot_mask(slot: usize, n: usize) -> u8 {
+    assert!(n > 0 && slot + n <= SHM_NLOCK);
+    (((1u16 << n) - 1) << slot) as u8
+}
+
+impl ShmLocks {
+    pub(crate) fn lock_with(
+        &mut self,
+        held: &mut ShmHeld,
+        slot: usize,
+        n: usize,
+        kind: ShmLockKind,
+        process: &mut impl ShmProcessLock,
+    ) -> SqliteResult<()> {
+        let mask = slot_mask(slot, n);
+        match kind {
+            ShmLockKind::Shared => {
+                if self.exclusive & mask & !held.exclusive != 0 {
+                    return Err(busy());
+                }
+                for i in slot..slot + n {
+                    let bit = 1 << i;
+                    if held.shared & bit != 0 || held.exclusive & bit != 0 {
+                        continue;
+                    }
+                    if self.shared[i] == 0 {
+                        process.lock(i, 1, ShmLockKind::Shared)?;
+                    }
+                    self.shared[i] += 1;
+                    held.shared |= bit;
+                }
+            }
+            ShmLockKind::Exclusive => {
+                if held.exclusive & mask == mask {
+                    return Ok(());
+                }
+                for i in slot..slot + n {
+                    let bit = 1 << i;
+                    let others = self.shared[i] - usize::from(held.shared & bit != 0);
+                    if others > 0 || self.exclusive & bit & !held.exclusive != 0 {
+                        return Err(busy());
+                    }
+                }
+                process.lock(slot, n, ShmLockKind::Exclusive)?;
+                self.exclusive |= mask;
+                held.exclusive |= mask;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn unlock_with(
+        &mut self,
+        held: &mut ShmHeld,
+        slot: usize,
+        n: usize,
+        process: &mut impl ShmProcessLock,
+    ) -> SqliteResult<()> {
+        for i in slot..slot + n {
+            let bit = 1 << i;
+            if held.shared & bit != 0 {
+                held.shared &= !bit;
+                self.shared[i] -= 1;
+                if self.shared[i] == 0 && held.exclusive & bit == 0 {
+                    process.unlock(i, 1)?;
+                }
+            }
+            if held.exclusive & bit != 0 {
+                held.exclusive &= !bit;
+                self.exclusive &= !bit;
+                if self.shared[i] == 0 {
+                    process.unlock(i, 1)?;
+                } else {
+                    process.lock(i, 1, ShmLockKind::Shared)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[derive(Debug, Default)]
+struct HeapShmData {
+    regions: Vec<ShmRegion>,
+    locks: ShmLocks,
+}
+
+/// Wal-index memory on the heap.  It is private to one connection unless handles are made
+/// with [`HeapShm::share`], which is how the [`MemoryVfs`](crate::vfs::MemoryVfs) shares it
+/// between the connections to one file.
+#[derive(Debug, Default)]
+pub struct HeapShm {
+    data: Arc<Mutex<HeapShmData>>,
+    held: ShmHeld,
+}
+
+impl HeapShm {
+    pub fn new() -> HeapShm {
+        HeapShm::default()
+    }
+
+    /// Another handle on the same memory, holding no locks
+    pub fn share(&self) -> HeapShm {
+        HeapShm {
+            data: self.data.clone(),
+            held: ShmHeld::default(),
+        }
+    }
+}
+
+impl Shm for HeapShm {
+    fn map(&mut self, region: usize, extend: bool) -> SqliteResult<Option<ShmRegion>> {
+        let mut data = lock(&self.data);
+        if region >= data.regions.len() {
+            if !extend {
+                return Ok(None);
+            }
+            data.regions.resize_with(region + 1, ShmRegion::heap);
+        }
+        Ok(Some(data.regions[region].clone()))
+    }
+
+    fn lock(&mut self, slot: usize, n: usize, kind: ShmLockKind) -> SqliteResult<()> {
+        lock(&self.data)
+            .locks
+            .lock_with(&mut self.held, slot, n, kind, &mut ())
+    }
+
+    fn unlock(&mut self, slot: usize, n: usize) -> SqliteResult<()> {
+        lock(&self.data)
+            .locks
+            .unlock_with(&mut self.held, slot, n, &mut ())
+    }
+
+    fn unmap(self: Box<Self>, delete: bool) -> SqliteResult<()> {
+        if delete && Arc::strong_count(&self.data) == 1 {
+            lock(&self.data).regions.clear();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for HeapShm {
+    fn drop(&mut self) {
+        let _ = self.unlock(0, SHM_NLOCK);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ExtendedResultCode;
+    use crate::SqliteError;
+
+    fn is_busy(result: SqliteResult<()>) -> bool {
+        matches!(
+            result,
+            Err(SqliteError::Busy {
+                code: ExtendedResultCode::Busy,
+                ..
+            })
+        )
+    }
+
+    #[test]
+    fn regions_are_shared_between_handles() {
+        let mut a = HeapShm::new();
+        let mut b = a.share();
+        assert!(b.map(0, false).unwrap().is_none());
+        let region = a.map(1, true).unwrap().unwrap();
+        region.store_u32(4, 7);
+        region.store_u16(SHM_REGION_SIZE - 2, 9);
+        let other = b.map(1, false).unwrap().unwrap();
+        assert_eq!(other.load_u32(4), 7);
+        assert_eq!(other.load_u16(SHM_REGION_SIZE - 2), 9);
+        let mut buf = [0u8; 8];
+        other.read(0, &mut buf);
+        assert_eq!(&buf[4..], &7u32.to_ne_bytes());
+        assert!(b.map(0, false).unwrap().is_some());
+        // a private handle sees none of it
+        assert!(HeapShm::new().map(0, false).unwrap().is_none());
+    }
+
+    #[test]
+    fn locks_between_handles() {
+        let mut a = HeapShm::new();
+        let mut b = a.share();
+        a.lock(3, 1, ShmLockKind::Shared).unwrap();
+        b.lock(3, 1, ShmLockKind::Shared).unwrap();
+        assert!(is_busy(b.lock(3, 1, ShmLockKind::Exclusive)));
+        a.unlock(3, 1).unwrap();
+        // the only shared holder may upgrade
+        b.lock(3, 1, ShmLockKind::Exclusive).unwrap();
+        assert!(is_busy(a.lock(3, 1, ShmLockKind::Shared)));
+        b.lock(0, 1, ShmLockKind::Exclusive).unwrap();
+        assert!(is_busy(a.lock(0, 3, ShmLockKind::Exclusive)));
+        drop(b);
+        a.lock(0, 4, ShmLockKind::Exclusive).unwrap();
+    }
+}
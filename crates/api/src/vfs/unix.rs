@@ -0,0 +1,1064 @@
+use crate::connection::ConnectionOptions;
+use crate::errors::{ExtendedResultCode, SqliteResult};
+use crate::vfs::lock::{busy, ProcessLock};
+use crate::vfs::shm::{ShmHeld, ShmLocks, ShmProcessLock};
+use crate::vfs::{
+    AccessFlags, LockLevel, LockState, OpenAccess, OpenFlags, Shm, ShmLockKind, ShmRegion,
+    SyncFlags, Vfs, VfsFile, SHM_DMS_BYTE, SHM_LOCK_OFFSET, SHM_NLOCK, SHM_REGION_SIZE, SHM_SUFFIX,
+    UNIX_VFS_NAME,
+};
+use crate::SqliteError;
+use memmap2::MmapOptions;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::mem::ManuallyDrop;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::Path;
+use std::ptr::NonNull;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, Weak};
+
+/// The byte locked while a writer waits for readers to finish.  sqlite3 places the lock bytes
+/// at 1 GiB so they never overlap data in files smaller than that.
+pub const PENDING_BYTE: i64 = 0x40000000;
+
+/// The byte locked by the one connection that intends to write
+pub const RESERVED_BYTE: i64 = PENDING_BYTE + 1;
+
+/// The first byte of the range readers lock
+pub const SHARED_FIRST: i64 = PENDING_BYTE + 2;
+
+pub const SHARED_SIZE: i64 = 510;
+
+/// Identifies a file independently of the path used to open it
+type InodeKey = (u64, u64);
+
+/// The state this process keeps for one file, shared by every handle to it
+#[derive(Debug, Default)]
+struct Inode {
+    locks: LockState,
+    handles: usize,
+    /// Closing any descriptor drops every POSIX lock the process holds on the file, so
+    /// handles closed while others hold locks leave their descriptor here instead
+    unused: Vec<File>,
+}
+
+type SharedInode = Arc<Mutex<Inode>>;
+
+fn inodes() -> &'static Mutex<HashMap<InodeKey, SharedInode>> {
+    static INODES: OnceLock<Mutex<HashMap<InodeKey, SharedInode>>> = OnceLock::new();
+    INODES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// The wal-index shared memory of each database open in this process, by the database's inode
+fn shm_nodes() -> &'static Mutex<HashMap<InodeKey, Weak<Mutex<ShmNode>>>> {
+    static NODES: OnceLock<Mutex<HashMap<InodeKey, Weak<Mutex<ShmNode>>>>> = OnceLock::new();
+    NODES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// fcntl byte-range locks on one descriptor, laid out as sqlite3 lays them out so that
+/// sqlite3 processes and this crate see each other's locks
+struct Fcntl {
+    fd: RawFd,
+}
+
+impl Fcntl {
+    fn set(
+        &self,
+        lock_type: i32,
+        start: i64,
+        len: i64,
+        code: ExtendedResultCode,
+    ) -> SqliteResult<()> {
+        // SAFETY: flock is plain data and zero is a valid value for every field
+        let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+        lock.l_type = lock_type as _;
+        lock.l_whence = libc::SEEK_SET as _;
+        lock.l_start = start as _;
+        lock.l_len = len as _;
+        // SAFETY: fd is open for as long as the handle that owns it
+        if unsafe { libc::fcntl(self.fd, libc::F_SETLK, &lock) } == 0 {
+            return Ok(());
+        }
+        let err = std::io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EAGAIN) | Some(libc::EACCES) => Err(busy()),
+            _ => Err(SqliteError::io(
+                code,
+                "unable to lock the database file",
+                err,
+            )),
+        }
+    }
+
+    /// Whether another process holds a write lock on the reserved byte
+    fn is_reserved(&self) -> SqliteResult<bool> {
+        // SAFETY: as in set
+        let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+        lock.l_type = libc::F_WRLCK as _;
+        lock.l_whence = libc::SEEK_SET as _;
+        lock.l_start = RESERVED_BYTE as _;
+        lock.l_len = 1;
+        if unsafe { libc::fcntl(self.fd, libc::F_GETLK, &mut lock) } != 0 {
+            return Err(SqliteError::io(
+                ExtendedResultCode::IoErrCheckReservedLock,
+                "unable to check the reserved lock",
+                std::io::Error::last_os_error(),
+            ));
+        }
+        Ok(lock.l_type as i32 != libc::F_UNLCK)
+    }
+}
+
+impl ProcessLock for Fcntl {
+    fn acquire_shared(&mut self) -> SqliteResult<()> {
+        // hold the pending byte while taking the shared range so that a writer waiting for
+        // readers to finish is not starved by new ones
+        self.set(
+            libc::F_RDLCK,
+            PENDING_BYTE,
+            1,
+            ExtendedResultCode::IoErrRdLock,
+        )?;
+        let shared = self.set(
+            libc::F_RDLCK,
+            SHARED_FIRST,
+            SHARED_SIZE,
+            ExtendedResultCode::IoErrRdLock,
+        );
+        self.set(
+            libc::F_UNLCK,
+            PENDING_BYTE,
+            1,
+            ExtendedResultCode::IoErrUnlock,
+        )?;
+        shared
+    }
+
+    fn acquire_reserved(&mut self) -> SqliteResult<()> {
+        self.set(
+            libc::F_WRLCK,
+            RESERVED_BYTE,
+            1,
+            ExtendedResultCode::IoErrLock,
+        )
+    }
+
+    fn acquire_pending(&mut self) -> SqliteResult<()> {
+        self.set(
+            libc::F_WRLCK,
+            PENDING_BYTE,
+            1,
+            ExtendedResultCode::IoErrLock,
+        )
+    }
+
+    fn acquire_exclusive(&mut self) -> SqliteResult<()> {
+        self.set(
+            libc::F_WRLCK,
+            SHARED_FIRST,
+            SHARED_SIZE,
+            ExtendedResultCode::IoErrLock,
+        )
+    }
+
+    fn downgrade(&mut self, from: LockLevel) -> SqliteResult<()> {
+        if from == LockLevel::Exclusive {
+            self.set(
+                libc::F_RDLCK,
+                SHARED_FIRST,
+                SHARED_SIZE,
+                ExtendedResultCode::IoErrRdLock,
+            )?;
+        }
+        // the pending and reserved bytes are adjacent
+        self.set(
+            libc::F_UNLCK,
+            PENDING_BYTE,
+            2,
+            ExtendedResultCode::IoErrUnlock,
+        )
+    }
+
+    fn release(&mut self) -> SqliteResult<()> {
+        self.set(libc::F_UNLCK, 0, 0, ExtendedResultCode::IoErrUnlock)
+    }
+}
+
+/// The wal-index locks live on the bytes sqlite3 uses in the `-shm` file
+impl ShmProcessLock for Fcntl {
+    fn lock(&mut self, slot: usize, n: usize, kind: ShmLockKind) -> SqliteResult<()> {
+        let lock_type = match kind {
+            ShmLockKind::Shared => libc::F_RDLCK,
+            ShmLockKind::Exclusive => libc::F_WRLCK,
+        };
+        self.set(
+            lock_type,
+            SHM_LOCK_OFFSET as i64 + slot as i64,
+            n as i64,
+            ExtendedResultCode::IoErrShmLock,
+        )
+    }
+
+    fn unlock(&mut self, slot: usize, n: usize) -> SqliteResult<()> {
+        self.set(
+            libc::F_UNLCK,
+            SHM_LOCK_OFFSET as i64 + slot as i64,
+            n as i64,
+            ExtendedResultCode::IoErrShmLock,
+        )
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn io_error(code: ExtendedResultCode, message: String, err: std::io::Error) -> SqliteError {
+    SqliteError::io(code, message, err)
+}
+
+fn cannot_open(path: &str, err: std::io::Error) -> SqliteError {
+    let code = match err.kind() {
+        ErrorKind::IsADirectory => ExtendedResultCode::CantOpenIsDir,
+        _ => ExtendedResultCode::CantOpen,
+    };
+    io_error(code, format!("unable to open database file: {}", path), err)
+}
+
+/// The VFS backed by the local file system
+#[derive(Debug, Default)]
+pub struct UnixVfs {}
+
+impl UnixVfs {
+    pub fn new() -> UnixVfs {
+        UnixVfs {}
+    }
+
+    /// Open for reading and writing, falling back to read only when an existing file is write
+    /// protected.  Returns whether the file is read only.
+    fn open_read_write(
+        path: &str,
+        create: bool,
+        options: &ConnectionOptions,
+    ) -> SqliteResult<(File, bool)> {
+        let existed = std::fs::exists(path).unwrap_or(false);
+        match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(create)
+            .truncate(false)
+            .open(path)
+        {
+            Ok(file) => {
+                if !existed {
+                    if let Some(mode_of) = options.mode_of() {
+                        let permissions = std::fs::metadata(mode_of)
+                            .map_err(|err| cannot_open(mode_of, err))?
+                            .permissions();
+                        file.set_permissions(permissions)
+                            .map_err(|err| cannot_open(path, err))?;
+                    }
+                }
+                Ok((file, false))
+            }
+            Err(err) if err.kind() == ErrorKind::PermissionDenied && existed => {
+                let file = File::open(path).map_err(|err| cannot_open(path, err))?;
+                Ok((file, true))
+            }
+            Err(err) => Err(cannot_open(path, err)),
+        }
+    }
+}
+
+impl Vfs for UnixVfs {
+    fn name(&self) -> &str {
+        UNIX_VFS_NAME
+    }
+
+    fn open(
+        &self,
+        path: &str,
+        flags: OpenFlags,
+        options: &ConnectionOptions,
+    ) -> SqliteResult<Box<dyn VfsFile>> {
+        if std::fs::metadata(path).is_ok_and(|m| m.is_dir()) {
+            return Err(SqliteError::CannotOpen {
+                code: ExtendedResultCode::CantOpenIsDir,
+                message: format!("unable to open database file: {} is a directory", path),
+                source: None,
+            });
+        }
+
+        let (file, read_only) = match flags.access {
+            OpenAccess::ReadOnly => {
+                let file = File::open(path).map_err(|err| cannot_open(path, err))?;
+                (file, true)
+            }
+            OpenAccess::ReadWrite => UnixVfs::open_read_write(path, false, options)?,
+            OpenAccess::ReadWriteCreate => UnixVfs::open_read_write(path, true, options)?,
+        };
+        if flags.delete_on_close {
+            // the open handle keeps the content alive until it is closed
+            self.delete(path, false)?;
+        }
+
+        let metadata = file.metadata().map_err(|err| {
+            io_error(
+                ExtendedResultCode::IoErrFstat,
+                format!("unable to stat {}", path),
+                err,
+            )
+        })?;
+        let key = (metadata.dev(), metadata.ino());
+        let inode = lock(inodes()).entry(key).or_default().clone();
+        lock(&inode).handles += 1;
+        Ok(Box::new(UnixFile {
+            file: ManuallyDrop::new(file),
+            path: String::from(path),
+            key,
+            inode,
+            lock: LockLevel::None,
+            read_only,
+            no_lock: options.no_lock() || options.immutable(),
+        }))
+    }
+
+    fn delete(&self, path: &str, sync_dir: bool) -> SqliteResult<()> {
+        std::fs::remove_file(path).map_err(|err| {
+            let code = match err.kind() {
+                ErrorKind::NotFound => ExtendedResultCode::IoErrDeleteNoEnt,
+                _ => ExtendedResultCode::IoErrDelete,
+            };
+            io_error(code, format!("unable to delete {}", path), err)
+        })?;
+        if sync_dir {
+            let dir = match Path::new(path).parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            File::open(dir)
+                .and_then(|dir| dir.sync_all())
+                .map_err(|err| {
+                    io_error(
+                        ExtendedResultCode::IoErrDirFsync,
+                        format!("unable to sync the directory of {}", path),
+                        err,
+                    )
+                })?;
+        }
+        Ok(())
+    }
+
+    fn access(&self, path: &str, flags: AccessFlags) -> SqliteResult<bool> {
+        match std::fs::metadata(path) {
+            Ok(metadata) => Ok(match flags {
+                AccessFlags::Exists | AccessFlags::Read => true,
+                AccessFlags::ReadWrite => !metadata.permissions().readonly(),
+            }),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(io_error(
+                ExtendedResultCode::IoErrAccess,
+                format!("unable to access {}", path),
+                err,
+            )),
+        }
+    }
+
+    fn full_pathname(&self, path: &str) -> SqliteResult<String> {
+        std::path::absolute(path)
+            .map(|path| path.to_string_lossy().into_owned())
+            .map_err(|err| {
+                io_error(
+                    ExtendedResultCode::CantOpenFullPath,
+                    format!("unable to resolve {}", path),
+                    err,
+                )
+            })
+    }
+}
+
+/// A handle to a file of the [`UnixVfs`].  Locks follow sqlite3's protocol using fcntl
+/// byte-range locks, unless the connection set `nolock` or `immutable`.
+#[derive(Debug)]
+pub struct UnixFile {
+    /// Dropped by hand so the descriptor can outlive the handle, see [`Inode::unused`]
+    file: ManuallyDrop<File>,
+    path: String,
+    key: InodeKey,
+    inode: SharedInode,
+    lock: LockLevel,
+    read_only: bool,
+    no_lock: bool,
+}
+
+impl UnixFile {
+    fn fcntl(&self) -> Fcntl {
+        Fcntl {
+            fd: self.file.as_raw_fd(),
+        }
+    }
+
+    fn io_error(&self, code: ExtendedResultCode, what: &str, err: std::io::Error) -> SqliteError {
+        io_error(code, format!("unable to {} {}", what, self.path), err)
+    }
+}
+
+impl VfsFile for UnixFile {
+    fn read(&mut self, buf: &mut [u8], offset: u64) -> SqliteResult<usize> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.file.read_at(&mut buf[read..], offset + read as u64) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(self.io_error(ExtendedResultCode::IoErrRead, "read", err)),
+            }
+        }
+        buf[read..].fill(0);
+        Ok(read)
+    }
+
+    fn write(&mut self, buf: &[u8], offset: u64) -> SqliteResult<()> {
+        self.file
+            .write_all_at(buf, offset)
+            .map_err(|err| self.io_error(ExtendedResultCode::IoErrWrite, "write", err))
+    }
+
+    fn truncate(&mut self, size: u64) -> SqliteResult<()> {
+        self.file
+            .set_len(size)
+            .map_err(|err| self.io_error(ExtendedResultCode::IoErrTruncate, "truncate", err))
+    }
+
+    fn sync(&mut self, flags: SyncFlags) -> SqliteResult<()> {
+        let result = match flags {
+            SyncFlags::Full => self.file.sync_all(),
+            SyncFlags::Normal | SyncFlags::DataOnly => self.file.sync_data(),
+        };
+        result.map_err(|err| self.io_error(ExtendedResultCode::IoErrFsync, "sync", err))
+    }
+
+    fn file_size(&self) -> SqliteResult<u64> {
+        self.file
+            .metadata()
+            .map(|m| m.len())
+            .map_err(|err| self.io_error(ExtendedResultCode::IoErrFstat, "stat", err))
+    }
+
+    fn lock(&mut self, level: LockLevel) -> SqliteResult<()> {
+        if self.no_lock {
+            self.lock = self.lock.max(level);
+            return Ok(());
+        }
+        let mut fcntl = self.fcntl();
+        lock(&self.inode)
+            .locks
+            .lock_with(&mut self.lock, level, &mut fcntl)
+    }
+
+    fn unlock(&mut self, level: LockLevel) -> SqliteResult<()> {
+        if self.no_lock {
+            self.lock = self.lock.min(level);
+            return Ok(());
+        }
+        let mut fcntl = self.fcntl();
+        let mut inode = lock(&self.inode);
+        inode.locks.unlock_with(&mut self.lock, level, &mut fcntl)?;
+        if inode.locks.is_unlocked() {
+            inode.unused.clear();
+        }
+        Ok(())
+    }
+
+    fn check_reserved_lock(&self) -> SqliteResult<bool> {
+        if self.no_lock {
+            return Ok(false);
+        }
+        if lock(&self.inode).locks.is_reserved() {
+            return Ok(true);
+        }
+        self.fcntl().is_reserved()
+    }
+
+    fn lock_level(&self) -> LockLevel {
+        self.lock
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn shm_open(&mut self) -> SqliteResult<Option<Box<dyn Shm>>> {
+        if self.no_lock {
+            return Ok(None);
+        }
+        let mut nodes = lock(shm_nodes());
+        if let Some(node) = nodes.get(&self.key).and_then(Weak::upgrade) {
+            return Ok(Some(Box::new(UnixShm {
+                node,
+                held: ShmHeld::default(),
+            })));
+        }
+        let node = Arc::new(Mutex::new(ShmNode::open(self)?));
+        nodes.insert(self.key, Arc::downgrade(&node));
+        Ok(Some(Box::new(UnixShm {
+            node,
+            held: ShmHeld::default(),
+        })))
+    }
+}
+
+/// The `-shm` file of a database and its mappings, shared by every connection in this
+/// process.  It keeps the one descriptor the process locks the file through.
+#[derive(Debug)]
+struct ShmNode {
+    file: File,
+    path: String,
+    key: InodeKey,
+    regions: Vec<ShmRegion>,
+    locks: ShmLocks,
+}
+
+impl ShmNode {
+    /// Open the `-shm` file next to `db` with the same permissions and take the shared lock
+    /// that marks it in use.  When no other process has it open, whatever it holds was left by
+    /// a crash and is discarded.
+    fn open(db: &UnixFile) -> SqliteResult<ShmNode> {
+        let path = format!("{}{}", db.path, SHM_SUFFIX);
+        let shm_error = |err| {
+            io_error(
+                ExtendedResultCode::IoErrShmOpen,
+                format!("unable to open {}", path),
+                err,
+            )
+        };
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(shm_error)?;
+        if let Ok(metadata) = db.file.metadata() {
+            let _ = file.set_permissions(metadata.permissions());
+        }
+        let fcntl = Fcntl {
+            fd: file.as_raw_fd(),
+        };
+        let dms = SHM_DMS_BYTE as i64;
+        match fcntl.set(libc::F_WRLCK, dms, 1, ExtendedResultCode::IoErrShmLock) {
+            Ok(()) => file.set_len(0).map_err(shm_error)?,
+            Err(SqliteError::Busy { .. }) => {}
+            Err(err) => return Err(err),
+        }
+        fcntl.set(libc::F_RDLCK, dms, 1, ExtendedResultCode::IoErrShmLock)?;
+        Ok(ShmNode {
+            file,
+            path,
+            key: db.key,
+            regions: Vec::new(),
+            locks: ShmLocks::default(),
+        })
+    }
+
+    fn fcntl(&self) -> Fcntl {
+        Fcntl {
+            fd: self.file.as_raw_fd(),
+        }
+    }
+
+    fn io_error(&self, code: ExtendedResultCode, what: &str, err: std::io::Error) -> SqliteError {
+        io_error(code, format!("unable to {} {}", what, self.path), err)
+    }
+
+    fn map(&mut self, region: usize, extend: bool) -> SqliteResult<Option<ShmRegion>> {
+        if let Some(mapped) = self.regions.get(region) {
+            return Ok(Some(mapped.clone()));
+        }
+        let size = ((region + 1) * SHM_REGION_SIZE) as u64;
+        let file_size = self
+            .file
+            .metadata()
+            .map_err(|err| self.io_error(ExtendedResultCode::IoErrShmSize, "stat", err))?
+            .len();
+        if file_size < size {
+            if !extend {
+                return Ok(None);
+            }
+            self.file
+                .set_len(size)
+                .map_err(|err| self.io_error(ExtendedResultCode::IoErrShmSize, "extend", err))?;
+        }
+        while self.regions.len() <= region {
+            let offset = (self.regions.len() * SHM_REGION_SIZE) as u64;
+            // SAFETY: the file stays at least this long while it is shared, and the memory is
+            // only accessed through atomics
+            let mut mmap = unsafe {
+                MmapOptions::new()
+                    .offset(offset)
+                    .len(SHM_REGION_SIZE)
+                    .map_mut(&self.file)
+            }
+            .map_err(|err| self.io_error(ExtendedResultCode::IoErrShmMap, "map", err))?;
+            let ptr = NonNull::new(mmap.as_mut_ptr()).expect("mappings are not null");
+            // SAFETY: page aligned, and the mapping does not move with its owner
+            let mapped = unsafe { ShmRegion::new(ptr, Arc::new(mmap)) };
+            self.regions.push(mapped);
+        }
+        Ok(Some(self.regions[region].clone()))
+    }
+}
+
+impl Drop for ShmNode {
+    fn drop(&mut self) {
+        let mut nodes = lock(shm_nodes());
+        if nodes
+            .get(&self.key)
+            .is_some_and(|node| node.strong_count() == 0)
+        {
+            nodes.remove(&self.key);
+        }
+    }
+}
+
+/// A connection's handle on the wal-index shared memory of a [`UnixFile`].  Its locks are
+/// fcntl locks on the bytes sqlite3 uses, so sqlite3 processes share the wal-index too.
+#[derive(Debug)]
+pub struct UnixShm {
+    node: Arc<Mutex<ShmNode>>,
+    held: ShmHeld,
+}
+
+impl Shm for UnixShm {
+    fn map(&mut self, region: usize, extend: bool) -> SqliteResult<Option<ShmRegion>> {
+        lock(&self.node).map(region, extend)
+    }
+
+    fn lock(&mut self, slot: usize, n: usize, kind: ShmLockKind) -> SqliteResult<()> {
+        let mut node = lock(&self.node);
+        let mut fcntl = node.fcntl();
+        node.locks
+            .lock_with(&mut self.held, slot, n, kind, &mut fcntl)
+    }
+
+    fn unlock(&mut self, slot: usize, n: usize) -> SqliteResult<()> {
+        let mut node = lock(&self.node);
+        let mut fcntl = node.fcntl();
+        node.locks.unlock_with(&mut self.held, slot, n, &mut fcntl)
+    }
+
+    fn unmap(mut self: Box<Self>, delete: bool) -> SqliteResult<()> {
+        self.unlock(0, SHM_NLOCK)?;
+        if delete && Arc::strong_count(&self.node) == 1 {
+            let path = lock(&self.node).path.clone();
+            std::fs::remove_file(&path).map_err(|err| {
+                io_error(
+                    ExtendedResultCode::IoErrDelete,
+                    format!("unable to delete {}", path),
+                    err,
+                )
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for UnixShm {
+    fn drop(&mut self) {
+        let _ = self.unlock(0, SHM_NLOCK);
+    }
+}
+
+impl Drop for UnixFile {
+    fn drop(&mut self) {
+        let _ = self.unlock(LockLevel::None);
+        let mut inodes = lock(inodes());
+        let mut inode = lock(&self.inode);
+        inode.handles -= 1;
+        // SAFETY: the file is never used again after this
+        let file = unsafe { ManuallyDrop::take(&mut self.file) };
+        if inode.handles == 0 {
+            // no locks remain, so every descriptor can be closed
+            inode.unused.clear();
+            drop(file);
+            drop(inode);
+            inodes.remove(&self.key);
+        } else if inode.locks.is_unlocked() {
+            drop(file);
+        } else {
+            inode.unused.push(file);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn path(dir: &TempDir, name: &str) -> String {
+        dir.path().join(name).to_str().unwrap().to_string()
+    }
+
+    fn flags(access: OpenAccess) -> OpenFlags {
+        OpenFlags::new(access, crate::vfs::FileKind::MainDb)
+    }
+
+    #[test]
+    fn read_write_truncate() {
+        let dir = TempDir::new().unwrap();
+        let vfs = UnixVfs::new();
+        let options = ConnectionOptions::default();
+        let name = path(&dir, "a.db");
+        let mut file = vfs
+            .open(&name, flags(OpenAccess::ReadWriteCreate), &options)
+            .unwrap();
+        file.write(b"hello", 3).unwrap();
+        file.sync(SyncFlags::Full).unwrap();
+        assert_eq!(file.file_size().unwrap(), 8);
+
+        let mut buf = [0xffu8; 10];
+        assert_eq!(file.read(&mut buf, 0).unwrap(), 8);
+        assert_eq!(&buf, b"\0\0\0hello\0\0");
+
+        file.truncate(4).unwrap();
+        assert_eq!(std::fs::metadata(&name).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn open_missing_err() {
+        let dir = TempDir::new().unwrap();
+        let vfs = UnixVfs::new();
+        let name = path(&dir, "missing.db");
+        for access in [OpenAccess::ReadOnly, OpenAccess::ReadWrite] {
+            let result = vfs.open(&name, flags(access), &ConnectionOptions::default());
+            assert!(matches!(
+                result,
+                Err(SqliteError::CannotOpen {
+                    code: ExtendedResultCode::CantOpen,
+                    source: Some(_),
+                    ..
+                })
+            ));
+        }
+        assert!(!vfs.access(&name, AccessFlags::Exists).unwrap());
+    }
+
+    #[test]
+    fn delete_and_access() {
+        let dir = TempDir::new().unwrap();
+        let vfs = UnixVfs::new();
+        let name = path(&dir, "a.db");
+        std::fs::write(&name, b"x").unwrap();
+        assert!(vfs.access(&name, AccessFlags::Exists).unwrap());
+        assert!(vfs.access(&name, AccessFlags::ReadWrite).unwrap());
+        vfs.delete(&name, true).unwrap();
+        assert!(!vfs.access(&name, AccessFlags::Read).unwrap());
+        assert!(matches!(
+            vfs.delete(&name, false),
+            Err(SqliteError::IoErr {
+                code: ExtendedResultCode::IoErrDeleteNoEnt,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn delete_on_close_removes_file() {
+        let dir = TempDir::new().unwrap();
+        let vfs = UnixVfs::new();
+        let name = path(&dir, "temp.db");
+        let mut temp = flags(OpenAccess::ReadWriteCreate);
+        temp.delete_on_close = true;
+        let mut file = vfs
+            .open(&name, temp, &ConnectionOptions::default())
+            .unwrap();
+        assert!(!vfs.access(&name, AccessFlags::Exists).unwrap());
+        file.write(b"still usable", 0).unwrap();
+        assert_eq!(file.file_size().unwrap(), 12);
+    }
+
+    #[test]
+    fn handles_share_locks() {
+        let dir = TempDir::new().unwrap();
+        let vfs = UnixVfs::new();
+        let options = ConnectionOptions::default();
+        let name = path(&dir, "a.db");
+        let mut a = vfs
+            .open(&name, flags(OpenAccess::ReadWriteCreate), &options)
+            .unwrap();
+        // a different path to the same file
+        let other = format!("{}/./a.db", dir.path().display());
+        let mut b = vfs
+            .open(&other, flags(OpenAccess::ReadWrite), &options)
+            .unwrap();
+        a.lock(LockLevel::Reserved).unwrap();
+        assert!(b.check_reserved_lock().unwrap());
+        b.lock(LockLevel::Shared).unwrap();
+        assert!(b.lock(LockLevel::Reserved).is_err());
+        assert!(a.lock(LockLevel::Exclusive).is_err());
+        assert_eq!(a.lock_level(), LockLevel::Pending);
+        b.unlock(LockLevel::None).unwrap();
+        a.lock(LockLevel::Exclusive).unwrap();
+        drop(a);
+        b.lock(LockLevel::Exclusive).unwrap();
+    }
+
+    /// Probe for a conflicting lock the way another process would see it.  Open file
+    /// description locks conflict with POSIX locks even within one process.
+    #[cfg(target_os = "linux")]
+    fn conflicts(probe: &File, lock_type: i32, start: i64, len: i64) -> bool {
+        let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+        lock.l_type = lock_type as _;
+        lock.l_whence = libc::SEEK_SET as _;
+        lock.l_start = start as _;
+        lock.l_len = len as _;
+        let rc = unsafe { libc::fcntl(probe.as_raw_fd(), libc::F_OFD_GETLK, &mut lock) };
+        assert_eq!(rc, 0);
+        lock.l_type as i32 != libc::F_UNLCK
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn locks_use_sqlite3_lock_bytes() {
+        let dir = TempDir::new().unwrap();
+        let vfs = UnixVfs::new();
+        let name = path(&dir, "a.db");
+        let mut file = vfs
+            .open(
+                &name,
+                flags(OpenAccess::ReadWriteCreate),
+                &Default::default(),
+            )
+            .unwrap();
+        let probe = File::open(&name).unwrap();
+        let shared_locked =
+            |probe: &File| conflicts(probe, libc::F_WRLCK, SHARED_FIRST, SHARED_SIZE);
+        let reserved_locked = |probe: &File| conflicts(probe, libc::F_WRLCK, RESERVED_BYTE, 1);
+
+        file.lock(LockLevel::Shared).unwrap();
+        assert!(shared_locked(&probe));
+        assert!(!conflicts(&probe, libc::F_RDLCK, SHARED_FIRST, SHARED_SIZE));
+        assert!(!reserved_locked(&probe));
+
+        file.lock(LockLevel::Reserved).unwrap();
+        assert!(reserved_locked(&probe));
+        file.lock(LockLevel::Exclusive).unwrap();
+        assert!(conflicts(&probe, libc::F_RDLCK, PENDING_BYTE, 1));
+        assert!(conflicts(&probe, libc::F_RDLCK, SHARED_FIRST, SHARED_SIZE));
+
+        file.unlock(LockLevel::Shared).unwrap();
+        assert!(!conflicts(&probe, libc::F_RDLCK, SHARED_FIRST, SHARED_SIZE));
+        assert!(!reserved_locked(&probe));
+        assert!(shared_locked(&probe));
+        file.unlock(LockLevel::None).unwrap();
+        assert!(!shared_locked(&probe));
+    }
+
+    /// Take a lock as another process would
+    #[cfg(target_os = "linux")]
+    fn ofd_lock(probe: &File, lock_type: i32, start: i64, len: i64) {
+        let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+        lock.l_type = lock_type as _;
+        lock.l_whence = libc::SEEK_SET as _;
+        lock.l_start = start as _;
+        lock.l_len = len as _;
+        let rc = unsafe { libc::fcntl(probe.as_raw_fd(), libc::F_OFD_SETLK, &lock) };
+        assert_eq!(rc, 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn other_process_locks_are_busy() {
+        let dir = TempDir::new().unwrap();
+        let vfs = UnixVfs::new();
+        let name = path(&dir, "a.db");
+        let mut file = vfs
+            .open(
+                &name,
+                flags(OpenAccess::ReadWriteCreate),
+                &Default::default(),
+            )
+            .unwrap();
+        let other = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&name)
+            .unwrap();
+
+        // another process is writing
+        ofd_lock(&other, libc::F_WRLCK, RESERVED_BYTE, 1);
+        file.lock(LockLevel::Shared).unwrap();
+        assert!(file.check_reserved_lock().unwrap());
+        let result = file.lock(LockLevel::Reserved);
+        assert!(matches!(
+            result,
+            Err(SqliteError::Busy {
+                code: ExtendedResultCode::Busy,
+                ..
+            })
+        ));
+        assert_eq!(file.lock_level(), LockLevel::Shared);
+        file.unlock(LockLevel::None).unwrap();
+
+        // another process is waiting to write, so no new readers
+        ofd_lock(&other, libc::F_WRLCK, PENDING_BYTE, 1);
+        assert!(matches!(
+            file.lock(LockLevel::Shared),
+            Err(SqliteError::Busy { .. })
+        ));
+        ofd_lock(&other, libc::F_UNLCK, 0, 0);
+
+        // another process is reading, so no exclusive lock
+        ofd_lock(&other, libc::F_RDLCK, SHARED_FIRST, SHARED_SIZE);
+        assert!(matches!(
+            file.lock(LockLevel::Exclusive),
+            Err(SqliteError::Busy { .. })
+        ));
+        assert_eq!(file.lock_level(), LockLevel::Pending);
+        ofd_lock(&other, libc::F_UNLCK, 0, 0);
+        file.lock(LockLevel::Exclusive).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn closing_a_handle_keeps_locks_of_others() {
+        let dir = TempDir::new().unwrap();
+        let vfs = UnixVfs::new();
+        let name = path(&dir, "a.db");
+        let options = ConnectionOptions::default();
+        let mut a = vfs
+            .open(&name, flags(OpenAccess::ReadWriteCreate), &options)
+            .unwrap();
+        let b = vfs
+            .open(&name, flags(OpenAccess::ReadWrite), &options)
+            .unwrap();
+        let probe = File::open(&name).unwrap();
+        a.lock(LockLevel::Shared).unwrap();
+        drop(b);
+        assert!(conflicts(&probe, libc::F_WRLCK, SHARED_FIRST, SHARED_SIZE));
+        a.unlock(LockLevel::None).unwrap();
+        assert!(!conflicts(&probe, libc::F_WRLCK, SHARED_FIRST, SHARED_SIZE));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn no_lock_skips_locking() {
+        let dir = TempDir::new().unwrap();
+        let vfs = UnixVfs::new();
+        let name = path(&dir, "a.db");
+        let options = ConnectionOptions::builder().no_lock(true).build().unwrap();
+        let mut file = vfs
+            .open(&name, flags(OpenAccess::ReadWriteCreate), &options)
+            .unwrap();
+        let other = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&name)
+            .unwrap();
+        ofd_lock(&other, libc::F_WRLCK, 0, 0);
+        file.lock(LockLevel::Exclusive).unwrap();
+        assert_eq!(file.lock_level(), LockLevel::Exclusive);
+        assert!(!file.check_reserved_lock().unwrap());
+        file.unlock(LockLevel::None).unwrap();
+        assert_eq!(file.lock_level(), LockLevel::None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn shm_is_shared_and_uses_sqlite3_lock_bytes() {
+        let dir = TempDir::new().unwrap();
+        let vfs = UnixVfs::new();
+        let name = path(&dir, "a.db");
+        let options = ConnectionOptions::default();
+        let mut a = vfs
+            .open(&name, flags(OpenAccess::ReadWriteCreate), &options)
+            .unwrap();
+        let mut b = vfs
+            .open(&name, flags(OpenAccess::ReadWrite), &options)
+            .unwrap();
+        let mut shm_a = a.shm_open().unwrap().unwrap();
+        let mut shm_b = b.shm_open().unwrap().unwrap();
+        let shm_name = format!("{}{}", name, SHM_SUFFIX);
+        let probe = File::open(&shm_name).unwrap();
+        let lock_byte = |slot: usize| SHM_LOCK_OFFSET as i64 + slot as i64;
+        assert!(conflicts(&probe, libc::F_WRLCK, SHM_DMS_BYTE as i64, 1));
+        assert!(!conflicts(&probe, libc::F_RDLCK, SHM_DMS_BYTE as i64, 1));
+
+        assert!(shm_a.map(0, false).unwrap().is_none());
+        let region = shm_a.map(1, true).unwrap().unwrap();
+        assert_eq!(
+            std::fs::metadata(&shm_name).unwrap().len(),
+            2 * SHM_REGION_SIZE as u64
+        );
+        region.store_u32(8, 42);
+        assert_eq!(shm_b.map(1, false).unwrap().unwrap().load_u32(8), 42);
+        let mut buf = [0u8; 4];
+        probe.read_at(&mut buf, SHM_REGION_SIZE as u64 + 8).unwrap();
+        assert_eq!(u32::from_ne_bytes(buf), 42);
+
+        shm_a.lock(3, 1, ShmLockKind::Shared).unwrap();
+        shm_b.lock(3, 1, ShmLockKind::Shared).unwrap();
+        assert!(conflicts(&probe, libc::F_WRLCK, lock_byte(3), 1));
+        assert!(shm_a.lock(3, 1, ShmLockKind::Exclusive).is_err());
+        shm_b.unlock(3, 1).unwrap();
+        shm_a.lock(3, 1, ShmLockKind::Exclusive).unwrap();
+        assert!(conflicts(&probe, libc::F_RDLCK, lock_byte(3), 1));
+        drop(shm_a);
+        assert!(!conflicts(&probe, libc::F_WRLCK, lock_byte(3), 1));
+
+        // another process holding the write lock
+        let other = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&shm_name)
+            .unwrap();
+        ofd_lock(&other, libc::F_WRLCK, lock_byte(0), 1);
+        assert!(matches!(
+            shm_b.lock(0, 1, ShmLockKind::Exclusive),
+            Err(SqliteError::Busy { .. })
+        ));
+        shm_b.unmap(true).unwrap();
+        assert!(!vfs.access(&shm_name, AccessFlags::Exists).unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn first_shm_user_resets_it() {
+        let dir = TempDir::new().unwrap();
+        let vfs = UnixVfs::new();
+        let name = path(&dir, "a.db");
+        let shm_name = format!("{}{}", name, SHM_SUFFIX);
+        std::fs::write(&shm_name, vec![1u8; SHM_REGION_SIZE]).unwrap();
+        let mut db = vfs
+            .open(
+                &name,
+                flags(OpenAccess::ReadWriteCreate),
+                &ConnectionOptions::default(),
+            )
+            .unwrap();
+
+        // another process has it open, so what it holds is live
+        let other = File::open(&shm_name).unwrap();
+        ofd_lock(&other, libc::F_RDLCK, SHM_DMS_BYTE as i64, 1);
+        let mut shm = db.shm_open().unwrap().unwrap();
+        assert_eq!(shm.map(0, false).unwrap().unwrap().load_u32(0), 0x01010101);
+        drop(shm);
+
+        // left over from a crash
+        ofd_lock(&other, libc::F_UNLCK, 0, 0);
+        let mut shm = db.shm_open().unwrap().unwrap();
+        assert!(shm.map(0, false).unwrap().is_none());
+        assert_eq!(std::fs::metadata(&shm_name).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn full_pathname_is_absolute() {
+        let vfs = UnixVfs::new();
+        let full = vfs.full_pathname("relative.db").unwrap();
+        assert!(Path::new(&full).is_absolute());
+        assert_eq!(vfs.full_pathname("/tmp/x.db").unwrap(), "/tmp/x.db");
+    }
+}